@@ -0,0 +1,62 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Sends a command to a running zzping-daemon through its control socket.
+//!
+//! i.e.: zzping-ctl list ; zzping-ctl pause 192.168.0.1 ; zzping-ctl help
+
+use std::io::{Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+
+use clap::Parser;
+
+#[derive(Parser)]
+#[clap(
+    version = "0.2.2-beta2",
+    author = "David Martinez Marti <deavidsedice@gmail.com>"
+)]
+struct Opts {
+    /// Path of the daemon control socket
+    #[clap(short, long, default_value = "zzping-daemon.sock")]
+    socket: String,
+    /// Command to send, "help" lists them
+    #[clap(required = true)]
+    command: Vec<String>,
+}
+
+fn main() {
+    let opts: Opts = Opts::parse();
+    let mut stream = UnixStream::connect(&opts.socket).unwrap_or_else(|e| {
+        eprintln!("Unable to connect to {}: {}", opts.socket, e);
+        std::process::exit(2);
+    });
+    let line = opts.command.join(" ") + "\n";
+    let mut reply = String::new();
+    let result = stream
+        .write_all(line.as_bytes())
+        .and_then(|_| stream.shutdown(Shutdown::Write))
+        .and_then(|_| stream.read_to_string(&mut reply));
+    if let Err(e) = result {
+        eprintln!("Error talking to the daemon: {}", e);
+        std::process::exit(2);
+    }
+    match reply.strip_prefix("OK\n") {
+        Some(output) => print!("{}", output),
+        None => {
+            eprint!("{}", reply);
+            std::process::exit(1);
+        }
+    }
+}
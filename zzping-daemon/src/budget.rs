@@ -0,0 +1,112 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Global probe budget
+//!
+//! A token bucket that caps how many pings per second the daemon sends across
+//! all targets, so a big config can't flood the uplink.
+//!
+
+use std::time::{Duration, Instant};
+
+/// Token bucket rate limiter.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    /// Tokens added per second.
+    rate: f64,
+    /// Maximum amount of tokens that can be saved up.
+    capacity: f64,
+    /// Tokens available right now.
+    tokens: f64,
+    /// Last time tokens were added.
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket that refills at "rate" tokens per second.
+    pub fn new(rate: u32, capacity: u32, now: Instant) -> Self {
+        let capacity = capacity.max(1) as f64;
+        Self {
+            rate: rate.max(1) as f64,
+            capacity,
+            tokens: capacity,
+            last: now,
+        }
+    }
+
+    /// Tokens added per second.
+    pub fn rate(&self) -> u32 {
+        self.rate as u32
+    }
+
+    /// Adds the tokens earned since the last refill.
+    pub fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last = now;
+    }
+
+    /// Whether there's at least one token available.
+    pub fn has_token(&self) -> bool {
+        self.tokens >= 1.0
+    }
+
+    /// Spends one token. Call only after has_token() returned true.
+    pub fn take(&mut self) {
+        self.tokens -= 1.0;
+    }
+
+    /// When the next token will be available, counting from the last refill.
+    /// In the past if there's one already.
+    pub fn next_token(&self) -> Instant {
+        if self.has_token() {
+            return self.last;
+        }
+        self.last + Duration::from_secs_f64((1.0 - self.tokens) / self.rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(100, 3, now);
+        for _ in 0..3 {
+            assert!(bucket.has_token());
+            bucket.take();
+        }
+        assert!(!bucket.has_token());
+        let next = bucket.next_token();
+        assert_eq!(next - now, Duration::from_millis(10));
+        // Not refilled since, still counts from then.
+        bucket.refill(now + Duration::from_millis(4));
+        assert_eq!(bucket.next_token(), next);
+
+        bucket.refill(now + Duration::from_millis(25));
+        assert!(bucket.has_token());
+        bucket.take();
+        bucket.take();
+        assert!(!bucket.has_token());
+
+        // Never saves more than its capacity.
+        bucket.refill(now + Duration::from_secs(60));
+        for _ in 0..3 {
+            bucket.take();
+        }
+        assert!(!bucket.has_token());
+    }
+}
@@ -0,0 +1,125 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Clocks
+//!
+//! All the daemon logic reads the time from a Clock instead of calling
+//! Instant::now() or Utc::now(), so tests can drive it with a ManualClock.
+//!
+//! There are two clocks in play: the monotonic one, used for every interval
+//! and timeout, and the wall clock, used only to name the log files and to
+//! stamp the keyframes. The wall clock can jump (NTP steps, suspend), the
+//! monotonic one can't, but it stops while the host is suspended. A third one,
+//! the boot time, keeps counting during suspends and tells them apart from
+//! steps of the wall clock.
+//!
+
+use chrono::{DateTime, Utc};
+use std::time::{Duration, Instant};
+
+/// Source of monotonic and wall clock time.
+pub trait Clock {
+    /// Monotonic time, for intervals and timeouts.
+    fn now(&self) -> Instant;
+    /// Wall clock time, for naming files and timestamping the logs.
+    fn wall(&self) -> DateTime<Utc>;
+    /// Time since boot, counting the time spent suspended.
+    fn boottime(&self) -> Duration;
+}
+
+#[cfg(target_os = "linux")]
+const BOOT_CLOCK: libc::clockid_t = libc::CLOCK_BOOTTIME;
+/// Stops on suspend, so suspends look like steps of the wall clock.
+#[cfg(not(target_os = "linux"))]
+const BOOT_CLOCK: libc::clockid_t = libc::CLOCK_MONOTONIC;
+
+/// The clocks of the operating system.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+    fn wall(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+    fn boottime(&self) -> Duration {
+        let mut ts = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        let ret = unsafe { libc::clock_gettime(BOOT_CLOCK, &mut ts) };
+        assert_eq!(ret, 0, "clock_gettime failed");
+        Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+    }
+}
+
+/// Clock that only moves when told to. Clones share the same time.
+#[cfg(test)]
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    time: std::rc::Rc<std::cell::Cell<(Instant, DateTime<Utc>, Duration)>>,
+}
+
+#[cfg(test)]
+impl ManualClock {
+    /// Starts the clock with the wall time given.
+    pub fn new(wall: DateTime<Utc>) -> Self {
+        Self {
+            time: std::rc::Rc::new(std::cell::Cell::new((
+                Instant::now(),
+                wall,
+                Duration::from_secs(1000),
+            ))),
+        }
+    }
+    /// Moves all clocks forward.
+    pub fn advance(&self, d: Duration) {
+        let (now, wall, boot) = self.time.get();
+        let wall = wall + chrono::Duration::from_std(d).unwrap();
+        self.time.set((now + d, wall, boot + d));
+    }
+    /// Moves only the wall clock, as a NTP step would.
+    pub fn jump_wall(&self, d: chrono::Duration) {
+        let (now, wall, boot) = self.time.get();
+        self.time.set((now, wall + d, boot));
+    }
+    /// Moves all clocks but the monotonic one, as a suspend would.
+    pub fn suspend(&self, d: Duration) {
+        let (now, wall, boot) = self.time.get();
+        let wall = wall + chrono::Duration::from_std(d).unwrap();
+        self.time.set((now, wall, boot + d));
+    }
+    /// Moves both clocks forward to "deadline", if it's ahead.
+    pub fn sleep_until(&self, deadline: Instant) {
+        let now = self.now();
+        if deadline > now {
+            self.advance(deadline - now);
+        }
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.time.get().0
+    }
+    fn wall(&self) -> DateTime<Utc> {
+        self.time.get().1
+    }
+    fn boottime(&self) -> Duration {
+        self.time.get().2
+    }
+}
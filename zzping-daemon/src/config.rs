@@ -22,70 +22,650 @@
 //!     udp_listen_address: "127.0.0.1:7878",
 //!     udp_client_address: "127.0.0.1:7879",
 //!     ping_targets: [
-//!         "192.168.0.1",
+//!         TargetHost(address: "192.168.0.1", frequency: 10),
 //!     ],
 //! )
 //! ```
+//!
+//! Files ending in ".toml" are read as TOML instead, with the same fields:
+//! ```toml
+//! udp_listen_address = "127.0.0.1:7878"
+//!
+//! [[ping_targets]]
+//! address = "192.168.0.1"
+//! frequency = 10
+//! ```
+//!
+//! Everything but the targets has a default. After parsing, the config is
+//! checked for values that would fail later on (bad addresses, zero rates,
+//! duplicated targets, a missing log directory...), and all of the problems
+//! are reported at once with the line they come from.
 
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt;
 use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+
+use super::icmp::MAX_PAYLOAD_SIZE;
+use zzping_lib::secure::MIN_PSK_LEN;
+
+/// A problem found in the config file, with its line number when known.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl ConfigError {
+    fn new(line: Option<usize>, message: String) -> Self {
+        Self { line, message }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "line {}: {}", line, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Syntax of a config file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Ron,
+    Toml,
+}
+
+impl ConfigFormat {
+    /// Guesses the syntax from the file extension, RON unless it's ".toml".
+    pub fn from_path(filepath: &str) -> Self {
+        match Path::new(filepath).extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("toml") => ConfigFormat::Toml,
+            _ => ConfigFormat::Ron,
+        }
+    }
+}
+
+/// Targets whose address is found by the daemon, and followed as it changes.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd)]
+#[serde(rename_all = "lowercase")]
+pub enum AutoTarget {
+    /// Gateway of the IPv4 default route.
+    Gateway,
+}
+
+impl AutoTarget {
+    /// Name used in place of the address, for labels and logs.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AutoTarget::Gateway => "gateway",
+        }
+    }
+}
 
 /// Config for a single target host
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, PartialOrd)]
+#[serde(deny_unknown_fields)]
 pub struct TargetHost {
-    /// Target Host to ping, IP Address in string format
+    /// Target Host to ping, IP Address in string format. Empty when "auto"
+    /// is set.
+    #[serde(default)]
     pub address: String,
+    /// Find the address instead, i.e. the current default gateway
+    #[serde(default)]
+    pub auto: Option<AutoTarget>,
     /// How many pings per second to do
     pub frequency: u32,
+    /// Bytes of payload after the ICMP header
+    #[serde(default = "default_payload_size")]
+    pub payload_size: usize,
+    /// Set the Don't Fragment bit, for path MTU checks with big payloads
+    #[serde(default)]
+    pub dont_fragment: bool,
+    /// Time To Live of the pings, system default if not set
+    #[serde(default)]
+    pub ttl: Option<u8>,
+    /// DSCP class (0-63) to mark the pings with
+    #[serde(default)]
+    pub dscp: Option<u8>,
+    /// Network interface the pings must leave through (SO_BINDTODEVICE)
+    #[serde(default)]
+    pub interface: Option<String>,
+    /// Local IP Address to send the pings from
+    #[serde(default)]
+    pub source_address: Option<String>,
+    /// Probe the same target through each of these interfaces side by side
+    #[serde(default)]
+    pub interfaces: Vec<String>,
+    /// Probe the same target from each of these local addresses side by side
+    #[serde(default)]
+    pub source_addresses: Vec<String>,
+    /// Switch to a faster rate while there's trouble on this target
+    #[serde(default)]
+    pub burst: Option<BurstConfig>,
+    /// Share of the probe budget this target gets when there isn't enough
+    /// for all of them. A target with priority 2 gets twice the pings of one
+    /// with priority 1.
+    #[serde(default = "default_priority")]
+    pub priority: u32,
+}
+
+fn default_priority() -> u32 {
+    1
+}
+
+/// Config for the burst rate of a target
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, PartialOrd)]
+#[serde(deny_unknown_fields)]
+pub struct BurstConfig {
+    /// How many pings per second to do while in trouble
+    pub frequency: u32,
+    /// Packet loss percentage that triggers the burst rate
+    #[serde(default = "default_burst_loss_pct")]
+    pub loss_pct: f32,
+    /// Average latency in milliseconds that triggers the burst rate
+    #[serde(default)]
+    pub latency_ms: Option<f32>,
+    /// Seconds without trouble before going back to the base rate
+    #[serde(default = "default_burst_hold_secs")]
+    pub hold_secs: u64,
+}
+
+/// Another daemon to exchange probes with, to measure each way on its own.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PeerConfig {
+    /// Name for its logs and stats, as in "peer-{name}".
+    pub name: String,
+    /// Its "peer_listen_address", as IP:port.
+    pub address: String,
+    /// Probes per second sent to it.
+    #[serde(default = "default_peer_frequency")]
+    pub frequency: u32,
+}
+
+fn default_peer_frequency() -> u32 {
+    10
+}
+
+fn default_burst_loss_pct() -> f32 {
+    1.0
+}
+
+fn default_burst_hold_secs() -> u64 {
+    30
+}
+
+fn default_payload_size() -> usize {
+    8
 }
 
 impl TargetHost {
-    #[allow(dead_code)]
+    #[cfg(test)]
     pub fn new(address: &str, frequency: u32) -> Self {
         Self {
             address: address.to_owned(),
+            auto: None,
             frequency,
+            payload_size: default_payload_size(),
+            dont_fragment: false,
+            ttl: None,
+            dscp: None,
+            interface: None,
+            source_address: None,
+            interfaces: vec![],
+            source_addresses: vec![],
+            burst: None,
+            priority: default_priority(),
+        }
+    }
+
+    /// The address, or what it's found from for auto targets.
+    pub fn name(&self) -> &str {
+        match self.auto {
+            Some(auto) => auto.as_str(),
+            None => &self.address,
+        }
+    }
+
+    /// Expands the target into one probing stream per interface and source
+    /// address listed. A target without any of them is a single stream.
+    pub fn streams(&self) -> Vec<TargetHost> {
+        let single = TargetHost {
+            interfaces: vec![],
+            source_addresses: vec![],
+            ..self.clone()
+        };
+        if self.interfaces.is_empty() && self.source_addresses.is_empty() {
+            return vec![single];
         }
+        let by_interface = self.interfaces.iter().map(|iface| TargetHost {
+            interface: Some(iface.clone()),
+            ..single.clone()
+        });
+        let by_source = self.source_addresses.iter().map(|src| TargetHost {
+            source_address: Some(src.clone()),
+            ..single.clone()
+        });
+        by_interface.chain(by_source).collect()
     }
 }
 
 /// Config for how long to keep the old pings
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(deny_unknown_fields)]
 pub struct ForgetConfig {
+    #[serde(default = "default_keep_secs")]
     pub inflight_secs: u64,
+    #[serde(default = "default_keep_secs")]
     pub lost_secs: u64,
+    #[serde(default = "default_keep_secs")]
     pub recv_secs: u64,
 }
 
+fn default_keep_secs() -> u64 {
+    10
+}
+
+impl Default for ForgetConfig {
+    fn default() -> Self {
+        Self {
+            inflight_secs: default_keep_secs(),
+            lost_secs: default_keep_secs(),
+            recv_secs: default_keep_secs(),
+        }
+    }
+}
+
 /// Configuration parameters for the pinger daemon
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct ServerConfig {
     /// IP Address:port where the GUI would connect to.
+    #[serde(default = "default_udp_listen_address")]
     pub udp_listen_address: String,
     /// IP Address:port where the GUI is listening.
+    #[serde(default = "default_udp_client_address")]
     pub udp_client_address: String,
     /// List of hosts that will be pinged.
     pub ping_targets: Vec<TargetHost>,
     /// How long to keep the packets
+    #[serde(default)]
     pub keep_packets: ForgetConfig,
-    /// Precision multiplier. Use 1.0 for low CPU usage, but frequencies might get off. 10.0 for excellent precision.
-    pub precision_mult: f64,
     /// How many updates per second for CLI, GUI and logging
+    #[serde(default = "default_refresh_freq")]
     pub refresh_freq: u32,
+    /// Limit of pings per second, adding up all targets
+    #[serde(default = "default_max_pings_per_sec")]
+    pub max_pings_per_sec: u32,
+    /// Path of the Unix socket for zzping-ctl. Empty to disable it.
+    #[serde(default = "default_control_socket")]
+    pub control_socket: String,
+    /// Folder where the logs are written.
+    #[serde(default = "default_log_dir")]
+    pub log_dir: String,
+    /// Seconds of frames kept in memory for GUIs that ask for them. 0 to
+    /// disable it.
+    #[serde(default = "default_history_secs")]
+    pub history_secs: u64,
+    /// File in log_dir where incidents found are appended. Empty to disable it.
+    #[serde(default = "default_incident_log")]
+    pub incident_log: String,
+    /// WiFi interfaces whose signal is logged with the pings that go through
+    /// them. Empty to disable it.
+    #[serde(default)]
+    pub wireless_interfaces: Vec<String>,
+    /// Interfaces whose traffic, errors and carrier changes are logged with
+    /// the pings that go through them. Empty to disable it.
+    #[serde(default)]
+    pub link_interfaces: Vec<String>,
+    /// Key shared with the GUIs to authenticate the UDP traffic. Empty to
+    /// send it as is.
+    #[serde(default)]
+    pub psk: String,
+    /// Encrypt the UDP traffic too, and refuse what comes unencrypted.
+    /// Needs a psk.
+    #[serde(default)]
+    pub encrypt: bool,
+    /// IP Address:port where the probes of the peers are answered, and the
+    /// own probes sent from. Empty to disable it.
+    #[serde(default)]
+    pub peer_listen_address: String,
+    /// Daemons to exchange probes with. Each needs this one in its peers too.
+    #[serde(default)]
+    pub peers: Vec<PeerConfig>,
+}
+
+fn default_udp_listen_address() -> String {
+    "127.0.0.1:7878".to_owned()
+}
+
+fn default_udp_client_address() -> String {
+    "127.0.0.1:7879".to_owned()
+}
+
+fn default_refresh_freq() -> u32 {
+    50
+}
+
+fn default_control_socket() -> String {
+    "zzping-daemon.sock".to_owned()
+}
+
+fn default_log_dir() -> String {
+    "logs".to_owned()
+}
+
+fn default_history_secs() -> u64 {
+    3600
+}
+
+fn default_incident_log() -> String {
+    "incidents.log".to_owned()
+}
+
+fn default_max_pings_per_sec() -> u32 {
+    500
 }
 
 impl ServerConfig {
     /// Reads a file located in 'filepath' and constructs a ServerConfig from it.
+    #[cfg(test)]
     pub fn from_filepath(filepath: &str) -> Result<Self, Box<dyn std::error::Error>> {
         let contents = fs::read_to_string(filepath)?;
-        Self::from_str(&contents)
+        Ok(Self::parse(&contents, ConfigFormat::from_path(filepath))?)
     }
     /// Constructs a ServerConfig from the string passed.
+    #[cfg(test)]
     pub fn from_str(contents: &str) -> Result<Self, Box<dyn std::error::Error>> {
-        Ok(ron::de::from_str(contents)?)
+        Ok(Self::parse(contents, ConfigFormat::Ron)?)
+    }
+    /// Constructs a ServerConfig from the string passed, in the given syntax.
+    pub fn parse(contents: &str, format: ConfigFormat) -> Result<Self, ConfigError> {
+        // Unknown keys come without a position (RON) or with the one of their
+        // table (TOML), look for them instead.
+        let unknown_key_line = |message: &str| {
+            let key = message.strip_prefix("unknown field `")?.split('`').next()?;
+            find_key_line(contents, key, None, 0)
+        };
+        match format {
+            ConfigFormat::Ron => ron::de::from_str(contents).map_err(|e| {
+                let message = e.code.to_string();
+                let line =
+                    unknown_key_line(&message).or_else(|| Some(e.position.line).filter(|&l| l > 0));
+                ConfigError::new(line, message)
+            }),
+            ConfigFormat::Toml => toml::from_str(contents).map_err(|e| {
+                // The message already ends with the position, drop it.
+                let message = e.to_string();
+                let message = message.split(" at line ").next().unwrap_or_default();
+                let line =
+                    unknown_key_line(message).or_else(|| e.line_col().map(|(line, _)| line + 1));
+                ConfigError::new(line, message.to_owned())
+            }),
+        }
+    }
+    /// Reads, parses and validates the file located in 'filepath'. Returns
+    /// all the problems found if it can't be used.
+    pub fn load(filepath: &str) -> Result<Self, Vec<ConfigError>> {
+        let contents = fs::read_to_string(filepath)
+            .map_err(|e| vec![ConfigError::new(None, format!("unable to read: {}", e))])?;
+        let cfg = Self::parse(&contents, ConfigFormat::from_path(filepath)).map_err(|e| vec![e])?;
+        let errors = cfg.validate(&contents);
+        match errors.is_empty() {
+            true => Ok(cfg),
+            false => Err(errors),
+        }
+    }
+    /// Hash of the settings in effect, recorded in the logs on start up to
+    /// tell runs with different configs apart. Comments, formatting and
+    /// defaults written out don't change it. FNV-1a, so it's the same across
+    /// builds.
+    pub fn hash(&self) -> u64 {
+        let text = ron::to_string(self).unwrap();
+        text.bytes().fold(0xcbf2_9ce4_8422_2325, |h, b| {
+            (h ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+        })
+    }
+    /// Checks the values for anything that would fail once the daemon starts.
+    /// "source" is the text the config was parsed from, used to find the line
+    /// numbers.
+    pub fn validate(&self, source: &str) -> Vec<ConfigError> {
+        let mut errors = vec![];
+        let key_line = |key: &str| find_key_line(source, key, None, 0);
+
+        for (key, addr) in [
+            ("udp_listen_address", &self.udp_listen_address),
+            ("udp_client_address", &self.udp_client_address),
+        ] {
+            if addr.parse::<SocketAddr>().is_err() {
+                errors.push(ConfigError::new(
+                    key_line(key),
+                    format!("{} '{}' is not a valid IP:port", key, addr),
+                ));
+            }
+        }
+        for (key, value) in [
+            ("refresh_freq", self.refresh_freq as u64),
+            ("max_pings_per_sec", self.max_pings_per_sec as u64),
+            ("inflight_secs", self.keep_packets.inflight_secs),
+            ("lost_secs", self.keep_packets.lost_secs),
+            ("recv_secs", self.keep_packets.recv_secs),
+        ] {
+            if value == 0 {
+                errors.push(ConfigError::new(
+                    key_line(key),
+                    format!("{} must be at least 1", key),
+                ));
+            }
+        }
+        if !self.psk.is_empty() && self.psk.len() < MIN_PSK_LEN {
+            errors.push(ConfigError::new(
+                key_line("psk"),
+                format!("psk must be at least {} characters long", MIN_PSK_LEN),
+            ));
+        }
+        if self.encrypt && self.psk.is_empty() {
+            errors.push(ConfigError::new(
+                key_line("encrypt"),
+                "encrypt needs a psk".to_owned(),
+            ));
+        }
+        if !self.peer_listen_address.is_empty()
+            && self.peer_listen_address.parse::<SocketAddr>().is_err()
+        {
+            errors.push(ConfigError::new(
+                key_line("peer_listen_address"),
+                format!(
+                    "peer_listen_address '{}' is not a valid IP:port",
+                    self.peer_listen_address
+                ),
+            ));
+        }
+        if !self.peers.is_empty() && self.peer_listen_address.is_empty() {
+            errors.push(ConfigError::new(
+                key_line("peers"),
+                "peers need a peer_listen_address".to_owned(),
+            ));
+        }
+        for (i, peer) in self.peers.iter().enumerate() {
+            let nth = self.peers[..i].iter().filter(|p| p.name == peer.name);
+            let line = find_key_line(source, "name", Some(&peer.name), nth.count());
+            let mut error = |msg: String| {
+                let message = format!("peer '{}': {}", peer.name, msg);
+                errors.push(ConfigError::new(line, message))
+            };
+            // The name goes in file names and in the labels of the GUI.
+            if peer.name.is_empty() || peer.name.contains(['/', '%', '@']) {
+                error("name must be set, without '/', '%' or '@'".to_owned());
+            }
+            if self.peers[..i].iter().any(|p| p.name == peer.name) {
+                error("listed more than once".to_owned());
+            }
+            if peer.address.parse::<SocketAddr>().is_err() {
+                error(format!("address '{}' is not a valid IP:port", peer.address));
+            }
+            if peer.frequency == 0 {
+                error("frequency must be at least 1".to_owned());
+            }
+        }
+        if self.ping_targets.is_empty() {
+            errors.push(ConfigError::new(
+                key_line("ping_targets"),
+                "there are no ping_targets".to_owned(),
+            ));
+        }
+
+        let mut seen_streams = HashSet::new();
+        for (i, target) in self.ping_targets.iter().enumerate() {
+            // Targets are found in the text by their address; count the ones
+            // before with the same address to find the right one. Auto
+            // targets by their "auto" key.
+            let nth = self.ping_targets[..i]
+                .iter()
+                .filter(|t| match target.auto {
+                    Some(_) => t.auto.is_some(),
+                    None => t.address == target.address,
+                })
+                .count();
+            let line = match target.auto {
+                Some(_) => find_key_line(source, "auto", None, nth),
+                None => find_key_line(source, "address", Some(&target.address), nth),
+            };
+            let mut error = |msg: String| {
+                let message = format!("target '{}': {}", target.name(), msg);
+                errors.push(ConfigError::new(line, message))
+            };
+            match target.auto {
+                Some(_) if !target.address.is_empty() => {
+                    error("set either an address or auto, not both".to_owned());
+                }
+                Some(_) => {}
+                None => match target.address.parse::<IpAddr>() {
+                    Ok(IpAddr::V4(_)) => {}
+                    Ok(IpAddr::V6(_)) => error("IPv6 targets are not supported".to_owned()),
+                    Err(_) => error("not a valid IP address".to_owned()),
+                },
+            }
+            let mut rates = vec![("frequency", target.frequency)];
+            if let Some(burst) = &target.burst {
+                rates.push(("burst frequency", burst.frequency));
+                if !(0.0..=100.0).contains(&burst.loss_pct) {
+                    error(format!("burst loss_pct {} is not a %", burst.loss_pct));
+                }
+                if matches!(burst.latency_ms, Some(ms) if ms <= 0.0) {
+                    error("burst latency_ms must be over 0".to_owned());
+                }
+            }
+            for (name, freq) in rates {
+                if freq == 0 {
+                    error(format!("{} must be at least 1", name));
+                } else if freq > self.max_pings_per_sec {
+                    error(format!(
+                        "{} {} can never be reached, max_pings_per_sec is {}",
+                        name, freq, self.max_pings_per_sec
+                    ));
+                }
+            }
+            if target.payload_size > MAX_PAYLOAD_SIZE {
+                error(format!(
+                    "payload_size {} is over the maximum of {}",
+                    target.payload_size, MAX_PAYLOAD_SIZE
+                ));
+            }
+            if target.ttl == Some(0) {
+                error("ttl must be at least 1".to_owned());
+            }
+            if let Some(dscp) = target.dscp.filter(|&d| d > 63) {
+                error(format!("dscp {} is over 63", dscp));
+            }
+            if target.priority == 0 {
+                error("priority must be at least 1".to_owned());
+            }
+            let sources = target.source_address.iter();
+            for src in sources.chain(target.source_addresses.iter()) {
+                match src.parse::<IpAddr>() {
+                    Ok(IpAddr::V4(_)) => {}
+                    Ok(IpAddr::V6(_)) => {
+                        error(format!("source address '{}' is IPv6, not supported", src))
+                    }
+                    Err(_) => error(format!("source address '{}' is not a valid IP", src)),
+                }
+            }
+            for stream in target.streams() {
+                let key = (
+                    stream.name().to_owned(),
+                    stream.interface,
+                    stream.source_address,
+                );
+                if seen_streams.contains(&key) {
+                    let mut what = "listed more than once".to_owned();
+                    if let Some(iface) = &key.1 {
+                        what += &format!(" for interface {}", iface);
+                    }
+                    if let Some(src) = &key.2 {
+                        what += &format!(" from {}", src);
+                    }
+                    error(what);
+                }
+                seen_streams.insert(key);
+            }
+        }
+
+        if let Err(msg) = check_log_dir(&self.log_dir) {
+            errors.push(ConfigError::new(
+                key_line("log_dir"),
+                format!("log_dir '{}' {}", self.log_dir, msg),
+            ));
+        }
+        errors
     }
 }
 
+/// Checks that the log folder exists and files can be created in it.
+fn check_log_dir(dir: &str) -> Result<(), String> {
+    match fs::metadata(dir) {
+        Err(e) => return Err(format!("can't be used: {}", e)),
+        Ok(meta) if !meta.is_dir() => return Err("is not a directory".to_owned()),
+        Ok(_) => {}
+    }
+    tempfile::tempfile_in(dir).map_err(|e| format!("is not writable: {}", e))?;
+    Ok(())
+}
+
+/// Line number (from 1) of the nth place where "key" is set, in RON or TOML.
+/// If "value" is given, only where it's set to that string. Commented out
+/// lines are skipped.
+fn find_key_line(source: &str, key: &str, value: Option<&str>, nth: usize) -> Option<usize> {
+    let value = value.map(|v| format!("\"{}\"", v)).unwrap_or_default();
+    let patterns = [format!("{}:{}", key, value), format!("{}={}", key, value)];
+    let is_match = |line: &str| {
+        let line: String = line.chars().filter(|c| !c.is_whitespace()).collect();
+        if line.starts_with("//") || line.starts_with('#') {
+            return false;
+        }
+        patterns.iter().any(|pat| {
+            line.match_indices(pat.as_str()).any(|(pos, _)| {
+                // Don't take "source_address" for "address".
+                let before = line[..pos].chars().last();
+                !matches!(before, Some(c) if c.is_alphanumeric() || c == '_')
+            })
+        })
+    };
+    source
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| is_match(line))
+        .nth(nth)
+        .map(|(n, _)| n + 1)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -100,17 +680,227 @@ mod tests {
                     address: "192.168.0.1",
                     frequency: 10,
                 ),
+                TargetHost(
+                    address: "192.168.0.2",
+                    frequency: 5,
+                    payload_size: 1472,
+                    dont_fragment: true,
+                    ttl: Some(4),
+                    dscp: Some(46),
+                    interface: Some("eth0"),
+                    source_address: Some("192.168.0.10"),
+                    burst: Some((
+                        frequency: 50,
+                        latency_ms: Some(80.0),
+                    )),
+                ),
             ],
             keep_packets: (
                 inflight_secs: 10,
                 lost_secs: 10,
                 recv_secs: 10,
             ),
-            precision_mult: 1.0,
             refresh_freq: 15,
         )        
     "#;
 
+    #[test]
+    fn test_streams() {
+        let target = TargetHost::new("192.168.0.1", 10);
+        assert_eq!(target.streams(), vec![target.clone()]);
+
+        let target = TargetHost {
+            interfaces: vec!["wlan0".to_owned(), "eth0".to_owned()],
+            source_addresses: vec!["192.168.0.10".to_owned()],
+            ..TargetHost::new("192.168.0.1", 10)
+        };
+        let streams = target.streams();
+        assert_eq!(streams.len(), 3);
+        assert_eq!(streams[0].interface.as_deref(), Some("wlan0"));
+        assert_eq!(streams[1].interface.as_deref(), Some("eth0"));
+        assert_eq!(streams[2].interface, None);
+        assert_eq!(streams[2].source_address.as_deref(), Some("192.168.0.10"));
+        assert!(streams.iter().all(|s| s.interfaces.is_empty()));
+        assert!(streams.iter().all(|s| s.frequency == 10));
+    }
+
+    #[test]
+    fn test_defaults_and_toml() {
+        let ron_cfg = r#"ServerConfig(ping_targets: [
+            TargetHost(address: "192.168.0.1", frequency: 10),
+            TargetHost(auto: Some(gateway), frequency: 10),
+        ])"#;
+        let toml_cfg = r#"
+            refresh_freq = 50
+
+            [[ping_targets]]
+            address = "192.168.0.1"
+            frequency = 10
+
+            [[ping_targets]]
+            auto = "gateway"
+            frequency = 10
+        "#;
+        let gateway = TargetHost {
+            auto: Some(AutoTarget::Gateway),
+            ..TargetHost::new("", 10)
+        };
+        let from_ron = ServerConfig::parse(ron_cfg, ConfigFormat::Ron).unwrap();
+        let from_toml = ServerConfig::parse(toml_cfg, ConfigFormat::Toml).unwrap();
+        for cfg in [from_ron, from_toml] {
+            assert_eq!(cfg.udp_listen_address, "127.0.0.1:7878");
+            assert_eq!(
+                cfg.ping_targets,
+                vec![TargetHost::new("192.168.0.1", 10), gateway.clone()]
+            );
+            assert_eq!(cfg.keep_packets, ForgetConfig::default());
+            assert_eq!(cfg.refresh_freq, 50);
+            assert_eq!(cfg.log_dir, "logs");
+        }
+        assert_eq!(ConfigFormat::from_path("a/b.TOML"), ConfigFormat::Toml);
+        assert_eq!(
+            ConfigFormat::from_path("daemon_config.ron"),
+            ConfigFormat::Ron
+        );
+
+        let err = ServerConfig::parse("\n[[ping_targets]]\naddress = 3\n", ConfigFormat::Toml);
+        assert_eq!(err.unwrap_err().line, Some(3));
+        let err = ServerConfig::parse(
+            "ServerConfig(\n  refresh_freq: \"x\",\n)",
+            ConfigFormat::Ron,
+        );
+        assert_eq!(err.unwrap_err().line, Some(2));
+
+        // Misspelled keys aren't taken for a missing optional one.
+        let err = ServerConfig::parse(
+            "ServerConfig(\n  ping_targets: [\n    TargetHost(adress: \"1.1.1.1\"),\n  ],\n)",
+            ConfigFormat::Ron,
+        )
+        .unwrap_err();
+        assert_eq!(err.line, Some(3));
+        assert!(err.message.contains("adress"), "{}", err.message);
+        let err = ServerConfig::parse(
+            "refresh_freq = 50\nlogdir = \"/tmp\"\nping_targets = []\n",
+            ConfigFormat::Toml,
+        )
+        .unwrap_err();
+        assert_eq!(err.line, Some(2));
+        assert!(err.message.contains("logdir"), "{}", err.message);
+    }
+
+    #[test]
+    fn test_validate() {
+        let logdir = tempfile::tempdir().unwrap();
+        let source = format!(
+            r#"
+            ServerConfig(
+                udp_listen_address: "127.0.0.1",
+                ping_targets: [
+                    // TargetHost(address: "192.168.0.1", frequency: 10),
+                    TargetHost(address: "192.168.0.1", frequency: 10),
+                    TargetHost(
+                        address: "192.168.0.2",
+                        source_address: Some("192.168.0.1"),
+                        frequency: 0,
+                    ),
+                    TargetHost(address: "192.168.0.1", frequency: 600, dscp: Some(64)),
+                    TargetHost(address: "192.168.0.300", frequency: 10),
+                    TargetHost(auto: Some(gateway), frequency: 10),
+                    TargetHost(auto: Some(gateway), frequency: 10),
+                    TargetHost(address: "10.0.0.1", auto: Some(gateway), frequency: 10, interface: Some("eth0")),
+                    TargetHost(address: "fe80::1", frequency: 10),
+                ],
+                refresh_freq: 0,
+                log_dir: "{}",
+                psk: "hunter2",
+            )
+        "#,
+            logdir.path().display()
+        );
+        let cfg = ServerConfig::from_str(&source).unwrap();
+        let errors: Vec<String> = cfg
+            .validate(&source)
+            .iter()
+            .map(|e| e.to_string())
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                "line 3: udp_listen_address '127.0.0.1' is not a valid IP:port",
+                "line 19: refresh_freq must be at least 1",
+                "line 21: psk must be at least 16 characters long",
+                "line 8: target '192.168.0.2': frequency must be at least 1",
+                "line 12: target '192.168.0.1': frequency 600 can never be reached, max_pings_per_sec is 500",
+                "line 12: target '192.168.0.1': dscp 64 is over 63",
+                "line 12: target '192.168.0.1': listed more than once",
+                "line 13: target '192.168.0.300': not a valid IP address",
+                "line 15: target 'gateway': listed more than once",
+                "line 16: target 'gateway': set either an address or auto, not both",
+                "line 17: target 'fe80::1': IPv6 targets are not supported",
+            ]
+        );
+
+        let cfg = ServerConfig {
+            log_dir: logdir.path().join("missing").display().to_string(),
+            ..ServerConfig::from_str(SAMPLE_CFG).unwrap()
+        };
+        let errors = cfg.validate(SAMPLE_CFG);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].message.starts_with("log_dir"));
+
+        let cfg = ServerConfig {
+            encrypt: true,
+            log_dir: logdir.path().display().to_string(),
+            ..ServerConfig::from_str(SAMPLE_CFG).unwrap()
+        };
+        let errors = cfg.validate(SAMPLE_CFG);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "encrypt needs a psk");
+
+        let source = format!(
+            r#"
+            ServerConfig(
+                ping_targets: [TargetHost(address: "192.168.0.1", frequency: 10)],
+                log_dir: "{}",
+                peers: [
+                    (name: "pi", address: "192.168.0.20:7880"),
+                    (name: "pi", address: "192.168.0.21", frequency: 0),
+                ],
+            )
+        "#,
+            logdir.path().display()
+        );
+        let cfg = ServerConfig::from_str(&source).unwrap();
+        assert_eq!(cfg.peers[0].frequency, 10);
+        let errors: Vec<String> = cfg
+            .validate(&source)
+            .iter()
+            .map(|e| e.to_string())
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                "line 5: peers need a peer_listen_address",
+                "line 7: peer 'pi': listed more than once",
+                "line 7: peer 'pi': address '192.168.0.21' is not a valid IP:port",
+                "line 7: peer 'pi': frequency must be at least 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_hash() {
+        let cfg = ServerConfig::from_str(SAMPLE_CFG).unwrap();
+        let commented = format!("// Same settings\n{}", SAMPLE_CFG);
+        let same = ServerConfig::from_str(&commented).unwrap();
+        assert_eq!(cfg.hash(), same.hash());
+        let changed = ServerConfig {
+            refresh_freq: cfg.refresh_freq + 1,
+            ..cfg.clone()
+        };
+        assert_ne!(cfg.hash(), changed.hash());
+    }
+
     #[test]
     fn test_from_str_empty() {
         let config = "";
@@ -128,9 +918,28 @@ mod tests {
             Ok(cfg) => {
                 assert_eq!(cfg.udp_listen_address, "127.0.0.1:7878");
                 assert_eq!(cfg.udp_client_address, "127.0.0.1:7879");
-                assert_eq!(cfg.ping_targets, vec![TargetHost::new("192.168.0.1", 10)]);
-                assert!((cfg.precision_mult - 1.0).abs() < f64::EPSILON);
+                assert_eq!(cfg.ping_targets[0], TargetHost::new("192.168.0.1", 10));
+                assert_eq!(
+                    cfg.ping_targets[1],
+                    TargetHost {
+                        payload_size: 1472,
+                        dont_fragment: true,
+                        ttl: Some(4),
+                        dscp: Some(46),
+                        interface: Some("eth0".to_owned()),
+                        source_address: Some("192.168.0.10".to_owned()),
+                        burst: Some(BurstConfig {
+                            frequency: 50,
+                            loss_pct: 1.0,
+                            latency_ms: Some(80.0),
+                            hold_secs: 30,
+                        }),
+                        ..TargetHost::new("192.168.0.2", 5)
+                    }
+                );
                 assert_eq!(cfg.refresh_freq, 15);
+                assert_eq!(cfg.max_pings_per_sec, 500);
+                assert_eq!(cfg.control_socket, "zzping-daemon.sock");
                 assert_eq!(
                     cfg.keep_packets,
                     ForgetConfig {
@@ -158,8 +967,7 @@ mod tests {
             Ok(cfg) => {
                 assert_eq!(cfg.udp_listen_address, "127.0.0.1:7878");
                 assert_eq!(cfg.udp_client_address, "127.0.0.1:7879");
-                assert_eq!(cfg.ping_targets, vec![TargetHost::new("192.168.0.1", 10)]);
-                assert!((cfg.precision_mult - 1.0).abs() < f64::EPSILON);
+                assert_eq!(cfg.ping_targets[0], TargetHost::new("192.168.0.1", 10));
                 assert_eq!(cfg.refresh_freq, 15);
                 assert_eq!(
                     cfg.keep_packets,
@@ -0,0 +1,524 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Runtime control socket
+//!
+//! The daemon listens on a Unix domain socket for one line commands, so
+//! targets can be changed without restarting it (and losing the data in the
+//! queues). Each connection carries a single command; the daemon answers with
+//! "OK" or "ERR <reason>" on the first line, followed by any output, and
+//! closes the connection. zzping-ctl is a small client for it.
+//!
+
+use super::transport::{Comms, ProbeOptions};
+use std::io::{self, Read, Write};
+use std::net::IpAddr;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use zzping_lib::logevent::StopReason;
+
+/// How long a client has to send its command before it gets dropped.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(2);
+/// Longest command line accepted.
+const MAX_LINE: usize = 1024;
+/// Clients that can be connected at the same time, sending their command or
+/// reading the reply.
+const MAX_CLIENTS: usize = 16;
+/// Most of a reply kept for a client that doesn't read it.
+const MAX_REPLY: usize = 1 << 20;
+
+/// Commands accepted on the control socket.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    /// Show all targets with their live stats.
+    List,
+    /// Start probing a new target: address and pings per second.
+    Add(String, u32),
+    /// Stop probing a target, by label.
+    Remove(String),
+    /// Stop sending pings, to one target or all of them.
+    Pause(Option<String>),
+    /// Send pings again, to one target or all of them.
+    Resume(Option<String>),
+    /// Start new log files now.
+    Rotate,
+    /// Change the base rate of a target: label and pings per second.
+    Rate(String, u32),
+    /// Show the sizes of the internal queues.
+    Queues,
+    /// List the commands.
+    Help,
+}
+
+const HELP: &str = "\
+list                 targets with live stats
+add <addr> <freq>    start probing a new target
+remove <target>      stop probing a target
+pause [target]       stop sending pings (all targets if none given)
+resume [target]      send pings again (all targets if none given)
+rotate               start new log files now
+rate <target> <freq> change the base pings per second of a target
+queues               sizes of the internal queues
+help                 this list
+";
+
+impl Command {
+    /// Parses a command line as sent by the client. Frequencies over
+    /// "max_freq" (the probe budget) are refused, as in the config.
+    pub fn parse(line: &str, max_freq: u32) -> Result<Self, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let freq = |s: &str| -> Result<u32, String> {
+            match s.parse::<u32>() {
+                Ok(f) if f > max_freq => Err(format!(
+                    "frequency {} can never be reached, max_pings_per_sec is {}",
+                    f, max_freq
+                )),
+                Ok(f) if f > 0 => Ok(f),
+                _ => Err(format!("invalid frequency '{}'", s)),
+            }
+        };
+        // Pings go out over ICMPv4 only.
+        let addr = |s: &str| -> Result<String, String> {
+            match s.parse::<IpAddr>() {
+                Ok(IpAddr::V4(_)) => Ok(s.to_owned()),
+                Ok(IpAddr::V6(_)) => Err(format!("'{}': IPv6 targets are not supported", s)),
+                Err(_) => Err(format!("invalid address '{}'", s)),
+            }
+        };
+        match words.as_slice() {
+            ["list"] => Ok(Command::List),
+            ["add", a, f] => Ok(Command::Add(addr(a)?, freq(f)?)),
+            ["remove", label] => Ok(Command::Remove(label.to_string())),
+            ["pause"] => Ok(Command::Pause(None)),
+            ["pause", label] => Ok(Command::Pause(Some(label.to_string()))),
+            ["resume"] => Ok(Command::Resume(None)),
+            ["resume", label] => Ok(Command::Resume(Some(label.to_string()))),
+            ["rotate"] => Ok(Command::Rotate),
+            ["rate", label, f] => Ok(Command::Rate(label.to_string(), freq(f)?)),
+            ["queues"] => Ok(Command::Queues),
+            ["help"] => Ok(Command::Help),
+            [] => Err("empty command".to_owned()),
+            _ => Err(format!("unknown command '{}', try 'help'", line.trim())),
+        }
+    }
+}
+
+/// What the main loop needs to do after a command, besides the reply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    None,
+    /// Create new log files for all targets.
+    RotateLogs,
+    /// Create a log file for the last target added.
+    OpenLog,
+}
+
+/// A connection that hasn't sent its whole command yet.
+struct Client {
+    stream: UnixStream,
+    /// What was read so far.
+    buf: Vec<u8>,
+    /// When it connected, to drop it if it takes too long.
+    since: Instant,
+}
+
+impl Client {
+    /// Reads whatever the client sent, without blocking. Returns the command
+    /// line once it's complete.
+    fn read(&mut self) -> io::Result<Option<String>> {
+        let mut chunk = [0; 256];
+        loop {
+            match self.stream.read(&mut chunk) {
+                // Closed without a newline, take what was sent.
+                Ok(0) if !self.buf.is_empty() => break,
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => {
+                    self.buf.extend_from_slice(&chunk[..n]);
+                    if self.buf.contains(&b'\n') {
+                        break;
+                    }
+                    if self.buf.len() > MAX_LINE {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "command too long",
+                        ));
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let end = self.buf.iter().position(|&b| b == b'\n');
+        let line = &self.buf[..end.map_or(self.buf.len(), |e| e + 1)];
+        Ok(Some(String::from_utf8_lossy(line).into_owned()))
+    }
+}
+
+/// A reply the client hasn't taken in full yet.
+struct Reply {
+    stream: UnixStream,
+    /// What's left to send.
+    buf: Vec<u8>,
+    /// When it was first sent, to drop the client if it doesn't read it.
+    since: Instant,
+}
+
+impl Reply {
+    /// Sends as much as the client takes, without blocking. Returns whether
+    /// it was all sent.
+    fn flush(&mut self) -> io::Result<bool> {
+        while !self.buf.is_empty() {
+            match self.stream.write(&self.buf) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.buf.drain(..n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Listens for commands on a Unix domain socket.
+///
+/// Nothing here blocks: clients are read as their data arrives and replies
+/// are sent as they take them, so one that connects and stays quiet doesn't
+/// hold up the pings.
+pub struct ControlServer {
+    listener: UnixListener,
+    path: PathBuf,
+    /// Connected clients still sending their command.
+    clients: Vec<Client>,
+    /// Connected clients still reading their reply.
+    replies: Vec<Reply>,
+}
+
+impl ControlServer {
+    /// Binds the socket on "path", replacing a stale socket left behind by a
+    /// previous run.
+    ///
+    /// The socket is only usable by the user running the daemon (mode 0600),
+    /// whatever the umask: anyone able to connect could change the targets.
+    pub fn bind(path: &str) -> io::Result<Self> {
+        let path = PathBuf::from(path);
+        if let Ok(meta) = std::fs::symlink_metadata(&path) {
+            if !meta.file_type().is_socket() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} exists and is not a socket", path.display()),
+                ));
+            }
+            std::fs::remove_file(&path)?;
+        }
+        // Created without permissions for others from the start, so nobody
+        // can connect before the mode is set.
+        let umask = unsafe { libc::umask(0o177) };
+        let listener = UnixListener::bind(&path);
+        unsafe { libc::umask(umask) };
+        let listener = listener?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            path,
+            clients: vec![],
+            replies: vec![],
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Accepts the pending clients, sends what's left of the replies and
+    /// returns the first command that is complete, if any.
+    ///
+    /// Returns the stream to reply on along with the command line.
+    pub fn poll(&mut self) -> Option<(UnixStream, String)> {
+        self.flush_replies();
+        self.accept();
+        let now = Instant::now();
+        let mut i = 0;
+        while i < self.clients.len() {
+            let client = &mut self.clients[i];
+            match client.read() {
+                Ok(Some(line)) => return Some((self.clients.swap_remove(i).stream, line)),
+                Ok(None) if now.saturating_duration_since(client.since) < CLIENT_TIMEOUT => {
+                    i += 1;
+                }
+                Ok(None) => {
+                    warn!("Control socket: client took too long to send a command");
+                    self.clients.swap_remove(i);
+                }
+                Err(e) => {
+                    warn!("Control socket: error reading a command: {}", e);
+                    self.clients.swap_remove(i);
+                }
+            }
+        }
+        None
+    }
+
+    /// Sends the reply for a command and closes the connection. What the
+    /// client doesn't take right away is sent on the next polls.
+    pub fn reply(&mut self, stream: UnixStream, result: &Result<String, String>) {
+        let text = match result {
+            Ok(output) => format!("OK\n{}", output),
+            Err(e) => format!("ERR {}\n", e),
+        };
+        let mut reply = Reply {
+            stream,
+            buf: text.into_bytes(),
+            since: Instant::now(),
+        };
+        match reply.flush() {
+            Ok(true) => {}
+            Ok(false) if reply.buf.len() <= MAX_REPLY => self.replies.push(reply),
+            Ok(false) => warn!("Control socket: client isn't reading its reply, dropping it"),
+            Err(e) => warn!("Control socket: error replying: {}", e),
+        }
+    }
+
+    fn flush_replies(&mut self) {
+        let now = Instant::now();
+        self.replies.retain_mut(|reply| match reply.flush() {
+            Ok(true) => false,
+            Ok(false) if now.saturating_duration_since(reply.since) < CLIENT_TIMEOUT => true,
+            Ok(false) => {
+                warn!("Control socket: client took too long to read its reply");
+                false
+            }
+            Err(e) => {
+                warn!("Control socket: error replying: {}", e);
+                false
+            }
+        });
+    }
+
+    fn accept(&mut self) {
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    warn!("Control socket: error accepting a client: {}", e);
+                    return;
+                }
+            };
+            if self.clients.len() + self.replies.len() >= MAX_CLIENTS {
+                warn!("Control socket: too many clients, dropping one");
+                continue;
+            }
+            if let Err(e) = stream.set_nonblocking(true) {
+                warn!("Control socket: error accepting a client: {}", e);
+                continue;
+            }
+            self.clients.push(Client {
+                stream,
+                buf: vec![],
+                since: Instant::now(),
+            });
+        }
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Runs a command against the pinger. Returns the output for the client and
+/// what's left for the main loop to do.
+pub fn execute(cmd: &Command, t: &mut Comms) -> (Result<String, String>, Action) {
+    let now = t.clock.now();
+    let find = |t: &Comms, label: &str| -> Result<usize, String> {
+        t.dest
+            .iter()
+            .position(|d| d.label == label)
+            .ok_or_else(|| format!("no target named '{}'", label))
+    };
+    let set_paused = |t: &mut Comms, label: &Option<String>, paused: bool| {
+        match label {
+            Some(label) => {
+                let i = find(t, label)?;
+                t.dest[i].paused = paused;
+            }
+            None => t.dest.iter_mut().for_each(|d| d.paused = paused),
+        }
+        Ok(String::new())
+    };
+    match cmd {
+        Command::List => {
+            let mut out = String::new();
+            for d in t.dest.iter() {
+                let window = Duration::from_secs(1);
+                let (lost, recv) = d.loss_window(now, window, Duration::from_millis(300));
+                let avg = d.mean_recv_time(now, window).unwrap_or_default();
+                out += &format!(
+                    "{} rate={:.1}/s sent={} recv={} loss_1s={}/{} avg_ms={:.2}{}\n",
+                    d.label,
+                    d.probe_rate(),
+                    d.sent_count,
+                    d.recv_count,
+                    lost,
+                    lost + recv,
+                    avg.as_secs_f32() * 1000.0,
+                    if d.paused { " paused" } else { "" },
+                );
+            }
+            (Ok(out), Action::None)
+        }
+        Command::Add(addr, freq) => {
+            let label = ProbeOptions::default().label(addr);
+            if t.dest.iter().any(|d| d.label == label) {
+                return (
+                    Err(format!("'{}' is already a target", label)),
+                    Action::None,
+                );
+            }
+            let interval = Duration::from_secs(1) / *freq;
+            match t.add_destination(addr, interval, ProbeOptions::default()) {
+                Ok(()) => (Ok(String::new()), Action::OpenLog),
+                Err(e) => (Err(e.to_string()), Action::None),
+            }
+        }
+        Command::Remove(label) => match find(t, label) {
+            Ok(i) => {
+                let mut dest = t.dest.remove(i);
+                dest.stop(StopReason::TargetRemoved);
+                (Ok(String::new()), Action::None)
+            }
+            Err(e) => (Err(e), Action::None),
+        },
+        Command::Pause(label) => (set_paused(t, label, true), Action::None),
+        Command::Resume(label) => (set_paused(t, label, false), Action::None),
+        Command::Rotate => (Ok(String::new()), Action::RotateLogs),
+        Command::Rate(label, freq) => match find(t, label) {
+            Ok(i) => {
+                t.dest[i].set_base_interval(Duration::from_secs(1) / *freq);
+                (Ok(String::new()), Action::None)
+            }
+            Err(e) => (Err(e), Action::None),
+        },
+        Command::Queues => {
+            let mut out = String::new();
+            for d in t.dest.iter() {
+                out += &format!(
+                    "{} inflight={} recv={} lost={}\n",
+                    d.label,
+                    d.inflight_packets.len(),
+                    d.recv_packets.len(),
+                    d.lost_packets.len(),
+                );
+            }
+            (Ok(out), Action::None)
+        }
+        Command::Help => (Ok(HELP.to_owned()), Action::None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(Command::parse("list\n", 500), Ok(Command::List));
+        assert_eq!(
+            Command::parse("add 192.168.0.1 10", 500),
+            Ok(Command::Add("192.168.0.1".to_owned(), 10))
+        );
+        assert_eq!(Command::parse("pause", 500), Ok(Command::Pause(None)));
+        assert_eq!(
+            Command::parse(" resume  1.1.1.1 ", 500),
+            Ok(Command::Resume(Some("1.1.1.1".to_owned())))
+        );
+        assert_eq!(
+            Command::parse("rate 192.168.0.1%wlan0 50", 500),
+            Ok(Command::Rate("192.168.0.1%wlan0".to_owned(), 50))
+        );
+        assert!(Command::parse("rate 1.1.1.1 0", 500).is_err());
+        assert!(Command::parse("rate 1.1.1.1 501", 500).is_err());
+        assert!(Command::parse("add 1.1.1.1 4294967295", 500).is_err());
+        assert!(Command::parse("add 1.1.1.1", 500).is_err());
+        assert!(Command::parse("add 1.1.1 10", 500).is_err());
+        assert!(Command::parse("add fe80::1 10", 500).is_err());
+        assert!(Command::parse("", 500).is_err());
+        assert!(Command::parse("reboot", 500).is_err());
+    }
+
+    #[test]
+    fn test_poll_partial_command() {
+        let path = std::env::temp_dir().join(format!("zzping-test-{}.sock", std::process::id()));
+        let mut server = ControlServer::bind(path.to_str().unwrap()).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let mut quiet = UnixStream::connect(&path).unwrap();
+        let mut client = UnixStream::connect(&path).unwrap();
+        // Neither has sent a full line, so nothing to do and no waiting.
+        client.write_all(b"li").unwrap();
+        assert!(server.poll().is_none());
+        client.write_all(b"st\n").unwrap();
+        let (stream, line) = server.poll().unwrap();
+        assert_eq!(line, "list\n");
+        server.reply(stream, &Ok(String::new()));
+        let mut answer = String::new();
+        client.read_to_string(&mut answer).unwrap();
+        assert_eq!(answer, "OK\n");
+        quiet.write_all(b"rotate").unwrap();
+        drop(quiet);
+        assert_eq!(server.poll().unwrap().1, "rotate");
+    }
+
+    #[test]
+    fn test_reply_slow_client() {
+        let path =
+            std::env::temp_dir().join(format!("zzping-test-{}-slow.sock", std::process::id()));
+        let mut server = ControlServer::bind(path.to_str().unwrap()).unwrap();
+        let mut client = UnixStream::connect(&path).unwrap();
+        let mut stuck = UnixStream::connect(&path).unwrap();
+        client.write_all(b"list\n").unwrap();
+        stuck.write_all(b"list\n").unwrap();
+
+        // Neither reads yet: more than the socket holds is kept for later,
+        // and way more than that is not.
+        let output = "x".repeat(MAX_REPLY / 2);
+        let (stream, _) = server.poll().unwrap();
+        server.reply(stream, &Ok(output.clone()));
+        let (stream, _) = server.poll().unwrap();
+        server.reply(stream, &Ok("x".repeat(MAX_REPLY * 2)));
+        assert_eq!(server.replies.len(), 1);
+
+        client.set_nonblocking(true).unwrap();
+        let mut answer = vec![];
+        let mut chunk = [0; 4096];
+        loop {
+            match client.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(n) => answer.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => assert!(server.poll().is_none()),
+                Err(e) => panic!("{}", e),
+            }
+        }
+        assert_eq!(answer, format!("OK\n{}", output).into_bytes());
+        assert!(server.replies.is_empty());
+    }
+}
@@ -0,0 +1,142 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Finds the default gateway in the kernel routing table, for the targets
+//! that follow it instead of having a fixed address.
+//!
+//! The table lists one route per line. Default routes are the ones to
+//! 0.0.0.0/0 through a gateway, and when there are several, the one with the
+//! lowest metric is used, as the kernel does.
+//!
+//! Only IPv4 is followed: pings are sent over an ICMPv4 socket, so an IPv6
+//! gateway couldn't be probed.
+
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+
+/// IPv4 routing table.
+const ROUTE_PATH: &str = "/proc/net/route";
+
+/// Route flags, from linux/route.h.
+const RTF_UP: u32 = 0x1;
+const RTF_GATEWAY: u32 = 0x2;
+
+/// Gateway of the IPv4 default route with the lowest metric, out of the
+/// contents of /proc/net/route. Only routes through "interface", if set.
+///
+/// Addresses are in hex, in the byte order of the host.
+pub fn default_gateway_v4(table: &str, interface: Option<&str>) -> Option<Ipv4Addr> {
+    table
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let f: Vec<&str> = line.split_whitespace().collect();
+            if f.len() < 8 || interface.is_some_and(|iface| iface != f[0]) {
+                return None;
+            }
+            let dest = u32::from_str_radix(f[1], 16).ok()?;
+            let gateway = u32::from_str_radix(f[2], 16).ok()?;
+            let flags = u32::from_str_radix(f[3], 16).ok()?;
+            let metric: u32 = f[6].parse().ok()?;
+            let mask = u32::from_str_radix(f[7], 16).ok()?;
+            let up = flags & (RTF_UP | RTF_GATEWAY) == RTF_UP | RTF_GATEWAY;
+            match up && dest == 0 && mask == 0 {
+                true => Some((metric, Ipv4Addr::from(gateway.to_ne_bytes()))),
+                false => None,
+            }
+        })
+        .min_by_key(|(metric, _)| *metric)
+        .map(|(_, gateway)| gateway)
+}
+
+/// Follows the gateway for an auto target.
+#[derive(Debug)]
+pub struct GatewayWatch {
+    /// Only routes through this interface count, for targets bound to one.
+    interface: Option<String>,
+    table: PathBuf,
+    /// Gateway last found.
+    pub current: Option<IpAddr>,
+}
+
+impl GatewayWatch {
+    pub fn new(interface: Option<String>) -> Self {
+        Self {
+            interface,
+            table: PathBuf::from(ROUTE_PATH),
+            current: None,
+        }
+    }
+
+    /// Reads the routing table again. Returns the gateway if it changed.
+    /// Without a default route the last gateway is kept: pings to it fail,
+    /// and that's what's going on.
+    pub fn check(&mut self) -> io::Result<Option<IpAddr>> {
+        let table = fs::read_to_string(&self.table)?;
+        let found = default_gateway_v4(&table, self.interface.as_deref()).map(IpAddr::V4);
+        match found {
+            Some(gateway) if self.current != found => {
+                self.current = found;
+                Ok(Some(gateway))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROUTE: &str = include_str!("../fixtures/proc_net_route");
+
+    #[test]
+    fn test_default_gateway() {
+        // wg0 has the lowest metric, but it's down.
+        let v4 = |iface| default_gateway_v4(ROUTE, iface).map(|x| x.to_string());
+        assert_eq!(v4(None).as_deref(), Some("10.0.0.1"));
+        assert_eq!(v4(Some("wlan0")).as_deref(), Some("192.168.1.1"));
+        assert_eq!(v4(Some("wg0")), None);
+        assert_eq!(default_gateway_v4("Iface\tDestination\n", None), None);
+    }
+
+    #[test]
+    fn test_watch() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut watch = GatewayWatch::new(None);
+        watch.table = dir.path().join("route");
+        assert!(watch.check().is_err());
+
+        fs::write(&watch.table, ROUTE).unwrap();
+        let first = watch.check().unwrap();
+        assert_eq!(first, Some("10.0.0.1".parse().unwrap()));
+        assert_eq!(watch.check().unwrap(), None);
+
+        // Roaming: the wired link goes away.
+        let roamed: String = ROUTE
+            .lines()
+            .filter(|l| !l.starts_with("eth0"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        fs::write(&watch.table, &roamed).unwrap();
+        assert_eq!(watch.check().unwrap(), Some("192.168.1.1".parse().unwrap()));
+
+        // No route at all keeps the last one.
+        fs::write(&watch.table, ROUTE.lines().next().unwrap()).unwrap();
+        assert_eq!(watch.check().unwrap(), None);
+        assert_eq!(watch.current, Some("192.168.1.1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,137 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Health of the host running the daemon. When it's overloaded, pings go out
+//! late and replies are read late, and the latency looks worse than the
+//! network really is; frames measured like that are flagged as untrusted.
+
+use std::fs;
+use std::io;
+use std::time::Duration;
+
+use zzping_lib::logevent::HostHealth;
+
+const LOADAVG_PATH: &str = "/proc/loadavg";
+const CPU_PRESSURE_PATH: &str = "/proc/pressure/cpu";
+
+/// Pings sent this late after they were due make the frame untrusted.
+const UNTRUSTED_SEND_LATE: Duration = Duration::from_millis(50);
+
+/// Percent of time tasks waited for a CPU that makes frames untrusted.
+const UNTRUSTED_CPU_PRESSURE: f32 = 20.0;
+
+/// Load average over the last minute, out of the contents of /proc/loadavg.
+pub fn parse_loadavg(text: &str) -> Option<f32> {
+    text.split_whitespace().next()?.parse().ok()
+}
+
+/// Share of the last 10s that some task waited for a CPU, in percent, out
+/// of the contents of /proc/pressure/cpu.
+pub fn parse_cpu_pressure(text: &str) -> Option<f32> {
+    let some = text.lines().find(|l| l.starts_with("some "))?;
+    some.split_whitespace()
+        .find_map(|x| x.strip_prefix("avg10="))?
+        .parse()
+        .ok()
+}
+
+/// Worst delays seen by the main loop since they were last taken.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Lateness {
+    /// From when a ping was due to when it was sent.
+    pub send: Duration,
+    /// From when the kernel received a reply to when it was matched. Only
+    /// known with kernel stamps, and then it doesn't add to the RTT, so it's
+    /// logged but doesn't make frames untrusted.
+    pub recv: Duration,
+}
+
+impl Lateness {
+    pub fn add_send(&mut self, late: Duration) {
+        self.send = self.send.max(late);
+    }
+
+    pub fn add_recv(&mut self, delay: Duration) {
+        self.recv = self.recv.max(delay);
+    }
+
+    pub fn merge(&mut self, other: &Lateness) {
+        self.add_send(other.send);
+        self.add_recv(other.recv);
+    }
+
+    /// Whether these delays, or the last sample of the host, are enough to
+    /// distrust what was measured meanwhile.
+    pub fn untrusted(&self, health: Option<&HostHealth>) -> bool {
+        let pressure = health.and_then(|h| h.cpu_pressure).unwrap_or_default();
+        self.send >= UNTRUSTED_SEND_LATE || pressure >= UNTRUSTED_CPU_PRESSURE
+    }
+}
+
+/// Takes a sample of the host, with the worst delays since the last one.
+/// Fails if the load average can't be read; the CPU pressure is left out
+/// on kernels without it.
+pub fn sample(lateness: &Lateness) -> io::Result<HostHealth> {
+    let text = fs::read_to_string(LOADAVG_PATH)?;
+    let load1 = parse_loadavg(&text)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "unexpected loadavg format"))?;
+    let cpu_pressure = fs::read_to_string(CPU_PRESSURE_PATH)
+        .ok()
+        .and_then(|x| parse_cpu_pressure(&x));
+    Ok(HostHealth {
+        load1,
+        cpu_pressure,
+        send_late_ms: lateness.send.as_secs_f32() * 1000.0,
+        recv_delay_ms: lateness.recv.as_secs_f32() * 1000.0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse_loadavg("0.35 0.42 0.27 2/71 27168\n"), Some(0.35));
+        assert_eq!(parse_loadavg(""), None);
+        let pressure = "some avg10=12.50 avg60=2.86 avg300=3.15 total=366245386\n\
+                        full avg10=0.00 avg60=0.00 avg300=0.00 total=0\n";
+        assert_eq!(parse_cpu_pressure(pressure), Some(12.5));
+        assert_eq!(parse_cpu_pressure("full avg10=1.00\n"), None);
+    }
+
+    #[test]
+    fn test_untrusted() {
+        let mut late = Lateness::default();
+        late.add_send(Duration::from_millis(3));
+        late.add_recv(Duration::from_millis(1));
+        assert!(!late.untrusted(None));
+
+        let busy = HostHealth {
+            cpu_pressure: Some(35.0),
+            ..Default::default()
+        };
+        assert!(late.untrusted(Some(&busy)));
+
+        // Replies read late were stamped by the kernel, the RTT is fine.
+        late.merge(&Lateness {
+            send: Duration::ZERO,
+            recv: Duration::from_millis(25),
+        });
+        assert_eq!(late.send, Duration::from_millis(3));
+        assert!(!late.untrusted(None));
+        late.add_send(Duration::from_millis(60));
+        assert!(late.untrusted(None));
+    }
+}
@@ -0,0 +1,202 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Recent frames of each destination, kept in memory so GUIs that connect
+//! late can ask for them over UDP.
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use zzping_lib::framedata::{FrameData, FrameTime};
+use zzping_lib::framedataq::{Complete, FrameDataQ};
+use zzping_lib::framestats::{HistoryChunk, HistoryRequest};
+
+/// Maximum size of a chunk, so it fits a packet without fragmenting.
+const CHUNK_BYTES: usize = 1200;
+
+/// Frames to look at for a chunk; more than ever fit in CHUNK_BYTES.
+const CHUNK_FRAMES: usize = 256;
+
+/// Frames written in the last "keep" time, with full timestamps.
+#[derive(Debug, Default)]
+pub struct History {
+    keep: Duration,
+    /// Time of the last keyframe, to place the frames in between.
+    last_keyframe: Option<DateTime<Utc>>,
+    frames: VecDeque<FrameDataQ<Complete>>,
+}
+
+impl History {
+    /// A history of zero seconds keeps nothing.
+    pub fn new(keep: Duration) -> Self {
+        Self {
+            keep,
+            ..Default::default()
+        }
+    }
+
+    /// Adds a frame, and returns it placed in time; frames before the first
+    /// keyframe can't be.
+    pub fn push(&mut self, fd: &FrameData) -> Option<FrameDataQ<Complete>> {
+        let time = match fd.time {
+            FrameTime::Timestamp(ts) => {
+                self.last_keyframe = Some(ts);
+                ts
+            }
+            FrameTime::Elapsed(e) => match self.last_keyframe {
+                Some(ts) => ts + chrono::Duration::from_std(e).unwrap(),
+                None => return None,
+            },
+        };
+        let mut fd = fd.clone();
+        fd.time = FrameTime::Timestamp(time);
+        // Neither the history nor the incidents use sketches.
+        let frame = FrameDataQ::from_framedata_no_sketch(&fd);
+        if self.keep.is_zero() {
+            return Some(frame);
+        }
+        self.frames.push_back(frame.clone());
+        let oldest = time - chrono::Duration::from_std(self.keep).unwrap();
+        while matches!(self.frames.front(), Some(f) if f.get_datetime() < oldest) {
+            self.frames.pop_front();
+        }
+        Some(frame)
+    }
+
+    /// Up to "limit" frames from "from_ms" on, and how many there are in
+    /// total. Times are in milliseconds since the epoch.
+    ///
+    /// Frames are kept in time order, so the range is found by bisection.
+    fn after(
+        &self,
+        from_ms: i64,
+        to_ms: Option<i64>,
+        limit: usize,
+    ) -> (Vec<FrameDataQ<Complete>>, usize) {
+        let ts = |f: &FrameDataQ<Complete>| f.get_timestamp_ms() as i64;
+        let start = self.frames.partition_point(|f| ts(f) < from_ms);
+        let end = match to_ms {
+            Some(to) => self.frames.partition_point(|f| ts(f) <= to).max(start),
+            None => self.frames.len(),
+        };
+        let frames = self.frames.range(start..end).take(limit).cloned();
+        (frames.collect(), end - start)
+    }
+}
+
+/// A HistoryRequest being answered, a few chunks on every refresh.
+#[derive(Debug)]
+pub struct Backfill {
+    /// Where to send the chunks, the address the request came from.
+    pub addr: SocketAddr,
+    pub target: String,
+    /// Time of the next frame to send.
+    cursor_ms: i64,
+    to_ms: Option<i64>,
+    seq: u32,
+}
+
+impl Backfill {
+    pub fn new(addr: SocketAddr, req: HistoryRequest) -> Self {
+        Self {
+            addr,
+            target: req.target,
+            cursor_ms: req.from_ms,
+            to_ms: req.to_ms,
+            seq: 0,
+        }
+    }
+
+    /// Encodes the next chunk to send, and whether it's the last one. With no
+    /// end time, it's the last one once it catches up with the history.
+    pub fn next_chunk(&mut self, history: &History) -> (Vec<u8>, bool) {
+        let (frames, total) = history.after(self.cursor_ms, self.to_ms, CHUNK_FRAMES);
+        let (msg, used) = HistoryChunk::encode_frames(&self.target, self.seq, &frames, CHUNK_BYTES);
+        if let Some(last) = frames[..used].last() {
+            self.cursor_ms = last.get_timestamp_ms() as i64 + 1;
+        }
+        self.seq += 1;
+        (msg, used == total)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use zzping_lib::logevent::FrameMeta;
+
+    fn frame(time: FrameTime) -> FrameData {
+        FrameData {
+            time,
+            inflight: 0,
+            lost_packets: 0,
+            recv_us: vec![1000; 20],
+            meta: FrameMeta::default(),
+        }
+    }
+
+    #[test]
+    fn test_backfill() {
+        let start = Utc.timestamp_millis(1_600_000_000_000);
+        let mut history = History::new(Duration::from_secs(60));
+        // Frames before the first keyframe can't be placed.
+        assert!(history
+            .push(&frame(FrameTime::Elapsed(Duration::from_millis(100))))
+            .is_none());
+        for n in 0..1000 {
+            let time = match n % 150 {
+                0 => FrameTime::Timestamp(start + chrono::Duration::milliseconds(n * 100)),
+                e => FrameTime::Elapsed(Duration::from_millis(e as u64 * 100)),
+            };
+            history.push(&frame(time));
+        }
+        // 100s were pushed, only the last 60s are kept.
+        assert_eq!(history.frames.len(), 601);
+        assert_eq!(history.frames[0].get_timestamp_ms(), 1_600_000_039_900);
+
+        let req = HistoryRequest {
+            target: "10.0.0.1".to_owned(),
+            from_ms: 1_600_000_090_000,
+            to_ms: None,
+        };
+        let mut backfill = Backfill::new("127.0.0.1:7878".parse().unwrap(), req);
+        let mut frames = vec![];
+        loop {
+            let (msg, done) = backfill.next_chunk(&history);
+            assert!(msg.len() <= CHUNK_BYTES);
+            frames.extend(HistoryChunk::decode(&msg).unwrap().frames);
+            if done {
+                break;
+            }
+        }
+        assert!(backfill.seq > 1);
+        assert_eq!(frames.len(), 100);
+        assert_eq!(frames[99].get_timestamp_ms(), 1_600_000_099_900);
+
+        let (frames, total) = history.after(1_600_000_090_000, Some(1_600_000_090_500), 4);
+        assert_eq!((frames.len(), total), (4, 6));
+        assert_eq!(frames[0].get_timestamp_ms(), 1_600_000_090_000);
+        assert_eq!(history.after(1_600_000_090_000, Some(0), 4).1, 0);
+        assert_eq!(history.after(0, None, 4).1, 601);
+
+        // Once caught up, new frames go in a new, last, chunk.
+        history.push(&frame(FrameTime::Elapsed(Duration::from_millis(10_000))));
+        let (msg, done) = backfill.next_chunk(&history);
+        assert!(done);
+        assert_eq!(HistoryChunk::decode(&msg).unwrap().frames.len(), 1);
+    }
+}
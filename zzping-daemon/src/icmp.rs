@@ -21,12 +21,22 @@
 use pnet::packet::icmp::echo_reply::MutableEchoReplyPacket;
 use pnet::packet::icmp::IcmpTypes;
 use pnet::packet::icmp::{echo_request, IcmpPacket};
+use pnet::packet::ipv4::Ipv4Packet;
 use pnet::packet::Packet;
 use pnet::util;
 use pnet_transport::TransportSender;
 
+use super::timestamping::StampSource;
+
+use std::io;
 use std::net::IpAddr;
-use std::time::{Duration, Instant, SystemTime};
+use std::time::{Duration, Instant};
+
+/// Size of the ICMP Echo header: type, code, checksum, identifier and sequence.
+pub const ECHO_HEADER_LEN: usize = 8;
+
+/// Biggest payload that fits in an IPv4 datagram after the IP and ICMP headers.
+pub const MAX_PAYLOAD_SIZE: usize = 65507 - ECHO_HEADER_LEN;
 
 /// Describes an ICMP Packet; Usually not sent yet, unless inside of PacketSent.
 #[derive(Debug, Clone)]
@@ -39,6 +49,8 @@ pub struct PacketData {
     pub addr: IpAddr,
     /// Time when it was received (if it was). Used to compute later the timing
     pub received: Option<Instant>,
+    /// How the receive time was taken.
+    pub stamp: StampSource,
 }
 
 impl PacketData {
@@ -49,8 +61,16 @@ impl PacketData {
             ident,
             addr,
             received: None,
+            stamp: StampSource::Userspace,
         }
     }
+    /// Parse a received IPv4 packet carrying ICMP from given address.
+    pub fn parse_ipv4(buffer: &[u8], addr: IpAddr) -> Option<Self> {
+        let ip_header = Ipv4Packet::new(buffer)?;
+        let offset = ip_header.get_header_length() as usize * 4;
+        let packet = IcmpPacket::new(buffer.get(offset..)?)?;
+        Some(Self::parse(packet, addr))
+    }
     /// Parse a received ICMP Packet from given address.
     pub fn parse(packet: IcmpPacket, addr: IpAddr) -> Self {
         let mut pck = packet.packet().to_vec();
@@ -60,13 +80,21 @@ impl PacketData {
             ident: packet.get_identifier(),
             addr,
             received: None,
+            stamp: StampSource::Userspace,
         }
     }
-    /// Send this ICMP packet using the given transport sender.
-    pub fn send(self, tx: &mut TransportSender) -> PacketSent {
-        PacketSent::new(self, tx)
+    /// Send this ICMP packet with "payload_size" bytes of payload using the
+    /// given transport sender.
+    pub fn send(&self, tx: &mut TransportSender, payload_size: usize) -> io::Result<()> {
+        let mut payload = vec![0; ECHO_HEADER_LEN + payload_size];
+        let echo_packet = self.create_echo_packet(&mut payload[..]);
+        tx.send_to(echo_packet, self.addr)?;
+        Ok(())
     }
     /// Constructs an EchoRequestPacket so it can be sent via TransportSender.
+    ///
+    /// The packet takes the whole buffer given, anything after the first
+    /// ECHO_HEADER_LEN bytes is payload.
     pub fn create_echo_packet<'a>(
         &self,
         payload: &'a mut [u8],
@@ -90,8 +118,6 @@ pub struct PacketSent {
     pub data: PacketData,
     /// When it was sent, monotonic clock. (Used to calculate received Duration later)
     pub sent: Instant,
-    /// When it was sent, system clock.
-    pub when: SystemTime,
     /// Wether it was received, and how long it took to be received.
     pub received: Option<Duration>,
 }
@@ -105,29 +131,39 @@ pub struct PacketReceived {
  */
 
 impl PacketSent {
-    /// Send a PacketData using the TransportSender specified. Constructs a PacketSent with the details.
-    pub fn new(data: PacketData, tx: &mut TransportSender) -> Self {
-        let mut payload = vec![0; 16];
-        let echo_packet = data.create_echo_packet(&mut payload[..]);
-        // TODO: This unwrap returns OS:Network unreachable error, and program ends
-        tx.send_to(echo_packet, data.addr).unwrap();
+    /// Constructs a PacketSent for a packet being sent at "sent" (monotonic).
+    ///
+    /// Take it before sending, the reply might be read by the receiver thread
+    /// before send_to even returns (i.e. on loopback). Packets that fail to be
+    /// sent are kept as well, to be accounted for as lost.
+    pub fn new(data: PacketData, sent: Instant) -> Self {
         Self {
             data,
-            sent: Instant::now(),
-            when: SystemTime::now(),
+            sent,
             received: None,
         }
     }
     // TODO: This lacks a receiving method. Code probably exists in transport.rs.
 }
 
-// TODO: Add tests here:
 #[cfg(test)]
 mod tests {
-    /*
     use super::*;
+    use std::net::Ipv4Addr;
 
     #[test]
-    fn test_XXX() {}
-    */
+    fn test_create_echo_packet_payload() {
+        let data = PacketData::new(7, 42, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        let mut buf = vec![0xff; ECHO_HEADER_LEN + 100];
+        let len = data.create_echo_packet(&mut buf[..]).packet().len();
+        assert_eq!(len, ECHO_HEADER_LEN + 100);
+        let parsed = PacketData::parse(IcmpPacket::new(&buf).unwrap(), data.addr);
+        assert_eq!(parsed.seqn, 7);
+        assert_eq!(parsed.ident, 42);
+        // The checksum covers the payload as well.
+        assert_eq!(
+            util::checksum(&buf, 1),
+            u16::from_be_bytes([buf[2], buf[3]])
+        );
+    }
 }
@@ -0,0 +1,89 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Incidents found on the frames written, appended to the incident log as
+//! they end so GUIs and scripts can follow it.
+
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+use zzping_lib::framedataq::{Complete, FrameDataQ};
+use zzping_lib::incident::{Incident, IncidentCfg, IncidentDetector};
+
+/// Incidents kept for the console display.
+const RECENT: usize = 5;
+
+#[derive(Debug)]
+pub struct IncidentLog {
+    detector: IncidentDetector,
+    file: Option<File>,
+    recent: VecDeque<Incident>,
+}
+
+impl IncidentLog {
+    /// Appends to "name" in "log_dir". With an empty name, incidents are only
+    /// kept for the display.
+    pub fn open(log_dir: &str, name: &str) -> io::Result<Self> {
+        let file = match name.is_empty() {
+            true => None,
+            false => Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(Path::new(log_dir).join(name))?,
+            ),
+        };
+        Ok(Self {
+            detector: IncidentDetector::new(IncidentCfg::default()),
+            file,
+            recent: VecDeque::new(),
+        })
+    }
+
+    pub fn push(&mut self, target: &str, frame: &FrameDataQ<Complete>) {
+        self.detector.push(target, frame);
+    }
+
+    /// Writes the incidents that ended since the last call.
+    pub fn flush(&mut self) -> io::Result<()> {
+        let incidents = self.detector.poll();
+        self.write(incidents)
+    }
+
+    /// Ends the incidents still open and writes them, when stopping.
+    pub fn finish(&mut self) -> io::Result<()> {
+        let incidents = self.detector.finish();
+        self.write(incidents)
+    }
+
+    fn write(&mut self, incidents: Vec<Incident>) -> io::Result<()> {
+        for incident in incidents {
+            if let Some(f) = self.file.as_mut() {
+                writeln!(f, "{}", incident.to_line())?;
+            }
+            self.recent.push_back(incident);
+            if self.recent.len() > RECENT {
+                self.recent.pop_front();
+            }
+        }
+        Ok(())
+    }
+
+    /// The last few incidents, oldest first.
+    pub fn recent(&self) -> impl Iterator<Item = &Incident> {
+        self.recent.iter()
+    }
+}
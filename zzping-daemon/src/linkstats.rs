@@ -0,0 +1,176 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Samples the counters of the network interfaces from /sys/class/net, so
+//! latency spikes can be told apart from a saturated or flapping link.
+//!
+//! Each interface has a folder with one file per counter under "statistics",
+//! and "carrier_changes" next to it. All of them count since the interface
+//! was created, so the logs get the difference between two samples.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use zzping_lib::logevent::LinkStats;
+
+const SYS_CLASS_NET: &str = "/sys/class/net";
+
+/// Counters of an interface at a given time.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Counters {
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub errors: u64,
+    pub drops: u64,
+    pub carrier_changes: u64,
+}
+
+impl Counters {
+    /// Traffic from "prev" to these, "secs" later. None if any went back.
+    pub fn since(&self, prev: &Counters, secs: f32) -> Option<LinkStats> {
+        if secs <= 0.0 {
+            return None;
+        }
+        Some(LinkStats {
+            rx_bytes_sec: self.rx_bytes.checked_sub(prev.rx_bytes)? as f32 / secs,
+            tx_bytes_sec: self.tx_bytes.checked_sub(prev.tx_bytes)? as f32 / secs,
+            errors: self.errors.checked_sub(prev.errors)? as u32,
+            drops: self.drops.checked_sub(prev.drops)? as u32,
+            carrier_changes: self.carrier_changes.checked_sub(prev.carrier_changes)? as u32,
+        })
+    }
+}
+
+/// Reads the counters in the folder of an interface.
+pub fn read_counters(dir: &Path) -> io::Result<Counters> {
+    let read = |name: &str| -> io::Result<u64> {
+        let text = fs::read_to_string(dir.join(name))?;
+        text.trim()
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", name, e)))
+    };
+    Ok(Counters {
+        rx_bytes: read("statistics/rx_bytes")?,
+        tx_bytes: read("statistics/tx_bytes")?,
+        errors: read("statistics/rx_errors")? + read("statistics/tx_errors")?,
+        drops: read("statistics/rx_dropped")? + read("statistics/tx_dropped")?,
+        carrier_changes: read("carrier_changes")?,
+    })
+}
+
+/// Follows the counters of an interface.
+#[derive(Debug)]
+pub struct LinkWatch {
+    pub interface: String,
+    dir: PathBuf,
+    last: Option<(Instant, Counters)>,
+    /// Whether the last read failed, to warn only once.
+    pub failing: bool,
+}
+
+impl LinkWatch {
+    pub fn new(interface: &str) -> Self {
+        Self {
+            interface: interface.to_owned(),
+            dir: Path::new(SYS_CLASS_NET).join(interface),
+            last: None,
+            failing: false,
+        }
+    }
+
+    /// Reads the counters again. Returns the traffic since the last sample,
+    /// none on the first one or if the counters went back, which happens
+    /// when the interface is recreated.
+    pub fn sample(&mut self, now: Instant) -> io::Result<Option<LinkStats>> {
+        let counters = match read_counters(&self.dir) {
+            Ok(c) => c,
+            Err(e) => {
+                self.last = None;
+                return Err(e);
+            }
+        };
+        let last = self.last.replace((now, counters));
+        let (when, prev) = match last {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        Ok(counters.since(&prev, now.duration_since(when).as_secs_f32()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/sys_class_net/eth0");
+
+    #[test]
+    fn test_read_counters() {
+        let c = read_counters(Path::new(FIXTURE)).unwrap();
+        assert_eq!(
+            c,
+            Counters {
+                rx_bytes: 1000000,
+                tx_bytes: 50000,
+                errors: 1,
+                drops: 4,
+                carrier_changes: 3,
+            }
+        );
+        assert!(read_counters(Path::new("/nonexistent")).is_err());
+    }
+
+    #[test]
+    fn test_watch() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |rx_bytes: u64, carrier_changes: u64| {
+            fs::create_dir_all(dir.path().join("statistics")).unwrap();
+            for (name, value) in [
+                ("statistics/rx_bytes", rx_bytes),
+                ("statistics/tx_bytes", 0),
+                ("statistics/rx_errors", 0),
+                ("statistics/tx_errors", 0),
+                ("statistics/rx_dropped", 0),
+                ("statistics/tx_dropped", 0),
+                ("carrier_changes", carrier_changes),
+            ] {
+                fs::write(dir.path().join(name), format!("{}\n", value)).unwrap();
+            }
+        };
+        let mut watch = LinkWatch::new("eth0");
+        watch.dir = dir.path().to_owned();
+        let t0 = Instant::now();
+        assert!(watch.sample(t0).is_err());
+
+        write(1000, 2);
+        assert_eq!(watch.sample(t0).unwrap(), None);
+        write(501000, 4);
+        let stats = watch
+            .sample(t0 + Duration::from_millis(500))
+            .unwrap()
+            .unwrap();
+        assert_eq!(stats.rx_bytes_sec, 1e6);
+        assert_eq!(stats.carrier_changes, 2);
+
+        // The interface was recreated.
+        write(10, 0);
+        assert_eq!(watch.sample(t0 + Duration::from_secs(1)).unwrap(), None);
+        write(20, 0);
+        let stats = watch.sample(t0 + Duration::from_secs(2)).unwrap();
+        assert_eq!(stats.unwrap().rx_bytes_sec, 10.0);
+    }
+}
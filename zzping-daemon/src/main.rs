@@ -12,14 +12,33 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod budget;
+mod clock;
 mod config;
+mod control;
+mod gateway;
+mod health;
+mod history;
 mod icmp;
+mod incidents;
+mod linkstats;
+mod network;
+mod peer;
+mod schedule;
+#[cfg(test)]
+mod sim;
+mod sockopt;
+mod timestamping;
 mod transport;
+mod wireless;
 
-use chrono::Utc;
 use rand::Rng;
-use std::net::UdpSocket;
-use std::time::{Duration, Instant};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 #[macro_use]
 extern crate log;
@@ -28,11 +47,34 @@ extern crate rmp;
 extern crate zzping_lib;
 
 use clap::Parser;
-use zzping_lib::framedata::{FrameData, FrameTime};
-use zzping_lib::framestats::FrameStats;
+use zzping_lib::framestats::{FrameStats, HistoryRequest};
+use zzping_lib::logevent::{DaemonStart, StartReason, StopReason};
+use zzping_lib::secure::SecureChannel;
+
+/// Chunks of history sent to each GUI on every refresh, at most.
+const BACKFILL_CHUNKS_PER_REFRESH: usize = 20;
+/// History requests answered at the same time, at most.
+const MAX_BACKFILLS: usize = 8;
+
+/// How often the routing table is read again for auto targets.
+const GATEWAY_CHECK_EVERY: Duration = Duration::from_secs(5);
+
+/// How often the WiFi links are sampled.
+const WIRELESS_EVERY: Duration = Duration::from_secs(1);
+
+/// How often the interface counters are sampled.
+const LINK_STATS_EVERY: Duration = Duration::from_secs(1);
+
+/// How often the load of the host is sampled.
+const HOST_HEALTH_EVERY: Duration = Duration::from_secs(1);
+
+/// File in the log folder that exists while the daemon runs. If it's there on
+/// start up, the last run didn't stop cleanly.
+const RUNNING_MARKER: &str = "zzping-daemon.running";
 
 struct CLIStats {
-    dest_addr: std::net::IpAddr,
+    dest_label: String,
+    probe_rate: f32,
     inflight_count: usize,
     recv_per_sec: f32,
     avg_time: Duration,
@@ -42,6 +84,9 @@ struct CLIStats {
     packets_recv: usize,
     dest_ident: u16,
     dest_seq: u16,
+    send_errors: u64,
+    last_send_error: Option<String>,
+    undersampled: Option<f32>,
 }
 
 #[derive(Parser)]
@@ -50,8 +95,12 @@ struct CLIStats {
     author = "David Martinez Marti <deavidsedice@gmail.com>"
 )]
 struct Opts {
+    /// Config file, RON or TOML (by the ".toml" extension)
     #[clap(short, long, default_value = "daemon_config.ron")]
     config: String,
+    /// Only check the config file for errors, then exit
+    #[clap(long)]
+    check_config: bool,
 }
 
 fn clearscreen() {
@@ -59,36 +108,181 @@ fn clearscreen() {
 }
 
 fn read_config(filepath: &str) -> config::ServerConfig {
-    match config::ServerConfig::from_filepath(filepath) {
+    match config::ServerConfig::load(filepath) {
         Ok(cfg) => cfg,
-        Err(e) => {
-            panic!("Error parsing config file '{}': {:?}", filepath, e);
+        Err(errors) => {
+            eprintln!("Error in config file '{}':", filepath);
+            for e in errors {
+                eprintln!("  {}", e);
+            }
+            std::process::exit(1);
+        }
+    }
+}
+
+fn probe_options(target: &config::TargetHost) -> transport::ProbeOptions {
+    transport::ProbeOptions {
+        payload_size: target.payload_size,
+        dont_fragment: target.dont_fragment,
+        ttl: target.ttl,
+        dscp: target.dscp,
+        interface: target.interface.clone(),
+        source_address: target.source_address.as_ref().map(|addr| {
+            addr.parse()
+                .unwrap_or_else(|e| panic!("Invalid source address '{}': {}", addr, e))
+        }),
+        burst: target.burst.as_ref().map(|burst| transport::BurstPolicy {
+            interval: Duration::from_secs(1) / burst.frequency,
+            loss_pct: burst.loss_pct,
+            latency: burst
+                .latency_ms
+                .map(|ms| Duration::from_secs_f32(ms / 1000.0)),
+            hold: Duration::from_secs(burst.hold_secs),
+        }),
+        priority: target.priority,
+    }
+}
+
+/// Points the auto targets to their current gateway, if it changed.
+fn follow_gateways(gateways: &mut Vec<(String, gateway::GatewayWatch)>, t: &mut transport::Comms) {
+    // Targets removed through the control socket are not followed anymore.
+    gateways.retain(|(label, _)| t.dest.iter().any(|d| &d.label == label));
+    for (label, watch) in gateways.iter_mut() {
+        let gateway = match watch.check() {
+            Ok(Some(gateway)) => gateway,
+            Ok(None) => continue,
+            Err(e) => {
+                warn!("{}: unable to read the routing table: {}", label, e);
+                continue;
+            }
+        };
+        let dest = t.dest.iter_mut().find(|d| &d.label == label).unwrap();
+        if dest.addr.is_unspecified() {
+            info!("{}: found gateway {}", label, gateway);
+            dest.paused = false;
+        } else {
+            info!(
+                "{}: gateway changed from {} to {}",
+                label, dest.addr, gateway
+            );
+        }
+        dest.set_addr(gateway);
+    }
+}
+
+/// Interface out of "interfaces" the pings to "dest" go through: the one it's
+/// bound to if it's listed, or the first listed.
+fn watched_interface<'a>(
+    interfaces: &'a [String],
+    dest: &transport::Destination,
+) -> Option<&'a String> {
+    let bound = dest.options.interface.as_ref();
+    interfaces
+        .iter()
+        .find(|x| Some(*x) == bound)
+        .or_else(|| interfaces.first())
+}
+
+/// Attaches to each destination the last sample of the WiFi link its pings go
+/// through.
+fn sample_wireless(interfaces: &[String], t: &mut transport::Comms) -> std::io::Result<()> {
+    let samples = wireless::read_wireless()?;
+    for dest in t.dest.iter_mut() {
+        dest.wireless = watched_interface(interfaces, dest).and_then(|x| samples.get(x).copied());
+    }
+    Ok(())
+}
+
+/// Samples the counters of the interfaces and attaches to each destination
+/// the traffic of the interface its pings go through. Interfaces that can't
+/// be read are warned about once, and tried again on the next sample, as
+/// they may come back.
+fn sample_links(
+    watches: &mut [linkstats::LinkWatch],
+    interfaces: &[String],
+    now: std::time::Instant,
+    t: &mut transport::Comms,
+) {
+    let mut samples = std::collections::HashMap::new();
+    for watch in watches.iter_mut() {
+        match watch.sample(now) {
+            Ok(stats) => {
+                watch.failing = false;
+                if let Some(stats) = stats {
+                    samples.insert(watch.interface.clone(), stats);
+                }
+            }
+            Err(e) if !watch.failing => {
+                warn!("Unable to read the counters of {}: {}", watch.interface, e);
+                watch.failing = true;
+            }
+            Err(_) => {}
         }
     }
+    for dest in t.dest.iter_mut() {
+        dest.link_stats = watched_interface(interfaces, dest).and_then(|x| samples.get(x).copied());
+    }
 }
 
-fn get_logfile_now() -> String {
-    let mut strnow = Utc::now()
-        .to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
-        .replace('-', "")
-        .replace(':', "");
-    strnow.truncate(11);
-    strnow
+/// Wraps a datagram for the GUIs, if the traffic is authenticated.
+fn seal(secure: &mut Option<SecureChannel>, msg: Vec<u8>, now: SystemTime) -> Vec<u8> {
+    match secure {
+        Some(s) => s.seal(&msg, now),
+        None => msg,
+    }
 }
+
 fn main() {
+    env_logger::init();
     let mut rng = rand::thread_rng();
 
     let opts: Opts = Opts::parse();
     let cfg = read_config(&opts.config);
+    if opts.check_config {
+        let streams: usize = cfg.ping_targets.iter().map(|t| t.streams().len()).sum();
+        println!(
+            "Config file '{}' is valid: {} targets, {} streams.",
+            opts.config,
+            cfg.ping_targets.len(),
+            streams
+        );
+        return;
+    }
     let mut t = transport::Comms::new(transport::CommConfig {
         forget_lost: Duration::from_secs(cfg.keep_packets.lost_secs),
         forget_inflight: Duration::from_secs(cfg.keep_packets.inflight_secs),
         forget_recv: Duration::from_secs(cfg.keep_packets.recv_secs),
-        precision_mult: cfg.precision_mult,
+        max_pings_per_sec: cfg.max_pings_per_sec,
+        history: Duration::from_secs(cfg.history_secs),
     });
 
     let socket = UdpSocket::bind(&cfg.udp_listen_address).unwrap();
     socket.set_nonblocking(true).unwrap();
+    let client_addr: SocketAddr = cfg.udp_client_address.parse().unwrap();
+    let mut secure = match cfg.psk.is_empty() {
+        true => None,
+        false => Some(SecureChannel::new(&cfg.psk, cfg.encrypt)),
+    };
+    let mut peers = match cfg.peer_listen_address.as_str() {
+        "" => None,
+        addr => {
+            let secure = match cfg.psk.is_empty() {
+                true => None,
+                false => Some(SecureChannel::new(&cfg.psk, cfg.encrypt)),
+            };
+            let mut peers = peer::Peers::bind(addr, secure)
+                .unwrap_or_else(|e| panic!("Unable to listen for peers on {}: {}", addr, e));
+            for p in cfg.peers.iter() {
+                let addr = p.address.parse().unwrap();
+                let mut link = peer::PeerLink::new(&p.name, addr, p.frequency, t.clock.now());
+                link.set_history(Duration::from_secs(cfg.history_secs));
+                peers.links.push(link);
+            }
+            Some(peers)
+        }
+    };
+    // History requests being answered.
+    let mut backfills: Vec<history::Backfill> = vec![];
 
     // How often the console UI is refreshed / how often to write a frame
     let cli_refresh = Duration::from_secs(1) / cfg.refresh_freq;
@@ -100,52 +294,262 @@ fn main() {
     let pckt_loss_inflight_time = Duration::from_millis(300);
     let pckt_loss_recv_time = Duration::from_millis(1000);
 
+    let clock = t.clock.clone();
+
     // Timer to make the UI refresh every "cli_refresh"
-    let mut last_refresh = Instant::now() - Duration::from_secs(60);
-    // Timer to both enable the disk log to switch to a new file, and to write a complete packet every X
-    let mut time_since_report = Instant::now() - Duration::from_secs(60);
-    let report_every_secs = Duration::from_secs(15);
+    let mut last_refresh = clock.now() - Duration::from_secs(60);
+    // Writes a complete timestamp every 15s, and switches to a new file every hour
+    let mut log_schedule = schedule::LogSchedule::new(Duration::from_secs(15), clock.as_ref());
 
     // Timer to smooth the averages on the program load, to avoid seeing lower averages upon program start
-    let program_start = Instant::now();
-
-    // Contains the current ending of the file, changes every hour
-    let mut strnow = get_logfile_now();
+    let program_start = clock.now();
 
-    for target in cfg.ping_targets {
+    // Auto targets, by label, and when to look for their gateway again.
+    let mut gateways: Vec<(String, gateway::GatewayWatch)> = vec![];
+    let mut next_gateway_check = clock.now() + GATEWAY_CHECK_EVERY;
+    let mut watch_wireless = !cfg.wireless_interfaces.is_empty();
+    let mut next_wireless_sample = clock.now();
+    let mut link_watches: Vec<linkstats::LinkWatch> = cfg
+        .link_interfaces
+        .iter()
+        .map(|x| linkstats::LinkWatch::new(x))
+        .collect();
+    let mut next_link_sample = clock.now();
+    // Worst delays since the last sample of the host, and that sample.
+    let mut watch_host = true;
+    let mut next_host_sample = clock.now();
+    let mut host_lateness = health::Lateness::default();
+    let mut host_health = None;
+    for target in cfg.ping_targets.iter().flat_map(|t| t.streams()) {
         let interval = Duration::from_secs(1) / target.frequency;
-        // Add a random amount to avoid having all targets at exactly the same time
-        let rng_time: u64 = rng.gen_range(0..interval.as_millis()) as u64 + 1;
+        // Add a random amount to avoid having all targets at exactly the same
+        // time. Over 1000 pings/s the interval is under a millisecond.
+        let rng_time: u64 = rng.gen_range(0..interval.as_micros().max(1)) as u64 + 1;
         let interval_n = interval + Duration::from_nanos(rng_time);
 
-        t.add_destination(&target.address, interval_n);
+        let options = probe_options(&target);
+        let label = options.label(target.name());
+        let mut watch = target
+            .auto
+            .map(|_| gateway::GatewayWatch::new(target.interface.clone()));
+        let address = match &mut watch {
+            Some(watch) => {
+                if let Err(e) = watch.check() {
+                    warn!("{}: unable to read the routing table: {}", label, e);
+                }
+                watch
+                    .current
+                    .unwrap_or_else(|| Ipv4Addr::UNSPECIFIED.into())
+                    .to_string()
+            }
+            None => target.address.clone(),
+        };
+        if let Err(e) = t.add_destination(&address, interval_n, options) {
+            panic!("Unable to set up target '{}': {}", label, e);
+        }
+        if let Some(watch) = watch {
+            // Auto targets keep their name as label, so the logs and graphs
+            // go on when the gateway changes.
+            let dest = t.dest.last_mut().unwrap();
+            dest.label = label.clone();
+            match watch.current {
+                Some(gateway) => info!("{}: probing {}", label, gateway),
+                None => {
+                    warn!("{}: there's no default route, waiting for one", label);
+                    dest.paused = true;
+                }
+            }
+            gateways.push((label, watch));
+        }
+    }
+    let wanted_rate = t.wanted_rate();
+    if wanted_rate > cfg.max_pings_per_sec as f32 {
+        warn!(
+            "Targets want up to {:.0} pings/s, over the budget of {}/s. Some will be under-sampled.",
+            wanted_rate, cfg.max_pings_per_sec
+        );
+    }
+    let marker = Path::new(&cfg.log_dir).join(RUNNING_MARKER);
+    let start_reason = match marker.exists() {
+        true => {
+            warn!("The last run didn't stop cleanly, its logs end without a stop.");
+            StartReason::Recovered
+        }
+        false => StartReason::Started,
+    };
+    if let Err(e) = std::fs::write(&marker, std::process::id().to_string()) {
+        warn!("Unable to write {}: {}", marker.display(), e);
     }
+    let mut incident_log = match incidents::IncidentLog::open(&cfg.log_dir, &cfg.incident_log) {
+        Ok(log) => log,
+        Err(e) => panic!("Unable to open incident log '{}': {}", cfg.incident_log, e),
+    };
+    let config_hash = cfg.hash();
     for dest in t.dest.iter_mut() {
-        dest.create_log_file(&strnow);
+        dest.create_log_file(&cfg.log_dir, log_schedule.hour());
+        dest.daemon_start = Some(DaemonStart {
+            reason: start_reason,
+            config_hash,
+        });
     }
-    // Recommended wait ammount to be able to push all pings in time
-    let wait = t.get_delay();
-
-    // Amount of extra time taken in one round, to be able to correct it.
+    for link in peers.iter_mut().flat_map(|p| p.links.iter_mut()) {
+        link.create_log_file(&cfg.log_dir, log_schedule.hour());
+        link.daemon_start = Some(DaemonStart {
+            reason: start_reason,
+            config_hash,
+        });
+    }
+    // Set on SIGTERM or Ctrl+C, to record the stop in the logs before exiting.
+    let stop = Arc::new(AtomicBool::new(false));
+    for signal in [SIGTERM, SIGINT] {
+        signal_hook::flag::register(signal, Arc::clone(&stop)).unwrap();
+    }
+    let mut control = match cfg.control_socket.as_str() {
+        "" => None,
+        path => match control::ControlServer::bind(path) {
+            Ok(server) => {
+                info!("Control socket listening on {}", server.path().display());
+                Some(server)
+            }
+            Err(e) => {
+                error!("Unable to listen on control socket {}: {}", path, e);
+                None
+            }
+        },
+    };
     loop {
-        t.recv_all(wait);
-        t.send_all(2);
+        // Sleep until either the next ping is due or it's time to refresh,
+        // handling replies as they arrive in the meantime.
+        let next_refresh = last_refresh + cli_refresh;
+        let mut deadline = t.next_send().map_or(next_refresh, |x| x.min(next_refresh));
+        if let Some(next) = peers.as_ref().and_then(|p| p.next_send()) {
+            deadline = deadline.min(next);
+        }
+        t.recv_until(deadline);
+        t.send_due();
+        if let Some(peers) = peers.as_mut() {
+            // Replies are stamped by the kernel, reading them late is fine.
+            peers.recv_all(clock.now());
+            peers.send_due(clock.now());
+        }
+
+        while let Some((stream, line)) = control.as_mut().and_then(|c| c.poll()) {
+            let (result, action) = match control::Command::parse(&line, cfg.max_pings_per_sec) {
+                Ok(cmd) => control::execute(&cmd, &mut t),
+                Err(e) => (Err(e), control::Action::None),
+            };
+            match action {
+                control::Action::None => {}
+                control::Action::RotateLogs => {
+                    let name = schedule::file_second(clock.wall());
+                    for dest in t.dest.iter_mut() {
+                        dest.create_log_file(&cfg.log_dir, &name);
+                    }
+                    log_schedule.force_keyframe();
+                }
+                control::Action::OpenLog => {
+                    if let Some(dest) = t.dest.last_mut() {
+                        let name = schedule::file_second(clock.wall());
+                        dest.create_log_file(&cfg.log_dir, &name);
+                        dest.daemon_start = Some(DaemonStart {
+                            reason: StartReason::TargetAdded,
+                            config_hash,
+                        });
+                    }
+                    log_schedule.force_keyframe();
+                }
+            }
+            if let Some(c) = control.as_mut() {
+                c.reply(stream, &result);
+            }
+        }
+
+        if stop.load(Ordering::Relaxed) {
+            for dest in t.dest.iter_mut() {
+                dest.stop(StopReason::Signal);
+            }
+            for link in peers.iter_mut().flat_map(|p| p.links.iter_mut()) {
+                link.stop(StopReason::Signal);
+            }
+            if let Err(e) = incident_log.finish() {
+                println!("Error writing to incident log: {}", e);
+            }
+            if let Err(e) = std::fs::remove_file(&marker) {
+                warn!("Unable to remove {}: {}", marker.display(), e);
+            }
+            println!("Stopped.");
+            return;
+        }
 
-        let elapsed = last_refresh.elapsed();
-        if elapsed > cli_refresh {
-            last_refresh = Instant::now();
+        let now = clock.now();
+        if now >= next_refresh {
+            last_refresh = now;
             // Remove now the old packets from their queues. (Packets never received, old packets lost & received)
             t.cleanup();
-            let since_report_elapsed = time_since_report.elapsed();
-            if since_report_elapsed > report_every_secs {
-                time_since_report = Instant::now();
-                let newstrnow = get_logfile_now();
-                if newstrnow != strnow {
-                    strnow = newstrnow;
-                    for dest in t.dest.iter_mut() {
-                        dest.create_log_file(&strnow);
+            t.update_rates();
+            t.check_sampling();
+            let tick = log_schedule.tick(clock.as_ref());
+            if matches!(tick.discontinuity, Some(d) if d.suspend_secs > 0.0) {
+                for dest in t.dest.iter_mut() {
+                    dest.forget_inflight();
+                }
+            }
+            if let Some(name) = &tick.rotate {
+                for dest in t.dest.iter_mut() {
+                    dest.create_log_file(&cfg.log_dir, name);
+                }
+                for link in peers.iter_mut().flat_map(|p| p.links.iter_mut()) {
+                    link.create_log_file(&cfg.log_dir, name);
+                }
+            }
+            // Pings are all late after a suspend, that's not the host's fault.
+            let lateness = match tick.discontinuity {
+                Some(_) => health::Lateness::default(),
+                None => t.lateness,
+            };
+            t.lateness = health::Lateness::default();
+            host_lateness.merge(&lateness);
+            if watch_host && now >= next_host_sample {
+                next_host_sample = now + HOST_HEALTH_EVERY;
+                match health::sample(&host_lateness) {
+                    Ok(sample) => host_health = Some(sample),
+                    Err(e) => {
+                        warn!(
+                            "Unable to sample the load of the host, not trying again: {}",
+                            e
+                        );
+                        watch_host = false;
                     }
                 }
+                host_lateness = health::Lateness::default();
+            }
+            let untrusted = lateness.untrusted(host_health.as_ref());
+            for dest in t.dest.iter_mut() {
+                dest.host_health = host_health;
+                dest.untrusted = untrusted;
+            }
+            for link in peers.iter_mut().flat_map(|p| p.links.iter_mut()) {
+                link.host_health = host_health;
+                link.untrusted = untrusted;
+                // The offsets were measured against the clock before the step.
+                if tick.discontinuity.is_some() {
+                    link.forget_offsets();
+                }
+            }
+            if watch_wireless && now >= next_wireless_sample {
+                next_wireless_sample = now + WIRELESS_EVERY;
+                if let Err(e) = sample_wireless(&cfg.wireless_interfaces, &mut t) {
+                    warn!("Unable to sample the WiFi links, not trying again: {}", e);
+                    watch_wireless = false;
+                }
+            }
+            if !link_watches.is_empty() && now >= next_link_sample {
+                next_link_sample = now + LINK_STATS_EVERY;
+                sample_links(&mut link_watches, &cfg.link_interfaces, now, &mut t);
+            }
+            if now >= next_gateway_check {
+                next_gateway_check = now + GATEWAY_CHECK_EVERY;
+                follow_gateways(&mut gateways, &mut t);
             }
             // --- Compute stats phase ---
 
@@ -153,7 +557,7 @@ fn main() {
             let recv_time_size = t
                 .config
                 .forget_recv
-                .min(program_start.elapsed())
+                .min(now.saturating_duration_since(program_start))
                 .as_secs_f32();
 
             // Vector to hold the stats found in each destination host
@@ -165,22 +569,21 @@ fn main() {
                 let time_avg = (dest.interval * 5).max(cli_refresh);
                 let inflight_count = dest.inflight_packets.len();
                 let recv_count = dest.recv_packets.len();
-                let inflight_long = dest.inflight_after(pckt_loss_inflight_time).len();
+                let inflight_long = dest.inflight_after(now, pckt_loss_inflight_time).len();
                 let packets_lost = inflight_long + dest.lost_packets.len();
-                let packets_recv = dest.received_last(pckt_loss_recv_time).len();
+                let packets_recv = dest.received_last(now, pckt_loss_recv_time).len();
                 let packet_loss =
                     (100.0 * packets_lost as f32) / ((packets_lost + packets_recv) as f32 + 0.1);
                 let avg_time: Duration = dest
-                    .mean_recv_time(time_avg)
+                    .mean_recv_time(now, time_avg)
                     .unwrap_or(default_recv_avg_no_packets);
-                let last_pckt_received = dest
-                    .recv_packets
-                    .last()
-                    .map_or(last_refresh, |x| x.sent)
-                    .elapsed();
+                let last_pckt_received = now.saturating_duration_since(
+                    dest.recv_packets.last().map_or(last_refresh, |x| x.sent),
+                );
                 let recv_per_sec = recv_count as f32 / recv_time_size;
                 cli_stats.push(CLIStats {
-                    dest_addr: dest.addr,
+                    dest_label: dest.label.clone(),
+                    probe_rate: dest.probe_rate(),
                     inflight_count,
                     recv_per_sec,
                     avg_time,
@@ -190,61 +593,153 @@ fn main() {
                     packets_recv,
                     dest_ident: dest.ident,
                     dest_seq: dest.seq,
+                    send_errors: dest.send_errors,
+                    last_send_error: dest.last_send_error.clone(),
+                    undersampled: dest.undersampled,
                 });
             }
+            // --- Answer history requests from GUIs ---
+            let mut buf = [0_u8; 1500];
+            while let Ok((len, addr)) = socket.recv_from(&mut buf) {
+                let msg = match secure
+                    .as_mut()
+                    .map(|s| s.open(&buf[..len], clock.wall().into()))
+                {
+                    Some(Ok(msg)) => msg,
+                    Some(Err(e)) => {
+                        warn!("Request from {} refused: {}", addr, e);
+                        continue;
+                    }
+                    // Anyone can send a request with a forged source address,
+                    // so without a key only the configured GUI gets answers.
+                    None if addr == client_addr => buf[..len].to_vec(),
+                    None => {
+                        warn!("Request from {} refused: not udp_client_address", addr);
+                        continue;
+                    }
+                };
+                match HistoryRequest::decode(&msg) {
+                    Ok(req) if t.dest.iter().any(|d| d.label == req.target) => {
+                        backfills.retain(|b| b.addr != addr || b.target != req.target);
+                        if backfills.len() >= MAX_BACKFILLS {
+                            warn!("History request from {} refused: too many", addr);
+                            continue;
+                        }
+                        backfills.push(history::Backfill::new(addr, req));
+                    }
+                    Ok(req) => warn!("History requested for unknown target {}", req.target),
+                    Err(e) => warn!("Invalid request from {}: {:#}", addr, e),
+                }
+            }
+            backfills.retain_mut(|b| {
+                let dest = match t.dest.iter().find(|d| d.label == b.target) {
+                    Some(dest) => dest,
+                    None => return false,
+                };
+                for _ in 0..BACKFILL_CHUNKS_PER_REFRESH {
+                    let (msg, done) = b.next_chunk(&dest.history);
+                    let msg = seal(&mut secure, msg, clock.wall().into());
+                    if let Err(e) = socket.send_to(&msg, b.addr) {
+                        warn!("Error sending history to {}: {}", b.addr, e);
+                        return false;
+                    }
+                    if done {
+                        return false;
+                    }
+                }
+                true
+            });
             // --- Send stats to GUI via UDP ---
             let mut udp_ok = true;
-            for st in cli_stats.iter() {
+            // While a GUI gets the history of a target, the live stats wait.
+            let backfilling = |label: &str| {
+                backfills
+                    .iter()
+                    .any(|b| b.addr == client_addr && b.target == label)
+            };
+            for st in cli_stats.iter().filter(|st| !backfilling(&st.dest_label)) {
                 match FrameStats::encode_stats(
-                    st.dest_addr,
+                    &st.dest_label,
                     st.inflight_count,
                     st.avg_time,
                     st.last_pckt_received,
                     st.packet_loss,
                 ) {
                     Ok(msg) => {
+                        let msg = seal(&mut secure, msg, clock.wall().into());
                         udp_ok = udp_ok && socket.send_to(&msg, &cfg.udp_client_address).is_ok()
                     }
                     Err(e) => println!("UDP Encode error: {}", e),
                 }
             }
+            // Each way to a peer goes as a target of its own.
+            for link in peers.iter().flat_map(|p| p.links.iter()) {
+                let since = link.last_reply.map_or(Duration::ZERO, |t| now - t);
+                let ways = match link.path {
+                    Some(p) => [
+                        ("up", p.up_ms, p.up_loss_pct),
+                        ("down", p.down_ms, p.down_loss_pct),
+                    ],
+                    None => [("up", 0.0, 100.0), ("down", 0.0, 100.0)],
+                };
+                for (way, ms, loss) in ways {
+                    let delay = Duration::from_secs_f32(ms.max(0.0) / 1000.0);
+                    let label = format!("{}-{}", link.label, way);
+                    match FrameStats::encode_stats(&label, 0, delay, since, loss) {
+                        Ok(msg) => {
+                            let msg = seal(&mut secure, msg, clock.wall().into());
+                            udp_ok = udp_ok && socket.send_to(&msg, &cfg.udp_client_address).is_ok()
+                        }
+                        Err(e) => println!("UDP Encode error: {}", e),
+                    }
+                }
+            }
             if !udp_ok {
                 println!("Error sending via UDP. Client might not be connected.")
             }
             // -- Logging phase ---
             for dest in t.dest.iter_mut() {
-                let last_recv = dest.received_last(cli_refresh + cli_refresh / 2);
-                let inflight = dest.inflight_after(cli_refresh);
-                let mut last_recv_us: Vec<u128> = last_recv
-                    .iter()
-                    .map(|p| p.received.unwrap_or_default().as_micros())
-                    .collect();
-                last_recv_us.sort_unstable();
-                if let Some(mut f) = dest.logfile.as_mut() {
-                    let time: FrameTime = if since_report_elapsed > report_every_secs {
-                        FrameTime::Timestamp(Utc::now())
-                    } else {
-                        FrameTime::Elapsed(since_report_elapsed)
-                    };
-                    let framedata = FrameData {
-                        time,
-                        inflight: inflight.len(),
-                        lost_packets: dest.lost_packets.len(),
-                        recv_us: last_recv_us,
-                    };
-                    if let Err(e) = framedata.encode(&mut f) {
-                        println!("Error writing to file: {:?}", e);
-                    }
+                match dest.log_frame(now, &tick, cli_refresh) {
+                    Ok(Some(frame)) => incident_log.push(&dest.label, &frame),
+                    Ok(None) => {}
+                    Err(e) => println!("Error writing to file: {:?}", e),
+                }
+            }
+            for link in peers.iter_mut().flat_map(|p| p.links.iter_mut()) {
+                let freq = 1.0 / link.interval.as_secs_f32();
+                match link.log_frame(now, &tick, freq) {
+                    Ok(Some(frame)) => incident_log.push(&link.label, &frame),
+                    Ok(None) => {}
+                    Err(e) => println!("Error writing to file: {:?}", e),
                 }
             }
+            if let Err(e) = incident_log.flush() {
+                println!("Error writing to incident log: {}", e);
+            }
             // --- CLI Stats display phase ---
             // All printing behavior is sent to the end to avoid delays that cause flickering
             clearscreen();
 
+            // Until a packet arrives, report what the socket accepted.
+            println!("RX timestamps: {}", t.last_stamp.unwrap_or(t.stamp_method));
+            if let Some(h) = host_health {
+                println!(
+                    "Host: load {:.2}, cpu pressure {}, sent up to {:.1}ms late, read up to {:.1}ms late{}",
+                    h.load1,
+                    h.cpu_pressure.map_or("n/a".to_owned(), |x| format!("{:.1}%", x)),
+                    h.send_late_ms,
+                    h.recv_delay_ms,
+                    match untrusted {
+                        true => " - UNTRUSTED",
+                        false => "",
+                    }
+                );
+            }
             for st in cli_stats.iter() {
                 println!(
-                    "{:>14?} - {:>4} in-flight - {:>4.2} recv/s - {:>7.2?}ms / {:>4.1?}s - {:>7.2}% loss ({}/{}) ident: {},{}",
-                    st.dest_addr,
+                    "{:>14} - {:>5.1}/s - {:>4} in-flight - {:>4.2} recv/s - {:>7.2?}ms / {:>4.1?}s - {:>7.2}% loss ({}/{}) ident: {},{}",
+                    st.dest_label,
+                    st.probe_rate,
                     st.inflight_count,
                     st.recv_per_sec,
                     st.avg_time.as_secs_f32() * 1000.0,
@@ -255,6 +750,41 @@ fn main() {
                     st.dest_ident,
                     st.dest_seq,
                 );
+                if let Some(ratio) = st.undersampled {
+                    println!(
+                        "{:>14} under-sampled: {:.0}% of the configured pings sent",
+                        "",
+                        ratio * 100.0
+                    );
+                }
+                if let Some(e) = &st.last_send_error {
+                    println!("{:>14} send errors: {} - last: {}", "", st.send_errors, e);
+                }
+            }
+            for link in peers.iter().flat_map(|p| p.links.iter()) {
+                match (link.path, link.last_reply) {
+                    (Some(p), _) => println!(
+                        "{:>14} - up {:>7.2}ms {:>5.1}% loss - down {:>7.2}ms {:>5.1}% loss - clock offset {:+.2}ms",
+                        link.label,
+                        p.up_ms,
+                        p.up_loss_pct,
+                        p.down_ms,
+                        p.down_loss_pct,
+                        p.offset_ms
+                    ),
+                    (None, Some(t)) => println!(
+                        "{:>14} - no replies for {:.1}s",
+                        link.label,
+                        (now - t).as_secs_f32()
+                    ),
+                    (None, None) => println!("{:>14} - no replies yet", link.label),
+                }
+                if let Some(e) = &link.send_error {
+                    println!("{:>14} send error: {}", "", e);
+                }
+            }
+            for incident in incident_log.recent() {
+                println!("{}", incident);
             }
         }
     }
@@ -0,0 +1,198 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Network access for the pinger
+//!
+//! Comms sends and receives pings only through the Network trait. The real
+//! implementation uses raw ICMP sockets; tests use a simulated network
+//! instead (see sim.rs), so they need neither root nor a network.
+//!
+
+use super::icmp;
+use super::sockopt;
+use super::timestamping::{StampSource, StampedReceiver};
+use super::transport::ProbeOptions;
+use pnet_transport::{TransportChannelType, TransportSender};
+use std::io;
+use std::net::IpAddr;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Sends pings and hands back the replies.
+pub trait Network {
+    /// Sets up a sender with these options applied, for destinations that
+    /// can't use the shared one. Returns the id to pass to send().
+    ///
+    /// Sender 0 is always available and has no options applied.
+    fn add_sender(&mut self, options: &ProbeOptions) -> io::Result<usize>;
+
+    /// Sends the ping described in "packet" through the given sender, with
+    /// "payload_size" bytes of payload.
+    fn send(
+        &mut self,
+        sender: usize,
+        packet: &icmp::PacketSent,
+        payload_size: usize,
+    ) -> io::Result<()>;
+
+    /// Waits for the next reply, until "deadline" at most. Returns None when
+    /// the deadline passed without any reply left to read.
+    fn recv(&mut self, deadline: Instant) -> Option<icmp::PacketData>;
+
+    /// Best receive timestamping method available.
+    fn stamp_method(&self) -> StampSource;
+}
+
+/// Creates a TransportChannelType for ICMP over IPv4
+pub fn protocol_ipv4() -> TransportChannelType {
+    use pnet::packet::ip::IpNextHeaderProtocols;
+    use pnet_transport::TransportChannelType::Layer4;
+    use pnet_transport::TransportProtocol::Ipv4;
+    Layer4(Ipv4(IpNextHeaderProtocols::Icmp))
+}
+
+/// Network over raw ICMP sockets. Requires root or CAP_NET_RAW.
+pub struct IcmpNetwork {
+    /// Write channels. The first one is shared by all destinations that don't
+    /// need socket options of their own.
+    senders: Vec<TransportSender>,
+    /// Best receive timestamping method enabled on the socket.
+    stamp_method: StampSource,
+    /// Packets read by the reader thread, consumed in recv
+    readbuf: Receiver<icmp::PacketData>,
+    /// Handle of the thread for joining. Unused, as the thread never ends
+    _read_thread_handle: thread::JoinHandle<()>,
+}
+
+/// First wait after an error reading the ICMP socket.
+const RECV_MIN_BACKOFF: Duration = Duration::from_millis(10);
+/// Longest wait between reads of an ICMP socket that keeps failing.
+const RECV_MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Reader thread implementation
+///
+/// This blocks on the socket and hands every packet to the main thread as soon
+/// as it arrives, so the main thread can sleep until there's work to do.
+///
+/// On errors it backs off, doubling the wait up to RECV_MAX_BACKOFF, so a
+/// socket that keeps failing doesn't spin a core and flood the log.
+fn receiver_thread(mut rx: StampedReceiver, readbuf: Sender<icmp::PacketData>) {
+    let mut backoff = Duration::ZERO;
+    loop {
+        match rx.recv() {
+            Ok((buffer, addr, received, stamp)) => {
+                backoff = Duration::ZERO;
+                let mut packet = match icmp::PacketData::parse_ipv4(buffer, addr) {
+                    Some(packet) => packet,
+                    None => continue,
+                };
+                packet.received = Some(received);
+                packet.stamp = stamp;
+                if readbuf.send(packet).is_err() {
+                    // Main thread is gone, nobody will read these anymore.
+                    return;
+                }
+            }
+            Err(e) => {
+                backoff = (backoff * 2).clamp(RECV_MIN_BACKOFF, RECV_MAX_BACKOFF);
+                error!(
+                    "Error reading from ICMP socket: {} (retrying in {:?})",
+                    e, backoff
+                );
+                thread::sleep(backoff);
+            }
+        }
+    }
+}
+
+impl IcmpNetwork {
+    /// Opens the shared ICMP socket and starts the reader thread.
+    pub fn new() -> io::Result<Self> {
+        let bufsize = 65536;
+        // TODO: Caller should have two Comms, one for IpV4, and another for IpV6.
+        let (tx, rx) = pnet_transport::transport_channel(bufsize, protocol_ipv4())?;
+        let rx = StampedReceiver::new(rx);
+        let stamp_method = rx.method;
+        info!("Receive timestamps: {}", stamp_method);
+        // rx is sent to the thread as an exclusive thing, we lose track of it here.
+        let (thread_buf, readbuf) = mpsc::channel();
+        let read_thread_handle: thread::JoinHandle<()> =
+            std::thread::spawn(move || receiver_thread(rx, thread_buf));
+        Ok(Self {
+            senders: vec![tx],
+            stamp_method,
+            readbuf,
+            _read_thread_handle: read_thread_handle,
+        })
+    }
+}
+
+impl Network for IcmpNetwork {
+    fn add_sender(&mut self, options: &ProbeOptions) -> io::Result<usize> {
+        // The receiving side of this socket is never read, the shared receiver
+        // gets all replies.
+        let (tx, _rx) = pnet_transport::transport_channel(4096, protocol_ipv4())?;
+        let fd = tx.socket.fd;
+        sockopt::shrink_recv_buffer(fd)?;
+        if options.dont_fragment {
+            sockopt::set_dont_fragment(fd)?;
+        }
+        if let Some(ttl) = options.ttl {
+            sockopt::set_ttl(fd, ttl)?;
+        }
+        if let Some(dscp) = options.dscp {
+            sockopt::set_dscp(fd, dscp)?;
+        }
+        if let Some(interface) = &options.interface {
+            sockopt::bind_device(fd, interface)?;
+        }
+        match options.source_address {
+            Some(IpAddr::V4(addr)) => sockopt::bind_source(fd, addr)?,
+            Some(IpAddr::V6(_)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "IPv6 source addresses are not supported",
+                ))
+            }
+            None => {}
+        }
+        self.senders.push(tx);
+        Ok(self.senders.len() - 1)
+    }
+
+    fn send(
+        &mut self,
+        sender: usize,
+        packet: &icmp::PacketSent,
+        payload_size: usize,
+    ) -> io::Result<()> {
+        packet.data.send(&mut self.senders[sender], payload_size)
+    }
+
+    fn recv(&mut self, deadline: Instant) -> Option<icmp::PacketData> {
+        // With the deadline gone this still returns the packets already
+        // queued, so none are left behind.
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        match self.readbuf.recv_timeout(timeout) {
+            Ok(packet) => Some(packet),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => panic!("recv: reader thread died"),
+        }
+    }
+
+    fn stamp_method(&self) -> StampSource {
+        self.stamp_method
+    }
+}
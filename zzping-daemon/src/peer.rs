@@ -0,0 +1,751 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Probes exchanged with other daemons, to measure each way on its own.
+//!
+//! ICMP only gives the round trip, so a queue building up on the upload looks
+//! the same as one on the download. Daemons listed as peers of each other
+//! send timestamped UDP probes and answer the other's, and each probe gets
+//! four times, NTP-style:
+//!
+//! ```text
+//! t1: probe sent, our clock        t2: probe received, peer clock
+//! t4: reply received, our clock    t3: reply sent, peer clock
+//! ```
+//!
+//! The round trip is (t4 - t1) - (t3 - t2), and the clock of the peer is
+//! ((t2 - t1) + (t3 - t4)) / 2 ahead of ours if both ways take the same.
+//! That holds when the queues are empty, so the offset is taken from the
+//! quickest round trip among the last OFFSET_SAMPLES, and with it each probe
+//! tells the delay of each way.
+//!
+//! Replies also carry how many probes the peer got from us. Probes the peer
+//! never counted were lost going, and replies it sent that never came back
+//! were lost returning.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::net::{SocketAddr, UdpSocket};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime};
+
+use rand::Rng;
+use zzping_lib::framedata::FrameData;
+use zzping_lib::framedataq::{Complete, FrameDataQ};
+use zzping_lib::logevent::{DaemonStart, FrameMeta, HostHealth, LogEvent, PeerPath, StopReason};
+use zzping_lib::secure::SecureChannel;
+
+use crate::history::History;
+use crate::schedule::Tick;
+use crate::timestamping;
+
+/// Round trips kept to pick the clock offset from. At the default rate it's
+/// a minute, longer than most bursts of traffic.
+const OFFSET_SAMPLES: usize = 600;
+
+/// Sessions counted by the responder; a few per peer, as they restart.
+const MAX_SESSIONS: usize = 64;
+
+/// Probes without a reply after this long are lost.
+const LOST_AFTER: Duration = Duration::from_secs(1);
+
+/// Time over which the loss of each way is told.
+const LOSS_WINDOW: Duration = Duration::from_secs(10);
+
+/// A datagram between peers. Times are microseconds since the epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerMessage {
+    Probe {
+        /// Random for each run, so the counts of a past run are not mixed in.
+        session: u64,
+        seq: u64,
+        sent_us: i64,
+    },
+    Reply {
+        session: u64,
+        seq: u64,
+        sent_us: i64,
+        recv_us: i64,
+        reply_us: i64,
+        /// Probes of this session received, this one included.
+        received: u64,
+    },
+}
+
+impl PeerMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut v: Vec<u8> = vec![];
+        let wr = &mut v;
+        match *self {
+            PeerMessage::Probe {
+                session,
+                seq,
+                sent_us,
+            } => {
+                rmp::encode::write_array_len(wr, 4).unwrap();
+                rmp::encode::write_str(wr, "probe").unwrap();
+                rmp::encode::write_uint(wr, session).unwrap();
+                rmp::encode::write_uint(wr, seq).unwrap();
+                rmp::encode::write_sint(wr, sent_us).unwrap();
+            }
+            PeerMessage::Reply {
+                session,
+                seq,
+                sent_us,
+                recv_us,
+                reply_us,
+                received,
+            } => {
+                rmp::encode::write_array_len(wr, 7).unwrap();
+                rmp::encode::write_str(wr, "reply").unwrap();
+                rmp::encode::write_uint(wr, session).unwrap();
+                rmp::encode::write_uint(wr, seq).unwrap();
+                rmp::encode::write_sint(wr, sent_us).unwrap();
+                rmp::encode::write_sint(wr, recv_us).unwrap();
+                rmp::encode::write_sint(wr, reply_us).unwrap();
+                rmp::encode::write_uint(wr, received).unwrap();
+            }
+        }
+        v
+    }
+
+    pub fn decode(mut buf: &[u8]) -> Result<Self, String> {
+        let rd = &mut buf;
+        let len = rmp::decode::read_array_len(rd).map_err(|e| e.to_string())?;
+        let mut kind = [0_u8; 8];
+        let kind = rmp::decode::read_str(rd, &mut kind).map_err(|e| format!("{:?}", e))?;
+        let mut uint = || rmp::decode::read_int::<u64, _>(rd).map_err(|e| e.to_string());
+        match (kind, len) {
+            ("probe", 4) => Ok(PeerMessage::Probe {
+                session: uint()?,
+                seq: uint()?,
+                sent_us: uint()? as i64,
+            }),
+            ("reply", 7) => Ok(PeerMessage::Reply {
+                session: uint()?,
+                seq: uint()?,
+                sent_us: uint()? as i64,
+                recv_us: uint()? as i64,
+                reply_us: uint()? as i64,
+                received: uint()?,
+            }),
+            _ => Err(format!("unknown message '{}' of {} fields", kind, len)),
+        }
+    }
+}
+
+/// The four times of a probe, in microseconds since the epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sample {
+    pub t1: i64,
+    pub t2: i64,
+    pub t3: i64,
+    pub t4: i64,
+}
+
+impl Sample {
+    /// Time on the network, without the time the peer took to answer.
+    pub fn rtt_us(&self) -> i64 {
+        (self.t4 - self.t1) - (self.t3 - self.t2)
+    }
+
+    /// How far ahead the clock of the peer is, if both ways took the same.
+    pub fn offset_us(&self) -> i64 {
+        ((self.t2 - self.t1) + (self.t3 - self.t4)) / 2
+    }
+}
+
+/// Answers the probes of the peers, counting them for each session.
+#[derive(Debug, Default)]
+pub struct Responder {
+    received: HashMap<u64, u64>,
+}
+
+impl Responder {
+    /// The reply to a probe received at "recv_us", to be sent at "reply_us".
+    /// None if it's not a probe.
+    pub fn answer(
+        &mut self,
+        msg: &PeerMessage,
+        recv_us: i64,
+        reply_us: i64,
+    ) -> Option<PeerMessage> {
+        let (session, seq, sent_us) = match *msg {
+            PeerMessage::Probe {
+                session,
+                seq,
+                sent_us,
+            } => (session, seq, sent_us),
+            PeerMessage::Reply { .. } => return None,
+        };
+        if !self.received.contains_key(&session) && self.received.len() >= MAX_SESSIONS {
+            self.received.clear();
+        }
+        let received = self.received.entry(session).or_default();
+        *received += 1;
+        Some(PeerMessage::Reply {
+            session,
+            seq,
+            sent_us,
+            recv_us,
+            reply_us,
+            received: *received,
+        })
+    }
+}
+
+/// Counters as of a reply: its seq, the probes the peer had got by then,
+/// and the replies we had got.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Counts {
+    seq: u64,
+    received: u64,
+    replies: u64,
+}
+
+/// What was measured to a peer over a frame.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerFrame {
+    /// Round trips of the replies got, sorted.
+    pub rtt_us: Vec<u128>,
+    /// Probes awaiting a reply.
+    pub inflight: usize,
+    /// Probes that got no reply in time, lost either way.
+    pub lost: usize,
+    /// Last measure of each way, None when nothing came back for a while.
+    pub path: Option<PeerPath>,
+}
+
+/// A peer we send probes to, and what came back.
+#[derive(Debug)]
+pub struct PeerLink {
+    /// Name for the logs and stats, "peer-{name}".
+    pub label: String,
+    pub addr: SocketAddr,
+    pub interval: Duration,
+    pub next_send: Instant,
+    session: u64,
+    seq: u64,
+    /// Probes awaiting a reply, and when they were sent.
+    pending: VecDeque<(u64, Instant)>,
+    /// Replies got since the start.
+    replies: u64,
+    /// Counts as of the replies in the last LOSS_WINDOW, and one before.
+    counts: VecDeque<(Instant, Counts)>,
+    /// Round trip and offset of the last replies, to pick the offset from.
+    offsets: VecDeque<(i64, i64)>,
+    /// Replies got since the last frame.
+    samples: Vec<Sample>,
+    /// When the last reply came.
+    pub last_reply: Option<Instant>,
+    /// Last error sending a probe, until one goes out.
+    pub send_error: Option<String>,
+    /// Last measure, for the CLI and the GUIs.
+    pub path: Option<PeerPath>,
+
+    logfile: Option<BufWriter<File>>,
+    log_meta: FrameMeta,
+    history: History,
+    pub daemon_start: Option<DaemonStart>,
+    pub host_health: Option<HostHealth>,
+    pub untrusted: bool,
+}
+
+impl PeerLink {
+    /// A peer to send "frequency" probes per second to, the first one at
+    /// "now".
+    pub fn new(name: &str, addr: SocketAddr, frequency: u32, now: Instant) -> Self {
+        Self {
+            label: format!("peer-{}", name),
+            addr,
+            interval: Duration::from_secs(1) / frequency,
+            next_send: now,
+            session: rand::thread_rng().gen(),
+            seq: 0,
+            pending: VecDeque::new(),
+            replies: 0,
+            counts: VecDeque::new(),
+            offsets: VecDeque::new(),
+            samples: vec![],
+            last_reply: None,
+            send_error: None,
+            path: None,
+            logfile: None,
+            log_meta: FrameMeta::default(),
+            history: History::default(),
+            daemon_start: None,
+            host_health: None,
+            untrusted: false,
+        }
+    }
+
+    /// The next probe, sent now at "sent_us".
+    pub fn probe(&mut self, now: Instant, sent_us: i64) -> PeerMessage {
+        self.seq += 1;
+        self.pending.push_back((self.seq, now));
+        self.next_send += self.interval;
+        // After a stall, go on from now rather than sending a burst.
+        if self.next_send < now {
+            self.next_send = now + self.interval;
+        }
+        PeerMessage::Probe {
+            session: self.session,
+            seq: self.seq,
+            sent_us,
+        }
+    }
+
+    /// Takes a reply received at "recv_us". False if it's not to one of our
+    /// probes, or it came too late.
+    pub fn reply(&mut self, msg: &PeerMessage, recv_us: i64, now: Instant) -> bool {
+        let (seq, received, sample) = match *msg {
+            PeerMessage::Reply {
+                session,
+                seq,
+                sent_us,
+                recv_us: t2,
+                reply_us,
+                received,
+            } if session == self.session => (
+                seq,
+                received,
+                Sample {
+                    t1: sent_us,
+                    t2,
+                    t3: reply_us,
+                    t4: recv_us,
+                },
+            ),
+            _ => return false,
+        };
+        match self.pending.iter().position(|(s, _)| *s == seq) {
+            Some(n) => self.pending.remove(n),
+            None => return false,
+        };
+        self.replies += 1;
+        let counts = Counts {
+            seq,
+            received,
+            replies: self.replies,
+        };
+        // The peer restarting resets its counts; that's not loss.
+        if matches!(self.counts.back(), Some((_, c)) if c.received > received) {
+            self.counts.clear();
+        }
+        if !matches!(self.counts.back(), Some((_, c)) if c.seq > seq) {
+            self.counts.push_back((now, counts));
+        }
+        while matches!(self.counts.get(1), Some((t, _)) if now - *t > LOSS_WINDOW) {
+            self.counts.pop_front();
+        }
+        if self.offsets.len() >= OFFSET_SAMPLES {
+            self.offsets.pop_front();
+        }
+        self.offsets
+            .push_back((sample.rtt_us(), sample.offset_us()));
+        self.samples.push(sample);
+        self.last_reply = Some(now);
+        true
+    }
+
+    /// Forgets the offsets measured, i.e. after the wall clock was stepped.
+    pub fn forget_offsets(&mut self) {
+        self.offsets.clear();
+    }
+
+    /// Offset of the quickest recent round trip, the least queued.
+    pub fn offset_us(&self) -> Option<i64> {
+        self.offsets
+            .iter()
+            .min_by_key(|(rtt, _)| *rtt)
+            .map(|(_, offset)| *offset)
+    }
+
+    /// Percent lost going and coming back, over the last LOSS_WINDOW.
+    fn loss_pct(&self) -> (f32, f32) {
+        let (first, last) = match (self.counts.front(), self.counts.back()) {
+            (Some((_, first)), Some((_, last))) if last.seq > first.seq => (first, last),
+            _ => return (0.0, 0.0),
+        };
+        let sent = last.seq - first.seq;
+        let got = (last.received - first.received).min(sent);
+        let back = (last.replies - first.replies).min(got);
+        let up = 100.0 * (sent - got) as f32 / sent as f32;
+        let down = match got {
+            0 => 0.0,
+            got => 100.0 * (got - back) as f32 / got as f32,
+        };
+        (up, down)
+    }
+
+    /// Measures of the replies got since the last frame, taken at "now".
+    pub fn frame(&mut self, now: Instant) -> PeerFrame {
+        let mut lost = 0;
+        while matches!(self.pending.front(), Some((_, sent)) if now - *sent > LOST_AFTER) {
+            self.pending.pop_front();
+            lost += 1;
+        }
+        let samples = std::mem::take(&mut self.samples);
+        let mut rtt_us: Vec<u128> = samples.iter().map(|s| s.rtt_us().max(0) as u128).collect();
+        rtt_us.sort_unstable();
+        match self.offset_us() {
+            Some(offset) if !samples.is_empty() => {
+                let median = |mut v: Vec<i64>| {
+                    v.sort_unstable();
+                    v[v.len() / 2] as f32 / 1000.0
+                };
+                let up = samples.iter().map(|s| s.t2 - offset - s.t1).collect();
+                let down = samples.iter().map(|s| s.t4 - (s.t3 - offset)).collect();
+                let (up_loss_pct, down_loss_pct) = self.loss_pct();
+                self.path = Some(PeerPath {
+                    offset_ms: offset as f32 / 1000.0,
+                    up_ms: median(up),
+                    down_ms: median(down),
+                    up_loss_pct,
+                    down_loss_pct,
+                });
+            }
+            // The last measure holds until the replies are overdue.
+            _ if matches!(self.last_reply, Some(t) if now - t <= LOST_AFTER) => {}
+            _ => self.path = None,
+        }
+        PeerFrame {
+            rtt_us,
+            inflight: self.pending.len(),
+            lost,
+            path: self.path,
+        }
+    }
+
+    /// Keeps the frames of the last "keep" in memory, like destinations do.
+    pub fn set_history(&mut self, keep: Duration) {
+        self.history = History::new(keep);
+    }
+
+    /// Enables logging the frames to disk, to
+    /// {dir}/pingd-log-peer-{name}-{now}.log, as destinations do.
+    pub fn create_log_file(&mut self, dir: &str, now: &str) {
+        let filename = Path::new(dir).join(format!("pingd-log-{}-{}.log", self.label, now));
+        let f = File::create(&filename)
+            .unwrap_or_else(|e| panic!("unable to create file {}: {}", filename.display(), &e));
+        self.close_log_file();
+        self.logfile = Some(BufWriter::new(f));
+        self.log_meta = FrameMeta::default();
+    }
+
+    /// Records that measures stop for "reason", and closes the log file.
+    pub fn stop(&mut self, reason: StopReason) {
+        if let Some(log) = self.logfile.as_mut() {
+            if let Err(e) = LogEvent::DaemonStop(reason).encode(log) {
+                error!("Error writing to the log of {}: {}", self.label, e);
+            }
+        }
+        self.close_log_file();
+    }
+
+    fn close_log_file(&mut self) {
+        if let Some(mut log) = self.logfile.take() {
+            if let Err(e) = log.flush() {
+                error!("Error flushing the log of {}: {}", self.label, e);
+            }
+        }
+    }
+
+    /// Takes the frame of the last refresh and writes it to the log file, as
+    /// Destination::log_frame does. The round trips take the place of the
+    /// pings, and the delay each way goes in the events.
+    pub fn log_frame(
+        &mut self,
+        now: Instant,
+        tick: &Tick,
+        freq: f32,
+    ) -> Result<Option<FrameDataQ<Complete>>, rmp::encode::ValueWriteError> {
+        let frame = self.frame(now);
+        let meta = FrameMeta {
+            probe_rate: Some(freq),
+            discontinuity: tick.discontinuity,
+            daemon_start: self.daemon_start.take(),
+            host_health: self.host_health,
+            untrusted: self.untrusted,
+            peer_path: frame.path,
+            ..Default::default()
+        };
+        let events = self.log_meta.diff(&meta);
+        self.log_meta = meta;
+        let framedata = FrameData {
+            time: tick.time.clone(),
+            inflight: frame.inflight,
+            lost_packets: frame.lost,
+            recv_us: frame.rtt_us,
+            meta,
+        };
+        let placed = self.history.push(&framedata);
+        let f = match self.logfile.as_mut() {
+            Some(f) => f,
+            None => return Ok(placed),
+        };
+        for event in events {
+            event.encode(f)?;
+        }
+        framedata.encode(f)?;
+        Ok(placed)
+    }
+}
+
+/// Microseconds since the epoch.
+pub fn unix_us(t: SystemTime) -> i64 {
+    match t.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(d) => d.as_micros() as i64,
+        Err(e) => -(e.duration().as_micros() as i64),
+    }
+}
+
+/// The socket shared by all the peers, sending our probes and answering
+/// theirs.
+#[derive(Debug)]
+pub struct Peers {
+    socket: UdpSocket,
+    /// Authenticates the probes, with the same psk as the GUI traffic.
+    secure: Option<SecureChannel>,
+    responder: Responder,
+    pub links: Vec<PeerLink>,
+}
+
+impl Peers {
+    pub fn bind(addr: &str, secure: Option<SecureChannel>) -> io::Result<Self> {
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_nonblocking(true)?;
+        timestamping::enable_udp_timestamps(&socket);
+        Ok(Self {
+            socket,
+            secure,
+            responder: Responder::default(),
+            links: vec![],
+        })
+    }
+
+    #[cfg(test)]
+    pub fn local_addr(&self) -> SocketAddr {
+        self.socket.local_addr().unwrap()
+    }
+
+    /// When the next probe is due.
+    pub fn next_send(&self) -> Option<Instant> {
+        self.links.iter().map(|l| l.next_send).min()
+    }
+
+    /// Sends the probes that are due at "now".
+    pub fn send_due(&mut self, now: Instant) {
+        for link in self.links.iter_mut().filter(|l| l.next_send <= now) {
+            let wall = SystemTime::now();
+            let msg = link.probe(now, unix_us(wall)).encode();
+            let msg = match self.secure.as_mut() {
+                Some(s) => s.seal(&msg, wall),
+                None => msg,
+            };
+            match self.socket.send_to(&msg, link.addr) {
+                Ok(_) => link.send_error = None,
+                Err(e) => link.send_error = Some(e.to_string()),
+            }
+        }
+    }
+
+    /// Answers the probes and takes the replies that arrived. Only the peers
+    /// listed get answers, from their IP at any port.
+    pub fn recv_all(&mut self, now: Instant) {
+        let mut buf = [0_u8; 1500];
+        while let Ok((len, from, received)) =
+            timestamping::recv_from_stamped(&self.socket, &mut buf)
+        {
+            let msg = match self.secure.as_mut() {
+                Some(s) => match s.open(&buf[..len], received) {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        debug!("Peer datagram from {} refused: {}", from, e);
+                        continue;
+                    }
+                },
+                None => buf[..len].to_vec(),
+            };
+            let msg = match PeerMessage::decode(&msg) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    debug!("Invalid peer datagram from {}: {}", from, e);
+                    continue;
+                }
+            };
+            let recv_us = unix_us(received);
+            if let PeerMessage::Reply { .. } = msg {
+                if let Some(link) = self.links.iter_mut().find(|l| l.addr == from) {
+                    link.reply(&msg, recv_us, now);
+                }
+                continue;
+            }
+            if !self.links.iter().any(|l| l.addr.ip() == from.ip()) {
+                debug!("Probe from {} ignored, not a peer", from);
+                continue;
+            }
+            let wall = SystemTime::now();
+            if let Some(reply) = self.responder.answer(&msg, recv_us, unix_us(wall)) {
+                let reply = match self.secure.as_mut() {
+                    Some(s) => s.seal(&reply.encode(), wall),
+                    None => reply.encode(),
+                };
+                if let Err(e) = self.socket.send_to(&reply, from) {
+                    debug!("Unable to answer the probe of {}: {}", from, e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Replies to probes sent every 100ms from "start", for a peer with its
+    /// clock "offset_us" ahead, and the delays each way given per probe; -1
+    /// when lost. Returns the frame taken 2s after the last probe.
+    fn exchange(
+        link: &mut PeerLink,
+        responder: &mut Responder,
+        start: Instant,
+        offset_us: i64,
+        ways: &[(i64, i64)],
+    ) -> PeerFrame {
+        for (n, (up, down)) in ways.iter().enumerate() {
+            let now = start + Duration::from_millis(100 * n as u64);
+            let t1 = 1_600_000_000_000_000 + 100_000 * n as i64;
+            let probe = link.probe(now, t1);
+            if *up < 0 {
+                continue;
+            }
+            let t2 = t1 + up + offset_us;
+            let reply = responder.answer(&probe, t2, t2 + 50).unwrap();
+            if *down >= 0 {
+                assert!(link.reply(&reply, t2 + 50 - offset_us + down, now));
+            }
+        }
+        link.frame(start + Duration::from_millis(100 * ways.len() as u64 + 2000))
+    }
+
+    #[test]
+    fn test_messages() {
+        let probe = PeerMessage::Probe {
+            session: u64::MAX,
+            seq: 1,
+            sent_us: 1_600_000_000_000_000,
+        };
+        assert_eq!(PeerMessage::decode(&probe.encode()), Ok(probe));
+        let reply = Responder::default().answer(&probe, 7, 8).unwrap();
+        assert_eq!(PeerMessage::decode(&reply.encode()), Ok(reply));
+        assert!(PeerMessage::decode(b"\x95plain").is_err());
+    }
+
+    #[test]
+    fn test_one_way_delay() {
+        let addr = "127.0.0.1:7880".parse().unwrap();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut link = PeerLink::new("pi", addr, 10, start);
+        let mut responder = Responder::default();
+        let offset = 250_000;
+
+        // Quiet first: the offset is found, and both ways look the same.
+        let frame = exchange(
+            &mut link,
+            &mut responder,
+            at(0),
+            offset,
+            &[(5000, 5000); 10],
+        );
+        let path = frame.path.unwrap();
+        assert_eq!(path.offset_ms, 250.0);
+        assert_eq!((path.up_ms, path.down_ms), (5.0, 5.0));
+        assert_eq!(frame.rtt_us, vec![10000; 10]);
+
+        // The upload queues up; only the way up shows it.
+        let frame = exchange(
+            &mut link,
+            &mut responder,
+            at(20),
+            offset,
+            &[(80_000, 5000); 10],
+        );
+        let path = frame.path.unwrap();
+        assert_eq!((path.up_ms, path.down_ms), (80.0, 5.0));
+        assert_eq!(path.offset_ms, 250.0);
+
+        // One probe lost going, two replies coming back.
+        let ways = [
+            (5000, 5000),
+            (-1, 0),
+            (5000, -1),
+            (5000, 5000),
+            (5000, -1),
+            (5000, 5000),
+        ];
+        let frame = exchange(&mut link, &mut responder, at(40), offset, &ways);
+        let path = frame.path.unwrap();
+        assert_eq!(path.up_loss_pct, 100.0 / 6.0);
+        assert_eq!(path.down_loss_pct, 40.0);
+        assert_eq!(frame.lost, 3);
+
+        // Nothing back for a while, and then it's told which way it was.
+        let frame = exchange(&mut link, &mut responder, at(60), offset, &[(-1, 0); 4]);
+        assert_eq!((frame.lost, frame.path), (4, None));
+        let frame = exchange(
+            &mut link,
+            &mut responder,
+            at(64),
+            offset,
+            &[(5000, 5000); 6],
+        );
+        assert_eq!(frame.lost, 0);
+        assert_eq!(frame.path.unwrap().up_loss_pct, 40.0);
+        assert_eq!(frame.path.unwrap().down_loss_pct, 0.0);
+    }
+
+    #[test]
+    fn test_loopback() {
+        let mut a = Peers::bind("127.0.0.1:0", None).unwrap();
+        let mut b = Peers::bind("127.0.0.1:0", None).unwrap();
+        let now = Instant::now();
+        a.links.push(PeerLink::new("b", b.local_addr(), 10, now));
+        b.links.push(PeerLink::new("a", a.local_addr(), 10, now));
+        for _ in 0..5 {
+            let now = Instant::now();
+            a.send_due(now);
+            b.send_due(now);
+            std::thread::sleep(Duration::from_millis(20));
+            b.recv_all(now);
+            a.recv_all(now);
+            std::thread::sleep(Duration::from_millis(20));
+            a.recv_all(now);
+            b.recv_all(now);
+            for link in a.links.iter_mut().chain(b.links.iter_mut()) {
+                link.next_send = now;
+            }
+        }
+        for peers in [&mut a, &mut b] {
+            let frame = peers.links[0].frame(Instant::now());
+            let path = frame.path.unwrap();
+            assert_eq!(frame.rtt_us.len(), 5);
+            // Same clock on both ends.
+            assert!(path.offset_ms.abs() < 5.0, "{:?}", path);
+            assert_eq!((path.up_loss_pct, path.down_loss_pct), (0.0, 0.0));
+        }
+    }
+}
@@ -0,0 +1,260 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Keyframes and log rotation
+//!
+//! Frames carry the time elapsed since the last keyframe, measured on the
+//! monotonic clock; keyframes carry the wall clock time. Log files are
+//! switched every hour of wall clock time, always on a keyframe, so every
+//! file can be read on its own.
+//!
+//! The wall clock is compared against the monotonic one on every frame. When
+//! it jumps (NTP step, suspend), a keyframe is written right away so the
+//! frames after it get the right time, preceded by a Discontinuity event
+//! saying what happened. Suspends are told apart from steps by the boot time,
+//! which keeps counting while suspended. If the jump takes the wall clock to
+//! another hour, or the clock gets back to an hour already written in the
+//! last day, the new files are named down to the second so they don't
+//! replace older logs.
+//!
+
+use super::clock::Clock;
+use chrono::{DateTime, Utc};
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+use zzping_lib::framedata::FrameTime;
+use zzping_lib::logevent::Discontinuity;
+
+/// Difference between the clocks that counts as a step or a suspend.
+const MAX_CLOCK_DRIFT: Duration = Duration::from_secs(1);
+
+/// How far back the hours that got log files are remembered. Steps back
+/// further than this are not expected.
+const USED_HOURS_KEPT: Duration = Duration::from_secs(24 * 3600);
+
+/// Name of the log files for the hour of "wall", i.e. 20210425T18.
+pub fn file_hour(wall: DateTime<Utc>) -> String {
+    let mut name = file_second(wall);
+    name.truncate(11);
+    name
+}
+
+/// Like file_hour, but down to the second. For log files created out of the
+/// hourly schedule, so they don't replace the current ones.
+pub fn file_second(wall: DateTime<Utc>) -> String {
+    let mut name = wall
+        .to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+        .replace(['-', ':'], "");
+    name.truncate(15);
+    name
+}
+
+/// What to do for the frames written now.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tick {
+    /// Time to store in the frames.
+    pub time: FrameTime,
+    /// Name for new log files, if they have to be switched before writing.
+    pub rotate: Option<String>,
+    /// Step of the wall clock or suspend since the last frame, if any.
+    pub discontinuity: Option<Discontinuity>,
+}
+
+/// Decides when to write keyframes and when to switch log files.
+#[derive(Debug)]
+pub struct LogSchedule {
+    /// Maximum time between keyframes.
+    keyframe_every: Duration,
+    /// Time of the last keyframe: monotonic, wall clock and boot time.
+    last_keyframe: Option<(Instant, DateTime<Utc>, Duration)>,
+    /// Hour of the current log files, as in their names.
+    hour: String,
+    /// Hours that got log files already, within USED_HOURS_KEPT.
+    used_hours: HashSet<String>,
+}
+
+impl LogSchedule {
+    pub fn new(keyframe_every: Duration, clock: &dyn Clock) -> Self {
+        let hour = file_hour(clock.wall());
+        Self {
+            keyframe_every,
+            last_keyframe: None,
+            used_hours: HashSet::from([hour.clone()]),
+            hour,
+        }
+    }
+
+    /// Hour of the current log files, as in their names.
+    pub fn hour(&self) -> &str {
+        &self.hour
+    }
+
+    /// Makes the next frame a keyframe, i.e. for a log file just created.
+    pub fn force_keyframe(&mut self) {
+        self.last_keyframe = None;
+    }
+
+    /// Decides how the frames written now are timestamped, and whether the
+    /// log files need to be switched first.
+    pub fn tick(&mut self, clock: &dyn Clock) -> Tick {
+        let now = clock.now();
+        let wall = clock.wall();
+        let boot = clock.boottime();
+        let mut discontinuity = None;
+        if let Some((last_now, last_wall, last_boot)) = self.last_keyframe {
+            let elapsed = now.saturating_duration_since(last_now);
+            let suspended = boot.saturating_sub(last_boot).saturating_sub(elapsed);
+            let expected = last_wall + chrono::Duration::from_std(elapsed + suspended).unwrap();
+            let step = wall - expected;
+            let stepped =
+                step.num_milliseconds().unsigned_abs() > MAX_CLOCK_DRIFT.as_millis() as u64;
+            if stepped || suspended > MAX_CLOCK_DRIFT {
+                let d = Discontinuity {
+                    step_secs: step.num_milliseconds() as f64 / 1000.0,
+                    suspend_secs: suspended.as_secs_f64(),
+                };
+                if suspended > MAX_CLOCK_DRIFT {
+                    warn!("Woke up after {:.3}s suspended", d.suspend_secs);
+                }
+                if stepped {
+                    warn!("Wall clock stepped {:.3}s", d.step_secs);
+                }
+                discontinuity = Some(d);
+            } else if elapsed <= self.keyframe_every {
+                return Tick {
+                    time: FrameTime::Elapsed(elapsed),
+                    rotate: None,
+                    discontinuity,
+                };
+            }
+        }
+        self.last_keyframe = Some((now, wall, boot));
+        let hour = file_hour(wall);
+        let mut rotate = None;
+        if hour != self.hour {
+            // Names sort in time order.
+            let oldest = file_hour(wall - chrono::Duration::from_std(USED_HOURS_KEPT).unwrap());
+            self.used_hours.retain(|h| *h >= oldest);
+            let reused = !self.used_hours.insert(hour.clone());
+            rotate = match discontinuity.is_some() || reused {
+                true => Some(file_second(wall)),
+                false => Some(hour.clone()),
+            };
+            self.hour = hour;
+        }
+        Tick {
+            time: FrameTime::Timestamp(wall),
+            rotate,
+            discontinuity,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_file_names() {
+        let wall = Utc.ymd(2021, 4, 25).and_hms(18, 5, 9);
+        assert_eq!(file_hour(wall), "20210425T18");
+        assert_eq!(file_second(wall), "20210425T180509");
+    }
+
+    #[test]
+    fn test_keyframes_and_rotation() {
+        let clock = ManualClock::new(Utc.ymd(2021, 4, 25).and_hms(18, 59, 40));
+        let mut sched = LogSchedule::new(Duration::from_secs(15), &clock);
+        let step = Duration::from_secs(5);
+        let tick = sched.tick(&clock);
+        assert_eq!(tick.time, FrameTime::Timestamp(clock.wall()));
+        assert_eq!(tick.rotate, None);
+        clock.advance(step);
+        assert_eq!(sched.tick(&clock).time, FrameTime::Elapsed(step));
+        clock.advance(step * 2);
+        assert_eq!(sched.tick(&clock).time, FrameTime::Elapsed(step * 3));
+        // Keyframe 20s after the last one, crossing into the next hour.
+        clock.advance(step);
+        let tick = sched.tick(&clock);
+        assert_eq!(tick.time, FrameTime::Timestamp(clock.wall()));
+        assert_eq!(tick.rotate.as_deref(), Some("20210425T19"));
+        assert_eq!(sched.hour(), "20210425T19");
+        clock.advance(step);
+        assert_eq!(sched.tick(&clock).time, FrameTime::Elapsed(step));
+
+        sched.force_keyframe();
+        let tick = sched.tick(&clock);
+        assert_eq!(tick.time, FrameTime::Timestamp(clock.wall()));
+        assert_eq!(tick.rotate, None);
+    }
+
+    #[test]
+    fn test_clock_jump() {
+        let clock = ManualClock::new(Utc.ymd(2021, 4, 25).and_hms(19, 0, 5));
+        let mut sched = LogSchedule::new(Duration::from_secs(15), &clock);
+        sched.tick(&clock);
+        clock.advance(Duration::from_secs(1));
+        // Small drift is tolerated.
+        clock.jump_wall(chrono::Duration::milliseconds(500));
+        let tick = sched.tick(&clock);
+        assert_eq!(tick.time, FrameTime::Elapsed(Duration::from_secs(1)));
+        assert_eq!(tick.discontinuity, None);
+
+        // Back to the previous hour: keyframe, and don't reuse its file name.
+        clock.jump_wall(chrono::Duration::seconds(-60));
+        let tick = sched.tick(&clock);
+        assert_eq!(tick.time, FrameTime::Timestamp(clock.wall()));
+        assert_eq!(tick.rotate.as_deref(), Some("20210425T185906"));
+        let d = tick.discontinuity.unwrap();
+        assert!(d.step_secs < -59.0 && d.suspend_secs == 0.0);
+        assert_eq!(sched.hour(), "20210425T18");
+
+        // Forward within the same hour: keyframe only.
+        clock.advance(Duration::from_secs(1));
+        clock.jump_wall(chrono::Duration::seconds(30));
+        let tick = sched.tick(&clock);
+        assert_eq!(tick.time, FrameTime::Timestamp(clock.wall()));
+        assert_eq!(tick.rotate, None);
+        assert!(tick.discontinuity.is_some());
+
+        // Getting to 19:00 again, the first log of that hour is kept.
+        clock.advance(Duration::from_secs(30));
+        let tick = sched.tick(&clock);
+        assert_eq!(tick.discontinuity, None);
+        assert_eq!(tick.rotate.as_deref(), Some("20210425T190007"));
+
+        // A suspend isn't a step, even if the wall clock moves the same.
+        clock.advance(Duration::from_secs(1));
+        clock.suspend(Duration::from_secs(300));
+        let tick = sched.tick(&clock);
+        assert_eq!(tick.time, FrameTime::Timestamp(clock.wall()));
+        let d = tick.discontinuity.unwrap();
+        assert_eq!((d.step_secs, d.suspend_secs), (0.0, 300.0));
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(sched.tick(&clock).discontinuity, None);
+    }
+
+    #[test]
+    fn test_used_hours_kept() {
+        let clock = ManualClock::new(Utc.ymd(2021, 4, 25).and_hms(19, 0, 5));
+        let mut sched = LogSchedule::new(Duration::from_secs(15), &clock);
+        for _ in 0..24 * 30 {
+            clock.advance(Duration::from_secs(3600));
+            assert!(sched.tick(&clock).rotate.is_some());
+        }
+        assert_eq!(sched.used_hours.len(), 25);
+    }
+}
@@ -0,0 +1,320 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Simulated network for tests
+//!
+//! Implements Network in process: each address has a SimLink describing its
+//! latency, loss, duplication and reordering, and replies are handed back
+//! when their time comes. All the random choices come from a seeded
+//! generator, and time is read from a ManualClock that waiting for replies
+//! moves forward, so a run is repeatable and takes no real time.
+//!
+//! Replies are stamped with the exact time they were scheduled for, so the
+//! round trip times seen by Destination are the ones drawn here, no matter
+//! how late they're read.
+//!
+
+use super::clock::{Clock, ManualClock};
+use super::icmp;
+use super::network::Network;
+use super::timestamping::StampSource;
+use super::transport::ProbeOptions;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::io;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// Distribution of the round trip time of a link.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Latency {
+    /// Always the same.
+    Fixed(Duration),
+    /// Anything between the two, evenly.
+    Uniform(Duration, Duration),
+    /// Normal distribution, cut at zero.
+    Normal { mean: Duration, std_dev: Duration },
+}
+
+impl Latency {
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> Duration {
+        match *self {
+            Latency::Fixed(d) => d,
+            Latency::Uniform(lo, hi) => lo + hi.saturating_sub(lo).mul_f64(rng.gen()),
+            Latency::Normal { mean, std_dev } => {
+                // Box-Muller transform.
+                let u1: f64 = 1.0 - rng.gen::<f64>();
+                let u2: f64 = rng.gen();
+                let z = (-2.0 * u1.ln()).sqrt() * (std::f64::consts::TAU * u2).cos();
+                let secs = mean.as_secs_f64() + z * std_dev.as_secs_f64();
+                Duration::from_secs_f64(secs.max(0.0))
+            }
+        }
+    }
+}
+
+/// How a simulated link behaves.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimLink {
+    pub latency: Latency,
+    /// Chance (0 to 1) of each ping being lost.
+    pub loss: f64,
+    /// Chance (0 to 1) of a loss burst starting on a ping.
+    pub burst_chance: f64,
+    /// Pings lost in a row on each burst.
+    pub burst_len: u32,
+    /// Chance (0 to 1) of a reply arriving twice.
+    pub duplicate: f64,
+    /// Chance (0 to 1) of a reply being held back, so later ones overtake it.
+    pub reorder: f64,
+    /// How long reordered replies are held back.
+    pub reorder_delay: Duration,
+}
+
+impl SimLink {
+    /// A perfect link with the given latency.
+    pub fn new(latency: Latency) -> Self {
+        Self {
+            latency,
+            loss: 0.0,
+            burst_chance: 0.0,
+            burst_len: 0,
+            duplicate: 0.0,
+            reorder: 0.0,
+            reorder_delay: Duration::ZERO,
+        }
+    }
+}
+
+/// A reply on its way back, ordered by arrival.
+#[derive(Debug)]
+struct Reply {
+    at: Instant,
+    /// Tie breaker, so replies due at the same time keep the order they
+    /// were scheduled in.
+    order: u64,
+    packet: icmp::PacketData,
+}
+
+impl PartialEq for Reply {
+    fn eq(&self, other: &Self) -> bool {
+        (self.at, self.order) == (other.at, other.order)
+    }
+}
+
+impl Eq for Reply {}
+
+impl PartialOrd for Reply {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Reply {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.at, self.order).cmp(&(other.at, other.order))
+    }
+}
+
+/// Counters of what the simulated network did, to check against.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SimStats {
+    pub sent: u64,
+    pub lost: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+}
+
+/// In process network with simulated links.
+#[derive(Debug)]
+pub struct SimNetwork {
+    rng: StdRng,
+    clock: ManualClock,
+    /// Link to each address, and the pings left in its current loss burst.
+    /// Pings to any other address fail to be sent.
+    links: HashMap<IpAddr, (SimLink, u32)>,
+    /// Options of each sender; the first one is the shared one.
+    senders: Vec<ProbeOptions>,
+    /// Replies on their way back, earliest first.
+    pending: BinaryHeap<Reverse<Reply>>,
+    order: u64,
+    pub stats: SimStats,
+}
+
+impl SimNetwork {
+    pub fn new(seed: u64, clock: ManualClock) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+            clock,
+            links: HashMap::new(),
+            senders: vec![ProbeOptions::default()],
+            pending: BinaryHeap::new(),
+            order: 0,
+            stats: SimStats::default(),
+        }
+    }
+
+    /// Adds or replaces the link to "addr".
+    pub fn with_link(mut self, addr: &str, link: SimLink) -> Self {
+        self.links.insert(addr.parse().unwrap(), (link, 0));
+        self
+    }
+
+    fn schedule(&mut self, at: Instant, packet: icmp::PacketData) {
+        self.order += 1;
+        let order = self.order;
+        self.pending.push(Reverse(Reply { at, order, packet }));
+    }
+}
+
+impl Network for SimNetwork {
+    fn add_sender(&mut self, options: &ProbeOptions) -> io::Result<usize> {
+        self.senders.push(options.clone());
+        Ok(self.senders.len() - 1)
+    }
+
+    fn send(
+        &mut self,
+        _sender: usize,
+        packet: &icmp::PacketSent,
+        _payload_size: usize,
+    ) -> io::Result<()> {
+        let addr = packet.data.addr;
+        let (link, burst_left) = match self.links.get_mut(&addr) {
+            Some(link) => link,
+            None => return Err(io::Error::from_raw_os_error(libc::ENETUNREACH)),
+        };
+        let link = link.clone();
+        self.stats.sent += 1;
+        if *burst_left == 0 && self.rng.gen_bool(link.burst_chance) {
+            *burst_left = link.burst_len;
+        }
+        if *burst_left > 0 {
+            *burst_left -= 1;
+            self.stats.lost += 1;
+            return Ok(());
+        }
+        if self.rng.gen_bool(link.loss) {
+            self.stats.lost += 1;
+            return Ok(());
+        }
+        let mut rtt = link.latency.sample(&mut self.rng);
+        if self.rng.gen_bool(link.reorder) {
+            self.stats.reordered += 1;
+            rtt += link.reorder_delay;
+        }
+        let reply = icmp::PacketData::new(packet.data.seqn, packet.data.ident, addr);
+        if self.rng.gen_bool(link.duplicate) {
+            self.stats.duplicated += 1;
+            let again = rtt + link.latency.sample(&mut self.rng);
+            self.schedule(packet.sent + again, reply.clone());
+        }
+        self.schedule(packet.sent + rtt, reply);
+        Ok(())
+    }
+
+    fn recv(&mut self, deadline: Instant) -> Option<icmp::PacketData> {
+        loop {
+            let now = self.clock.now();
+            match self.pending.peek() {
+                Some(Reverse(reply)) if reply.at <= now => {
+                    let Reverse(reply) = self.pending.pop().unwrap();
+                    let mut packet = reply.packet;
+                    packet.received = Some(reply.at);
+                    packet.stamp = StampSource::Kernel;
+                    return Some(packet);
+                }
+                Some(Reverse(reply)) if reply.at <= deadline => self.clock.sleep_until(reply.at),
+                _ => {
+                    self.clock.sleep_until(deadline);
+                    return None;
+                }
+            }
+        }
+    }
+
+    fn stamp_method(&self) -> StampSource {
+        StampSource::Kernel
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_sample() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let ms = Duration::from_millis;
+        assert_eq!(Latency::Fixed(ms(5)).sample(&mut rng), ms(5));
+        for _ in 0..100 {
+            let d = Latency::Uniform(ms(5), ms(10)).sample(&mut rng);
+            assert!(d >= ms(5) && d <= ms(10));
+        }
+        let normal = Latency::Normal {
+            mean: ms(20),
+            std_dev: ms(2),
+        };
+        let n = 2000;
+        let total: Duration = (0..n).map(|_| normal.sample(&mut rng)).sum();
+        let mean = total / n;
+        assert!(mean > ms(19) && mean < ms(21), "{:?}", mean);
+    }
+
+    #[test]
+    fn test_sim_is_repeatable() {
+        let link = SimLink {
+            loss: 0.2,
+            duplicate: 0.1,
+            reorder: 0.1,
+            reorder_delay: Duration::from_millis(3),
+            ..SimLink::new(Latency::Uniform(
+                Duration::from_millis(1),
+                Duration::from_millis(2),
+            ))
+        };
+        let run = || {
+            let clock = ManualClock::new(chrono::Utc::now());
+            let mut net = SimNetwork::new(42, clock.clone()).with_link("10.0.0.1", link.clone());
+            let start = clock.now();
+            for seq in 0..50 {
+                let data = icmp::PacketData::new(seq, 7, "10.0.0.1".parse().unwrap());
+                let packet = icmp::PacketSent::new(data, start);
+                net.send(0, &packet, 8).unwrap();
+            }
+            let deadline = start + Duration::from_millis(10);
+            let mut replies = vec![];
+            while let Some(p) = net.recv(deadline) {
+                replies.push((p.seqn, p.received.unwrap() - start));
+            }
+            (net.stats, replies)
+        };
+        let (stats, replies) = run();
+        assert_eq!((stats, replies.clone()), run());
+        assert_eq!(stats.sent, 50);
+        assert!(stats.lost > 0 && stats.duplicated > 0 && stats.reordered > 0);
+        let expected = stats.sent - stats.lost + stats.duplicated;
+        assert_eq!(replies.len() as u64, expected);
+        // Handed out in order of arrival.
+        assert!(replies.windows(2).all(|w| w[0].1 <= w[1].1));
+
+        let clock = ManualClock::new(chrono::Utc::now());
+        let mut net = SimNetwork::new(1, clock.clone());
+        let data = icmp::PacketData::new(1, 7, "10.0.0.2".parse().unwrap());
+        let packet = icmp::PacketSent::new(data, clock.now());
+        assert!(net.send(0, &packet, 8).is_err());
+    }
+}
@@ -0,0 +1,125 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Socket options for the ICMP senders
+//!
+//! Thin wrappers over setsockopt/bind for the per-target probe options. They
+//! only apply to IPv4 sockets, as that's the only transport the daemon has.
+//!
+
+use std::io;
+use std::mem;
+use std::net::Ipv4Addr;
+
+fn setsockopt_int(
+    fd: libc::c_int,
+    level: libc::c_int,
+    opt: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            opt,
+            &value as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Sets the Time To Live of the packets sent.
+pub fn set_ttl(fd: libc::c_int, ttl: u8) -> io::Result<()> {
+    setsockopt_int(fd, libc::IPPROTO_IP, libc::IP_TTL, ttl as libc::c_int)
+}
+
+/// Sets the DSCP class of the packets sent. ECN bits are left as zero.
+pub fn set_dscp(fd: libc::c_int, dscp: u8) -> io::Result<()> {
+    let tos = ((dscp & 0x3f) << 2) as libc::c_int;
+    setsockopt_int(fd, libc::IPPROTO_IP, libc::IP_TOS, tos)
+}
+
+/// Sets the Don't Fragment bit. Packets bigger than the path MTU will fail to
+/// be sent (or be dropped on the way) instead of being fragmented.
+#[cfg(target_os = "linux")]
+pub fn set_dont_fragment(fd: libc::c_int) -> io::Result<()> {
+    setsockopt_int(
+        fd,
+        libc::IPPROTO_IP,
+        libc::IP_MTU_DISCOVER,
+        libc::IP_PMTUDISC_DO,
+    )
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_dont_fragment(_fd: libc::c_int) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "DF is only supported on Linux",
+    ))
+}
+
+/// Forces all packets to leave through the given network interface.
+#[cfg(target_os = "linux")]
+pub fn bind_device(fd: libc::c_int, interface: &str) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            interface.as_ptr() as *const libc::c_void,
+            interface.len() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn bind_device(_fd: libc::c_int, _interface: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "SO_BINDTODEVICE is only supported on Linux",
+    ))
+}
+
+/// Sends the packets from the given local address.
+pub fn bind_source(fd: libc::c_int, addr: Ipv4Addr) -> io::Result<()> {
+    let mut sin: libc::sockaddr_in = unsafe { mem::zeroed() };
+    sin.sin_family = libc::AF_INET as libc::sa_family_t;
+    sin.sin_addr.s_addr = u32::from(addr).to_be();
+    let ret = unsafe {
+        libc::bind(
+            fd,
+            &sin as *const libc::sockaddr_in as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Shrinks the receive buffer to the minimum. Used for sockets that are only
+/// used to send, as raw sockets get a copy of every ICMP packet.
+pub fn shrink_recv_buffer(fd: libc::c_int) -> io::Result<()> {
+    setsockopt_int(fd, libc::SOL_SOCKET, libc::SO_RCVBUF, 0)
+}
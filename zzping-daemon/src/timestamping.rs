@@ -0,0 +1,316 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Receive timestamps for incoming packets
+//!
+//! Taking `Instant::now()` after a packet has crossed into userspace adds the
+//! scheduling delay of the reader thread to every RTT. On Linux the kernel can
+//! stamp each packet as it arrives (SO_TIMESTAMPING / SO_TIMESTAMPNS). This
+//! module reads packets along with those timestamps, falling back to userspace
+//! time when they aren't available.
+//!
+//! Only the receive side is stamped by the kernel. The send time is still
+//! taken in userspace, just before send_to, so an RTT also includes the time
+//! the kernel took to put the request on the wire: a few microseconds, and
+//! always in the same direction, against the scheduling delay saved on receive.
+//!
+//! Hardware (NIC) timestamps are not used, only the software ones of the
+//! kernel. The card only stamps packets once hardware stamping is turned on
+//! for the whole interface (SIOCSHWTSTAMP), which needs CAP_NET_ADMIN and
+//! changes it for every other program using it. And its stamps are on the
+//! clock of the card, not the system clock the send time is taken from,
+//! unless something like phc2sys keeps the two in sync.
+//!
+
+use pnet_transport::TransportReceiver;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Where the receive time of a packet came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum StampSource {
+    /// Taken in userspace after reading the packet. Includes scheduling noise.
+    Userspace,
+    /// Taken by the kernel network stack when the packet was received.
+    Kernel,
+}
+
+impl std::fmt::Display for StampSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            StampSource::Userspace => "userspace",
+            StampSource::Kernel => "kernel",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Reads packets from a TransportReceiver along with their receive time, as
+/// stamped by the kernel in software. Never by the NIC, see the module docs.
+pub struct StampedReceiver {
+    rx: TransportReceiver,
+    /// Buffer for the ancillary data. u64 to keep cmsghdr aligned.
+    control: Vec<u64>,
+    /// Best timestamping method that the socket accepted.
+    pub method: StampSource,
+}
+
+impl StampedReceiver {
+    /// Takes ownership of the receiver and enables the best timestamping
+    /// method available on its socket.
+    pub fn new(rx: TransportReceiver) -> Self {
+        let method = enable_timestamps(rx.socket.fd);
+        Self {
+            rx,
+            control: vec![0; 64],
+            method,
+        }
+    }
+
+    /// Blocks until a packet is received.
+    ///
+    /// Returns the packet as read from the socket (for IPv4, including the IP
+    /// header), who sent it, and when and how it was stamped.
+    pub fn recv(&mut self) -> io::Result<(&[u8], IpAddr, Instant, StampSource)> {
+        let (len, addr, stamp) =
+            recvmsg(self.rx.socket.fd, &mut self.rx.buffer, &mut self.control)?;
+        let now = Instant::now();
+        let wall = SystemTime::now();
+        let (received, source) = match stamp {
+            Some((stamp, source)) => (to_instant(stamp, now, wall), source),
+            None => (now, StampSource::Userspace),
+        };
+        let ip = sockaddr_to_ip(&addr)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "unknown address family"))?;
+        Ok((&self.rx.buffer[..len], ip, received, source))
+    }
+}
+
+/// Enables the best timestamping method available on a UDP socket, for
+/// recv_from_stamped.
+pub fn enable_udp_timestamps(socket: &UdpSocket) -> StampSource {
+    enable_timestamps(socket.as_raw_fd())
+}
+
+/// Reads a datagram from a UDP socket, along with who sent it and the wall
+/// clock time it was received, as the kernel stamped it if it did.
+pub fn recv_from_stamped(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, SystemTime)> {
+    let mut control = [0_u64; 64];
+    let (len, addr, stamp) = recvmsg(socket.as_raw_fd(), buf, &mut control)?;
+    let received = stamp.map_or_else(SystemTime::now, |(stamp, _)| stamp);
+    let ip = sockaddr_to_ip(&addr)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "unknown address family"))?;
+    Ok((len, SocketAddr::new(ip, sockaddr_port(&addr)), received))
+}
+
+/// A packet read: its length, who sent it, and its timestamp, if any.
+type Received = (
+    usize,
+    libc::sockaddr_storage,
+    Option<(SystemTime, StampSource)>,
+);
+
+/// Reads a packet into "buf", with its timestamp if the kernel added one to
+/// "control".
+fn recvmsg(fd: libc::c_int, buf: &mut [u8], control: &mut [u64]) -> io::Result<Received> {
+    let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = &mut addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+    msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = mem::size_of_val(control) as _;
+
+    let len = unsafe { libc::recvmsg(fd, &mut msg, 0) };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((len as usize, addr, unsafe { read_timestamp(&msg) }))
+}
+
+/// Translates a wall clock timestamp into the monotonic clock, using a pair of
+/// readings of both clocks taken at the same time.
+pub fn to_instant(stamp: SystemTime, now: Instant, wall: SystemTime) -> Instant {
+    match wall.duration_since(stamp) {
+        Ok(age) => now.checked_sub(age).unwrap_or(now),
+        // Stamped "in the future": the wall clock was stepped back meanwhile.
+        Err(_) => now,
+    }
+}
+
+fn sockaddr_to_ip(addr: &libc::sockaddr_storage) -> Option<IpAddr> {
+    match addr.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let sin = unsafe { &*(addr as *const _ as *const libc::sockaddr_in) };
+            Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(
+                sin.sin_addr.s_addr,
+            ))))
+        }
+        libc::AF_INET6 => {
+            let sin6 = unsafe { &*(addr as *const _ as *const libc::sockaddr_in6) };
+            Some(IpAddr::V6(Ipv6Addr::from(sin6.sin6_addr.s6_addr)))
+        }
+        _ => None,
+    }
+}
+
+fn sockaddr_port(addr: &libc::sockaddr_storage) -> u16 {
+    match addr.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let sin = unsafe { &*(addr as *const _ as *const libc::sockaddr_in) };
+            u16::from_be(sin.sin_port)
+        }
+        libc::AF_INET6 => {
+            let sin6 = unsafe { &*(addr as *const _ as *const libc::sockaddr_in6) };
+            u16::from_be(sin6.sin6_port)
+        }
+        _ => 0,
+    }
+}
+
+fn timespec_to_systime(ts: &libc::timespec) -> Option<SystemTime> {
+    if ts.tv_sec == 0 && ts.tv_nsec == 0 {
+        return None;
+    }
+    Some(UNIX_EPOCH + Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32))
+}
+
+/// Picks the timestamp out of an SCM_TIMESTAMPING message: software, (legacy),
+/// raw hardware. Only software stamps are requested, so the first one.
+pub fn pick_timestamping(ts: &[libc::timespec; 3]) -> Option<(SystemTime, StampSource)> {
+    timespec_to_systime(&ts[0]).map(|sw| (sw, StampSource::Kernel))
+}
+
+#[cfg(target_os = "linux")]
+fn enable_timestamps(fd: libc::c_int) -> StampSource {
+    // Hardware stamps would need SIOCSHWTSTAMP on each interface, and a NIC
+    // clock synced to the system one; software stamps are good enough here.
+    let flags: libc::c_uint = libc::SOF_TIMESTAMPING_RX_SOFTWARE | libc::SOF_TIMESTAMPING_SOFTWARE;
+    if setsockopt(fd, libc::SO_TIMESTAMPING, flags).is_ok() {
+        return StampSource::Kernel;
+    }
+    if setsockopt(fd, libc::SO_TIMESTAMPNS, 1).is_ok() {
+        return StampSource::Kernel;
+    }
+    StampSource::Userspace
+}
+
+#[cfg(not(target_os = "linux"))]
+fn enable_timestamps(_fd: libc::c_int) -> StampSource {
+    StampSource::Userspace
+}
+
+#[cfg(target_os = "linux")]
+fn setsockopt(fd: libc::c_int, opt: libc::c_int, value: libc::c_uint) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            opt,
+            &value as *const libc::c_uint as *const libc::c_void,
+            mem::size_of::<libc::c_uint>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Looks for a kernel timestamp in the ancillary data of a message.
+///
+/// # Safety
+///
+/// msg must come from a successful call to recvmsg.
+#[cfg(target_os = "linux")]
+unsafe fn read_timestamp(msg: &libc::msghdr) -> Option<(SystemTime, StampSource)> {
+    let mut cmsg = libc::CMSG_FIRSTHDR(msg);
+    while !cmsg.is_null() {
+        let hdr = &*cmsg;
+        if hdr.cmsg_level == libc::SOL_SOCKET {
+            let data = libc::CMSG_DATA(cmsg);
+            if hdr.cmsg_type == libc::SCM_TIMESTAMPING {
+                let ts = std::ptr::read_unaligned(data as *const [libc::timespec; 3]);
+                return pick_timestamping(&ts);
+            }
+            if hdr.cmsg_type == libc::SCM_TIMESTAMPNS {
+                let ts = std::ptr::read_unaligned(data as *const libc::timespec);
+                return timespec_to_systime(&ts).map(|t| (t, StampSource::Kernel));
+            }
+        }
+        cmsg = libc::CMSG_NXTHDR(msg, cmsg);
+    }
+    None
+}
+
+#[cfg(not(target_os = "linux"))]
+unsafe fn read_timestamp(_msg: &libc::msghdr) -> Option<(SystemTime, StampSource)> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ts(sec: i64, nsec: i64) -> libc::timespec {
+        libc::timespec {
+            tv_sec: sec as libc::time_t,
+            tv_nsec: nsec as _,
+        }
+    }
+
+    #[test]
+    fn test_pick_timestamping() {
+        let zero = ts(0, 0);
+        // Only software
+        let got = pick_timestamping(&[ts(100, 5), zero, zero]).unwrap();
+        assert_eq!(
+            got,
+            (UNIX_EPOCH + Duration::new(100, 5), StampSource::Kernel)
+        );
+        // Hardware stamps are not asked for, ignored if they show up
+        let got = pick_timestamping(&[ts(100, 500), zero, ts(100, 400)]).unwrap();
+        assert_eq!(
+            got,
+            (UNIX_EPOCH + Duration::new(100, 500), StampSource::Kernel)
+        );
+        // Nothing at all
+        assert!(pick_timestamping(&[zero, zero, zero]).is_none());
+    }
+
+    #[test]
+    fn test_to_instant() {
+        let now = Instant::now();
+        let wall = SystemTime::now();
+        let stamp = wall - Duration::from_micros(250);
+        assert_eq!(
+            now.duration_since(to_instant(stamp, now, wall)),
+            Duration::from_micros(250)
+        );
+        // A stamp in the future can't be older than now.
+        let stamp = wall + Duration::from_secs(1);
+        assert_eq!(to_instant(stamp, now, wall), now);
+    }
+}
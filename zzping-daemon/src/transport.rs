@@ -17,25 +17,27 @@
 //! This is the core of the zzping-daemon binary, it holds its main behavior.
 //!
 
+use super::budget::TokenBucket;
+use super::clock::{Clock, SystemClock};
+use super::health::Lateness;
+use super::history::History;
 use super::icmp;
-use pnet_transport::{TransportChannelType, TransportReceiver, TransportSender};
+use super::network::{IcmpNetwork, Network};
+use super::schedule::Tick;
+use super::timestamping::StampSource;
 use rand::Rng;
+use std::io::{self, BufWriter};
+use std::net::IpAddr;
+use std::path::Path;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 use std::{fs::File, io::Write};
-use std::{io::BufWriter, sync::Mutex};
-use std::{net::IpAddr, sync::Arc};
-use std::{
-    thread,
-    time::{Duration, Instant},
+use zzping_lib::framedata::FrameData;
+use zzping_lib::framedataq::{Complete, FrameDataQ};
+use zzping_lib::logevent::{
+    DaemonStart, FrameMeta, HostHealth, LinkStats, LogEvent, StopReason, Wireless,
 };
 
-/// Creates a TransportChannelType for ICMP over IPv4
-pub fn protocol_ipv4() -> TransportChannelType {
-    use pnet::packet::ip::IpNextHeaderProtocols;
-    use pnet_transport::TransportChannelType::Layer4;
-    use pnet_transport::TransportProtocol::Ipv4;
-    Layer4(Ipv4(IpNextHeaderProtocols::Icmp))
-}
-
 /// Parses a string into an IP Address.
 pub fn parse_ipaddr(ipaddr: &str) -> Option<IpAddr> {
     // TODO: This function is basically useless. What do we do with it?
@@ -68,15 +70,104 @@ pub fn sent_after(pck: &icmp::PacketSent, now: Instant, wait: Duration) -> bool
     !sent_before(pck, now, wait)
 }
 
+/// How long an inflight packet has to wait to be counted as lost when
+/// deciding whether there's trouble.
+const TROUBLE_LOST_AFTER: Duration = Duration::from_millis(300);
+
+/// How often to check if destinations are getting the pings they're configured for.
+const SAMPLING_WINDOW: Duration = Duration::from_secs(5);
+
+/// A destination getting less than this fraction of its pings is under-sampled.
+const MIN_SAMPLING_RATIO: f32 = 0.9;
+
+/// When and how to switch a destination to a faster rate.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BurstPolicy {
+    /// Interval between pings while in trouble.
+    pub interval: Duration,
+    /// Packet loss percentage that counts as trouble.
+    pub loss_pct: f32,
+    /// Average latency that counts as trouble.
+    pub latency: Option<Duration>,
+    /// How long without trouble before going back to the base interval.
+    pub hold: Duration,
+}
+
+/// Per destination options on how the pings are crafted and sent.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProbeOptions {
+    /// Bytes of payload after the ICMP header.
+    pub payload_size: usize,
+    /// Set the Don't Fragment bit.
+    pub dont_fragment: bool,
+    /// Time To Live, system default if None.
+    pub ttl: Option<u8>,
+    /// DSCP class to mark the packets with.
+    pub dscp: Option<u8>,
+    /// Network interface the packets must leave through.
+    pub interface: Option<String>,
+    /// Local address to send the packets from.
+    pub source_address: Option<IpAddr>,
+    /// Faster rate to use while there's trouble, if any.
+    pub burst: Option<BurstPolicy>,
+    /// Share of the global budget this destination gets when there isn't
+    /// enough for everyone. Zero is taken as one.
+    pub priority: u32,
+}
+
+impl ProbeOptions {
+    /// Whether these options require a socket on their own, instead of the
+    /// one shared by all destinations.
+    pub fn needs_own_socket(&self) -> bool {
+        self.dont_fragment
+            || self.ttl.is_some()
+            || self.dscp.is_some()
+            || self.interface.is_some()
+            || self.source_address.is_some()
+    }
+
+    /// Name for a probing stream to "addr" with these options.
+    pub fn label(&self, addr: &str) -> String {
+        match (&self.interface, &self.source_address) {
+            (Some(iface), _) => format!("{}%{}", addr, iface),
+            (None, Some(src)) => format!("{}@{}", addr, src),
+            (None, None) => addr.to_owned(),
+        }
+    }
+}
+
 /// Defines a destination host with parameters and internal queues.
 #[derive(Debug)]
 pub struct Destination {
-    /// String Address, as used when creating this destination.
-    pub str_addr: String,
+    /// Name of this probing stream, used for logs and stats. It's the address,
+    /// followed by "%interface" or "@source" when those are set.
+    pub label: String,
 
     /// Limit on how frequently to send pings to the target host.
+    ///
+    /// This is the interval in effect; it changes between base_interval and
+    /// the burst interval.
     pub interval: Duration,
 
+    /// Interval to use when there's no trouble.
+    pub base_interval: Duration,
+
+    /// Last time that trouble was seen, while on the burst rate.
+    pub last_trouble: Option<Instant>,
+
+    /// No pings are sent while paused.
+    pub paused: bool,
+
+    /// Virtual time for fair scheduling, advances 1/priority on each ping.
+    pub vtime: f64,
+
+    /// Start of the current sampling window: when, sent_count and interval.
+    sampling_mark: (Instant, u64, Duration),
+
+    /// Fraction of the configured pings actually sent on the last sampling
+    /// window, when it was too low.
+    pub undersampled: Option<f32>,
+
     /// Target Host address.
     pub addr: IpAddr,
 
@@ -89,6 +180,12 @@ pub struct Destination {
     /// When was the last packet sent
     pub last_pckt_sent: Instant,
 
+    /// How the pings to this destination are crafted and sent.
+    pub options: ProbeOptions,
+
+    /// Id of the sender used for this destination in the Network.
+    pub sender: usize,
+
     /// Queue of packets sent awaiting for response.
     ///
     /// When received, they move to recv_packets. If a certain amount of time
@@ -117,22 +214,59 @@ pub struct Destination {
     /// For stats only, this will be reset each time the program restarts.
     pub recv_count: u64,
 
+    /// Stat counter of pings that the OS refused to send.
+    ///
+    /// These are also accounted in lost_packets.
+    pub send_errors: u64,
+
+    /// Last error returned when sending a ping, if any.
+    pub last_send_error: Option<String>,
+
     /// Thread Random generator. Used only for caching purposes.
     pub rng: rand::rngs::ThreadRng,
 
     /// Where to write the packets to disk. To be deprecated.
     pub logfile: Option<BufWriter<File>>,
+
+    /// State already written to the current log file through events.
+    pub log_meta: FrameMeta,
+
+    /// Recent frames, for the GUIs that ask for them.
+    pub history: History,
+
+    /// Start to record with the next frame, when measures begin.
+    pub daemon_start: Option<DaemonStart>,
+
+    /// Last sample of the WiFi link the pings go through, if watched.
+    pub wireless: Option<Wireless>,
+
+    /// Last sample of the counters of the interface the pings go through.
+    pub link_stats: Option<LinkStats>,
+
+    /// Last sample of the load of the host, if watched.
+    pub host_health: Option<HostHealth>,
+
+    /// Whether the host was too busy to trust the next frame.
+    pub untrusted: bool,
 }
 
 impl Destination {
     /// Create a new destination from a IP Address in a string and a interval
-    /// for the frequency of the pings.
-    pub fn new(str_addr: &str, interval: Duration) -> Self {
+    /// for the frequency of the pings. The first ping is due at "now".
+    pub fn new(str_addr: &str, interval: Duration, options: ProbeOptions, now: Instant) -> Self {
         Self {
             addr: parse_ipaddr(str_addr).unwrap(),
-            str_addr: str_addr.to_owned(),
-            last_pckt_sent: Instant::now() - interval,
+            label: options.label(str_addr),
+            last_pckt_sent: now - interval,
+            options,
+            sender: 0,
             interval,
+            base_interval: interval,
+            last_trouble: None,
+            paused: false,
+            vtime: 0.0,
+            sampling_mark: (now, 0, interval),
+            undersampled: None,
             seq: 1,
             ident: rand::thread_rng().gen(),
             inflight_packets: vec![],
@@ -140,8 +274,17 @@ impl Destination {
             lost_packets: vec![],
             sent_count: 0,
             recv_count: 0,
+            send_errors: 0,
+            last_send_error: None,
             rng: rand::thread_rng(),
             logfile: None,
+            log_meta: FrameMeta::default(),
+            history: History::default(),
+            daemon_start: None,
+            wireless: None,
+            link_stats: None,
+            host_health: None,
+            untrusted: false,
         }
     }
 
@@ -149,11 +292,11 @@ impl Destination {
     /// switch to the new file. If the file exists, it will be replaced by a new
     /// one.
     ///
-    /// The filename follows the format ./logs/pingd-log-{str_addr}-{now}.log
-    pub fn create_log_file(&mut self, now: &str) {
-        let filename = format!("logs/pingd-log-{}-{}.log", self.str_addr, now);
+    /// The filename follows the format {dir}/pingd-log-{label}-{now}.log
+    pub fn create_log_file(&mut self, dir: &str, now: &str) {
+        let filename = Path::new(dir).join(format!("pingd-log-{}-{}.log", self.label, now));
         let f = File::create(&filename)
-            .unwrap_or_else(|e| panic!("unable to create file {}: {}", &filename, &e));
+            .unwrap_or_else(|e| panic!("unable to create file {}: {}", filename.display(), &e));
         let mut oldlog = self.logfile.take();
         if let Some(log) = oldlog.as_mut() {
             log.flush().unwrap();
@@ -161,6 +304,100 @@ impl Destination {
         // Buffering is needed to avoid wearing SSDs by not writting the same
         // sector dozens of times. 8KB by default. It auto-flushes.
         self.logfile = Some(BufWriter::new(f));
+        // Every file carries its own events, so it can be read on its own.
+        self.log_meta = FrameMeta::default();
+    }
+
+    /// Records that measures stop for "reason" at the end of the log file,
+    /// and closes it.
+    pub fn stop(&mut self, reason: StopReason) {
+        if let Some(log) = self.logfile.as_mut() {
+            if let Err(e) = LogEvent::DaemonStop(reason).encode(log) {
+                error!("Error writing to the log of {}: {}", self.label, e);
+            }
+        }
+        self.close_log_file();
+    }
+
+    /// Flushes and closes the log file, if there's one.
+    pub fn close_log_file(&mut self) {
+        if let Some(mut log) = self.logfile.take() {
+            if let Err(e) = log.flush() {
+                error!("Error flushing the log of {}: {}", self.label, e);
+            }
+        }
+    }
+
+    /// Writes a frame with the pings of the last "refresh" to the log file, if
+    /// there's one, preceded by the events for any change in state or
+    /// discontinuity in time. The frame is kept in the history too, and it's
+    /// returned with its full timestamp.
+    pub fn log_frame(
+        &mut self,
+        now: Instant,
+        tick: &Tick,
+        refresh: Duration,
+    ) -> Result<Option<FrameDataQ<Complete>>, rmp::encode::ValueWriteError> {
+        let last_recv = self.received_last(now, refresh + refresh / 2);
+        let inflight = self.inflight_after(now, refresh);
+        let mut recv_us: Vec<u128> = last_recv
+            .iter()
+            .map(|p| p.received.unwrap_or_default().as_micros())
+            .collect();
+        recv_us.sort_unstable();
+        let meta = FrameMeta {
+            probe_rate: Some(self.probe_rate()),
+            discontinuity: tick.discontinuity,
+            daemon_start: self.daemon_start.take(),
+            daemon_stop: None,
+            wireless: self.wireless,
+            link_stats: self.link_stats,
+            host_health: self.host_health,
+            untrusted: self.untrusted,
+            peer_path: None,
+        };
+        let events = self.log_meta.diff(&meta);
+        self.log_meta = meta;
+        let framedata = FrameData {
+            time: tick.time.clone(),
+            inflight: inflight.len(),
+            lost_packets: self.lost_packets.len(),
+            recv_us,
+            meta,
+        };
+        let frame = self.history.push(&framedata);
+        let f = match self.logfile.as_mut() {
+            Some(f) => f,
+            None => return Ok(frame),
+        };
+        for event in events {
+            event.encode(f)?;
+        }
+        framedata.encode(f)?;
+        Ok(frame)
+    }
+
+    /// Drops the pings awaiting a reply, i.e. after a suspend: their replies
+    /// were never read, and that's not loss in the network.
+    pub fn forget_inflight(&mut self) {
+        self.inflight_packets.clear();
+    }
+
+    /// Sends the next pings to "addr", keeping the label, i.e. when the gateway
+    /// of an auto target changes. The pings to the old address are dropped:
+    /// their replies, if any, would not be matched.
+    pub fn set_addr(&mut self, addr: IpAddr) {
+        self.addr = addr;
+        self.forget_inflight();
+    }
+
+    /// Changes the interval used when there's no trouble. If the burst rate
+    /// is in effect, it stays until things are stable.
+    pub fn set_base_interval(&mut self, interval: Duration) {
+        self.base_interval = interval;
+        if self.last_trouble.is_none() {
+            self.interval = interval;
+        }
     }
 
     /// Try to match an incoming packet against the inflight_packets queue.
@@ -168,7 +405,9 @@ impl Destination {
     /// If the packet is one that we sent, this function will complete the
     /// packet with the elapsed time of the response and move it to the
     /// recv_packets queue, removing it from the inflight_packets queue.
-    pub fn recv(&mut self, packet: &icmp::PacketData) -> Option<(IpAddr, Duration)> {
+    ///
+    /// Packets without a receive time are taken as received at "now".
+    pub fn recv(&mut self, packet: &icmp::PacketData, now: Instant) -> Option<(IpAddr, Duration)> {
         // TODO: A queue to detect duplicate responses would be nice to have.
         // TODO: Part of this code belongs to icmp::PacketSent::recv.
         // TODO: This code should consume PacketSent and craft a PacketReceived.
@@ -180,7 +419,7 @@ impl Destination {
             if sent.data.seqn == packet.seqn && sent.received.is_none() {
                 sent.received = match packet.received {
                     Some(received) => received.checked_duration_since(sent.sent),
-                    None => Some(sent.sent.elapsed()),
+                    None => Some(now.saturating_duration_since(sent.sent)),
                 };
                 if sent.received.is_none() {
                     // Received before sending. This must be because a duplicate packet was matched.
@@ -198,12 +437,30 @@ impl Destination {
         ret
     }
 
-    /// Send another ping to this destination
+    /// When the next ping to this destination is due.
+    pub fn next_send(&self) -> Instant {
+        self.last_pckt_sent + self.interval
+    }
+
+    /// Send another ping to this destination, if it is due.
     ///
     /// If the destination keeps creeping up in the inflight_packets (not responding)
     /// then this function will randomly be a no-op to avoid DoS to a device, and
     /// also to avoid having insane amounts of packets to search later.
-    pub fn send(&mut self, tx: &mut TransportSender, min_delay: Duration) -> bool {
+    pub fn send(&mut self, net: &mut dyn Network, clock: &dyn Clock) -> bool {
+        let now = clock.now();
+        let next_send = self.next_send();
+        if now < next_send {
+            return false;
+        }
+        // Keep the schedule anchored to when the ping was planned, so a late
+        // wake-up doesn't make every following ping late as well. If we fell
+        // behind by a whole interval or more, restart the schedule from now.
+        self.last_pckt_sent = if now - next_send < self.interval {
+            next_send
+        } else {
+            now
+        };
         let inflight = self.inflight_packets.len() as u16;
         /*
          rnd_num and skipping is a hack to avoid a bug creating nasty sizes of
@@ -214,12 +471,22 @@ impl Destination {
         if rnd_num < inflight {
             return false;
         }
-        if self.last_pckt_sent.elapsed() + min_delay < self.interval {
-            return false;
+        let data = icmp::PacketData::new(self.seq, self.ident, self.addr);
+        let packet = icmp::PacketSent::new(data, now);
+        match net.send(self.sender, &packet, self.options.payload_size) {
+            Ok(()) => self.inflight_packets.push(packet),
+            Err(e) => {
+                // A ping that could not leave this host (i.e. network
+                // unreachable, or too big with DF set) counts as lost.
+                let error = e.to_string();
+                if self.last_send_error.as_ref() != Some(&error) {
+                    warn!("Error sending ping to {}: {}", self.label, error);
+                }
+                self.last_send_error = Some(error);
+                self.send_errors += 1;
+                self.lost_packets.push(packet);
+            }
         }
-        let packet = icmp::PacketData::new(self.seq, self.ident, self.addr).send(tx);
-        self.last_pckt_sent = Instant::now() - Duration::from_micros(self.rng.gen_range(0..101));
-        self.inflight_packets.push(packet);
 
         // The sequence is random to avoid a device "guessing" what the next sequence will be.
         // TODO: This opens the door to sending two packets with the same seq number.
@@ -231,8 +498,7 @@ impl Destination {
     /// Return the packets that were received on the last "wait" seconds.
     ///
     /// This clones the packets, so it might be a bit intensive.
-    pub fn received_last(&self, wait: Duration) -> Vec<icmp::PacketSent> {
-        let now = Instant::now();
+    pub fn received_last(&self, now: Instant, wait: Duration) -> Vec<icmp::PacketSent> {
         self.recv_packets
             .iter()
             .filter(|x| recv_before(x, now, wait))
@@ -243,8 +509,7 @@ impl Destination {
     /// Return the packets that are awaiting for response and sent in the last "wait" seconds.
     ///
     /// This clones the packets, so it might be a bit intensive.
-    pub fn inflight_after(&self, wait: Duration) -> Vec<icmp::PacketSent> {
-        let now = Instant::now();
+    pub fn inflight_after(&self, now: Instant, wait: Duration) -> Vec<icmp::PacketSent> {
         self.inflight_packets
             .iter()
             .filter(|x| !recv_before(x, now, wait))
@@ -252,15 +517,101 @@ impl Destination {
             .collect()
     }
 
+    /// Pings per second currently in effect.
+    pub fn probe_rate(&self) -> f32 {
+        1.0 / self.interval.as_secs_f32()
+    }
+
+    /// Count of packets lost and received among those sent during the last
+    /// "window". Packets still inflight for longer than "lost_after" count as
+    /// lost.
+    pub fn loss_window(
+        &self,
+        now: Instant,
+        window: Duration,
+        lost_after: Duration,
+    ) -> (usize, usize) {
+        let in_window = |x: &&icmp::PacketSent| sent_before(x, now, window);
+        let lost = self.lost_packets.iter().filter(in_window).count()
+            + self
+                .inflight_packets
+                .iter()
+                .filter(in_window)
+                .filter(|x| !sent_before(x, now, lost_after))
+                .count();
+        let recv = self.recv_packets.iter().filter(in_window).count();
+        (lost, recv)
+    }
+
+    /// Switches between the base and the burst interval depending on the
+    /// recent loss and latency. Returns true if the interval changed.
+    pub fn update_rate(&mut self, now: Instant) -> bool {
+        let burst = match &self.options.burst {
+            Some(burst) => burst,
+            None => return false,
+        };
+        // Look at about 5 pings at the base rate, but never less than a second.
+        let window = (self.base_interval * 5).max(Duration::from_secs(1));
+        let (lost, recv) = self.loss_window(now, window, TROUBLE_LOST_AFTER);
+        let loss_pct = match lost + recv {
+            0 => 0.0,
+            total => 100.0 * lost as f32 / total as f32,
+        };
+        let slow = match (burst.latency, self.mean_recv_time(now, window)) {
+            (Some(limit), Some(mean)) => recv > 0 && mean > limit,
+            _ => false,
+        };
+        let old_interval = self.interval;
+        if (lost > 0 && loss_pct >= burst.loss_pct) || slow {
+            self.last_trouble = Some(now);
+            self.interval = burst.interval;
+        } else if let Some(last) = self.last_trouble {
+            if now.saturating_duration_since(last) >= burst.hold {
+                self.last_trouble = None;
+                self.interval = self.base_interval;
+            }
+        }
+        self.interval != old_interval
+    }
+
+    /// Compares the pings sent since the last call against the ones expected
+    /// at the current rate, once every SAMPLING_WINDOW. Returns true if the
+    /// under-sampling state changed.
+    pub fn check_sampling(&mut self, now: Instant) -> bool {
+        let (since, sent_count, interval) = self.sampling_mark;
+        let elapsed = now.saturating_duration_since(since);
+        if elapsed < SAMPLING_WINDOW {
+            return false;
+        }
+        self.sampling_mark = (now, self.sent_count, self.interval);
+        if self.paused {
+            let old = self.undersampled.take();
+            return old.is_some();
+        }
+        if interval != self.interval {
+            // The rate changed in the middle of the window, can't tell.
+            return false;
+        }
+        let expected = elapsed.as_secs_f32() / interval.as_secs_f32();
+        let ratio = (self.sent_count - sent_count) as f32 / expected;
+        let old = self.undersampled.is_some();
+        self.undersampled = if ratio < MIN_SAMPLING_RATIO {
+            Some(ratio)
+        } else {
+            None
+        };
+        old != self.undersampled.is_some()
+    }
+
     /// Calculate the average time that packets are taking to return over a period of time.
-    pub fn mean_recv_time(&self, time_avg: Duration) -> Option<Duration> {
+    pub fn mean_recv_time(&self, now: Instant, time_avg: Duration) -> Option<Duration> {
         if self.recv_packets.is_empty() {
             return None;
         }
         let avg: Vec<_> = self
             .recv_packets
             .iter()
-            .filter(|x| (x.sent + x.received.unwrap()).elapsed() < time_avg)
+            .filter(|x| now.saturating_duration_since(x.sent + x.received.unwrap()) < time_avg)
             .collect();
         let avg_len = avg.len().max(1);
         let tot_time: Duration = avg.iter().fold(Duration::from_micros(0), |acc, x| {
@@ -281,8 +632,10 @@ pub struct CommConfig {
     pub forget_lost: Duration,
     /// How long received packets are hold.
     pub forget_recv: Duration,
-    /// Timing precision multiplier, makes the wait smaller
-    pub precision_mult: f64,
+    /// Global limit of pings per second across all destinations.
+    pub max_pings_per_sec: u32,
+    /// How long the frames are kept in the history of each destination.
+    pub history: Duration,
     // TODO: Add TransportChannelType here?, so it can configure IpV4 or IpV6.
 }
 
@@ -290,44 +643,25 @@ pub struct CommConfig {
 pub struct Comms {
     /// Collection of hosts to send pings to
     pub dest: Vec<Destination>,
-    /// Write channel
-    tx: TransportSender,
+    /// Where the pings are sent and the replies come from.
+    net: Box<dyn Network>,
+    /// Where the time is read from.
+    pub clock: Rc<dyn Clock>,
     /// Timings Config
     pub config: CommConfig,
-    /// Recommended delay
-    pub delay: Duration,
-    // ---- Reader Thread Data ----
-    /// Buffer for the reader thread to fill, will be emptied in recv_all
-    readbuf: Arc<Mutex<Vec<icmp::PacketData>>>,
-    /// Handle of the thread for joining. Unused, as the thread never ends
-    _read_thread_handle: thread::JoinHandle<()>,
-}
-
-/// Reader thread implementation
-///
-/// This continuosly reads from the socket and sends the data to the main thread
-/// every 0.1ms. This is done to prevent blocking, also mutexes are expensive.
-fn receiver_thread(mut rx: TransportReceiver, readbuf: Arc<Mutex<Vec<icmp::PacketData>>>) {
-    let mut buffer: Vec<icmp::PacketData> = vec![];
-    let mut last_sync = Instant::now();
-    let sync_time = Duration::from_micros(100);
-    let mut packet_iter = pnet_transport::icmp_packet_iter(&mut rx);
-    loop {
-        if let Some((packet, addr)) = packet_iter.next_with_timeout(sync_time).unwrap_or_default() {
-            let mut packet: icmp::PacketData = icmp::PacketData::parse(packet, addr);
-            packet.received = Some(Instant::now());
-            buffer.push(packet);
-        }
-
-        if last_sync.elapsed() > sync_time {
-            // Try to lock the buffer, if it would block, just try later. Don't block!!
-            if let Ok(mut locked_buffer) = readbuf.try_lock() {
-                // Dump our state to the external buffer
-                locked_buffer.append(&mut buffer);
-                last_sync = Instant::now();
-            }
-        }
-    }
+    /// Global budget of pings shared by all destinations.
+    budget: TokenBucket,
+    /// Virtual time of the last ping sent, for fair scheduling.
+    vtime: f64,
+    /// Best receive timestamping method enabled on the socket.
+    pub stamp_method: StampSource,
+    /// How the last packet received was timestamped.
+    pub last_stamp: Option<StampSource>,
+    /// Worst delays sending and receiving since they were last taken.
+    pub lateness: Lateness,
+    /// When the budget has a token again, if it last ran out. Pings held back
+    /// for it are late from then on, not from when they were due.
+    token_wait: Option<Instant>,
 }
 
 impl std::fmt::Debug for Comms {
@@ -340,67 +674,159 @@ impl std::fmt::Debug for Comms {
 }
 
 impl Comms {
-    /// Create a new Comms object from config
+    /// Create a new Comms object from config, pinging through raw ICMP sockets.
     pub fn new(config: CommConfig) -> Self {
-        let bufsize = 65536;
-        // TODO: Caller should have two Comms, one for IpV4, and another for IpV6.
-        let (tx, rx) = match pnet_transport::transport_channel(bufsize, protocol_ipv4()) {
-            Ok((tx, rx)) => (tx, rx),
+        match IcmpNetwork::new() {
+            Ok(net) => Self::with_network(config, Box::new(net), Rc::new(SystemClock)),
             Err(e) => panic!("{}", e.to_string()),
-        };
-        // rx is sent to the thread as an exclusive thing, we lose track of it here.
-        let readbuf = Arc::new(Mutex::new(vec![]));
-        let thread_buf = readbuf.clone();
-        let read_thread_handle: thread::JoinHandle<()> =
-            std::thread::spawn(move || receiver_thread(rx, thread_buf));
+        }
+    }
+    /// Create a new Comms object from config, using the given network and
+    /// clock.
+    pub fn with_network(config: CommConfig, net: Box<dyn Network>, clock: Rc<dyn Clock>) -> Self {
+        let now = clock.now();
         Self {
             dest: vec![],
-            tx,
+            stamp_method: net.stamp_method(),
+            net,
             config,
-            delay: Duration::from_millis(1),
-            readbuf,
-            _read_thread_handle: read_thread_handle,
+            // Allow a tenth of a second worth of pings at once, so targets that
+            // happen to be due at the same time don't get delayed.
+            budget: TokenBucket::new(config.max_pings_per_sec, config.max_pings_per_sec / 10, now),
+            vtime: 0.0,
+            last_stamp: None,
+            lateness: Lateness::default(),
+            token_wait: None,
+            clock,
         }
     }
     /// Add a new destination from a given string address
-    pub fn add_destination(&mut self, addr: &str, interval: Duration) {
+    ///
+    /// Fails if the socket for the probe options cannot be set up.
+    pub fn add_destination(
+        &mut self,
+        addr: &str,
+        interval: Duration,
+        options: ProbeOptions,
+    ) -> io::Result<()> {
         if interval.as_nanos() == 0 {
             panic!("Interval for a target host cannot be zero.")
         }
-        self.dest.push(Destination::new(addr, interval));
-        self.delay = self.get_delay();
+        if options.payload_size > icmp::MAX_PAYLOAD_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "payload size {} is over the maximum of {}",
+                    options.payload_size,
+                    icmp::MAX_PAYLOAD_SIZE
+                ),
+            ));
+        }
+        let mut dest = Destination::new(addr, interval, options, self.clock.now());
+        dest.history = History::new(self.config.history);
+        if dest.options.needs_own_socket() {
+            dest.sender = self.net.add_sender(&dest.options)?;
+        }
+        self.dest.push(dest);
+        Ok(())
     }
 
-    /// Sends a ping for each destination
-    pub fn send_all(&mut self, limit: usize) -> usize {
-        let mut count = 0;
-        let mut dests: Vec<_> = self
-            .dest
-            .iter()
-            .map(|x| {
-                x.last_pckt_sent
-                    .saturating_duration_since(Instant::now())
-                    .as_micros() as i128
-            })
-            .enumerate()
+    /// Sends a ping to every destination that is due, as long as the global
+    /// budget allows. Returns how many were sent.
+    ///
+    /// When the budget runs short, destinations are served in order of their
+    /// virtual time, so each one gets a share proportional to its priority.
+    pub fn send_due(&mut self) -> usize {
+        let now = self.clock.now();
+        self.budget.refill(now);
+        let mut due: Vec<usize> = (0..self.dest.len())
+            .filter(|&i| !self.dest[i].paused && self.dest[i].next_send() <= now)
             .collect();
-        dests.sort_unstable_by_key(|(_, x)| -*x);
-        let delay = self.delay / 2;
-        for (n, _) in dests {
-            if self.dest[n].send(&mut self.tx, delay) {
+        due.sort_by(|&a, &b| self.dest[a].vtime.total_cmp(&self.dest[b].vtime));
+        let mut count = 0;
+        for i in due {
+            if !self.budget.has_token() {
+                self.token_wait = Some(self.budget.next_token());
+                break;
+            }
+            let dest = &mut self.dest[i];
+            let planned = dest.next_send();
+            if dest.send(self.net.as_mut(), self.clock.as_ref()) {
+                // Held back by the budget is not the host being slow.
+                let due = self.token_wait.map_or(planned, |t| t.max(planned));
+                self.lateness.add_send(now.saturating_duration_since(due));
+                self.budget.take();
+                // A destination that was idle doesn't get to catch up.
+                let start = dest.vtime.max(self.vtime);
+                self.vtime = start;
+                dest.vtime = start + 1.0 / dest.options.priority.max(1) as f64;
                 count += 1;
-                if limit > 0 && count >= limit {
-                    break;
-                }
             }
         }
         count
     }
 
+    /// Checks whether destinations are getting the pings they're configured for.
+    pub fn check_sampling(&mut self) {
+        let now = self.clock.now();
+        for dest in self.dest.iter_mut() {
+            if dest.check_sampling(now) {
+                match dest.undersampled {
+                    Some(ratio) => warn!(
+                        "{}: under-sampled, only {:.0}% of the pings were sent. Probe budget is {}/s",
+                        dest.label,
+                        ratio * 100.0,
+                        self.budget.rate()
+                    ),
+                    None => info!("{}: no longer under-sampled", dest.label),
+                }
+            }
+        }
+    }
+
+    /// Pings per second wanted by all destinations at their fastest rate.
+    pub fn wanted_rate(&self) -> f32 {
+        self.dest
+            .iter()
+            .map(|d| {
+                let fastest = d
+                    .options
+                    .burst
+                    .as_ref()
+                    .map_or(d.base_interval, |b| b.interval.min(d.base_interval));
+                1.0 / fastest.as_secs_f32()
+            })
+            .sum()
+    }
+
+    /// Moves each destination to its burst or base rate as needed.
+    pub fn update_rates(&mut self) {
+        let now = self.clock.now();
+        for dest in self.dest.iter_mut() {
+            if dest.update_rate(now) {
+                info!("{}: probe rate now {:.1}/s", dest.label, dest.probe_rate());
+            }
+        }
+    }
+
+    /// When the earliest ping of all destinations is due, if there's any.
+    ///
+    /// If the budget is exhausted, this is not before the next token arrives.
+    pub fn next_send(&self) -> Option<Instant> {
+        let next = self
+            .dest
+            .iter()
+            .filter(|d| !d.paused)
+            .map(|d| d.next_send())
+            .min()?;
+        let next_token = self.budget.next_token();
+        Some(next.max(next_token))
+    }
+
     /// Forget old packets following the config specs.
     pub fn cleanup(&mut self) {
         let c = self.config;
-        let now = Instant::now();
+        let now = self.clock.now();
         for dest in self.dest.iter_mut() {
             for pck in dest
                 .inflight_packets
@@ -418,65 +844,274 @@ impl Comms {
         }
     }
 
-    /// Estimate the desired delay to complete all packets in one second
-    pub fn get_delay(&self) -> Duration {
-        let mut freq = 0.0;
-        for dest in &self.dest {
-            freq += dest.interval.as_secs_f64().recip();
-        }
-        // Extra precision, will check X times faster
-        freq *= self.config.precision_mult;
-        dbg!(freq);
-        if freq > 0.0 {
-            Duration::from_secs_f64(freq.recip())
-        } else {
-            // Sensible default when there are no targets.
-            // It might also be that all targets have interval=0. Wrong, but whatever.
-            Duration::from_millis(1)
+    /// Waits for incoming packets until "deadline" and matches them against
+    /// the different destinations and their recv queues.
+    ///
+    /// The network blocks until a packet arrives or the deadline passes, so
+    /// no CPU is used while there's nothing to do.
+    pub fn recv_until(&mut self, deadline: Instant) {
+        while let Some(packet) = self.net.recv(deadline) {
+            self.recv_packet(&packet);
         }
     }
 
-    /// Listens for packets up to "timeout" time and matches incoming packets
-    /// with the different destinations and their recv queues.
-    ///
-    /// There is a thread reading the socket continuosly, this function just
-    /// dumps that data into local memory and matches the packets against the
-    /// different target hosts.
-    pub fn recv_all(&mut self, timeout: Duration) {
-        let starttime = Instant::now();
-        if timeout.as_millis() > 5000 {
-            dbg!(timeout);
-            panic!("recv_all: Tried to wait more than 5000ms");
-        }
-        loop {
-            let mut buffer = vec![];
-            let mut locked_buffer = self.readbuf.lock().unwrap();
-            buffer.append(&mut locked_buffer);
-            // Release the lock early once the Vec is empty.
-            drop(locked_buffer);
-            // Now we can work freely with the values.
-            for packet in buffer {
-                for dest in &mut self.dest {
-                    dest.recv(&packet);
-                }
-            }
-            if let Some(wait) = timeout.checked_sub(starttime.elapsed()) {
-                assert!(wait <= timeout);
-                thread::sleep(wait);
-            } else {
-                break;
-            }
+    fn recv_packet(&mut self, packet: &icmp::PacketData) {
+        self.last_stamp = Some(packet.stamp);
+        let now = self.clock.now();
+        // Userspace timestamps are taken when the packet is read, so they
+        // can't tell how late that was.
+        if let (Some(received), StampSource::Kernel) = (packet.received, packet.stamp) {
+            self.lateness
+                .add_recv(now.saturating_duration_since(received));
+        }
+        for dest in &mut self.dest {
+            dest.recv(packet, now);
         }
     }
 }
 
-// TODO: Add tests here:
 #[cfg(test)]
 mod tests {
-    /*
     use super::*;
+    use crate::clock::ManualClock;
+    use crate::sim::{Latency, SimLink, SimNetwork};
+    use chrono::Utc;
+    use zzping_lib::framedata::{FrameDataVec, FrameTime};
+    use zzping_lib::logevent::StartReason;
+
+    fn sim_comms(clock: &ManualClock, net: SimNetwork) -> Comms {
+        let forget = Duration::from_millis(100);
+        let config = CommConfig {
+            forget_inflight: forget,
+            forget_lost: Duration::from_secs(10),
+            forget_recv: forget,
+            max_pings_per_sec: 1000,
+            history: Duration::ZERO,
+        };
+        let mut t = Comms::with_network(config, Box::new(net), Rc::new(clock.clone()));
+        let interval = Duration::from_millis(10);
+        for addr in ["10.0.0.1", "10.0.0.2", "10.0.0.3"] {
+            t.add_destination(addr, interval, ProbeOptions::default())
+                .unwrap();
+        }
+        t
+    }
+
+    /// Runs the main loop for "time", calling "each_refresh" every 50ms.
+    fn run_for(t: &mut Comms, time: Duration, mut each_refresh: impl FnMut(&mut Comms)) {
+        let refresh = Duration::from_millis(50);
+        let end = t.clock.now() + time;
+        let mut next_refresh = t.clock.now() + refresh;
+        while t.clock.now() < end {
+            let deadline = t.next_send().map_or(next_refresh, |x| x.min(next_refresh));
+            t.recv_until(deadline);
+            t.send_due();
+            if t.clock.now() >= next_refresh {
+                next_refresh += refresh;
+                t.cleanup();
+                each_refresh(t);
+            }
+        }
+    }
+
+    #[test]
+    fn test_sim_accounting() {
+        let clock = ManualClock::new(Utc::now());
+        let rtt = Duration::from_millis(5);
+        let net = SimNetwork::new(7, clock.clone())
+            .with_link("10.0.0.1", SimLink::new(Latency::Fixed(rtt)))
+            .with_link(
+                "10.0.0.2",
+                SimLink {
+                    loss: 1.0,
+                    ..SimLink::new(Latency::Fixed(rtt))
+                },
+            );
+        // 10.0.0.3 has no link, sending fails.
+        let mut t = sim_comms(&clock, net);
+        let start = clock.now();
+        run_for(&mut t, Duration::from_millis(400), |_| {});
+        assert_eq!(clock.now() - start, Duration::from_millis(400));
+        assert_eq!(t.stamp_method, StampSource::Kernel);
+
+        let (good, lossy, unreachable) = (&t.dest[0], &t.dest[1], &t.dest[2]);
+        // One ping every 10ms, from 0 to 400ms both included.
+        assert_eq!(good.sent_count, 41);
+        assert_eq!(good.recv_count, 40);
+        assert_eq!(good.inflight_packets.len(), 1);
+        assert!(good.lost_packets.is_empty());
+        let now = clock.now();
+        assert_eq!(good.mean_recv_time(now, Duration::from_secs(10)), Some(rtt));
+
+        assert_eq!(lossy.sent_count, 41);
+        assert_eq!(lossy.recv_count, 0);
+        // Declared lost once in flight for 100ms, on the cleanup at 400ms.
+        assert_eq!(lossy.lost_packets.len(), 31);
+        assert_eq!(lossy.inflight_packets.len(), 10);
+        let forget = t.config.forget_inflight;
+        assert!(lossy.lost_packets.iter().all(|p| now - p.sent >= forget));
+        assert!(lossy.inflight_packets.iter().all(|p| now - p.sent < forget));
+
+        assert_eq!(unreachable.sent_count, 41);
+        assert_eq!(unreachable.send_errors, 41);
+        assert_eq!(unreachable.lost_packets.len(), 41);
+        assert!(unreachable.inflight_packets.is_empty());
+        assert!(unreachable.last_send_error.is_some());
+    }
+
+    #[test]
+    fn test_sim_duplicates_and_reordering() {
+        let clock = ManualClock::new(Utc::now());
+        let link = SimLink {
+            duplicate: 0.5,
+            reorder: 0.3,
+            reorder_delay: Duration::from_millis(25),
+            ..SimLink::new(Latency::Normal {
+                mean: Duration::from_millis(8),
+                std_dev: Duration::from_millis(3),
+            })
+        };
+        let net = SimNetwork::new(3, clock.clone())
+            .with_link("10.0.0.1", link.clone())
+            .with_link("10.0.0.2", link);
+        let mut t = sim_comms(&clock, net);
+        run_for(&mut t, Duration::from_millis(400), |_| {});
+        for dest in t.dest[..2].iter() {
+            // Duplicated replies are not counted twice, late ones still count.
+            let inflight = dest.inflight_packets.len() as u64;
+            assert_eq!(dest.recv_count + inflight, dest.sent_count);
+            assert!(dest.lost_packets.is_empty());
+            let mut seqs: Vec<u16> = dest.recv_packets.iter().map(|p| p.data.seqn).collect();
+            let count = seqs.len();
+            seqs.sort_unstable();
+            seqs.dedup();
+            assert_eq!(seqs.len(), count);
+        }
+    }
+
+    #[test]
+    fn test_sim_frame_logging() {
+        let clock = ManualClock::new(Utc::now());
+        let rtt = Duration::from_micros(2500);
+        let net = SimNetwork::new(11, clock.clone())
+            .with_link("10.0.0.1", SimLink::new(Latency::Fixed(rtt)))
+            .with_link(
+                "10.0.0.2",
+                SimLink {
+                    loss: 1.0,
+                    ..SimLink::new(Latency::Fixed(rtt))
+                },
+            );
+        let mut t = sim_comms(&clock, net);
+        let dir = tempfile::tempdir().unwrap();
+        let logdir = dir.path().to_str().unwrap();
+        for dest in t.dest.iter_mut() {
+            dest.create_log_file(logdir, "test");
+            dest.daemon_start = Some(DaemonStart {
+                reason: StartReason::Started,
+                config_hash: 7,
+            });
+        }
+        let mut frames: u32 = 0;
+        run_for(&mut t, Duration::from_millis(300), |t| {
+            let time = match frames {
+                0 => FrameTime::Timestamp(t.clock.wall()),
+                n => FrameTime::Elapsed(Duration::from_millis(50) * n),
+            };
+            let tick = Tick {
+                time,
+                rotate: None,
+                discontinuity: None,
+            };
+            let now = t.clock.now();
+            for dest in t.dest.iter_mut() {
+                dest.log_frame(now, &tick, Duration::from_millis(50))
+                    .unwrap();
+            }
+            frames += 1;
+        });
+        for dest in t.dest.iter_mut() {
+            dest.stop(StopReason::Signal);
+        }
+        assert_eq!(frames, 6);
+
+        let read = |label: &str| {
+            let path = dir.path().join(format!("pingd-log-{}-test.log", label));
+            let mut file = std::io::BufReader::new(File::open(path).unwrap());
+            let mut fdv = FrameDataVec::new();
+            fdv.read(&mut file, frames as u64).unwrap();
+            // The stop is the last record, after it there's nothing else.
+            assert!(fdv.read(&mut file, 1).is_err());
+            assert_eq!(fdv.meta.daemon_stop, Some(StopReason::Signal));
+            fdv.v
+        };
+        let good = read("10.0.0.1");
+        assert_eq!(good.len(), 6);
+        assert_eq!(good[0].meta.daemon_start.unwrap().config_hash, 7);
+        assert!(good[1..].iter().all(|f| f.meta.daemon_start.is_none()));
+        for frame in good.iter() {
+            assert_eq!(frame.meta.probe_rate, Some(100.0));
+            assert!(frame.recv_us.len() >= 5, "{:?}", frame.recv_us);
+            assert!(frame.recv_us.iter().all(|&us| us == 2500));
+            assert_eq!(frame.lost_packets, 0);
+        }
+        let lossy = read("10.0.0.2");
+        assert!(lossy.iter().all(|f| f.recv_us.is_empty()));
+        let lost: Vec<usize> = lossy.iter().map(|f| f.lost_packets).collect();
+        assert_eq!(lost, vec![0, 1, 6, 11, 16, 21]);
+    }
 
     #[test]
-    fn test_XXX() {}
-    */
+    fn test_probe_options_label() {
+        let mut opts = ProbeOptions::default();
+        assert_eq!(opts.label("192.168.0.1"), "192.168.0.1");
+        opts.source_address = Some("192.168.0.10".parse().unwrap());
+        assert_eq!(opts.label("192.168.0.1"), "192.168.0.1@192.168.0.10");
+        opts.interface = Some("wlan0".to_owned());
+        assert_eq!(opts.label("192.168.0.1"), "192.168.0.1%wlan0");
+    }
+
+    #[test]
+    fn test_budget_not_late() {
+        let clock = ManualClock::new(Utc::now());
+        let net = SimNetwork::new(3, clock.clone());
+        let mut t = sim_comms(&clock, net);
+        // 300 pings/s wanted, only 100/s allowed.
+        t.budget = TokenBucket::new(100, 10, clock.now());
+        run_for(&mut t, Duration::from_millis(400), |_| {});
+        let sent: u64 = t.dest.iter().map(|d| d.sent_count).sum();
+        assert!(sent < 60, "{}", sent);
+        // Pings waited for the budget, but went out as soon as it allowed.
+        assert_eq!(t.lateness.send, Duration::ZERO);
+    }
+
+    #[test]
+    fn test_update_rate() {
+        let options = ProbeOptions {
+            burst: Some(BurstPolicy {
+                interval: Duration::from_millis(10),
+                loss_pct: 10.0,
+                latency: None,
+                hold: Duration::from_secs(30),
+            }),
+            ..Default::default()
+        };
+        let now = Instant::now();
+        let mut dest = Destination::new("127.0.0.1", Duration::from_millis(500), options, now);
+        assert!(!dest.update_rate(now));
+        assert_eq!(dest.interval, Duration::from_millis(500));
+
+        let data = icmp::PacketData::new(1, dest.ident, dest.addr);
+        dest.lost_packets.push(icmp::PacketSent::new(data, now));
+        assert!(dest.update_rate(now));
+        assert_eq!(dest.interval, Duration::from_millis(10));
+        assert_eq!(dest.probe_rate(), 100.0);
+
+        // The lost packet is out of the window, but we hold the burst rate.
+        dest.lost_packets.clear();
+        assert!(!dest.update_rate(now + Duration::from_secs(10)));
+        assert_eq!(dest.interval, Duration::from_millis(10));
+        assert!(dest.update_rate(now + Duration::from_secs(31)));
+        assert_eq!(dest.interval, Duration::from_millis(500));
+    }
 }
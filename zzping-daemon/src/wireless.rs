@@ -0,0 +1,82 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Samples the WiFi links from /proc/net/wireless, so their signal can be
+//! logged next to the latency.
+//!
+//! The file has two header lines, and then one line per wireless interface:
+//! name, status, link quality, signal and noise levels, four counters of
+//! discarded packets (nwid, crypt, frag, retry), other discarded packets and
+//! missed beacons. Levels updated since the last read end with a dot.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+
+use zzping_lib::logevent::Wireless;
+
+const WIRELESS_PATH: &str = "/proc/net/wireless";
+
+/// Samples of all the wireless interfaces, by name.
+pub fn parse_wireless(table: &str) -> HashMap<String, Wireless> {
+    table
+        .lines()
+        .skip(2)
+        .filter_map(|line| {
+            let (iface, rest) = line.split_once(':')?;
+            let f: Vec<&str> = rest.split_whitespace().collect();
+            if f.len() < 10 {
+                return None;
+            }
+            let level = |x: &str| x.trim_end_matches('.').parse::<f32>().ok();
+            let sample = Wireless {
+                link: level(f[1])?,
+                signal_dbm: level(f[2])?,
+                noise_dbm: level(f[3])?,
+                retries: f[7].parse().ok()?,
+                missed_beacons: f[9].parse().ok()?,
+            };
+            Some((iface.trim().to_owned(), sample))
+        })
+        .collect()
+}
+
+/// Reads the current samples. Without WiFi support in the kernel, the file
+/// is not there and this fails.
+pub fn read_wireless() -> io::Result<HashMap<String, Wireless>> {
+    Ok(parse_wireless(&fs::read_to_string(WIRELESS_PATH)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_wireless() {
+        let samples = parse_wireless(include_str!("../fixtures/proc_net_wireless"));
+        assert_eq!(samples.len(), 2);
+        assert_eq!(
+            samples["wlan0"],
+            Wireless {
+                link: 54.0,
+                signal_dbm: -56.0,
+                noise_dbm: -256.0,
+                retries: 12,
+                missed_beacons: 4,
+            }
+        );
+        assert_eq!(samples["wlp3s0"].noise_dbm, -95.0);
+        assert!(parse_wireless("Inter-| sta-|\n face | status |\n").is_empty());
+    }
+}
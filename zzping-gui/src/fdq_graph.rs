@@ -19,6 +19,7 @@ use iced::{
     Color, Point, Size, Vector,
 };
 use zzping_lib::framedataq::{Complete, FDCodecIter, FrameDataQ, IterFold, SubSecType};
+use zzping_lib::logevent::LinkStats;
 
 use crate::gui::Message;
 
@@ -107,6 +108,9 @@ impl PlotAssist {
 #[derive(Debug, Default, Clone)]
 pub struct FDQGraph {
     fd: Vec<FrameDataQ<Complete>>,
+    /// Spans of time, in ms, where nothing was measured: the daemon wasn't
+    /// running or the host was suspended.
+    unmeasured: Vec<(i64, i64)>,
     fdcache: Vec<(i64, Vec<FrameDataQ<Complete>>)>,
     changed: bool,
     zoomx: f64,
@@ -136,14 +140,58 @@ impl FDQGraph {
         let mut timer_rm = Instant::now();
         let mut last_ts = 0;
         let mut last_dt = None;
+        let mut unmeasured: Vec<(i64, i64)> = vec![];
         for mut fdq in fdreader {
             self.max_inflight = self.max_inflight.max(fdq.inflight);
-            stdmean_inflight += (fdq.inflight as f32).powi(2);
+            stdmean_inflight += fdq.inflight.powi(2);
             self.max_lostpackets = self.max_lostpackets.max(fdq.lost_packets);
-            stdmean_lostpackets += (fdq.lost_packets as f32).powi(2);
-            self.max_recv = self.max_recv.max(fdq.recv_us[6]);
+            stdmean_lostpackets += fdq.lost_packets.powi(2);
+            self.max_recv = self.max_recv.max(fdq.percentile_us(1.0));
             if fdq.recv_us_len == 0 {
-                fdq.recv_us = [0, 0, 0, 0, 0, 0, 0];
+                fdq.recv_us = vec![0; fdq.recv_us.len()];
+            }
+            // Whether nothing was measured since the last frame.
+            let mut unmeasured_gap = false;
+            if let Some(d) = fdq.meta.discontinuity {
+                eprintln!(
+                    "Found a discontinuity at {}: clock stepped {:.3}s, suspended {:.3}s",
+                    fdq.get_datetime(),
+                    d.step_secs,
+                    d.suspend_secs
+                );
+                // The frames before a step were off by it. Move them so they
+                // line up with the corrected clock and stay in order.
+                let step_ms = (d.step_secs * 1000.0).round() as i64;
+                if step_ms != 0 {
+                    fd.iter_mut().for_each(|x| shift_frame(x, step_ms));
+                    unmeasured.iter_mut().for_each(|(a, b)| {
+                        *a += step_ms;
+                        *b += step_ms;
+                    });
+                    if let Some(last) = fd.last() {
+                        last_ts = last.timestamp.unwrap();
+                        last_dt = Some(last.get_datetime());
+                    }
+                }
+                unmeasured_gap = d.suspend_secs > 0.0;
+            }
+            if let Some(start) = fdq.meta.daemon_start {
+                eprintln!(
+                    "Daemon started at {} ({}, after {}), config {:016x}",
+                    fdq.get_datetime(),
+                    start.reason.as_str(),
+                    fdq.meta
+                        .daemon_stop
+                        .map_or("no recorded stop", |x| x.as_str()),
+                    start.config_hash
+                );
+                unmeasured_gap = true;
+            }
+            if let (true, Some(last)) = (unmeasured_gap, fd.last()) {
+                unmeasured.push((
+                    last.get_timestamp_ms() as i64,
+                    fdq.get_timestamp_ms() as i64,
+                ));
             }
             let gap = fdq.timestamp.unwrap() - last_ts;
             if last_ts > 0 && gap > 10 {
@@ -153,26 +201,34 @@ impl FDQGraph {
                     last_dt.unwrap(),
                     fdq.get_datetime()
                 );
+                // Pings were lost, unless nobody was measuring.
+                let lost_packets = match unmeasured_gap {
+                    true => 0.0,
+                    false => 1.0,
+                };
                 for ts in (last_ts + 1)..(fdq.timestamp.unwrap() - 1) {
                     let new_fdq = FrameDataQ::<Complete> {
                         phantom: Default::default(),
                         timestamp: Some(ts),
                         subsec_ms: SubSecType::Abs(0),
                         inflight: 0.0,
-                        lost_packets: 1.0,
+                        lost_packets,
                         recv_us_len: 0,
-                        recv_us: [0, 0, 0, 0, 0, 0, 0],
+                        percentiles: fdq.percentiles.clone(),
+                        recv_us: vec![0; fdq.recv_us.len()],
+                        sketch: None,
+                        meta: fdq.meta,
                     };
                     fd.push(new_fdq);
                 }
             }
+            last_ts = fdq.timestamp.unwrap();
+            last_dt = Some(fdq.get_datetime());
             fd.push(fdq);
             if timer_rm.elapsed().as_secs() >= 1 {
                 timer_rm = Instant::now();
                 eprintln!("Still loading... got {} items now.", fd.len());
             }
-            last_ts = fdq.timestamp.unwrap();
-            last_dt = Some(fdq.get_datetime());
         }
         dbg!(fd.len());
         stdmean_inflight /= fd.len() as f32;
@@ -181,6 +237,7 @@ impl FDQGraph {
         dbg!(self.max_inflight);
         dbg!(stdmean_lostpackets);
         dbg!(self.max_lostpackets);
+        self.unmeasured = unmeasured;
         self.fd = fd.clone(); // fd.chunks(1000).map(|x| FrameDataQ::fold_vec(x)).collect();
         eprintln!("loaded, caching: {:?}", timer.elapsed());
         let timer = Instant::now();
@@ -236,6 +293,28 @@ impl FDQGraph {
     }
 }
 
+/// Moves a frame in time by "ms" milliseconds.
+fn shift_frame(fdq: &mut FrameDataQ<Complete>, ms: i64) {
+    let t = fdq.get_timestamp_ms() as i64 + ms;
+    fdq.timestamp = Some(t.div_euclid(1000));
+    fdq.subsec_ms = SubSecType::Abs(t.rem_euclid(1000) as u32);
+}
+
+/// WiFi signal at the top of the graph, and range down to the bottom.
+const WIFI_TOP_DBM: f32 = -30.0;
+const WIFI_RANGE_DB: f32 = 60.0;
+
+/// Colors of the latency bands, from the lowest reply to the highest.
+const RECV_COLORS: [(u8, u8, u8); 7] = [
+    (100, 50, 50),
+    (220, 50, 50),
+    (200, 150, 50),
+    (200, 200, 50),
+    (50, 220, 50),
+    (50, 200, 200),
+    (50, 150, 200),
+];
+
 fn fill_color(color: Color) -> iced::widget::canvas::Fill {
     iced::widget::canvas::Fill {
         color,
@@ -243,6 +322,27 @@ fn fill_color(color: Color) -> iced::widget::canvas::Fill {
     }
 }
 
+/// A fill for each of "bands" percentiles, spread over RECV_COLORS.
+fn recv_fills(bands: usize) -> Vec<iced::widget::canvas::Fill> {
+    let last = (RECV_COLORS.len() - 1) as f32;
+    (0..bands)
+        .map(|n| {
+            let at = n as f32 * last / (bands.max(2) - 1) as f32;
+            let (c0, c1) = (
+                RECV_COLORS[at.floor() as usize],
+                RECV_COLORS[at.ceil() as usize],
+            );
+            let fr = at - at.floor();
+            let mix = |a: u8, b: u8| (a as f32 * (1.0 - fr) + b as f32 * fr).round() as u8;
+            fill_color(Color::from_rgb8(
+                mix(c0.0, c1.0),
+                mix(c0.1, c1.1),
+                mix(c0.2, c1.2),
+            ))
+        })
+        .collect()
+}
+
 impl canvas::Program<Message> for FDQGraph {
     fn draw(
         &self,
@@ -252,16 +352,10 @@ impl canvas::Program<Message> for FDQGraph {
         let timer_begin = Instant::now();
         let f = FrameScaler::new(&bounds);
         let mut frame = canvas::Frame::new(bounds.size());
-        let color_r0 = Color::from_rgba8(100, 50, 50, 1.0);
-        let color_r1 = Color::from_rgba8(220, 50, 50, 1.0);
-        let color_r2 = Color::from_rgba8(200, 150, 50, 1.0);
-        let color_r3 = Color::from_rgba8(200, 200, 50, 1.0);
-        let color_r4 = Color::from_rgba8(50, 220, 50, 1.0);
-        let color_r5 = Color::from_rgba8(50, 200, 200, 1.0);
-        let color_r6 = Color::from_rgba8(50, 150, 200, 1.0);
-        // let color_r6 = Color::from_rgba8(70, 100, 200, 1.0);
         let color_inflight = Color::from_rgba8(0, 0, 0, 0.3);
         let color_lost = Color::from_rgba8(255, 0, 0, 0.1);
+        let color_unmeasured = Color::from_rgba8(200, 200, 200, 0.4);
+        let color_untrusted = Color::from_rgba8(255, 200, 0, 0.25);
 
         let green10 = Color::from_rgba8(0, 255, 0, 0.1);
         let white90 = Color::from_rgba8(255, 255, 255, 0.9);
@@ -277,16 +371,24 @@ impl canvas::Program<Message> for FDQGraph {
             color: black50,
             ..Stroke::default()
         };
-        let fill_r0 = fill_color(color_r0);
-        let fill_r1 = fill_color(color_r1);
-        let fill_r2 = fill_color(color_r2);
-        let fill_r3 = fill_color(color_r3);
-        let fill_r4 = fill_color(color_r4);
-        let fill_r5 = fill_color(color_r5);
-        let fill_r6 = fill_color(color_r6);
-        let fill_recv = vec![
-            fill_r0, fill_r1, fill_r2, fill_r3, fill_r4, fill_r5, fill_r6,
-        ];
+        let wifi_stroke = Stroke {
+            width: 1.5,
+            color: Color::from_rgba8(230, 50, 230, 0.8),
+            ..Stroke::default()
+        };
+        let traffic_stroke = Stroke {
+            width: 1.5,
+            color: Color::from_rgba8(0, 180, 230, 0.8),
+            ..Stroke::default()
+        };
+        let carrier_stroke = Stroke {
+            width: 2.0,
+            color: Color::from_rgba8(255, 140, 0, 0.9),
+            ..Stroke::default()
+        };
+        // All frames of a file keep the same percentiles.
+        let bands = self.fd.first().map_or(0, |x| x.recv_us.len());
+        let fill_recv = recv_fills(bands);
         let fill_inflight = fill_color(color_inflight);
         let fill_lost = fill_color(color_lost);
 
@@ -365,7 +467,7 @@ impl canvas::Program<Message> for FDQGraph {
                 src_bottom,
             });
             let mut points: Vec<_> = vec![];
-            for i in 0..7 {
+            for i in 0..bands {
                 let points_i: Vec<_> = fd
                     .iter()
                     .map(|x| {
@@ -385,7 +487,7 @@ impl canvas::Program<Message> for FDQGraph {
             let line = canvas::Path::line(f.pt(0.0, 1.0 - 1.0 / 16.0), f.pt(1.0, 1.0 - 1.0 / 16.0));
             frame.stroke(&line, black_stroke);
 
-            let mut path_bldr: Vec<_> = (0..7).map(|_| path::Builder::new()).collect();
+            let mut path_bldr: Vec<_> = (0..bands).map(|_| path::Builder::new()).collect();
             path_bldr.iter_mut().for_each(|b| b.move_to(f.pt(0.0, 1.0)));
 
             let mut path_inflight = path::Builder::new();
@@ -455,10 +557,79 @@ impl canvas::Program<Message> for FDQGraph {
             let poly = path_lost.build();
             frame.fill(&poly, fill_lost);
 
+            // WiFi signal over the latency, from -30dBm at the top to -90dBm
+            // at the bottom. Frames without a sample break the line.
+            let signal_y = |dbm: f32| ((WIFI_TOP_DBM - dbm) / WIFI_RANGE_DB).clamp(0.0, 1.0) as f64;
+            let mut path_wifi = path::Builder::new();
+            let mut wifi_drawn = false;
+            for fp in fd.iter() {
+                match fp.meta.wireless {
+                    Some(w) => {
+                        let p = pa.ptx(fp.get_timestamp_ms() as f64, signal_y(w.signal_dbm));
+                        match wifi_drawn {
+                            true => path_wifi.line_to(p),
+                            false => path_wifi.move_to(p),
+                        }
+                        wifi_drawn = true;
+                    }
+                    None => wifi_drawn = false,
+                }
+            }
+            frame.stroke(&path_wifi.build(), wifi_stroke);
+
+            // Traffic on the interface, relative to the busiest frame in
+            // view, and a mark where the carrier went up or down.
+            let traffic = |l: &LinkStats| l.rx_bytes_sec + l.tx_bytes_sec;
+            let busiest = fd
+                .iter()
+                .filter_map(|fp| fp.meta.link_stats.as_ref().map(traffic))
+                .fold(f32::MIN_POSITIVE, f32::max);
+            let mut path_traffic = path::Builder::new();
+            let mut traffic_drawn = false;
+            for fp in fd.iter() {
+                let t = fp.get_timestamp_ms() as f64;
+                match fp.meta.link_stats {
+                    Some(l) => {
+                        let p = pa.ptx(t, 1.0 - (traffic(&l) / busiest) as f64);
+                        match traffic_drawn {
+                            true => path_traffic.line_to(p),
+                            false => path_traffic.move_to(p),
+                        }
+                        traffic_drawn = true;
+                        if l.carrier_changes > 0 {
+                            let line = canvas::Path::line(pa.ptx(t, 0.0), pa.ptx(t, 1.0));
+                            frame.stroke(&line, carrier_stroke);
+                        }
+                    }
+                    None => traffic_drawn = false,
+                }
+            }
+            frame.stroke(&path_traffic.build(), traffic_stroke);
+
+            // Grey out the time nobody was measuring, so it's not taken for
+            // a network failure.
+            for (a, b) in self.unmeasured.iter() {
+                let x0 = pa.ptp((*a as f64, 0.0)).0.max(0.0) as f32;
+                let x1 = pa.ptp((*b as f64, 0.0)).0.min(1.0) as f32;
+                if x1 > x0 {
+                    frame.fill_rectangle(f.pt(x0, 0.0), f.sz(x1 - x0, 1.0), color_unmeasured);
+                }
+            }
+
+            // Shade in yellow the frames measured while the host was too busy
+            // to trust the latency.
+            for w in fd.windows(2).filter(|w| w[0].meta.untrusted) {
+                let x0 = pa.ptp((w[0].get_timestamp_ms() as f64, 0.0)).0.max(0.0) as f32;
+                let x1 = pa.ptp((w[1].get_timestamp_ms() as f64, 0.0)).0.min(1.0) as f32;
+                if x1 > x0 {
+                    frame.fill_rectangle(f.pt(x0, 0.0), f.sz(x1 - x0, 1.0), color_untrusted);
+                }
+            }
+
             let fd_first = fd.first().unwrap();
             let fd_last = fd.last().unwrap();
             let mid_pos = ((fd.len() - 1) as f32 * self.posx as f32).round();
-            let fd_mid = fd[mid_pos as usize];
+            let fd_mid = &fd[mid_pos as usize];
 
             // Zoom X locator
             let line = canvas::Path::line(f.pt(self.posx as f32, 0.0), f.pt(self.posx as f32, 1.0));
@@ -497,11 +668,35 @@ impl canvas::Program<Message> for FDQGraph {
             frame.fill_text(text);
             let text = canvas::Text {
                 content: format!(
-                    "Viewport width: {}\nZoom: {:.2}x / Points in view: {}\n{}",
+                    "Viewport width: {}\nZoom: {:.2}x / Points in view: {}\n{}{}{}{}{}",
                     vw_width_text,
                     self.zoomx,
                     ifd_len,
-                    fd_mid.get_datetime()
+                    fd_mid.get_datetime(),
+                    fd_mid.meta.wireless.map_or(String::new(), |w| format!(
+                        "\nWiFi: {:.0}dBm, quality {:.0}",
+                        w.signal_dbm, w.link
+                    )),
+                    fd_mid.meta.link_stats.map_or(String::new(), |l| format!(
+                        "\nLink: rx {:.0}kB/s, tx {:.0}kB/s, errors {}, drops {}",
+                        l.rx_bytes_sec / 1000.0,
+                        l.tx_bytes_sec / 1000.0,
+                        l.errors,
+                        l.drops
+                    )),
+                    fd_mid.meta.host_health.map_or(String::new(), |h| format!(
+                        "\nHost: load {:.2}, sent up to {:.0}ms late{}",
+                        h.load1,
+                        h.send_late_ms,
+                        match fd_mid.meta.untrusted {
+                            true => " (untrusted)",
+                            false => "",
+                        }
+                    )),
+                    fd_mid.meta.peer_path.map_or(String::new(), |p| format!(
+                        "\nPeer: up {:.2}ms {:.1}% loss, down {:.2}ms {:.1}% loss",
+                        p.up_ms, p.up_loss_pct, p.down_ms, p.down_loss_pct
+                    ))
                 ),
                 position: f.pt(0.5, 0.01),
                 color: white90,
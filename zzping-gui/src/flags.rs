@@ -15,18 +15,108 @@
 use serde::{Deserialize, Serialize};
 use std::fs;
 
+use super::custom_errors::UnexpectedError;
+
 #[derive(Default, Serialize, Deserialize, Debug, Clone)]
 pub struct GuiConfig {
     pub udp_listen_address: String,
+    /// The daemon to show, when there's only one. Ignored if "daemons" is set.
+    #[serde(default)]
     pub udp_server_address: String,
+    /// Daemons to show, each with a name to tell their targets apart.
+    #[serde(default)]
+    pub daemons: Vec<DaemonEndpoint>,
     pub display_address: Vec<String>,
     pub sample_limit: usize,
+    /// Seconds of history to ask the daemon for on start up. 0 to disable it.
+    #[serde(default = "default_backfill_secs")]
+    pub backfill_secs: u64,
+    /// Incident log written by the daemon, to list its last incidents.
+    #[serde(default)]
+    pub incident_log: Option<String>,
+    /// Key shared with the daemon to authenticate the UDP traffic. Empty if
+    /// the daemon has none.
+    #[serde(default)]
+    pub psk: String,
+    /// Encrypt the UDP traffic too, and refuse what comes unencrypted.
+    #[serde(default)]
+    pub encrypt: bool,
+}
+
+/// A daemon sending its stats to this GUI.
+#[derive(Default, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DaemonEndpoint {
+    /// Goes before its targets, as in "laptop/1.1.1.1".
+    pub name: String,
+    /// Its "udp_listen_address", where its stats come from.
+    pub address: String,
+    /// Its key, if its traffic is authenticated.
+    #[serde(default)]
+    pub psk: String,
+    #[serde(default)]
+    pub encrypt: bool,
+}
+
+/// A graph, with the daemon and target of each of its series.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GraphSpec {
+    pub title: String,
+    pub series: Vec<(String, String)>,
+}
+
+fn default_backfill_secs() -> u64 {
+    60
 }
 
 impl GuiConfig {
     pub fn from_filepath(filepath: &str) -> Result<Self, Box<dyn std::error::Error>> {
         let contents = fs::read_to_string(filepath)?;
-        Ok(ron::de::from_str(&contents)?)
+        let cfg: Self = ron::de::from_str(&contents)?;
+        cfg.graphs()?;
+        Ok(cfg)
+    }
+
+    /// The daemons to show. The old single daemon config is one without a
+    /// name.
+    pub fn endpoints(&self) -> Vec<DaemonEndpoint> {
+        match self.daemons.is_empty() {
+            true => vec![DaemonEndpoint {
+                name: String::new(),
+                address: self.udp_server_address.clone(),
+                psk: self.psk.clone(),
+                encrypt: self.encrypt,
+            }],
+            false => self.daemons.clone(),
+        }
+    }
+
+    /// A graph for each of "display_address". Those written "daemon/target"
+    /// show that target from that daemon only; the rest, from every daemon,
+    /// so the vantage points can be compared.
+    pub fn graphs(&self) -> Result<Vec<GraphSpec>, UnexpectedError> {
+        let endpoints = self.endpoints();
+        self.display_address
+            .iter()
+            .map(|addr| {
+                let series = match addr.split_once('/') {
+                    Some((name, target)) => match endpoints.iter().any(|d| d.name == name) {
+                        true => vec![(name.to_owned(), target.to_owned())],
+                        false => {
+                            let msg = format!("display_address '{}': no daemon '{}'", addr, name);
+                            return Err(UnexpectedError::new(&msg));
+                        }
+                    },
+                    None => endpoints
+                        .iter()
+                        .map(|d| (d.name.clone(), addr.clone()))
+                        .collect(),
+                };
+                Ok(GraphSpec {
+                    title: addr.clone(),
+                    series,
+                })
+            })
+            .collect()
     }
 }
 
@@ -40,3 +130,51 @@ pub struct Flags {
     pub guiconfig: GuiConfig,
     pub otheropts: OtherOpts,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_graphs() {
+        let cfg: GuiConfig = ron::de::from_str(
+            r#"GuiConfig(
+                udp_listen_address: "0.0.0.0:7879",
+                daemons: [
+                    (name: "desktop", address: "192.168.1.10:7878"),
+                    (name: "pi", address: "192.168.1.20:7878", psk: "0123456789abcdef"),
+                ],
+                display_address: ["pi/192.168.1.1", "1.1.1.1"],
+                sample_limit: 300,
+            )"#,
+        )
+        .unwrap();
+        let graphs = cfg.graphs().unwrap();
+        assert_eq!(
+            graphs[0].series,
+            vec![("pi".to_owned(), "192.168.1.1".to_owned())]
+        );
+        assert_eq!(graphs[1].title, "1.1.1.1");
+        assert_eq!(graphs[1].series.len(), 2);
+        assert_eq!(graphs[1].series[0].0, "desktop");
+
+        let typo = GuiConfig {
+            display_address: vec!["laptop/1.1.1.1".to_owned()],
+            ..cfg.clone()
+        };
+        assert!(typo.graphs().is_err());
+
+        // A single daemon, as before.
+        let single = GuiConfig {
+            udp_server_address: "127.0.0.1:7878".to_owned(),
+            daemons: vec![],
+            display_address: vec!["1.1.1.1".to_owned()],
+            ..cfg
+        };
+        assert_eq!(single.endpoints()[0].address, "127.0.0.1:7878");
+        assert_eq!(
+            single.graphs().unwrap()[0].series,
+            vec![(String::new(), "1.1.1.1".to_owned())]
+        );
+    }
+}
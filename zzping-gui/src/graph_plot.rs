@@ -17,39 +17,73 @@ use crate::gui::Message;
 use super::udp_comm::UdpStats;
 use iced::{canvas, Color, Point};
 use std::time::{Duration, Instant};
+use zzping_lib::framestats::HistoryChunk;
 
+/// Colors of the series, by their order in the graph.
+const SERIES_COLORS: [(u8, u8, u8); 4] =
+    [(0, 255, 0), (0, 200, 255), (255, 200, 0), (255, 80, 255)];
+
+/// A target as measured by one daemon.
 #[derive(Debug, Clone)]
-pub struct LatencyGraph {
-    pub samples: usize,
+pub struct Series {
+    pub daemon: String,
+    pub target: String,
     pub latency_us: Vec<u32>,
     pub packet_loss_x100_000: Vec<u32>,
+}
+
+impl Series {
+    fn avg_latency_us(&self) -> u32 {
+        self.latency_us.iter().sum::<u32>() / (self.latency_us.len().max(1) as u32)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LatencyGraph {
+    pub samples: usize,
+    pub series: Vec<Series>,
     pub current: Instant,
     pub display_address: String,
 }
 
 impl LatencyGraph {
-    pub fn new(display_address: &str, samples: usize) -> Self {
+    /// A graph titled "display_address", with a series for each pair of
+    /// daemon and target.
+    pub fn new(display_address: &str, series: &[(String, String)], samples: usize) -> Self {
         Self {
-            latency_us: vec![],
-            packet_loss_x100_000: vec![],
+            series: series
+                .iter()
+                .map(|(daemon, target)| Series {
+                    daemon: daemon.clone(),
+                    target: target.clone(),
+                    latency_us: vec![],
+                    packet_loss_x100_000: vec![],
+                })
+                .collect(),
             current: Instant::now(),
             display_address: display_address.to_owned(),
             samples,
         }
     }
-    pub fn update(&mut self, now: Instant, stats: &[UdpStats]) -> bool {
+    /// Adds the stats that belong to this graph, each with the daemon that
+    /// sent it.
+    pub fn update(&mut self, now: Instant, stats: &[(String, UdpStats)]) -> bool {
         let mut modified = false;
-        for s in stats.iter() {
-            if s.addr == self.display_address {
-                self.latency_us.push(s.avg_time_us.min(500000));
-                self.packet_loss_x100_000.push(s.packet_loss_x100_000);
-                modified = true;
+        for (daemon, s) in stats.iter() {
+            for series in self.series.iter_mut() {
+                if series.target == s.addr && &series.daemon == daemon {
+                    series.latency_us.push(s.avg_time_us.min(500000));
+                    series.packet_loss_x100_000.push(s.packet_loss_x100_000);
+                    modified = true;
+                }
             }
         }
-        while self.latency_us.len() >= self.samples {
-            self.latency_us.remove(0);
-            self.packet_loss_x100_000.remove(0);
-            modified = true;
+        for series in self.series.iter_mut() {
+            while series.latency_us.len() >= self.samples {
+                series.latency_us.remove(0);
+                series.packet_loss_x100_000.remove(0);
+                modified = true;
+            }
         }
         if self.current.elapsed() > Duration::from_secs_f32(1.0) {
             modified = true;
@@ -59,11 +93,40 @@ impl LatencyGraph {
         }
         modified
     }
+    /// Adds the frames of a chunk of history from a daemon. The first chunk
+    /// replaces what was there; the next update trims it to the sample limit.
+    pub fn backfill(&mut self, daemon: &str, chunk: &HistoryChunk) {
+        let series = self
+            .series
+            .iter_mut()
+            .find(|s| s.target == chunk.target && s.daemon == daemon);
+        let series = match series {
+            Some(series) => series,
+            None => return,
+        };
+        if chunk.seq == 0 {
+            series.latency_us.clear();
+            series.packet_loss_x100_000.clear();
+        }
+        for f in chunk.frames.iter() {
+            // Same as the daemon computes the live stats, but with the median.
+            let latency_us = match f.recv_us_len {
+                0 => 0,
+                _ => f.percentile_us(0.5).max(0) as u32,
+            };
+            let lost = f.inflight + f.lost_packets;
+            let packet_loss = 100.0 * lost / (lost + f.recv_us_len as f32 + 0.1);
+            series.latency_us.push(latency_us.min(500000));
+            series
+                .packet_loss_x100_000
+                .push((packet_loss * 1000.0) as u32);
+        }
+    }
 }
 
 impl Default for LatencyGraph {
     fn default() -> Self {
-        Self::new("", 1000)
+        Self::new("", &[], 1000)
     }
 }
 
@@ -101,14 +164,16 @@ impl canvas::Program<Message> for LatencyGraph {
         let mut frame = canvas::Frame::new(bounds.size());
 
         frame.fill(&space, Color::from_rgba8(100, 100, 100, 1.0));
-        let avg_latency: u32 =
-            self.latency_us.iter().sum::<u32>() / (self.latency_us.len().max(1) as u32);
-        let text = canvas::Text {
-            content: format!(
+        let title = match self.series.as_slice() {
+            [series] => format!(
                 "{} - {:.2}ms avg",
                 self.display_address,
-                avg_latency as f32 / 1000.0
+                series.avg_latency_us() as f32 / 1000.0
             ),
+            _ => self.display_address.clone(),
+        };
+        let text = canvas::Text {
+            content: title,
             position: Point::new(0.0, 0.0),
             color: Color::from_rgba8(255, 255, 255, 0.9),
             size: 12.0,
@@ -117,15 +182,39 @@ impl canvas::Program<Message> for LatencyGraph {
             vertical_alignment: iced::alignment::Vertical::Top,
         };
         frame.fill_text(text);
-        if self.latency_us.is_empty() {
+        // With several vantage points, a legend tells them apart.
+        if self.series.len() > 1 {
+            for (n, series) in self.series.iter().enumerate() {
+                let (r, g, b) = SERIES_COLORS[n % SERIES_COLORS.len()];
+                let content = match series.latency_us.is_empty() {
+                    true => format!("{}: no data", series.daemon),
+                    false => format!(
+                        "{}: {:.2}ms avg",
+                        series.daemon,
+                        series.avg_latency_us() as f32 / 1000.0
+                    ),
+                };
+                frame.fill_text(canvas::Text {
+                    content,
+                    position: Point::new(0.0, 14.0 * (n + 1) as f32),
+                    color: Color::from_rgba8(r, g, b, 0.9),
+                    size: 12.0,
+                    font: iced::Font::Default,
+                    horizontal_alignment: iced::alignment::Horizontal::Left,
+                    vertical_alignment: iced::alignment::Vertical::Top,
+                });
+            }
+        }
+        if self.series.iter().all(|s| s.latency_us.is_empty()) {
             let line = canvas::Path::line(Point::new(0.0, 0.0), botright);
             frame.stroke(&line, red_stroke);
             return vec![frame.into_geometry()];
         }
         let ms: f32 = 1000.0;
         let max = self
-            .latency_us
+            .series
             .iter()
+            .flat_map(|s| s.latency_us.iter())
             .filter(|x| **x < 2000000)
             .max()
             .unwrap();
@@ -203,42 +292,65 @@ impl canvas::Program<Message> for LatencyGraph {
             };
             frame.fill_text(text);
         }
-        let mut oldp: Option<Point> = None;
-        for (n, p) in self.latency_us.iter().enumerate() {
-            let x = n as f32 * sx;
-            let y = bottom - *p as f32 * sy;
-            let point = Point::new(x, y);
-            if let Some(oldp) = oldp {
-                let line = canvas::Path::line(oldp, point);
-                frame.stroke(&line, green_stroke);
-            }
-            if n == len - 1 {
-                let x2 = frame.width();
-                let p2 = Point::new(x2, y);
-                let line = canvas::Path::line(point, p2);
-                frame.stroke(&line, green_stroke);
-            }
-            oldp = Some(point);
+        let loss_sy: f32 = (frame.height() / 100000.0) * 1.0;
+        // A lone series keeps green and red; several get a color each, with
+        // the packet loss dashed.
+        for (n, series) in self.series.iter().enumerate() {
+            let (latency_stroke, loss_stroke) = match self.series.len() {
+                1 => (green_stroke, red_stroke),
+                _ => {
+                    let (r, g, b) = SERIES_COLORS[n % SERIES_COLORS.len()];
+                    let stroke = Stroke {
+                        width: 1.0,
+                        color: Color::from_rgba8(r, g, b, 0.6),
+                        ..Stroke::default()
+                    };
+                    let dashed = Stroke {
+                        line_dash: canvas::LineDash {
+                            segments: &[4.0, 3.0],
+                            offset: 0,
+                        },
+                        ..stroke
+                    };
+                    (stroke, dashed)
+                }
+            };
+            let latency = plot_line(&series.latency_us, len, sx, sy, frame.width(), bottom);
+            frame.stroke(&latency, latency_stroke);
+            let loss = plot_line(
+                &series.packet_loss_x100_000,
+                len,
+                sx,
+                loss_sy,
+                frame.width(),
+                bottom,
+            );
+            frame.stroke(&loss, loss_stroke);
         }
-        // Packet Loss:
-        let sy: f32 = (frame.height() / 100000.0) * 1.0;
-        let mut oldp: Option<Point> = None;
-        for (n, p) in self.packet_loss_x100_000.iter().enumerate() {
-            let x = n as f32 * sx;
-            let y = bottom - *p as f32 * sy;
-            let point = Point::new(x, y);
-            if let Some(oldp) = oldp {
-                let line = canvas::Path::line(oldp, point);
-                frame.stroke(&line, red_stroke);
+        vec![frame.into_geometry()]
+    }
+}
+
+/// A line through "values", "sx" apart and scaled by "sy" from the bottom. The
+/// last sample, when the graph is full, is stretched to the right edge.
+fn plot_line(
+    values: &[u32],
+    len: usize,
+    sx: f32,
+    sy: f32,
+    right: f32,
+    bottom: f32,
+) -> canvas::Path {
+    canvas::Path::new(|b| {
+        for (n, p) in values.iter().enumerate() {
+            let point = Point::new(n as f32 * sx, bottom - *p as f32 * sy);
+            match n {
+                0 => b.move_to(point),
+                _ => b.line_to(point),
             }
             if n == len - 1 {
-                let x2 = frame.width();
-                let p2 = Point::new(x2, y);
-                let line = canvas::Path::line(point, p2);
-                frame.stroke(&line, red_stroke);
+                b.line_to(Point::new(right, point.y));
             }
-            oldp = Some(point);
         }
-        vec![frame.into_geometry()]
-    }
+    })
 }
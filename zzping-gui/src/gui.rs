@@ -17,15 +17,101 @@ use crate::{
     flags::{Flags, OtherOpts},
 };
 
-use super::flags::GuiConfig;
+use super::flags::{DaemonEndpoint, GuiConfig};
 use super::graph_plot::LatencyGraph;
-use super::udp_comm::UdpStats;
+use super::udp_comm::{UdpMessage, UdpStats};
 use iced::{
     executor, slider, Application, Canvas, Color, Column, Command, Element, Length, Row, Slider,
     Subscription, Text,
 };
-use std::net::UdpSocket;
-use std::time::Instant;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant, SystemTime};
+use zzping_lib::framestats::HistoryRequest;
+use zzping_lib::incident::Incident;
+use zzping_lib::secure::{is_sealed, SecureChannel};
+
+/// Incidents listed under the graphs.
+const INCIDENTS_SHOWN: usize = 5;
+
+/// How often the incident log is read again.
+const INCIDENTS_EVERY: Duration = Duration::from_secs(1);
+
+/// A daemon not heard from for this long is shown as not sending.
+const DAEMON_SILENT: Duration = Duration::from_secs(3);
+
+/// A daemon sending to this GUI, and how it's doing.
+struct DaemonLink {
+    endpoint: DaemonEndpoint,
+    /// Where its datagrams come from, resolved on start up.
+    addrs: Vec<SocketAddr>,
+    /// Authenticates its traffic, if it has a psk.
+    secure: Option<SecureChannel>,
+    last_msg: Option<Instant>,
+    /// Why the last datagram was refused, until one is accepted.
+    last_error: Option<String>,
+}
+
+impl DaemonLink {
+    fn new(endpoint: DaemonEndpoint) -> Self {
+        let (addrs, last_error) = match endpoint.address.to_socket_addrs() {
+            Ok(addrs) => (addrs.collect(), None),
+            Err(e) => {
+                println!("Unable to resolve the daemon '{}': {}", endpoint.address, e);
+                (
+                    vec![],
+                    Some(format!("unable to resolve {}", endpoint.address)),
+                )
+            }
+        };
+        let secure = match endpoint.psk.is_empty() {
+            true => None,
+            false => Some(SecureChannel::new(&endpoint.psk, endpoint.encrypt)),
+        };
+        Self {
+            endpoint,
+            addrs,
+            secure,
+            last_msg: None,
+            last_error,
+        }
+    }
+
+    /// Its name, or its address when it has none.
+    fn label(&self) -> &str {
+        match self.endpoint.name.is_empty() {
+            true => &self.endpoint.address,
+            false => &self.endpoint.name,
+        }
+    }
+
+    fn status(&self, now: Instant) -> String {
+        let status = match (&self.last_error, self.last_msg) {
+            (Some(e), _) => format!("refused, {}", e),
+            (None, None) => "waiting".to_owned(),
+            (None, Some(t)) if now - t < DAEMON_SILENT => "connected".to_owned(),
+            (None, Some(t)) => format!("no data for {}s", (now - t).as_secs()),
+        };
+        format!("{}: {}", self.label(), status)
+    }
+}
+
+/// The last "count" incidents of an incident log, oldest first.
+fn read_incidents(path: &str, count: usize) -> std::io::Result<Vec<Incident>> {
+    let mut f = File::open(path)?;
+    // Lines are under 200 bytes but for long target lists; the tail is enough.
+    let len = f.metadata()?.len();
+    f.seek(SeekFrom::Start(len.saturating_sub(count as u64 * 1024)))?;
+    let mut tail = vec![];
+    f.read_to_end(&mut tail)?;
+    let tail = String::from_utf8_lossy(&tail);
+    let incidents: Vec<Incident> = tail
+        .lines()
+        .filter_map(|line| Incident::from_line(line).ok())
+        .collect();
+    Ok(incidents[incidents.len().saturating_sub(count)..].to_vec())
+}
 
 #[derive(Debug, Clone, Copy)]
 pub enum Message {
@@ -39,14 +125,18 @@ pub enum Message {
 }
 
 pub struct PingmonGUI {
-    pub display_address: Vec<String>,
     pub guiconfig: GuiConfig,
     pub otheropts: OtherOpts,
     pub graph: Vec<LatencyGraph>,
     pub graph_cache: Vec<iced::widget::canvas::Cache>,
     pub socket: Option<UdpSocket>,
+    daemons: Vec<DaemonLink>,
+    /// Senders not in the config, reported once.
+    unknown_senders: Vec<SocketAddr>,
     pub fdqgraph: FDQGraph,
     pub fdqgraph_cache: iced::widget::canvas::Cache,
+    incidents: Vec<Incident>,
+    incidents_read: Option<Instant>,
     zoomw_slider_state: slider::State,
     zoomw_slider: f32,
     zoomy_slider_state: slider::State,
@@ -63,14 +153,17 @@ impl Default for PingmonGUI {
     fn default() -> Self {
         Self {
             posx_slider: 0.5,
-            display_address: vec![],
             guiconfig: Default::default(),
             otheropts: Default::default(),
             graph: Default::default(),
             graph_cache: Default::default(),
             socket: Default::default(),
+            daemons: vec![],
+            unknown_senders: vec![],
             fdqgraph: Default::default(),
             fdqgraph_cache: Default::default(),
+            incidents: vec![],
+            incidents_read: None,
             zoomw_slider_state: Default::default(),
             zoomw_slider: Default::default(),
             zoomy_slider_state: Default::default(),
@@ -91,34 +184,122 @@ impl PingmonGUI {
             None => {
                 let socket = UdpSocket::bind(&self.guiconfig.udp_listen_address).unwrap();
                 socket.set_nonblocking(true).unwrap();
-                socket.connect(&self.guiconfig.udp_server_address).unwrap();
+                self.daemons = (self.guiconfig.endpoints().into_iter())
+                    .map(DaemonLink::new)
+                    .collect();
+                if self.guiconfig.backfill_secs > 0 {
+                    self.request_history(&socket);
+                }
 
                 self.socket = Some(socket);
             }
         }
     }
-    fn recv(&mut self) -> Result<UdpStats, Box<dyn std::error::Error>> {
+    /// Asks each daemon for the last "backfill_secs" of the targets graphed
+    /// from it, and whatever arrives until it catches up.
+    fn request_history(&mut self, socket: &UdpSocket) {
+        let since = SystemTime::now() - Duration::from_secs(self.guiconfig.backfill_secs);
+        let from_ms = since
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as i64;
+        for daemon in self.daemons.iter_mut() {
+            let addr = match daemon.addrs.first() {
+                Some(addr) => *addr,
+                None => continue,
+            };
+            let series = self.graph.iter().flat_map(|g| g.series.iter());
+            let targets = series.filter(|s| s.daemon == daemon.endpoint.name);
+            for target in targets.map(|s| s.target.clone()) {
+                let req = HistoryRequest {
+                    target,
+                    from_ms,
+                    to_ms: None,
+                };
+                let msg = match daemon.secure.as_mut() {
+                    Some(s) => s.seal(&req.encode(), SystemTime::now()),
+                    None => req.encode(),
+                };
+                if let Err(e) = socket.send_to(&msg, addr) {
+                    println!("Unable to ask {} for history: {}", daemon.label(), e);
+                    break;
+                }
+            }
+        }
+    }
+    /// Reads what the daemons sent, each message with the daemon it came
+    /// from.
+    fn recv_all(&mut self, now: Instant) -> Vec<(usize, UdpMessage)> {
         let mut buf: [u8; 65536] = [0; 65536];
+        let mut ret: Vec<(usize, UdpMessage)> = vec![];
         let socket = self.socket.as_mut().unwrap();
-        let sz = socket.recv(&mut buf)?;
-        let stats = UdpStats::from_buf(&buf[..sz])?;
-        Ok(stats)
-    }
-    fn recv_all(&mut self) -> Vec<UdpStats> {
-        let mut ret: Vec<UdpStats> = vec![];
-        while let Ok(stats) = self.recv() {
-            ret.push(stats);
+        while let Ok((sz, from)) = socket.recv_from(&mut buf) {
+            let n = match self.daemons.iter().position(|d| d.addrs.contains(&from)) {
+                Some(n) => n,
+                None => {
+                    if !self.unknown_senders.contains(&from) {
+                        println!(
+                            "Ignoring messages from {}, not a daemon in the config",
+                            from
+                        );
+                        self.unknown_senders.push(from);
+                    }
+                    continue;
+                }
+            };
+            let daemon = &mut self.daemons[n];
+            let msg = match daemon.secure.as_mut() {
+                Some(s) => match s.open(&buf[..sz], SystemTime::now()) {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        println!("Message from {} refused: {}", daemon.label(), e);
+                        daemon.last_error = Some(e.to_string());
+                        continue;
+                    }
+                },
+                None if is_sealed(&buf[..sz]) => {
+                    if daemon.last_error.is_none() {
+                        println!(
+                            "{} authenticates its messages, set its psk in the config",
+                            daemon.label()
+                        );
+                    }
+                    daemon.last_error = Some("it needs a psk".to_owned());
+                    continue;
+                }
+                None => buf[..sz].to_vec(),
+            };
+            match UdpMessage::from_buf(&msg) {
+                Ok(msg) => {
+                    daemon.last_msg = Some(now);
+                    daemon.last_error = None;
+                    ret.push((n, msg));
+                }
+                Err(e) => println!("Invalid message from {}: {}", daemon.label(), e),
+            }
         }
         ret
     }
     fn tick(&mut self, instant: Instant) {
         if self.otheropts.input_file.is_none() {
-            let stats = self.recv_all();
+            let mut stats: Vec<(String, UdpStats)> = vec![];
+            for (n, msg) in self.recv_all(instant) {
+                let daemon = &self.daemons[n].endpoint.name;
+                match msg {
+                    UdpMessage::Stats(s) => stats.push((daemon.clone(), s)),
+                    UdpMessage::History(chunk) => {
+                        for graph in self.graph.iter_mut() {
+                            graph.backfill(daemon, &chunk);
+                        }
+                    }
+                }
+            }
             for (graph, canvas) in self.graph.iter_mut().zip(self.graph_cache.iter_mut()) {
                 if graph.update(instant, &stats) {
                     canvas.clear();
                 }
             }
+            self.update_incidents(instant);
         } else {
             if self.fdqgraph.update(instant) {
                 self.fdqgraph_cache.clear();
@@ -133,17 +314,33 @@ impl PingmonGUI {
                 if self.posdx_slider.abs() < 0.01 {
                     self.posdx_slider = 0.0;
                 }
-                self.posx_slider = self.posx_slider.max(0.0).min(1.0);
+                self.posx_slider = self.posx_slider.clamp(0.0, 1.0);
                 self.update_posx();
             }
         }
     }
+    fn update_incidents(&mut self, instant: Instant) {
+        let path = match self.guiconfig.incident_log.as_ref() {
+            Some(path) => path,
+            None => return,
+        };
+        if matches!(self.incidents_read, Some(t) if instant - t < INCIDENTS_EVERY) {
+            return;
+        }
+        self.incidents_read = Some(instant);
+        match read_incidents(path, INCIDENTS_SHOWN) {
+            Ok(incidents) => self.incidents = incidents,
+            // The daemon creates it on start up, it might not be there yet.
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => println!("Unable to read the incident log '{}': {}", path, e),
+        }
+    }
     fn update_posx(&mut self) {
         let x = self.posx_slider as f64;
         // let z = (self.zoomx_slider as f64).exp();
         // let dx = self.posdx_slider as f64 / z;
         // let fx = x;
-        self.fdqgraph.set_posx(x.max(0.0).min(1.0));
+        self.fdqgraph.set_posx(x.clamp(0.0, 1.0));
     }
 }
 
@@ -153,20 +350,14 @@ impl Application for PingmonGUI {
     type Flags = Flags;
 
     fn new(flags: Flags) -> (Self, Command<Message>) {
+        // Checked when the config was read.
+        let graphs = flags.guiconfig.graphs().unwrap();
         let app = Self {
-            display_address: flags.guiconfig.display_address.clone(),
-            graph: flags
-                .guiconfig
-                .display_address
+            graph: graphs
                 .iter()
-                .map(|addr| LatencyGraph::new(addr, flags.guiconfig.sample_limit))
-                .collect(),
-            graph_cache: flags
-                .guiconfig
-                .display_address
-                .iter()
-                .map(|_| Default::default())
+                .map(|g| LatencyGraph::new(&g.title, &g.series, flags.guiconfig.sample_limit))
                 .collect(),
+            graph_cache: graphs.iter().map(|_| Default::default()).collect(),
 
             guiconfig: flags.guiconfig,
             otheropts: flags.otheropts,
@@ -214,14 +405,10 @@ impl Application for PingmonGUI {
         Command::none()
     }
 
-    fn view(&mut self) -> Element<Message> {
+    fn view(&mut self) -> Element<'_, Message> {
         let mut window: Column<Message> = Column::new().padding(0);
         if self.otheropts.input_file.is_none() {
-            for (_addr, (graph, _cache)) in self
-                .display_address
-                .iter()
-                .zip(self.graph.iter().zip(self.graph_cache.iter()))
-            {
+            for (graph, _cache) in self.graph.iter().zip(self.graph_cache.iter()) {
                 // FIXME: This clones the graph data AND doesn't use the Cache!
                 // let widget_graph = Canvas::new(cache.with(graph))
                 //     .width(Length::Fill)
@@ -231,6 +418,17 @@ impl Application for PingmonGUI {
                     .height(Length::Fill);
                 window = window.push(widget_graph);
             }
+            let now = Instant::now();
+            let mut status: Row<Message> = Row::new().padding(2).spacing(20);
+            for daemon in self.daemons.iter() {
+                let text = Text::new(daemon.status(now)).size(16);
+                status = status.push(text.color(Color::BLACK));
+            }
+            window = window.push(status);
+            for incident in self.incidents.iter().rev() {
+                let text = Text::new(incident.to_string()).size(16);
+                window = window.push(text.color(Color::BLACK));
+            }
         } else {
             // FIXME: This clones the graph data AND doesn't use the Cache!
             let graph = Canvas::new(self.fdqgraph.clone())
@@ -13,12 +13,12 @@
 // limitations under the License.
 
 use super::custom_errors::UnexpectedError;
+use rmp::Marker;
+use zzping_lib::framestats::HistoryChunk;
 
 pub struct UdpStats {
     pub addr: String,
-    pub inflight_count: u16,
     pub avg_time_us: u32,
-    pub last_pckt_ms: u32,
     pub packet_loss_x100_000: u32,
 }
 
@@ -33,17 +33,34 @@ impl UdpStats {
             .map_err(|_| Box::new(UnexpectedError::new("Couldn't read string")))?
             .to_owned();
 
-        let inflight_count = rmp::decode::read_u16(&mut v)?;
+        // Inflight count, not shown.
+        rmp::decode::read_u16(&mut v)?;
         let avg_time_us = rmp::decode::read_u32(&mut v)?;
-        let last_pckt_ms = rmp::decode::read_u32(&mut v)?;
+        // Time since the last packet, not shown.
+        rmp::decode::read_u32(&mut v)?;
         let packet_loss_x100_000 = rmp::decode::read_u32(&mut v)?;
 
         Ok(Self {
             addr,
-            inflight_count,
             avg_time_us,
-            last_pckt_ms,
             packet_loss_x100_000,
         })
     }
 }
+
+/// Anything the daemon sends. Stats are arrays, the rest are maps.
+pub enum UdpMessage {
+    Stats(UdpStats),
+    History(HistoryChunk),
+}
+
+impl UdpMessage {
+    pub fn from_buf(v: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+        match v.first().map(|b| Marker::from_u8(*b)) {
+            Some(Marker::FixMap(_) | Marker::Map16 | Marker::Map32) => {
+                Ok(Self::History(HistoryChunk::decode(v)?))
+            }
+            _ => Ok(Self::Stats(UdpStats::from_buf(v)?)),
+        }
+    }
+}
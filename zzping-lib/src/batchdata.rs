@@ -55,7 +55,7 @@ impl BatchData {
         let recv_us: Vec<[f32; 7]> = v
             .iter()
             .filter(|x| !x.recv_us.is_empty()) // This will require extra process to put the parts
-            .map(|x| -> Vec<f32> { x.recv_us.iter().map(|x| (*x as f32)).collect() })
+            .map(|x| -> Vec<f32> { x.recv_us.iter().map(|x| *x as f32).collect() })
             .map(|x| Self::compute_percentiles(&x))
             .collect();
 
@@ -18,7 +18,7 @@ use std::thread;
 
 use clap::Parser;
 
-use zzping_lib::framedataq::{FDCodecCfg, FrameDataQ, RMPCodec};
+use zzping_lib::framedataq::{FDCodecCfg, FrameDataQ, Percentiles};
 use zzping_lib::{compress::quantize::LinearLogQuantizer, framedataq::FDCodecState};
 use zzping_lib::{framedata::FrameDataVec, framedataq::Complete};
 
@@ -38,6 +38,14 @@ struct Opts {
     time: i64,
     #[clap(short, long)]
     delta_enc: bool,
+    /// Writes a latency sketch with each frame, so percentiles stay right
+    /// when frames are aggregated.
+    #[clap(long)]
+    sketch: bool,
+    /// Percentiles of the replies to keep in each frame, as "50,95,99.9".
+    /// The lowest and highest are always kept. The usual seven if not set.
+    #[clap(long)]
+    percentiles: Option<Percentiles>,
     #[clap(short, long)]
     auto_output: bool,
 }
@@ -56,13 +64,16 @@ fn main() {
         full_encode_secs: interval,
         recv_llq: quantizer,
         delta_enc: opts.delta_enc,
+        sketch: opts.sketch,
+        percentiles: opts.percentiles.unwrap_or_default(),
     };
-    let header: Vec<u8> = FDCodecState::get_header(codeccfg);
+    let header: Vec<u8> = FDCodecState::get_header(&codeccfg);
     if let Some(buf) = obuffer.as_mut() {
         buf.write_all(&header).unwrap();
     }
     for input in opts.input {
-        let handle = thread::spawn(move || read_inputfile(&input, codeccfg));
+        let cfg = codeccfg.clone();
+        let handle = thread::spawn(move || read_inputfile(&input, cfg));
         handles.push(handle);
         if handles.len() > 7 {
             let (input_file, data) = handles.remove(0).join().unwrap();
@@ -99,12 +110,12 @@ fn read_inputfile(filename: &str, cfg: FDCodecCfg) -> (String, Vec<u8>) {
         dbg!(filename, e);
     }
     dbg!(fdv.v.len());
+    let percentiles = cfg.percentiles.clone();
     let mut codec = FDCodecState::new(cfg);
     let mut buf = Vec::with_capacity(fdv.v.len() * 12);
     for frame in fdv.v.iter() {
-        let fdq: FrameDataQ<Complete> = FrameDataQ::from_framedata(frame);
-        let fdq = codec.encode(fdq);
-        let mut rmp = fdq.to_rmp();
+        let fdq: FrameDataQ<Complete> = FrameDataQ::from_framedata_with(frame, &percentiles);
+        let mut rmp = codec.encode_rmp(fdq);
         buf.append(&mut rmp);
     }
     (filename.to_string(), buf)
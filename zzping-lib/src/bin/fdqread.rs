@@ -13,11 +13,13 @@
 // limitations under the License.
 
 use std::io::BufReader;
+use std::path::Path;
 use std::{fs::File, io::Write};
 
 use clap::Parser;
 
-use zzping_lib::framedataq::{FDCodecState, IterFold, RMPCodec};
+use zzping_lib::framedataq::{FDCodecState, IterFold, Percentiles};
+use zzping_lib::incident::{IncidentCfg, IncidentDetector};
 use zzping_lib::{
     compress::quantize::LinearLogQuantizer,
     framedataq::{FDCodecCfg, FDCodecIter},
@@ -45,6 +47,32 @@ struct Opts {
     time: i64,
     #[clap(short, long)]
     delta_enc: bool,
+    /// Writes a latency sketch with each frame, so percentiles stay right
+    /// when frames are aggregated.
+    #[clap(long)]
+    sketch: bool,
+    /// Percentiles of the replies to keep in each frame, as "50,95,99.9".
+    /// The lowest and highest are always kept. The usual seven if not set.
+    #[clap(long)]
+    percentiles: Option<Percentiles>,
+
+    /// Lists the incidents found instead of the frames.
+    #[clap(long)]
+    incidents: bool,
+}
+
+/// Target of a log file, from its name as zzping-daemon writes it:
+/// "pingd-log-<target>-<hour>.log", maybe followed by ".fdq.log".
+fn target_of(filename: &str) -> String {
+    let name = Path::new(filename)
+        .file_name()
+        .map_or(filename.into(), |x| x.to_string_lossy());
+    let name = name.trim_end_matches(".fdq.log").trim_end_matches(".log");
+    let name = name.strip_prefix("pingd-log-").unwrap_or(name);
+    match name.rsplit_once('-') {
+        Some((target, _hour)) => target.to_owned(),
+        None => name.to_owned(),
+    }
 }
 
 fn main() {
@@ -59,22 +87,29 @@ fn main() {
         full_encode_secs: interval,
         recv_llq: quantizer,
         delta_enc: opts.delta_enc,
+        sketch: opts.sketch,
+        percentiles: opts.percentiles.unwrap_or_default(),
     };
+    let header: Vec<u8> = FDCodecState::get_header(&codeccfg);
     let mut codec = FDCodecState::new(codeccfg);
-    let header: Vec<u8> = FDCodecState::get_header(codeccfg);
     if let Some(buf) = obuffer.as_mut() {
         buf.write_all(&header).unwrap();
     }
 
+    let mut detector = IncidentDetector::new(IncidentCfg::default());
     for filename in opts.input.iter() {
         let f = File::open(filename).unwrap();
         let buf = BufReader::new(f);
         let fdreader = FDCodecIter::new(buf);
+        let target = target_of(filename);
         for fdq in fdreader.iter_fold(opts.agg_window, opts.agg_step) {
+            if opts.incidents {
+                detector.push(&target, &fdq);
+                continue;
+            }
             match obuffer.as_mut() {
                 Some(buf) => {
-                    let fdq = codec.encode(fdq);
-                    let rmp = fdq.to_rmp();
+                    let rmp = codec.encode_rmp(fdq);
                     buf.write_all(&rmp).unwrap();
                 }
                 None => {
@@ -83,4 +118,7 @@ fn main() {
             }
         }
     }
+    for incident in detector.finish() {
+        println!("{}", incident);
+    }
 }
@@ -0,0 +1,120 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fs::File;
+use std::io::Write;
+
+use chrono::{DateTime, Utc};
+use clap::Parser;
+
+use zzping_lib::compress::quantize::LinearLogQuantizer;
+use zzping_lib::framedata::FrameTime;
+use zzping_lib::framedataq::{Complete, FDCodecCfg, FDCodecState, FrameDataQ, Percentiles};
+use zzping_lib::logevent::FrameMeta;
+use zzping_lib::synth::{Generator, Scenario};
+
+#[derive(Parser, Debug)]
+#[clap(
+    version = "0.2.2-beta2",
+    author = "David Martinez Marti <deavidsedice@gmail.com>"
+)]
+struct Opts {
+    /// Scenario to generate, in RON.
+    #[clap(short, long)]
+    scenario: String,
+    #[clap(short, long)]
+    output: String,
+    /// Write FrameDataQ, as datareadq does, instead of FrameData.
+    #[clap(short, long)]
+    fdq: bool,
+    #[clap(short, long)]
+    quantize: Option<f64>,
+    #[clap(short, long, default_value = "60")]
+    time: i64,
+    #[clap(short, long)]
+    delta_enc: bool,
+    /// Writes a latency sketch with each frame, as datareadq --sketch.
+    #[clap(long)]
+    sketch: bool,
+    /// Percentiles to keep in each frame, as datareadq --percentiles.
+    #[clap(long)]
+    percentiles: Option<Percentiles>,
+}
+
+fn main() {
+    let opts: Opts = Opts::parse();
+    let scenario = match Scenario::from_filepath(&opts.scenario) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("{:#}", e);
+            std::process::exit(1);
+        }
+    };
+    let generator = match Generator::new(scenario) {
+        Ok(g) => g,
+        Err(e) => {
+            eprintln!("{:#}", e);
+            std::process::exit(1);
+        }
+    };
+    let output = match File::create(&opts.output) {
+        Ok(f) => f,
+        Err(e) => {
+            eprintln!("Unable to create {}: {}", opts.output, e);
+            std::process::exit(1);
+        }
+    };
+    let mut obuffer = std::io::BufWriter::new(output);
+    let mut frames = 0;
+    if opts.fdq {
+        let codeccfg = FDCodecCfg {
+            full_encode_secs: opts.time,
+            recv_llq: opts.quantize.map(LinearLogQuantizer::new),
+            delta_enc: opts.delta_enc,
+            sketch: opts.sketch,
+            percentiles: opts.percentiles.clone().unwrap_or_default(),
+        };
+        obuffer
+            .write_all(&FDCodecState::get_header(&codeccfg))
+            .unwrap();
+        let percentiles = codeccfg.percentiles.clone();
+        let mut codec = FDCodecState::new(codeccfg);
+        let mut last_keyframe: Option<DateTime<Utc>> = None;
+        for mut fd in generator {
+            match fd.time {
+                FrameTime::Timestamp(ts) => last_keyframe = Some(ts),
+                FrameTime::Elapsed(e) => {
+                    fd.time = FrameTime::Timestamp(
+                        last_keyframe.unwrap() + chrono::Duration::from_std(e).unwrap(),
+                    )
+                }
+            }
+            let fdq: FrameDataQ<Complete> = FrameDataQ::from_framedata_with(&fd, &percentiles);
+            obuffer.write_all(&codec.encode_rmp(fdq)).unwrap();
+            frames += 1;
+        }
+    } else {
+        let mut meta = FrameMeta::default();
+        for fd in generator {
+            for event in meta.diff(&fd.meta) {
+                event.encode(&mut obuffer).unwrap();
+            }
+            meta = fd.meta;
+            fd.encode(&mut obuffer).unwrap();
+            frames += 1;
+        }
+    }
+    obuffer.flush().unwrap();
+    println!("{} frames written to {}", frames, opts.output);
+}
@@ -102,7 +102,7 @@ pub fn half_fft_cmplx(fft: &[Complex<f32>]) -> &[Complex<f32>] {
     let len = fft.len();
     let eps = 0.003;
     let mut errors = 0;
-    assert!(len % 2 == 0); // Odd ffts do not have midpoints
+    assert!(len.is_multiple_of(2)); // Odd ffts do not have midpoints
     for i in 1..len / 2 {
         let l = fft[i];
         let r = fft[len - i];
@@ -131,7 +131,7 @@ pub fn half_fft_polar(fft: &[(f32, f32)]) -> &[(f32, f32)] {
     let len = fft.len();
     let eps = 0.003;
     let mut errors = 0;
-    assert!(len % 2 == 0); // Odd ffts do not have midpoints
+    assert!(len.is_multiple_of(2)); // Odd ffts do not have midpoints
     for i in 1..len / 2 {
         let l = fft[i];
         let r = fft[len - i];
@@ -487,7 +487,7 @@ impl Compress<f32> for FFTPolarCompress {
     fn decompress(&self) -> Result<Vec<f32>, Error> {
         let radius = self.huffman_r.decompress()?;
         let theta = self.huffman_t.decompress()?;
-        let polar_half_fft: Vec<(f32, f32)> = radius.into_iter().zip(theta.into_iter()).collect();
+        let polar_half_fft: Vec<(f32, f32)> = radius.into_iter().zip(theta).collect();
         let len = polar_half_fft.len();
         let polar_half_adj: Vec<(f32, f32)> = polar_half_fft
             .into_iter()
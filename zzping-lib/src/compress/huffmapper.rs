@@ -72,7 +72,7 @@ impl HuffmanMapSBlock {
         HuffmanKey {
             qtype: ValueType::Corrected,
             key: huffkey * sign,
-            extra_bits: self.block_size_bits as usize,
+            extra_bits: self.block_size_bits,
             extra_data: extra,
             metadata: HKeyMetadata::Block(*self),
         }
@@ -115,19 +115,14 @@ impl HuffmanMapSRaw {
         }
     }
 }
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
 pub enum HKeyMetadata {
     Block(HuffmanMapSBlock),
     Raw(HuffmanMapSRaw),
+    #[default]
     None,
 }
 
-impl Default for HKeyMetadata {
-    fn default() -> Self {
-        Self::None
-    }
-}
-
 #[derive(Debug)]
 pub struct HuffmanKey {
     pub qtype: ValueType,
@@ -167,13 +167,10 @@ pub fn read_bin<R: Read>(rd: &mut R, marker: Marker) -> Result<Vec<u8>> {
     Ok(v)
 }
 
-pub fn read_bin_len<R: Read>(rd: &mut R, marker: Marker) -> Result<usize> {
-    match marker {
-        Marker::Bin8 => Ok(read_u8(rd)? as usize),
-        Marker::Bin16 => Ok(read_u16(rd)? as usize),
-        Marker::Bin32 => Ok(read_u32(rd)? as usize),
-        marker => Err(ValueReadError::TypeMismatch(marker)).context("read_bin_len")?,
-    }
+pub fn read_bin_len<R: Read>(rd: &mut R, _marker: Marker) -> Result<usize> {
+    rmp::decode::read_bin_len(rd)
+        .map(|x| x as usize)
+        .context("read_bin_len")
 }
 
 // ----- NULL -----
@@ -16,6 +16,7 @@
 
 use crate::dynrmp;
 use crate::dynrmp::variant::Variant;
+use crate::logevent::{FrameMeta, LogEvent};
 
 use chrono::{DateTime, Utc};
 use rmp::decode::ValueReadError;
@@ -34,7 +35,7 @@ where
 }
 
 /// Timestamp part of FrameData
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum FrameTime {
     /// On full frame encoding, a complete datetime is stored.
     Timestamp(DateTime<Utc>),
@@ -48,6 +49,8 @@ pub struct FrameData {
     pub inflight: usize,
     pub lost_packets: usize,
     pub recv_us: Vec<u128>,
+    /// Not part of the frame encoding, readers fill it from the events before it.
+    pub meta: FrameMeta,
 }
 
 impl FrameData {
@@ -79,6 +82,10 @@ impl FrameData {
     }
     pub fn decode<R: std::io::Read>(rd: &mut R) -> Result<Self> {
         let t = Variant::read(rd)?;
+        Self::decode_after(t, rd)
+    }
+    /// Decodes the rest of a frame whose first value was already read.
+    fn decode_after<R: std::io::Read>(t: Variant, rd: &mut R) -> Result<Self> {
         let time: FrameTime = match t {
             Variant::String(s) => {
                 let elapsed = rmp::decode::read_u32(rd)?;
@@ -106,13 +113,32 @@ impl FrameData {
             inflight,
             lost_packets,
             recv_us,
+            meta: FrameMeta::default(),
         })
     }
 }
 
+/// Anything that can be found in a log: either a frame or an event.
+#[derive(Debug, Clone)]
+pub enum LogRecord {
+    Frame(FrameData),
+    Event(LogEvent),
+}
+
+impl LogRecord {
+    pub fn decode<R: std::io::Read>(rd: &mut R) -> Result<Self> {
+        match Variant::read(rd)? {
+            Variant::Map(m) => Ok(Self::Event(LogEvent::from_map(m)?)),
+            t => Ok(Self::Frame(FrameData::decode_after(t, rd)?)),
+        }
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct FrameDataVec {
     pub last_keyframe: Option<DateTime<Utc>>,
+    /// State from the events read so far.
+    pub meta: FrameMeta,
     pub v: Vec<FrameData>,
 }
 
@@ -122,9 +148,18 @@ impl FrameDataVec {
     }
     pub fn read<R: std::io::Read>(&mut self, rd: &mut R, count: u64) -> Result<()> {
         let err = || ValueReadError::TypeMismatch(rmp::Marker::Str8);
-        for n in 0..count {
-            println!("{}", n);
-            let mut fd = FrameData::decode(rd)?;
+        let mut n = 0;
+        while n < count {
+            let mut fd = match LogRecord::decode(rd)? {
+                LogRecord::Frame(fd) => fd,
+                LogRecord::Event(ev) => {
+                    self.meta.apply(&ev);
+                    continue;
+                }
+            };
+            n += 1;
+            fd.meta = self.meta;
+            self.meta = self.meta.next();
             match &fd.time {
                 FrameTime::Timestamp(ts) => self.last_keyframe = Some(*ts),
                 FrameTime::Elapsed(e) => {
@@ -141,3 +176,60 @@ impl FrameDataVec {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logevent::Discontinuity;
+
+    #[test]
+    fn test_framedatavec_events() {
+        let frame = |elapsed_ms| FrameData {
+            time: FrameTime::Elapsed(Duration::from_millis(elapsed_ms)),
+            inflight: 1,
+            lost_packets: 0,
+            recv_us: vec![100, 200],
+            meta: FrameMeta::default(),
+        };
+        let mut buf: Vec<u8> = vec![];
+        FrameData {
+            time: FrameTime::Timestamp(Utc::now()),
+            ..frame(0)
+        }
+        .encode(&mut buf)
+        .unwrap();
+        LogEvent::ProbeRate(2.0).encode(&mut buf).unwrap();
+        frame(500).encode(&mut buf).unwrap();
+        LogEvent::Unknown("something_new".to_owned())
+            .encode(&mut buf)
+            .unwrap();
+        frame(1000).encode(&mut buf).unwrap();
+        let step = Discontinuity {
+            step_secs: -60.0,
+            suspend_secs: 0.0,
+        };
+        LogEvent::Discontinuity(step).encode(&mut buf).unwrap();
+        let stepped = DateTime::parse_from_rfc3339("2021-04-25T18:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        FrameData {
+            time: FrameTime::Timestamp(stepped),
+            ..frame(0)
+        }
+        .encode(&mut buf)
+        .unwrap();
+        frame(500).encode(&mut buf).unwrap();
+
+        let mut fdv = FrameDataVec::new();
+        fdv.read(&mut &buf[..], 5).unwrap();
+        let rates: Vec<_> = fdv.v.iter().map(|x| x.meta.probe_rate).collect();
+        assert_eq!(
+            rates,
+            vec![None, Some(2.0), Some(2.0), Some(2.0), Some(2.0)]
+        );
+        assert_eq!(fdv.v[2].recv_us, vec![100, 200]);
+        let steps: Vec<_> = fdv.v.iter().map(|x| x.meta.discontinuity).collect();
+        assert_eq!(steps, vec![None, None, None, Some(step), None]);
+        assert_eq!(fdv.v[3].time, FrameTime::Timestamp(stepped));
+    }
+}
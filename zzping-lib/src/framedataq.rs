@@ -12,7 +12,13 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{collections::VecDeque, marker::PhantomData};
+use std::{
+    collections::VecDeque,
+    marker::PhantomData,
+    ops::Deref,
+    str::FromStr,
+    sync::{Arc, OnceLock},
+};
 
 use chrono::{DateTime, NaiveDateTime, Utc};
 use dynrmp::variant::Variant;
@@ -27,6 +33,8 @@ use crate::{
     compress::quantize::LinearLogQuantizer,
     dynrmp,
     framedata::{FrameData, FrameTime},
+    logevent::{FrameMeta, LogEvent},
+    sketch::LatencySketch,
 };
 
 #[derive(Debug, Clone, Copy)]
@@ -63,7 +71,83 @@ pub struct Complete;
 #[derive(Debug, Clone, Copy)]
 pub struct Encoded;
 
-#[derive(Debug, Clone, Copy)]
+/// Percentiles of the replies kept in recv_us, as fractions from 0.0 to 1.0.
+/// They always start at 0.0 and end at 1.0, the lowest and highest reply.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Percentiles(Arc<[f32]>);
+
+impl Percentiles {
+    /// Most that a frame can keep.
+    pub const MAX_LEN: usize = 32;
+
+    pub fn new(values: &[f32]) -> Result<Self, XError> {
+        let valid = (2..=Self::MAX_LEN).contains(&values.len())
+            && values.first() == Some(&0.0)
+            && values.last() == Some(&1.0)
+            && values.windows(2).all(|w| w[0] < w[1]);
+        match valid {
+            true => Ok(Self(values.into())),
+            false => Err(XError::UnexpectedData(format!(
+                "percentiles {:?} must go up from 0.0 to 1.0, at most {} of them",
+                values,
+                Self::MAX_LEN
+            ))),
+        }
+    }
+}
+
+impl Default for Percentiles {
+    /// The ones kept before they could be chosen.
+    fn default() -> Self {
+        static STANDARD: OnceLock<Percentiles> = OnceLock::new();
+        STANDARD
+            .get_or_init(|| Self([0.0, 0.125, 0.25, 0.5, 0.75, 0.875, 1.0].into()))
+            .clone()
+    }
+}
+
+impl Deref for Percentiles {
+    type Target = [f32];
+
+    fn deref(&self) -> &[f32] {
+        &self.0
+    }
+}
+
+impl FromStr for Percentiles {
+    type Err = String;
+
+    /// Percents separated by commas, as "50,95,99.9". The lowest and highest
+    /// are always kept, 0 and 100 are added if missing.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut values = vec![0.0, 1.0];
+        for part in s.split(',') {
+            let pct: f32 = match part.trim().parse() {
+                Ok(pct) if (0.0..=100.0).contains(&pct) => pct,
+                _ => return Err(format!("'{}' is not a percent from 0 to 100", part)),
+            };
+            values.push(pct / 100.0);
+        }
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        values.dedup();
+        Self::new(&values).map_err(|e| e.to_string())
+    }
+}
+
+impl std::fmt::Display for Percentiles {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (n, p) in self.iter().enumerate() {
+            if n > 0 {
+                f.write_str(",")?;
+            }
+            // As written, 0.999 is 99.9 and not 99.899994.
+            f.write_fmt(format_args!("{}", (*p as f64 * 1e5).round() / 1e3))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct FrameDataQ<T> {
     pub phantom: PhantomData<T>,
     pub timestamp: Option<i64>,
@@ -71,24 +155,112 @@ pub struct FrameDataQ<T> {
     pub inflight: f32,
     pub lost_packets: f32,
     pub recv_us_len: usize,
-    pub recv_us: [i64; 7],
+    /// Which percentiles of the replies are in recv_us.
+    pub percentiles: Percentiles,
+    /// One for each of the percentiles, -1 without replies.
+    pub recv_us: Vec<i64>,
+    /// Histogram of the replies, to get the percentiles of several frames
+    /// together. Only encoded when the codec has "sketch" set.
+    pub sketch: Option<LatencySketch>,
+    /// State from the events preceding this frame. Not encoded in the frame.
+    pub meta: FrameMeta,
 }
 
 impl<Complete> std::fmt::Display for FrameDataQ<Complete> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_fmt(format_args!(
-            "FrameDataQ<Complete> {} i:{} l:{} sz:{}\t{:?}",
+            "FrameDataQ<Complete> {} i:{} l:{} sz:{}\t",
             self.get_datetime(),
             self.inflight,
             self.lost_packets,
             self.recv_us_len,
-            self.recv_us,
-        ))
+        ))?;
+        // Other percentiles than the usual are labelled, as "[p50:1200 ...]".
+        match self.percentiles == Percentiles::default() {
+            true => f.write_fmt(format_args!("{:?}", self.recv_us))?,
+            false => {
+                let labels = self.percentiles.to_string();
+                let values = labels.split(',').zip(self.recv_us.iter());
+                let values: Vec<_> = values.map(|(p, v)| format!("p{}:{}", p, v)).collect();
+                f.write_fmt(format_args!("[{}]", values.join(" ")))?
+            }
+        }
+        if let Some(rate) = self.meta.probe_rate {
+            f.write_fmt(format_args!(" rate:{:.1}/s", rate))?;
+        }
+        if let Some(d) = self.meta.discontinuity {
+            f.write_fmt(format_args!(
+                " step:{:.3}s suspend:{:.3}s",
+                d.step_secs, d.suspend_secs
+            ))?;
+        }
+        if let Some(reason) = self.meta.daemon_stop {
+            f.write_fmt(format_args!(" stopped:{}", reason.as_str()))?;
+        }
+        if let Some(start) = self.meta.daemon_start {
+            f.write_fmt(format_args!(
+                " started:{} config:{:016x}",
+                start.reason.as_str(),
+                start.config_hash
+            ))?;
+        }
+        if let Some(w) = self.meta.wireless {
+            f.write_fmt(format_args!(
+                " wifi:{:.0}dBm q:{:.0} retries:{}",
+                w.signal_dbm, w.link, w.retries
+            ))?;
+        }
+        if let Some(l) = self.meta.link_stats {
+            f.write_fmt(format_args!(
+                " rx:{:.0}kB/s tx:{:.0}kB/s errors:{} drops:{} carrier:{}",
+                l.rx_bytes_sec / 1000.0,
+                l.tx_bytes_sec / 1000.0,
+                l.errors,
+                l.drops,
+                l.carrier_changes
+            ))?;
+        }
+        if let Some(h) = self.meta.host_health {
+            f.write_fmt(format_args!(
+                " load:{:.2} late:{:.0}ms delay:{:.0}ms",
+                h.load1, h.send_late_ms, h.recv_delay_ms
+            ))?;
+            if let Some(pressure) = h.cpu_pressure {
+                f.write_fmt(format_args!(" cpu_pressure:{:.0}%", pressure))?;
+            }
+        }
+        if let Some(p) = self.meta.peer_path {
+            f.write_fmt(format_args!(
+                " up:{:.2}ms/{:.1}% down:{:.2}ms/{:.1}% offset:{:.2}ms",
+                p.up_ms, p.up_loss_pct, p.down_ms, p.down_loss_pct, p.offset_ms
+            ))?;
+        }
+        if self.meta.untrusted {
+            f.write_str(" untrusted")?;
+        }
+        Ok(())
     }
 }
 
 impl<Complete> FrameDataQ<Complete> {
     pub fn from_framedata(fd: &FrameData) -> Self {
+        Self::from_framedata_with(fd, &Percentiles::default())
+    }
+    pub fn from_framedata_with(fd: &FrameData, percentiles: &Percentiles) -> Self {
+        Self {
+            sketch: match fd.recv_us.is_empty() {
+                true => None,
+                false => Some(LatencySketch::from_values(&fd.recv_us)),
+            },
+            ..Self::without_sketch(fd, percentiles)
+        }
+    }
+    /// Like from_framedata, but without the latency sketch, for frames that
+    /// are never folded.
+    pub fn from_framedata_no_sketch(fd: &FrameData) -> Self {
+        Self::without_sketch(fd, &Percentiles::default())
+    }
+    fn without_sketch(fd: &FrameData, percentiles: &Percentiles) -> Self {
         let mut tsv = match fd.time {
             FrameTime::Timestamp(t) => (Some(t), 0),
             FrameTime::Elapsed(e) => (None, e.as_millis() as u32),
@@ -97,13 +269,16 @@ impl<Complete> FrameDataQ<Complete> {
         let e = tsv.1 + ts.map(|x| x.timestamp_subsec_millis()).unwrap_or_default();
 
         Self {
-            phantom: PhantomData::default(),
+            phantom: PhantomData,
             timestamp: ts.map(|x| x.timestamp()),
             subsec_ms: SubSecType::Abs(e),
             inflight: fd.inflight as f32,
             lost_packets: fd.lost_packets as f32,
             recv_us_len: fd.recv_us.len(),
-            recv_us: Self::compute_percentiles(&fd.recv_us),
+            percentiles: percentiles.clone(),
+            recv_us: Self::compute_percentiles(&fd.recv_us, percentiles),
+            sketch: None,
+            meta: fd.meta,
         }
     }
     pub fn get_datetime(&self) -> DateTime<Utc> {
@@ -118,12 +293,11 @@ impl<Complete> FrameDataQ<Complete> {
         ts as i128 * 1000 + subsec_ms as i128
     }
     // CAUTION:: This function requires the data to be pre-sorted, and all negative values removed!
-    pub fn compute_percentiles(v: &[u128]) -> [i64; 7] {
-        let mut ret = [-1_i64; 7];
+    pub fn compute_percentiles(v: &[u128], percentiles: &Percentiles) -> Vec<i64> {
+        let mut ret = vec![-1_i64; percentiles.len()];
         if v.is_empty() {
             return ret;
         }
-        let percentiles = [0f32, 0.125, 0.25, 0.5, 0.75, 0.875, 1.0];
         let vmax = v.len() - 1;
         for (i, p) in percentiles.iter().enumerate() {
             let p = *p * vmax as f32;
@@ -139,15 +313,55 @@ impl<Complete> FrameDataQ<Complete> {
         }
         ret
     }
+    /// The percentiles of the replies in "sketch", where the lowest and the
+    /// highest are known as they were.
+    fn sketch_percentiles(
+        sketch: &LatencySketch,
+        percentiles: &Percentiles,
+        min: i64,
+        max: i64,
+    ) -> Vec<i64> {
+        let mut ret: Vec<i64> = percentiles
+            .iter()
+            .map(|p| sketch.quantile(*p).map_or(-1, |v| v.clamp(min, max)))
+            .collect();
+        if sketch.count() > 0 {
+            ret[0] = min;
+            *ret.last_mut().unwrap() = max;
+        }
+        ret
+    }
+    /// The reply at "p" of the way from the lowest to the highest. Between
+    /// two of the percentiles kept, it's told from both. -1 without replies.
+    pub fn percentile_us(&self, p: f32) -> i64 {
+        if self.recv_us_len == 0 {
+            return -1;
+        }
+        let at = self.percentiles.partition_point(|x| *x < p);
+        match at {
+            0 => self.recv_us[0],
+            _ if at == self.percentiles.len() => *self.recv_us.last().unwrap(),
+            _ if self.percentiles[at] == p => self.recv_us[at],
+            _ => {
+                let (p0, p1) = (self.percentiles[at - 1], self.percentiles[at]);
+                let fr = (p - p0) / (p1 - p0);
+                let (v0, v1) = (self.recv_us[at - 1] as f32, self.recv_us[at] as f32);
+                (v0 * (1.0 - fr) + v1 * fr).round() as i64
+            }
+        }
+    }
     fn into_encoded(self) -> FrameDataQ<Encoded> {
         FrameDataQ {
-            phantom: PhantomData::default(),
+            phantom: PhantomData,
             timestamp: self.timestamp,
             subsec_ms: self.subsec_ms,
             inflight: self.inflight,
             lost_packets: self.lost_packets,
             recv_us_len: self.recv_us_len,
+            percentiles: self.percentiles,
             recv_us: self.recv_us,
+            sketch: self.sketch,
+            meta: self.meta,
         }
     }
     pub fn fold_vec(data: &[Self]) -> Self {
@@ -158,48 +372,83 @@ impl<Complete> FrameDataQ<Complete> {
         let timestamp = Some((mean_ts / 1000) as i64);
         let subsec_ms = SubSecType::Abs((mean_ts % 1000) as u32);
         // let inflight: usize = data.iter().map(|x| x.inflight).max().unwrap();
-        let inflight: f32 = (data.iter().map(|x| x.inflight.powi(2)).sum::<f32>() as f32
-            / datalen as f32)
+        let inflight: f32 = (data.iter().map(|x| x.inflight.powi(2)).sum::<f32>() / datalen as f32)
             .powf(2.0_f32.recip());
         let lost_packets: f32 = data.iter().map(|x| x.lost_packets).sum::<f32>() / datalen as f32;
         let recv_us_len: usize = data.iter().map(|x| x.recv_us_len).sum::<usize>() / datalen;
-        // TODO: This algorithm is wrong, we need to split each percentile and compute over it!
-        let mut recv_us_list: Vec<u128> = data
-            .iter()
-            .flat_map(|x| x.recv_us.iter())
-            .filter(|x| **x >= 0)
-            .map(|x| *x as u128)
-            .collect();
-        recv_us_list.sort_unstable();
-        let recv_us = Self::compute_percentiles(&recv_us_list);
-        // let recv_v: Vec<_> = (0..7)
-        //     .map(|n| {
-        //         data.iter()
-        //             .map(|x| x.recv_us[n])
-        //             .filter(|x| *x > 0)
-        //             .map(|x| x as u128)
-        //             .collect::<Vec<_>>()
-        //     })
-        //     .collect();
-
-        // let recv_us: [i64; 7] = [
-        //     Self::compute_percentiles(&recv_v[0])[0],
-        //     Self::compute_percentiles(&recv_v[1])[1],
-        //     Self::compute_percentiles(&recv_v[2])[2],
-        //     Self::compute_percentiles(&recv_v[3])[3],
-        //     Self::compute_percentiles(&recv_v[4])[4],
-        //     Self::compute_percentiles(&recv_v[5])[5],
-        //     Self::compute_percentiles(&recv_v[6])[6],
-        // ];
+        let percentiles = data[0].percentiles.clone();
+        let replied = || data.iter().filter(|x| x.recv_us_len > 0);
+        let (recv_us, sketch) = match replied().all(|x| x.sketch.is_some()) {
+            true => {
+                let mut sketch = LatencySketch::new();
+                for x in replied() {
+                    sketch.merge(x.sketch.as_ref().unwrap());
+                }
+                let (min, max) = match sketch.count() {
+                    0 => (-1, -1),
+                    _ => (
+                        replied().map(|x| x.percentile_us(0.0)).min().unwrap(),
+                        replied().map(|x| x.percentile_us(1.0)).max().unwrap(),
+                    ),
+                };
+                let recv_us = Self::sketch_percentiles(&sketch, &percentiles, min, max);
+                let sketch = match sketch.count() {
+                    0 => None,
+                    _ => Some(sketch),
+                };
+                (recv_us, sketch)
+            }
+            false => {
+                // Without a sketch for each frame, the percentiles of all of
+                // them are only a rough guess from those of each one.
+                let mut recv_us_list: Vec<u128> = data
+                    .iter()
+                    .flat_map(|x| x.recv_us.iter())
+                    .filter(|x| **x >= 0)
+                    .map(|x| *x as u128)
+                    .collect();
+                recv_us_list.sort_unstable();
+                (Self::compute_percentiles(&recv_us_list, &percentiles), None)
+            }
+        };
+        let meta = FrameMeta::fold(&data.iter().map(|x| x.meta).collect::<Vec<_>>());
 
         FrameDataQ {
-            phantom: PhantomData::default(),
+            phantom: PhantomData,
             timestamp,
             subsec_ms,
             inflight,
             lost_packets,
             recv_us_len,
+            percentiles,
             recv_us,
+            sketch,
+            meta,
+        }
+    }
+}
+
+impl FrameDataQ<Complete> {
+    /// The same frame keeping other percentiles: from the sketch if it has
+    /// one, or else told from the percentiles it kept.
+    pub fn with_percentiles(&self, percentiles: &Percentiles) -> Self {
+        if self.percentiles == *percentiles {
+            return self.clone();
+        }
+        let recv_us = match (&self.sketch, self.recv_us_len) {
+            (_, 0) => vec![-1; percentiles.len()],
+            (Some(sketch), _) => Self::sketch_percentiles(
+                sketch,
+                percentiles,
+                self.percentile_us(0.0),
+                self.percentile_us(1.0),
+            ),
+            (None, _) => percentiles.iter().map(|p| self.percentile_us(*p)).collect(),
+        };
+        Self {
+            percentiles: percentiles.clone(),
+            recv_us,
+            ..self.clone()
         }
     }
 }
@@ -207,18 +456,21 @@ impl<Complete> FrameDataQ<Complete> {
 impl<Encoded> FrameDataQ<Encoded> {
     fn into_complete(self) -> FrameDataQ<Complete> {
         FrameDataQ {
-            phantom: PhantomData::default(),
+            phantom: PhantomData,
             timestamp: self.timestamp,
             subsec_ms: self.subsec_ms,
             inflight: self.inflight,
             lost_packets: self.lost_packets,
             recv_us_len: self.recv_us_len,
+            percentiles: self.percentiles,
             recv_us: self.recv_us,
+            sketch: self.sketch,
+            meta: self.meta,
         }
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub struct FDCodecCfg {
     /// Amount of time between to fully encode the timestamp
     pub full_encode_secs: i64,
@@ -226,6 +478,10 @@ pub struct FDCodecCfg {
     pub recv_llq: Option<LinearLogQuantizer>,
     /// Enable delta encoding
     pub delta_enc: bool,
+    /// Write the latency sketch of each frame
+    pub sketch: bool,
+    /// Percentiles of the replies kept in each frame
+    pub percentiles: Percentiles,
 }
 
 impl Default for FDCodecCfg {
@@ -234,20 +490,29 @@ impl Default for FDCodecCfg {
             full_encode_secs: 60,
             recv_llq: None,
             delta_enc: false,
+            sketch: false,
+            percentiles: Percentiles::default(),
         }
     }
 }
-#[derive(Debug, Clone, Copy, Default)]
+#[derive(Debug, Clone, Default)]
 pub struct FDCodecState {
     cfg: FDCodecCfg,
     pub last_timestamp: Option<i64>,
     pub last_subsec_ms: u32,
+    /// With delta_enc, the first value of the last frame, quantized. The
+    /// values of the next one are written from it.
     pub last_recvq_0: i64,
+    /// State from the events written or read so far.
+    pub meta: FrameMeta,
 }
 
 impl FDCodecState {
     const HEADER_SCHEMA: &'static str = "FDCodec";
-    const HEADER_VERSION: u64 = 101;
+    /// 102: frames may carry a latency sketch.
+    /// 103: the percentiles kept are in the header.
+    /// 104: delta_enc applies without recv_llq too.
+    const HEADER_VERSION: u64 = 104;
 
     pub fn new(cfg: FDCodecCfg) -> Self {
         Self {
@@ -255,13 +520,13 @@ impl FDCodecState {
             ..Default::default()
         }
     }
-    pub fn get_header(cfg: FDCodecCfg) -> Vec<u8> {
+    pub fn get_header(cfg: &FDCodecCfg) -> Vec<u8> {
         Self::try_get_header(cfg).unwrap()
     }
-    pub fn try_get_header(cfg: FDCodecCfg) -> Result<Vec<u8>, XError> {
+    pub fn try_get_header(cfg: &FDCodecCfg) -> Result<Vec<u8>, XError> {
         let mut vbuf: Vec<u8> = vec![];
         let wr = &mut vbuf;
-        rmp::encode::write_map_len(wr, 5)?;
+        rmp::encode::write_map_len(wr, 7)?;
         rmp::encode::write_str(wr, "schema")?;
         rmp::encode::write_str(wr, Self::HEADER_SCHEMA)?;
 
@@ -280,6 +545,15 @@ impl FDCodecState {
         rmp::encode::write_str(wr, "delta_enc")?;
         rmp::encode::write_bool(wr, cfg.delta_enc)?;
 
+        rmp::encode::write_str(wr, "sketch")?;
+        rmp::encode::write_bool(wr, cfg.sketch)?;
+
+        rmp::encode::write_str(wr, "percentiles")?;
+        rmp::encode::write_array_len(wr, cfg.percentiles.len() as u32)?;
+        for p in cfg.percentiles.iter() {
+            rmp::encode::write_f64(wr, *p as f64)?;
+        }
+
         Ok(vbuf)
     }
     pub fn try_from_header<R: std::io::Read>(rd: &mut R) -> Result<FDCodecCfg> {
@@ -304,7 +578,27 @@ impl FDCodecState {
         let full_encode_secs = get_header("full_encode_secs")?.int()? as i64;
         let recv_llq = get_header("recv_llq")?;
         let delta_enc = get_header("delta_enc")?.as_bool();
+        // Before 104 it only applied to quantized values, others are raw.
+        let delta_enc = delta_enc && (version >= 104 || !matches!(recv_llq, Variant::Null(_)));
         // Extra parameters should have a default to allow for processing older formats!
+        let sketch = header.get("sketch").is_some_and(|v| v.as_bool());
+        let percentiles = match header.get("percentiles") {
+            Some(v) => {
+                let values: Result<Vec<f32>, XError> = v
+                    .slice()?
+                    .iter()
+                    .map(|x| match x {
+                        Variant::Float(f) => Ok(f.as_f64() as f32),
+                        Variant::Integer(i) => Ok(*i as f32),
+                        _ => Err(XError::unexpected_data(
+                            "percentiles expected to be numbers",
+                        )),
+                    })
+                    .collect();
+                Percentiles::new(&values?)?
+            }
+            None => Percentiles::default(),
+        };
 
         let recv_llq = match recv_llq {
             Variant::Null(_) => Ok(None),
@@ -317,6 +611,8 @@ impl FDCodecState {
             full_encode_secs,
             recv_llq,
             delta_enc,
+            sketch,
+            percentiles,
         })
     }
     pub fn from_header<R: std::io::Read>(rd: &mut R) -> FDCodecCfg {
@@ -327,11 +623,12 @@ impl FDCodecState {
         Self::new(Self::from_header(rd))
     }
 
-    pub fn get_cfg(&self) -> FDCodecCfg {
-        self.cfg
+    pub fn get_cfg(&self) -> &FDCodecCfg {
+        &self.cfg
     }
 
     pub fn push(&mut self, d: &FrameDataQ<Complete>) {
+        self.meta = d.meta.next();
         if let Some(ts) = d.timestamp {
             self.last_timestamp = Some(ts);
         }
@@ -339,14 +636,30 @@ impl FDCodecState {
             SubSecType::Abs(v) => self.last_subsec_ms = v,
             SubSecType::Delta(v) => self.last_subsec_ms += v,
         };
+    }
+    /// Moves the reference of delta_enc on, from the values written for a
+    /// frame. Both sides follow what was written, as values don't always
+    /// quantize again to the same. Frames without replies are written as
+    /// they are (-1), and the next one from that, as before version 104.
+    fn push_recv(&mut self, recv_us_len: usize, first_written: i64) {
         if self.cfg.delta_enc {
-            self.last_recvq_0 = match self.cfg.recv_llq {
-                Some(llq) => llq.encode(d.recv_us[0]),
-                None => d.recv_us[0],
-            }
+            self.last_recvq_0 = match recv_us_len {
+                0 => first_written,
+                _ => self.last_recvq_0 + first_written,
+            };
+        }
+    }
+    /// What the values of a frame are written from.
+    fn recv_reference(&self) -> i64 {
+        match self.cfg.delta_enc {
+            true => self.last_recvq_0,
+            false => 0,
         }
     }
     pub fn peek_encode(&self, mut d: FrameDataQ<Complete>) -> FrameDataQ<Encoded> {
+        if d.percentiles != self.cfg.percentiles {
+            d = d.with_percentiles(&self.cfg.percentiles);
+        }
         let mut d_ts = d.timestamp.unwrap();
         let subsec_ms = match d.subsec_ms {
             SubSecType::Abs(v) => v,
@@ -355,7 +668,14 @@ impl FDCodecState {
         let subsec_ms_part = subsec_ms % 1000;
         d_ts += ((subsec_ms - subsec_ms_part) / 1000) as i64;
 
-        let extra_subsecs: Option<u32> = match self.last_timestamp {
+        // The time is written in full after a discontinuity or a start, so a
+        // reader that skips the event still gets it right.
+        let restarted = d.meta.discontinuity.is_some() || d.meta.daemon_start.is_some();
+        let last_timestamp = match restarted {
+            true => None,
+            false => self.last_timestamp,
+        };
+        let extra_subsecs: Option<u32> = match last_timestamp {
             Some(last_ts) => {
                 if d_ts - last_ts >= self.cfg.full_encode_secs || d_ts < last_ts {
                     None
@@ -377,22 +697,47 @@ impl FDCodecState {
                     SubSecType::Delta(extra_subsecs + subsec_ms_part - self.last_subsec_ms);
             }
         };
-        if let Some(llq) = self.cfg.recv_llq {
-            if d.recv_us_len > 0 {
-                for val in d.recv_us.iter_mut() {
-                    *val = llq.encode(*val) - self.last_recvq_0;
-                }
+        if d.recv_us_len > 0 {
+            let reference = self.recv_reference();
+            for val in d.recv_us.iter_mut() {
+                let q = match self.cfg.recv_llq {
+                    Some(llq) => llq.encode(*val),
+                    None => *val,
+                };
+                *val = q - reference;
             }
         }
+        // With sketches, frames that have none get an empty one.
+        d.sketch = match self.cfg.sketch && d.recv_us_len > 0 {
+            true => Some(d.sketch.unwrap_or_default()),
+            false => None,
+        };
         d.into_encoded()
     }
 
     pub fn encode(&mut self, d: FrameDataQ<Complete>) -> FrameDataQ<Encoded> {
-        let dr = self.peek_encode(d);
+        let dr = self.peek_encode(d.clone());
         self.push(&d);
+        self.push_recv(dr.recv_us_len, dr.recv_us[0]);
         dr
     }
 
+    /// Encodes a frame to MessagePack, preceded by the events needed to carry
+    /// its meta over to the reader.
+    pub fn encode_rmp(&mut self, d: FrameDataQ<Complete>) -> Vec<u8> {
+        let mut buf: Vec<u8> = vec![];
+        for event in self.meta.diff(&d.meta) {
+            buf.append(&mut event.to_rmp());
+        }
+        buf.append(&mut self.encode(d).to_rmp());
+        buf
+    }
+
+    /// Updates the state with an event read from the stream.
+    pub fn apply(&mut self, event: &LogEvent) {
+        self.meta.apply(event);
+    }
+
     pub fn peek_decode(&self, mut d: FrameDataQ<Encoded>) -> FrameDataQ<Complete> {
         let mut ts = d.timestamp.unwrap_or_else(|| {
             self.last_timestamp
@@ -403,11 +748,15 @@ impl FDCodecState {
         ts += ((subsec_ms - subsec_ms_part) / 1000) as i64;
         d.timestamp = Some(ts);
         d.subsec_ms = SubSecType::Abs(subsec_ms_part);
-        if let Some(llq) = self.cfg.recv_llq {
-            if d.recv_us_len > 0 {
-                for val in d.recv_us.iter_mut() {
-                    *val = llq.decode(*val);
-                }
+        d.meta = self.meta;
+        if d.recv_us_len > 0 {
+            let reference = self.recv_reference();
+            for val in d.recv_us.iter_mut() {
+                let q = *val + reference;
+                *val = match self.cfg.recv_llq {
+                    Some(llq) => llq.decode(q),
+                    None => q,
+                };
             }
         }
 
@@ -415,8 +764,10 @@ impl FDCodecState {
     }
 
     pub fn decode(&mut self, d: FrameDataQ<Encoded>) -> FrameDataQ<Complete> {
+        let first_written = d.recv_us[0];
         let d = self.peek_decode(d);
         self.push(&d);
+        self.push_recv(d.recv_us_len, first_written);
         d
     }
 }
@@ -523,7 +874,7 @@ impl RMPCodec for FrameDataQ<Encoded> {
         }
         rmp::encode::write_uint(buf, self.recv_us_len as u64)?;
         if self.recv_us_len > 0 {
-            rmp::encode::write_array_len(buf, 7)?;
+            rmp::encode::write_array_len(buf, self.recv_us.len() as u32)?;
             let mut prev = 0;
             let mut dvvec = vec![];
             for v in &self.recv_us {
@@ -541,24 +892,46 @@ impl RMPCodec for FrameDataQ<Encoded> {
                     rmp::encode::write_uint(buf, dv as u64)?;
                 }
             }
+            if let Some(sketch) = &self.sketch {
+                sketch.write_rmp(buf)?;
+            }
         }
 
         Ok(data)
     }
 
+    /// Reads a frame of a stream written with the default FDCodecCfg. Others
+    /// are read with FDQRecord::try_from_rmp.
     fn try_from_rmp<R: std::io::Read>(rd: &mut R) -> Result<Self> {
-        let ts_var = Variant::read(rd)
-            .map_err(|e| {
-                let de = e.downcast_ref::<dynrmp::DError>();
-                match de {
-                    Some(dynrmp::DError::IOError(x)) => match x.kind() {
-                        std::io::ErrorKind::UnexpectedEof => anyhow::Error::from(XError::EOF),
-                        _ => e,
-                    },
+        let ts_var = read_first_value(rd)?;
+        Self::decode_after(ts_var, rd, &FDCodecCfg::default())
+    }
+}
+
+/// Reads the first value of a record, turning a clean end of the stream into
+/// XError::EOF.
+fn read_first_value<R: std::io::Read>(rd: &mut R) -> Result<Variant> {
+    Variant::read(rd)
+        .map_err(|e| {
+            let de = e.downcast_ref::<dynrmp::DError>();
+            match de {
+                Some(dynrmp::DError::IOError(x)) => match x.kind() {
+                    std::io::ErrorKind::UnexpectedEof => anyhow::Error::from(XError::EOF),
                     _ => e,
-                }
-            })
-            .context("ts_var")?;
+                },
+                _ => e,
+            }
+        })
+        .context("ts_var")
+}
+
+impl FrameDataQ<Encoded> {
+    /// Decodes the rest of a frame whose first value was already read.
+    fn decode_after<R: std::io::Read>(
+        ts_var: Variant,
+        rd: &mut R,
+        cfg: &FDCodecCfg,
+    ) -> Result<Self> {
         let timestamp = match ts_var {
             Variant::Null(_) => None,
             Variant::Integer(v) => Some(v as i64),
@@ -577,11 +950,18 @@ impl RMPCodec for FrameDataQ<Encoded> {
             rmp::decode::read_int::<usize, _>(rd).context("lost packets")? as f32
         };
         let recv_us_len: usize = rmp::decode::read_int(rd).context("recv_us_len")?;
-        let mut recv_us: [i64; 7] = [-1, -1, -1, -1, -1, -1, -1];
+        let mut recv_us = vec![-1_i64; cfg.percentiles.len()];
+        let mut sketch = None;
         if recv_us_len > 0 {
             let recv_var_t = Variant::read(rd).context("recv_var_t")?;
             let recv_var = recv_var_t.slice().context("recv_var slice")?;
-            // TODO: LinearLogQuantizer::decode
+            if recv_var.len() != recv_us.len() {
+                return Err(XError::UnexpectedData(format!(
+                    "frame with {} percentiles, the header has {}",
+                    recv_var.len(),
+                    recv_us.len()
+                )))?;
+            }
             let mut prev = 0;
             for (n, var) in recv_var.iter().enumerate() {
                 let dv = var.int().context("recv_var.int")?;
@@ -589,6 +969,10 @@ impl RMPCodec for FrameDataQ<Encoded> {
                 recv_us[n] = v as i64;
                 prev = v;
             }
+            if cfg.sketch {
+                let sketch_var = Variant::read(rd).context("sketch")?;
+                sketch = Some(LatencySketch::from_variant(&sketch_var)?).filter(|x| x.count() > 0);
+            }
         }
         Ok(Self {
             timestamp,
@@ -596,12 +980,34 @@ impl RMPCodec for FrameDataQ<Encoded> {
             inflight,
             lost_packets,
             recv_us_len,
+            percentiles: cfg.percentiles.clone(),
             recv_us,
-            phantom: PhantomData::default(),
+            sketch,
+            phantom: PhantomData,
+            meta: FrameMeta::default(),
         })
     }
 }
 
+/// Anything that can be found in a FrameDataQ stream: either a frame or an event.
+// Nearly all records are frames, boxing them would only add an allocation.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone)]
+pub enum FDQRecord {
+    Frame(FrameDataQ<Encoded>),
+    Event(LogEvent),
+}
+
+impl FDQRecord {
+    /// Reads the next record of a stream whose header gave "cfg".
+    pub fn try_from_rmp<R: std::io::Read>(rd: &mut R, cfg: &FDCodecCfg) -> Result<Self> {
+        match read_first_value(rd)? {
+            Variant::Map(m) => Ok(Self::Event(LogEvent::from_map(m)?)),
+            ts_var => Ok(Self::Frame(FrameDataQ::decode_after(ts_var, rd, cfg)?)),
+        }
+    }
+}
+
 pub struct FDCodecIter<R: std::io::Read> {
     buf: R,
     fdcs: FDCodecState,
@@ -618,13 +1024,16 @@ impl<R: std::io::Read> Iterator for FDCodecIter<R> {
     type Item = FrameDataQ<Complete>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let rfde = RMPCodec::try_from_rmp(&mut self.buf).context("FDCodecIter - next");
-        match rfde {
-            Ok(v) => Some(self.fdcs.decode(v)),
-            Err(e) => {
-                if matches!(e.downcast_ref::<XError>(), Some(XError::EOF)) {
-                    None
-                } else {
+        loop {
+            let rfde = FDQRecord::try_from_rmp(&mut self.buf, self.fdcs.get_cfg())
+                .context("FDCodecIter - next");
+            match rfde {
+                Ok(FDQRecord::Frame(v)) => return Some(self.fdcs.decode(v)),
+                Ok(FDQRecord::Event(ev)) => self.fdcs.apply(&ev),
+                Err(e) => {
+                    if matches!(e.downcast_ref::<XError>(), Some(XError::EOF)) {
+                        return None;
+                    }
                     println!("FDCodecIter::iterator::next(): Error: {}", e);
                     for cause in e.chain() {
                         dbg!(cause);
@@ -708,3 +1117,297 @@ where
         FDCodecIterFold::from_iter(self, window, step)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logevent::Discontinuity;
+
+    /// Frame taken "secs" after an arbitrary start, with these replies.
+    fn frame_at(secs: f64, recv_us: Vec<u128>) -> FrameData {
+        let nsecs = (secs.fract() * 1e9).round() as u32;
+        FrameData {
+            time: FrameTime::Timestamp(DateTime::from_utc(
+                NaiveDateTime::from_timestamp_opt(1_600_000_000 + secs as i64, nsecs).unwrap(),
+                Utc,
+            )),
+            inflight: 0,
+            lost_packets: 0,
+            recv_us,
+            meta: FrameMeta::default(),
+        }
+    }
+
+    #[test]
+    fn test_codec_meta_events() {
+        let frame = |secs: u64, rate: Option<f32>| {
+            let fd = FrameData {
+                meta: FrameMeta {
+                    probe_rate: rate,
+                    ..Default::default()
+                },
+                ..frame_at(secs as f64, vec![1000, 2000])
+            };
+            FrameDataQ::from_framedata(&fd)
+        };
+        let cfg = FDCodecCfg::default();
+        let mut buf = FDCodecState::get_header(&cfg);
+        let mut codec = FDCodecState::new(cfg);
+        for (secs, rate) in [(0, None), (1, Some(2.0)), (2, Some(2.0)), (3, Some(100.0))] {
+            buf.append(&mut codec.encode_rmp(frame(secs, rate)));
+        }
+        let rates: Vec<_> = FDCodecIter::new(&buf[..])
+            .map(|x| x.meta.probe_rate)
+            .collect();
+        assert_eq!(rates, vec![None, Some(2.0), Some(2.0), Some(100.0)]);
+    }
+
+    #[test]
+    fn test_codec_discontinuity() {
+        let frame = |ms: i64, discontinuity| {
+            let mut fdq = FrameDataQ::from_framedata(&frame_at(0.0, vec![1000]));
+            fdq.timestamp = Some(1_600_000_000 + ms / 1000);
+            fdq.subsec_ms = SubSecType::Abs((ms % 1000) as u32);
+            fdq.meta.discontinuity = discontinuity;
+            fdq
+        };
+        let step = Discontinuity {
+            step_secs: -1.5,
+            suspend_secs: 0.0,
+        };
+        let cfg = FDCodecCfg::default();
+        let mut buf = FDCodecState::get_header(&cfg);
+        let mut codec = FDCodecState::new(cfg);
+        for (ms, d) in [(0, None), (2500, None), (1000, Some(step)), (1200, None)] {
+            let fdq = frame(ms, d);
+            // Full time after a discontinuity, delta otherwise.
+            assert_eq!(
+                codec.peek_encode(fdq.clone()).timestamp.is_some(),
+                ms != 2500 && ms != 1200
+            );
+            buf.append(&mut codec.encode_rmp(fdq));
+        }
+        let frames: Vec<_> = FDCodecIter::new(&buf[..])
+            .map(|x| (x.get_timestamp_ms() % 100_000, x.meta.discontinuity))
+            .collect();
+        assert_eq!(
+            frames,
+            vec![(0, None), (2500, None), (1000, Some(step)), (1200, None)]
+        );
+    }
+
+    #[test]
+    fn test_fold_sketch() {
+        // A second of mostly fast replies, with a few frames of slow ones and
+        // some without any.
+        let frames: Vec<FrameData> = (0..50_u128)
+            .map(|n| {
+                let recv_us = match n % 10 {
+                    9 => vec![],
+                    4 => vec![40_000 + n * 10],
+                    _ => (0..5).map(|i| 1000 + n * 7 + i * 3).collect(),
+                };
+                frame_at(n as f64 * 0.02, recv_us)
+            })
+            .collect();
+        let mut all: Vec<u128> = frames.iter().flat_map(|x| x.recv_us.clone()).collect();
+        all.sort_unstable();
+        let want = FrameDataQ::<Complete>::compute_percentiles(&all, &Percentiles::default());
+
+        for sketch in [false, true] {
+            let cfg = FDCodecCfg {
+                sketch,
+                ..Default::default()
+            };
+            let mut buf = FDCodecState::get_header(&cfg);
+            let mut codec = FDCodecState::new(cfg);
+            for fd in frames.iter() {
+                buf.append(&mut codec.encode_rmp(FrameDataQ::from_framedata(fd)));
+            }
+            let read: Vec<_> = FDCodecIter::new(&buf[..]).collect();
+            assert_eq!(read.len(), 50);
+            assert!(read
+                .iter()
+                .all(|x| x.sketch.is_some() == (sketch && x.recv_us_len > 0)));
+
+            // Folded in steps, as zooming out does.
+            let tenths: Vec<_> = read.chunks(5).map(FrameDataQ::fold_vec).collect();
+            let folded = FrameDataQ::fold_vec(&tenths);
+            if sketch {
+                for (got, want) in folded.recv_us.iter().zip(want.iter()) {
+                    let err = (*got - *want).abs() as f64;
+                    assert!(
+                        err <= *want as f64 * 0.01 + 1.0,
+                        "{:?} vs {:?}",
+                        folded.recv_us,
+                        want
+                    );
+                }
+                assert_eq!(folded.sketch.unwrap().count(), all.len() as u64);
+            } else {
+                assert_ne!(folded.recv_us, want);
+                assert!(folded.sketch.is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let sla: Percentiles = "95, 99,99.9".parse().unwrap();
+        assert_eq!(*sla, [0.0, 0.95, 0.99, 0.999, 1.0]);
+        assert_eq!(sla.to_string(), "0,95,99,99.9,100");
+        assert!("101".parse::<Percentiles>().is_err());
+        assert!(Percentiles::new(&[0.0, 0.5]).is_err());
+
+        let fd = frame_at(0.0, (1..=1000).collect());
+        let usual = FrameDataQ::from_framedata(&fd);
+        let frame = FrameDataQ::from_framedata_with(&fd, &sla);
+        assert_eq!(frame.recv_us, vec![1, 950, 990, 999, 1000]);
+        assert_eq!(frame.percentile_us(0.5), 500);
+        assert_eq!(usual.percentile_us(0.5), 501);
+
+        // Written as the header says, and frames of other percentiles turned
+        // into those: from the sketch if there's one.
+        let cfg = FDCodecCfg {
+            percentiles: sla.clone(),
+            ..Default::default()
+        };
+        let mut buf = FDCodecState::get_header(&cfg);
+        let mut codec = FDCodecState::new(cfg);
+        let no_sketch = FrameDataQ {
+            sketch: None,
+            ..usual.clone()
+        };
+        for fdq in [frame.clone(), usual, no_sketch] {
+            buf.append(&mut codec.encode_rmp(fdq));
+        }
+        let read: Vec<_> = FDCodecIter::new(&buf[..]).collect();
+        assert!(read.iter().all(|x| x.percentiles == sla));
+        assert_eq!(read[0].recv_us, frame.recv_us);
+        let p99 = read[1].recv_us[2];
+        assert!((980..=1000).contains(&p99), "{:?}", read[1].recv_us);
+        assert_eq!(read[2].recv_us, vec![1, 950, 990, 999, 1000]);
+
+        // Files from before the percentiles were in the header.
+        let mut old = vec![];
+        rmp::encode::write_map_len(&mut old, 5).unwrap();
+        rmp::encode::write_str(&mut old, "schema").unwrap();
+        rmp::encode::write_str(&mut old, "FDCodec").unwrap();
+        rmp::encode::write_str(&mut old, "version").unwrap();
+        rmp::encode::write_uint(&mut old, 101).unwrap();
+        rmp::encode::write_str(&mut old, "full_encode_secs").unwrap();
+        rmp::encode::write_uint(&mut old, 60).unwrap();
+        rmp::encode::write_str(&mut old, "recv_llq").unwrap();
+        rmp::encode::write_nil(&mut old).unwrap();
+        rmp::encode::write_str(&mut old, "delta_enc").unwrap();
+        rmp::encode::write_bool(&mut old, false).unwrap();
+        let cfg = FDCodecCfg::default();
+        let mut codec = FDCodecState::new(cfg);
+        old.append(&mut codec.encode_rmp(FrameDataQ::from_framedata(&fd)));
+        let read: Vec<_> = FDCodecIter::new(&old[..]).collect();
+        assert_eq!(read[0].percentiles, Percentiles::default());
+        assert_eq!(read[0].recv_us, vec![1, 126, 251, 501, 750, 875, 1000]);
+    }
+
+    #[test]
+    fn test_codec_quantize() {
+        // Latencies from a few microseconds to seconds, and frames without
+        // replies in between.
+        let mut seed: u64 = 42;
+        let mut random = |max: u64| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+            (seed >> 33) % max
+        };
+        let frames: Vec<FrameData> = (0..300)
+            .map(|n| {
+                let scale = [50, 5_000, 30_000, 2_000_000][n % 4];
+                let mut recv_us: Vec<u128> =
+                    (0..random(8)).map(|_| random(scale) as u128).collect();
+                recv_us.sort_unstable();
+                frame_at(n as f64, recv_us)
+            })
+            .collect();
+        let frames: Vec<_> = frames.iter().map(FrameDataQ::from_framedata).collect();
+
+        let mut sizes = vec![];
+        for precision in [None, Some(0.001), Some(0.01), Some(0.1)] {
+            for delta_enc in [false, true] {
+                let recv_llq = precision.map(LinearLogQuantizer::new);
+                let cfg = FDCodecCfg {
+                    recv_llq,
+                    delta_enc,
+                    ..Default::default()
+                };
+                let mut buf = FDCodecState::get_header(&cfg);
+                let mut codec = FDCodecState::new(cfg);
+                for fdq in frames.iter() {
+                    buf.append(&mut codec.encode_rmp(fdq.clone()));
+                }
+                let read: Vec<_> = FDCodecIter::new(&buf[..]).collect();
+                assert_eq!(read.len(), frames.len());
+                for (got, want) in read.iter().zip(frames.iter()) {
+                    for (g, w) in got.recv_us.iter().zip(want.recv_us.iter()) {
+                        // Within the bucket the value falls in.
+                        let bucket = recv_llq.map_or(0, |q| q.bucket_size(q.encode(*w)));
+                        assert!(
+                            (g - w).abs() <= bucket,
+                            "{:?} {}: {} for {}, bucket {}",
+                            precision,
+                            delta_enc,
+                            g,
+                            w,
+                            bucket
+                        );
+                    }
+                }
+                sizes.push(buf.len());
+            }
+        }
+        // Coarser is smaller, with or without delta_enc.
+        for n in 0..2 {
+            assert!(sizes[n] > sizes[n + 4] && sizes[n + 4] > sizes[n + 6]);
+        }
+    }
+
+    #[test]
+    fn test_codec_v103_delta_raw() {
+        // Before 104, delta_enc without recv_llq left the values raw.
+        let header = |version| {
+            let mut buf = vec![];
+            rmp::encode::write_map_len(&mut buf, 5).unwrap();
+            rmp::encode::write_str(&mut buf, "schema").unwrap();
+            rmp::encode::write_str(&mut buf, "FDCodec").unwrap();
+            rmp::encode::write_str(&mut buf, "version").unwrap();
+            rmp::encode::write_uint(&mut buf, version).unwrap();
+            rmp::encode::write_str(&mut buf, "full_encode_secs").unwrap();
+            rmp::encode::write_uint(&mut buf, 60).unwrap();
+            rmp::encode::write_str(&mut buf, "recv_llq").unwrap();
+            rmp::encode::write_nil(&mut buf).unwrap();
+            rmp::encode::write_str(&mut buf, "delta_enc").unwrap();
+            rmp::encode::write_bool(&mut buf, true).unwrap();
+            buf
+        };
+        let frames: Vec<_> = [vec![1000, 2000], vec![], vec![1500, 1800]]
+            .into_iter()
+            .enumerate()
+            .map(|(n, recv_us)| FrameDataQ::from_framedata(&frame_at(n as f64, recv_us)))
+            .collect();
+        let mut codec = FDCodecState::new(FDCodecCfg::default());
+        let mut body = vec![];
+        for fdq in frames.iter() {
+            body.append(&mut codec.encode_rmp(fdq.clone()));
+        }
+        let read = |version| {
+            let buf = [header(version), body.clone()].concat();
+            let cfg = FDCodecState::try_from_header(&mut &buf[..]).unwrap();
+            let frames: Vec<_> = FDCodecIter::new(&buf[..]).map(|x| x.recv_us).collect();
+            (cfg.delta_enc, frames)
+        };
+        let want: Vec<_> = frames.iter().map(|x| x.recv_us.clone()).collect();
+        assert_eq!(read(103), (false, want.clone()));
+        // The same values are deltas in a newer file.
+        let (delta_enc, newer) = read(104);
+        assert!(delta_enc);
+        assert_ne!(newer, want);
+    }
+}
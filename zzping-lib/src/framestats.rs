@@ -12,8 +12,22 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+//! Messages exchanged over UDP between the daemon and its clients.
+//!
+//! The daemon sends a FrameStats to the client address on every refresh. A
+//! client can also ask for the recent history of a target with a
+//! HistoryRequest, sent to the daemon's listening address, and gets it back
+//! in HistoryChunks. FrameStats are arrays, the other messages are maps, so
+//! they can be told apart by the first marker.
+
+use std::collections::HashMap;
 use std::time::Duration;
 
+use anyhow::Result;
+
+use crate::dynrmp::variant::Variant;
+use crate::framedataq::{Complete, FDCodecCfg, FDCodecState, FDQRecord, FrameDataQ, XError};
+
 pub struct FrameStats {
     pub addr_str: String,
     pub inflight_count: usize,
@@ -37,7 +51,7 @@ impl FrameStats {
     }
 
     pub fn encode_stats(
-        addr: std::net::IpAddr,
+        label: &str,
         inflight_count: usize,
         avg_time: Duration,
         last_pckt_received: Duration,
@@ -45,7 +59,7 @@ impl FrameStats {
     ) -> Result<Vec<u8>, String> {
         let mut v: Vec<u8> = vec![];
         let stat = Self {
-            addr_str: addr.to_string(),
+            addr_str: label.to_owned(),
             inflight_count,
             avg_time_us: avg_time.as_micros(),
             last_pckt_ms: last_pckt_received.as_millis(),
@@ -57,3 +71,195 @@ impl FrameStats {
         }
     }
 }
+
+fn get_field<'a>(fields: &'a HashMap<String, Variant>, name: &str) -> Result<&'a Variant> {
+    Ok(fields
+        .get(name)
+        .ok_or_else(|| XError::HeaderFieldMissing(name.to_owned()))?)
+}
+
+fn read_fields(mut buf: &[u8]) -> Result<HashMap<String, Variant>> {
+    Variant::read(&mut buf)?.map()?.into_strhashmap()
+}
+
+/// Request for the frames of "target" between two times, in milliseconds
+/// since the epoch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryRequest {
+    pub target: String,
+    pub from_ms: i64,
+    /// When None, the daemon keeps sending the frames that arrive meanwhile
+    /// until it catches up, and then live stats resume.
+    pub to_ms: Option<i64>,
+}
+
+impl HistoryRequest {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut v: Vec<u8> = vec![];
+        let wr = &mut v;
+        rmp::encode::write_map_len(wr, 4).unwrap();
+        rmp::encode::write_str(wr, "request").unwrap();
+        rmp::encode::write_str(wr, "history").unwrap();
+        rmp::encode::write_str(wr, "target").unwrap();
+        rmp::encode::write_str(wr, &self.target).unwrap();
+        rmp::encode::write_str(wr, "from_ms").unwrap();
+        rmp::encode::write_sint(wr, self.from_ms).unwrap();
+        rmp::encode::write_str(wr, "to_ms").unwrap();
+        match self.to_ms {
+            Some(to_ms) => rmp::encode::write_sint(wr, to_ms).map(|_| ()).unwrap(),
+            None => rmp::encode::write_nil(wr).unwrap(),
+        }
+        v
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        let fields = read_fields(buf)?;
+        if get_field(&fields, "request")?.str()? != "history" {
+            return Err(XError::UnexpectedData("unknown request".to_owned()))?;
+        }
+        let to_ms = match get_field(&fields, "to_ms")? {
+            Variant::Null(_) => None,
+            v => Some(v.int()? as i64),
+        };
+        Ok(Self {
+            target: get_field(&fields, "target")?.string()?,
+            from_ms: get_field(&fields, "from_ms")?.int()? as i64,
+            to_ms,
+        })
+    }
+}
+
+/// Part of the answer to a HistoryRequest. The frames of each chunk are a
+/// FDCodec stream of their own, so a chunk lost on the way only leaves a hole.
+#[derive(Debug, Clone)]
+pub struct HistoryChunk {
+    pub target: String,
+    /// Starts at 0 for each request.
+    pub seq: u32,
+    /// Set on the last chunk of the answer.
+    pub done: bool,
+    pub frames: Vec<FrameDataQ<Complete>>,
+}
+
+impl HistoryChunk {
+    /// Encodes a chunk with as many of "frames" as fit in "max_bytes", and
+    /// returns it with the count of frames used. It's the last chunk when all
+    /// of them fit.
+    pub fn encode_frames(
+        target: &str,
+        seq: u32,
+        frames: &[FrameDataQ<Complete>],
+        max_bytes: usize,
+    ) -> (Vec<u8>, usize) {
+        let cfg = FDCodecCfg::default();
+        let mut data = FDCodecState::get_header(&cfg);
+        let mut codec = FDCodecState::new(cfg);
+        // The map around the frames takes up to 48 bytes plus the target.
+        let max_data = max_bytes.saturating_sub(target.len() + 48);
+        let mut used = 0;
+        for frame in frames {
+            let mut rmp = codec.encode_rmp(frame.clone());
+            // Always at least one frame, so the transfer moves forward.
+            if used > 0 && data.len() + rmp.len() > max_data {
+                break;
+            }
+            data.append(&mut rmp);
+            used += 1;
+        }
+        let mut v: Vec<u8> = vec![];
+        let wr = &mut v;
+        rmp::encode::write_map_len(wr, 4).unwrap();
+        rmp::encode::write_str(wr, "history").unwrap();
+        rmp::encode::write_str(wr, target).unwrap();
+        rmp::encode::write_str(wr, "seq").unwrap();
+        rmp::encode::write_u32(wr, seq).unwrap();
+        rmp::encode::write_str(wr, "done").unwrap();
+        rmp::encode::write_bool(wr, used == frames.len()).unwrap();
+        rmp::encode::write_str(wr, "frames").unwrap();
+        rmp::encode::write_bin(wr, &data).unwrap();
+        (v, used)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        let fields = read_fields(buf)?;
+        let data = match get_field(&fields, "frames")? {
+            Variant::Binary(data) => data,
+            _ => {
+                return Err(XError::UnexpectedData(
+                    "frames expected to be binary".to_owned(),
+                ))?
+            }
+        };
+        let mut rd = &data[..];
+        let mut codec = FDCodecState::new(FDCodecState::try_from_header(&mut rd)?);
+        let mut frames = vec![];
+        while !rd.is_empty() {
+            match FDQRecord::try_from_rmp(&mut rd, codec.get_cfg())? {
+                FDQRecord::Frame(f) => frames.push(codec.decode(f)),
+                FDQRecord::Event(ev) => codec.apply(&ev),
+            }
+        }
+        Ok(Self {
+            target: get_field(&fields, "history")?.string()?,
+            seq: get_field(&fields, "seq")?.int()? as u32,
+            done: get_field(&fields, "done")?.as_bool(),
+            frames,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framedata::{FrameData, FrameTime};
+    use crate::logevent::FrameMeta;
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn test_history_messages() {
+        let req = HistoryRequest {
+            target: "192.168.0.1%wlan0".to_owned(),
+            from_ms: 1_600_000_000_000,
+            to_ms: None,
+        };
+        assert_eq!(HistoryRequest::decode(&req.encode()).unwrap(), req);
+
+        let frames: Vec<_> = (0..200)
+            .map(|n| {
+                FrameDataQ::from_framedata(&FrameData {
+                    time: FrameTime::Timestamp(Utc.timestamp_millis(1_600_000_000_000 + n * 20)),
+                    inflight: 0,
+                    lost_packets: n as usize % 3,
+                    recv_us: vec![1000, 1200 + n as u128],
+                    meta: FrameMeta {
+                        probe_rate: Some(100.0),
+                        ..Default::default()
+                    },
+                })
+            })
+            .collect();
+        let mut received = vec![];
+        let mut seq = 0;
+        let mut rest = &frames[..];
+        loop {
+            let (msg, used) = HistoryChunk::encode_frames("10.0.0.1", seq, rest, 1200);
+            assert!(msg.len() <= 1200);
+            let chunk = HistoryChunk::decode(&msg).unwrap();
+            assert_eq!((chunk.target.as_str(), chunk.seq), ("10.0.0.1", seq));
+            assert_eq!(chunk.frames.len(), used);
+            received.extend(chunk.frames);
+            rest = &rest[used..];
+            seq += 1;
+            if chunk.done {
+                break;
+            }
+        }
+        assert!(seq > 1);
+        assert_eq!(received.len(), frames.len());
+        for (a, b) in received.iter().zip(frames.iter()) {
+            assert_eq!(a.get_timestamp_ms(), b.get_timestamp_ms());
+            assert_eq!((&a.recv_us, a.lost_packets), (&b.recv_us, b.lost_packets));
+            assert_eq!(a.meta.probe_rate, Some(100.0));
+        }
+    }
+}
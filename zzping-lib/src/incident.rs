@@ -0,0 +1,567 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Incidents: spans where a target stopped replying or its latency spiked,
+//! as in "lost 100% for 1.4s at 20:13".
+//!
+//! The IncidentDetector is fed the frames of each target, either as the daemon
+//! writes them or as read back from a FrameDataQ stream, so the incidents
+//! found live and offline are the same. Consecutive bad frames of a target are
+//! merged into one incident, and then incidents of different targets that
+//! overlap in time are merged into one, listing all the targets affected.
+//!
+//! A frame is bad for loss when nothing came back while new pings were
+//! pending, for longer than a few round trips; replies that are just late
+//! don't count. It's bad for latency when the median is well over the usual
+//! one, which is followed with a moving average. Counts of lost and received
+//! pings in an incident are estimates from the frames.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+
+use crate::framedataq::{Complete, FrameDataQ};
+
+/// Thresholds for the detection.
+#[derive(Debug, Clone, Copy)]
+pub struct IncidentCfg {
+    /// Bad frames this close are merged into one incident, and so are the
+    /// incidents of different targets.
+    pub merge_gap: Duration,
+    /// Minimum time without replies to take it as loss, unless the replies
+    /// then come late. It's also at least three times the usual latency.
+    pub late_min: Duration,
+    /// Time without replies that is loss even if the replies come later.
+    pub late_max: Duration,
+    /// A median latency over the usual one times this is a spike...
+    pub spike_factor: f32,
+    /// ...as long as it's also over this many milliseconds.
+    pub spike_min_ms: f32,
+    /// Incidents lasting at least this long are critical.
+    pub critical: Duration,
+    /// Time for the usual latency to follow a lasting change.
+    pub baseline_time: Duration,
+}
+
+impl Default for IncidentCfg {
+    fn default() -> Self {
+        Self {
+            merge_gap: Duration::from_secs(1),
+            late_min: Duration::from_millis(200),
+            late_max: Duration::from_secs(1),
+            spike_factor: 4.0,
+            spike_min_ms: 50.0,
+            critical: Duration::from_secs(5),
+            baseline_time: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IncidentKind {
+    /// Pings without reply.
+    Loss,
+    /// Replies much slower than usual.
+    Latency,
+}
+
+impl IncidentKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IncidentKind::Loss => "loss",
+            IncidentKind::Latency => "latency",
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "loss" => Ok(IncidentKind::Loss),
+            "latency" => Ok(IncidentKind::Latency),
+            _ => bail!("unknown incident kind '{}'", s),
+        }
+    }
+}
+
+/// How bad an incident was, by how long it lasted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// Under a second.
+    Minor,
+    /// A second or more.
+    Major,
+    /// IncidentCfg::critical or more.
+    Critical,
+}
+
+impl Severity {
+    pub fn of(duration: Duration, cfg: &IncidentCfg) -> Self {
+        if duration >= cfg.critical {
+            Severity::Critical
+        } else if duration >= Duration::from_secs(1) {
+            Severity::Major
+        } else {
+            Severity::Minor
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Minor => "minor",
+            Severity::Major => "major",
+            Severity::Critical => "critical",
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "minor" => Ok(Severity::Minor),
+            "major" => Ok(Severity::Major),
+            "critical" => Ok(Severity::Critical),
+            _ => bail!("unknown severity '{}'", s),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Incident {
+    pub kind: IncidentKind,
+    pub severity: Severity,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    /// Targets affected, sorted.
+    pub targets: Vec<String>,
+    /// Pings lost and received during the incident, estimated.
+    pub lost: u32,
+    pub received: u32,
+    /// Worst median latency seen, in milliseconds.
+    pub peak_latency_ms: f32,
+}
+
+impl Incident {
+    pub fn duration(&self) -> Duration {
+        (self.end - self.start).to_std().unwrap_or_default()
+    }
+
+    pub fn loss_pct(&self) -> f32 {
+        100.0 * self.lost as f32 / (self.lost + self.received).max(1) as f32
+    }
+
+    /// Whether both are of the same kind and at most "gap" apart.
+    fn overlaps(&self, other: &Incident, gap: chrono::Duration) -> bool {
+        self.kind == other.kind && self.start <= other.end + gap && other.start <= self.end + gap
+    }
+
+    fn merge(&mut self, other: &Incident) {
+        self.start = self.start.min(other.start);
+        self.end = self.end.max(other.end);
+        self.targets.extend(other.targets.iter().cloned());
+        self.targets.sort();
+        self.targets.dedup();
+        self.lost += other.lost;
+        self.received += other.received;
+        self.peak_latency_ms = self.peak_latency_ms.max(other.peak_latency_ms);
+    }
+
+    /// One line of the incident log, with the fields separated by tabs.
+    pub fn to_line(&self) -> String {
+        format!(
+            "{}\t{:.3}\t{}\t{}\t{}\t{}\t{:.2}\t{}",
+            self.start.to_rfc3339_opts(SecondsFormat::Millis, true),
+            self.duration().as_secs_f64(),
+            self.kind.as_str(),
+            self.severity.as_str(),
+            self.lost,
+            self.received,
+            self.peak_latency_ms,
+            self.targets.join(",")
+        )
+    }
+
+    pub fn from_line(line: &str) -> Result<Self> {
+        let fields: Vec<&str> = line.trim_end().split('\t').collect();
+        if fields.len() != 8 {
+            bail!("expected 8 fields, got {}", fields.len());
+        }
+        let start = DateTime::parse_from_rfc3339(fields[0])
+            .context("start")?
+            .with_timezone(&Utc);
+        let secs: f64 = fields[1].parse().context("duration")?;
+        Ok(Self {
+            kind: IncidentKind::parse(fields[2])?,
+            severity: Severity::parse(fields[3])?,
+            start,
+            end: start + chrono::Duration::milliseconds((secs * 1000.0).round() as i64),
+            lost: fields[4].parse().context("lost")?,
+            received: fields[5].parse().context("received")?,
+            peak_latency_ms: fields[6].parse().context("peak latency")?,
+            targets: fields[7].split(',').map(|x| x.to_owned()).collect(),
+        })
+    }
+}
+
+impl fmt::Display for Incident {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} for {:.1}s: ",
+            self.start.format("%Y-%m-%d %H:%M:%S%.3f"),
+            self.severity.as_str(),
+            self.kind.as_str(),
+            self.duration().as_secs_f32()
+        )?;
+        match self.kind {
+            IncidentKind::Loss => write!(f, "lost {:.0}%", self.loss_pct())?,
+            IncidentKind::Latency => write!(f, "up to {:.1}ms", self.peak_latency_ms)?,
+        }
+        write!(f, " on {}", self.targets.join(", "))
+    }
+}
+
+/// Time without replies while pings were pending.
+#[derive(Debug, Clone, Copy)]
+struct Silence {
+    since: DateTime<Utc>,
+    last: DateTime<Utc>,
+    /// Pings lost in it not in an incident yet.
+    lost: u32,
+    /// Lasted IncidentCfg::late_max and is in an incident already.
+    reported: bool,
+}
+
+#[derive(Debug, Default)]
+struct TargetState {
+    last_time: Option<DateTime<Utc>>,
+    /// Pings pending at the last frame, and at the last reply; those were
+    /// sent before it and are not a sign of a new problem.
+    pending: f32,
+    stale: f32,
+    /// Usual median latency, in microseconds.
+    baseline_us: Option<f32>,
+    silence: Option<Silence>,
+    open: Option<Incident>,
+    /// Pings received since the end of the open incident, counted in it if
+    /// it goes on.
+    received_after: u32,
+}
+
+/// Finds incidents on the frames of any number of targets.
+///
+/// Live, call poll() after each round of frames to get the incidents that are
+/// over. Offline, feed all the frames and then call finish().
+#[derive(Debug, Default)]
+pub struct IncidentDetector {
+    cfg: IncidentCfg,
+    targets: HashMap<String, TargetState>,
+    /// Incidents over for their targets, waiting for others to join them.
+    pending: Vec<Incident>,
+    /// Latest frame time seen.
+    now: Option<DateTime<Utc>>,
+}
+
+impl IncidentDetector {
+    pub fn new(cfg: IncidentCfg) -> Self {
+        Self {
+            cfg,
+            ..Default::default()
+        }
+    }
+
+    fn gap(&self) -> chrono::Duration {
+        chrono::Duration::from_std(self.cfg.merge_gap).unwrap()
+    }
+
+    /// Feeds the next frame of "target". The frame must have its timestamp.
+    pub fn push(&mut self, target: &str, frame: &FrameDataQ<Complete>) {
+        let cfg = self.cfg;
+        let gap = self.gap();
+        let time = frame.get_datetime();
+        self.now = Some(self.now.map_or(time, |now| now.max(time)));
+        let st = self.targets.entry(target.to_owned()).or_default();
+        let dt = st.last_time.map_or(Duration::ZERO, |last| {
+            (time - last).to_std().unwrap_or_default()
+        });
+        st.last_time = Some(time);
+
+        let mut closed = vec![];
+        // After time where nothing was measured, pending pings say nothing.
+        if frame.meta.daemon_start.is_some() || frame.meta.discontinuity.is_some() {
+            st.silence = None;
+            st.stale = frame.inflight;
+            closed.extend(st.open.take());
+        }
+        let received = frame.recv_us_len as u32;
+        let sent = match frame.meta.probe_rate {
+            Some(rate) => (rate * dt.as_secs_f32()).round() as u32,
+            None => (frame.inflight - st.pending).max(0.0) as u32,
+        };
+        st.pending = frame.inflight;
+
+        // Kind, span and pings lost of this frame, if it's bad.
+        let mut bad: Option<(IncidentKind, DateTime<Utc>, DateTime<Utc>, u32)> = None;
+        let mut latency_ms = 0.0;
+        let late = Duration::from_micros(3 * st.baseline_us.unwrap_or(0.0) as u64);
+        if received > 0 {
+            st.stale = frame.inflight;
+            let median_us = frame.percentile_us(0.5).max(0) as f32;
+            let baseline = *st.baseline_us.get_or_insert(median_us);
+            let threshold = (baseline * cfg.spike_factor).max(cfg.spike_min_ms * 1000.0);
+            let spike = median_us > threshold;
+            if spike {
+                bad = Some((IncidentKind::Latency, time, time, 0));
+                latency_ms = median_us / 1000.0;
+            } else {
+                let alpha = (dt.as_secs_f32() / cfg.baseline_time.as_secs_f32()).min(1.0);
+                st.baseline_us = Some(baseline + (median_us - baseline) * alpha);
+            }
+            // A silence long enough is loss, unless it ends with slow replies:
+            // then those pings were just late, and it's part of the spike.
+            if let Some(silence) = st.silence.take() {
+                let silent = (silence.last - silence.since).to_std().unwrap_or_default();
+                if !silence.reported && silent >= late.max(cfg.late_min) {
+                    bad = match spike {
+                        true => Some((IncidentKind::Latency, silence.since, time, 0)),
+                        false => Some((
+                            IncidentKind::Loss,
+                            silence.since,
+                            silence.last,
+                            silence.lost,
+                        )),
+                    };
+                }
+            }
+        } else {
+            st.stale = st.stale.min(frame.inflight);
+            if frame.inflight > st.stale {
+                let silence = st.silence.get_or_insert(Silence {
+                    since: time,
+                    last: time,
+                    lost: 0,
+                    reported: false,
+                });
+                silence.last = time;
+                silence.lost += sent.max(1);
+                if silence.reported {
+                    bad = Some((IncidentKind::Loss, time, time, silence.lost));
+                    silence.lost = 0;
+                } else if time - silence.since >= chrono::Duration::from_std(cfg.late_max).unwrap()
+                {
+                    bad = Some((IncidentKind::Loss, silence.since, time, silence.lost));
+                    silence.reported = true;
+                    silence.lost = 0;
+                }
+            }
+        }
+
+        match bad {
+            Some((kind, start, end, lost)) => match st.open.as_mut() {
+                Some(inc) if inc.kind == kind && start - inc.end <= gap => {
+                    inc.end = end;
+                    inc.lost += lost;
+                    inc.received += st.received_after + received;
+                    inc.peak_latency_ms = inc.peak_latency_ms.max(latency_ms);
+                    st.received_after = 0;
+                }
+                _ => {
+                    closed.extend(st.open.take());
+                    st.open = Some(Incident {
+                        kind,
+                        severity: Severity::Minor,
+                        start,
+                        end,
+                        targets: vec![target.to_owned()],
+                        lost,
+                        received,
+                        peak_latency_ms: latency_ms,
+                    });
+                    st.received_after = 0;
+                }
+            },
+            None => match st.open.as_ref() {
+                Some(inc) if time - inc.end > gap => closed.extend(st.open.take()),
+                Some(_) => st.received_after += received,
+                None => {}
+            },
+        }
+        for inc in closed {
+            self.settle(inc);
+        }
+    }
+
+    /// Adds an incident over for its target, merging it with the ones of
+    /// other targets it overlaps.
+    fn settle(&mut self, mut inc: Incident) {
+        let gap = self.gap();
+        while let Some(i) = self.pending.iter().position(|p| p.overlaps(&inc, gap)) {
+            let other = self.pending.remove(i);
+            inc.merge(&other);
+        }
+        inc.severity = Severity::of(inc.duration(), &self.cfg);
+        self.pending.push(inc);
+    }
+
+    /// Incidents that are over for all targets, by start time. Targets that
+    /// stopped getting frames have their incidents ended.
+    pub fn poll(&mut self) -> Vec<Incident> {
+        let now = match self.now {
+            Some(now) => now,
+            None => return vec![],
+        };
+        let gap = self.gap();
+        let mut closed = vec![];
+        for st in self.targets.values_mut() {
+            if st.last_time.is_some_and(|last| now - last > gap) {
+                closed.extend(st.open.take());
+            }
+        }
+        for inc in closed {
+            self.settle(inc);
+        }
+        let open: Vec<Incident> = self
+            .targets
+            .values()
+            .filter_map(|t| t.open.clone())
+            .collect();
+        let (mut done, pending): (Vec<_>, Vec<_>) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition(|p| now - p.end > gap + gap && !open.iter().any(|o| o.overlaps(p, gap)));
+        self.pending = pending;
+        done.sort_by_key(|x| x.start);
+        done
+    }
+
+    /// Ends everything, at the end of the data. Returns all the incidents
+    /// not returned yet, by start time.
+    pub fn finish(&mut self) -> Vec<Incident> {
+        let closed: Vec<Incident> = self
+            .targets
+            .values_mut()
+            .filter_map(|t| t.open.take())
+            .collect();
+        for inc in closed {
+            self.settle(inc);
+        }
+        let mut done = std::mem::take(&mut self.pending);
+        done.sort_by_key(|x| x.start);
+        done
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framedata::FrameTime;
+    use crate::synth::{Generator, Scenario, Span, Spikes};
+
+    /// Frames of a scenario, with their timestamps.
+    fn frames(scenario: Scenario) -> Vec<FrameDataQ<Complete>> {
+        let mut last_keyframe = None;
+        Generator::new(scenario)
+            .unwrap()
+            .map(|mut fd| {
+                match fd.time {
+                    FrameTime::Timestamp(ts) => last_keyframe = Some(ts),
+                    FrameTime::Elapsed(e) => {
+                        let ts = last_keyframe.unwrap() + chrono::Duration::from_std(e).unwrap();
+                        fd.time = FrameTime::Timestamp(ts);
+                    }
+                }
+                FrameDataQ::from_framedata(&fd)
+            })
+            .collect()
+    }
+
+    fn outage(seed: u64, at_secs: u64, duration_secs: u64) -> Scenario {
+        Scenario {
+            duration_secs: 60,
+            seed,
+            loss_pct: 1.0,
+            outages: vec![Span {
+                at_secs,
+                duration_secs,
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_detect_outages() {
+        let mut detector = IncidentDetector::new(IncidentCfg::default());
+        let a = frames(outage(1, 30, 2));
+        let b = frames(outage(2, 31, 3));
+        let start = a[0].get_datetime();
+        let mut found = vec![];
+        // Live, the frames of both come in turns.
+        for (fa, fb) in a.iter().zip(b.iter()) {
+            detector.push("10.0.0.1", fa);
+            detector.push("10.0.0.2", fb);
+            found.extend(detector.poll());
+        }
+        found.extend(detector.finish());
+        assert_eq!(found.len(), 1, "{:?}", found);
+        let inc = &found[0];
+        assert_eq!(inc.kind, IncidentKind::Loss);
+        assert_eq!(inc.severity, Severity::Major);
+        assert_eq!(inc.targets, vec!["10.0.0.1", "10.0.0.2"]);
+        let secs = |t: DateTime<Utc>| (t - start).num_milliseconds() as f64 / 1000.0;
+        assert!((secs(inc.start) - 30.0).abs() < 0.1, "{}", inc);
+        assert!((secs(inc.end) - 34.0).abs() < 0.1, "{}", inc);
+        assert!(inc.loss_pct() > 90.0, "{}", inc);
+
+        // Offline, one target after the other, the result is the same.
+        let mut detector = IncidentDetector::new(IncidentCfg::default());
+        a.iter().for_each(|f| detector.push("10.0.0.1", f));
+        b.iter().for_each(|f| detector.push("10.0.0.2", f));
+        assert_eq!(detector.finish(), found);
+
+        let line = found[0].to_line();
+        let back = Incident::from_line(&line).unwrap();
+        assert_eq!((back.start, back.end), (inc.start, inc.end));
+        assert_eq!(back.to_line(), line);
+    }
+
+    #[test]
+    fn test_detect_spikes() {
+        let scenario = Scenario {
+            duration_secs: 3600,
+            spikes: Some(Spikes {
+                per_hour: 10.0,
+                duration_ms: 2000,
+                extra_ms: 300.0,
+                loss_pct: 0.0,
+            }),
+            ..Default::default()
+        };
+        let mut detector = IncidentDetector::new(IncidentCfg::default());
+        frames(scenario)
+            .iter()
+            .for_each(|f| detector.push("10.0.0.1", f));
+        let found = detector.finish();
+        // About 10 spikes of 2s. Some come close together and are merged.
+        assert!((5..=15).contains(&found.len()), "{:?}", found);
+        for inc in found.iter() {
+            assert_eq!(inc.kind, IncidentKind::Latency, "{}", inc);
+            assert!(inc.peak_latency_ms > 100.0, "{}", inc);
+            assert!(inc.duration() >= Duration::from_millis(1900), "{}", inc);
+        }
+        // Late replies land a bit after the spike ends, but not much.
+        let short = found
+            .iter()
+            .filter(|x| x.duration() < Duration::from_millis(2500))
+            .count();
+        assert!(short * 4 >= found.len() * 3, "{:?}", found);
+    }
+}
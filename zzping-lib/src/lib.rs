@@ -21,6 +21,11 @@ pub mod dynrmp;
 pub mod framedata;
 pub mod framedataq;
 pub mod framestats;
+pub mod incident;
+pub mod logevent;
+pub mod secure;
+pub mod sketch;
+pub mod synth;
 
 /// This is a test macro that tries to do a dbg!() but inlined. Takes less space.
 #[macro_export]
@@ -0,0 +1,726 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Events interleaved with the frames in the logs.
+//!
+//! Frames are positional msgpack values starting with a timestamp or an
+//! integer. Events are written in between them as msgpack maps, so readers can
+//! tell them apart by the first value. Every event has an "event" key with its
+//! name; events that a reader doesn't know about are kept as Unknown so newer
+//! daemons can add events without breaking older tools.
+//!
+//! Some events describe a state (i.e. the probe rate) that holds for all the
+//! frames that follow. Readers track that state in a FrameMeta and attach it
+//! to each frame. Others, like Discontinuity or the daemon starting and
+//! stopping, are attached only to the frame right after them.
+
+use std::collections::HashMap;
+
+use anyhow::Result;
+
+use crate::dynrmp::map::Map;
+use crate::dynrmp::variant::Variant;
+use crate::framedataq::XError;
+
+/// An event recorded in the logs.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LogEvent {
+    /// Pings per second in effect from now on.
+    ProbeRate(f32),
+    /// The frames that follow don't continue in time from the ones before.
+    Discontinuity(Discontinuity),
+    /// The daemon started measuring. Nothing was measured since the frame
+    /// before, if any.
+    DaemonStart(DaemonStart),
+    /// The daemon stopped measuring; it's the last record of the log.
+    DaemonStop(StopReason),
+    /// State of the WiFi link the pings go through, from now on.
+    Wireless(Wireless),
+    /// Traffic and errors on the network interface the pings go through,
+    /// from now on.
+    LinkStats(LinkStats),
+    /// Load of the host running the daemon, from now on.
+    HostHealth(HostHealth),
+    /// Whether the frames from now on were measured while the host was too
+    /// busy to trust them.
+    Untrusted(bool),
+    /// Delay and loss each way to a peer daemon, from now on.
+    PeerPath(PeerPath),
+    /// Event written by a newer version, with its name.
+    Unknown(String),
+}
+
+/// Break in the timeline of a log, detected by comparing the wall clock with
+/// the monotonic one. The frame after it is always a keyframe.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Discontinuity {
+    /// Seconds the wall clock was stepped, negative when it went back. Frames
+    /// before the step are off by this much.
+    pub step_secs: f64,
+    /// Seconds the host was suspended. Nothing was measured meanwhile.
+    pub suspend_secs: f64,
+}
+
+impl Discontinuity {
+    /// Combines two discontinuities into one spanning both.
+    pub fn add(&self, other: &Discontinuity) -> Self {
+        Self {
+            step_secs: self.step_secs + other.step_secs,
+            suspend_secs: self.suspend_secs + other.suspend_secs,
+        }
+    }
+}
+
+/// Why the daemon started measuring a target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StartReason {
+    /// The daemon started, and it had stopped cleanly the last time.
+    Started,
+    /// The daemon started, but its last run didn't record a stop, i.e. it
+    /// crashed or the host lost power.
+    Recovered,
+    /// The target was added while running.
+    TargetAdded,
+    /// Reason written by a newer version.
+    Other,
+}
+
+impl StartReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StartReason::Started => "started",
+            StartReason::Recovered => "recovered",
+            StartReason::TargetAdded => "target_added",
+            StartReason::Other => "other",
+        }
+    }
+
+    pub fn parse(s: &str) -> Self {
+        match s {
+            "started" => StartReason::Started,
+            "recovered" => StartReason::Recovered,
+            "target_added" => StartReason::TargetAdded,
+            _ => StartReason::Other,
+        }
+    }
+}
+
+/// Why the daemon stopped measuring a target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// The daemon was asked to exit, i.e. with SIGTERM or Ctrl+C.
+    Signal,
+    /// The target was removed while running.
+    TargetRemoved,
+    /// Reason written by a newer version.
+    Other,
+}
+
+impl StopReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StopReason::Signal => "signal",
+            StopReason::TargetRemoved => "target_removed",
+            StopReason::Other => "other",
+        }
+    }
+
+    pub fn parse(s: &str) -> Self {
+        match s {
+            "signal" => StopReason::Signal,
+            "target_removed" => StopReason::TargetRemoved,
+            _ => StopReason::Other,
+        }
+    }
+}
+
+/// Start of a daemon run, or of the measures of a target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DaemonStart {
+    pub reason: StartReason,
+    /// Hash of the config in effect, to tell when it changed between runs.
+    pub config_hash: u64,
+}
+
+/// Sample of a WiFi link, as found in /proc/net/wireless.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Wireless {
+    /// Link quality, on the driver's own scale (usually out of 70).
+    pub link: f32,
+    /// Signal level, in dBm.
+    pub signal_dbm: f32,
+    /// Noise level, in dBm. Many drivers don't report it, and give -256.
+    pub noise_dbm: f32,
+    /// Packets discarded after too many retries, since the link came up.
+    pub retries: u32,
+    /// Beacons from the access point missed, since the link came up.
+    pub missed_beacons: u32,
+}
+
+/// Counters of a network interface, as found in /sys/class/net, over the
+/// second before the sample.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LinkStats {
+    /// Bytes received per second.
+    pub rx_bytes_sec: f32,
+    /// Bytes sent per second.
+    pub tx_bytes_sec: f32,
+    /// Packets with errors, received or sent.
+    pub errors: u32,
+    /// Packets dropped, received or sent.
+    pub drops: u32,
+    /// Times the carrier went up or down.
+    pub carrier_changes: u32,
+}
+
+/// Load of the host running the daemon, over the second before the sample.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct HostHealth {
+    /// Load average over the last minute, from /proc/loadavg.
+    pub load1: f32,
+    /// Percent of the last 10s that some task waited for a CPU, from
+    /// /proc/pressure/cpu. Not all kernels have it.
+    pub cpu_pressure: Option<f32>,
+    /// Worst delay waking up to send pings that were due, in ms.
+    pub send_late_ms: f32,
+    /// Worst delay between the kernel receiving a reply and the daemon
+    /// reading it, in ms. 0 without kernel timestamps.
+    pub recv_delay_ms: f32,
+}
+
+/// One-way delay and loss to a peer daemon and back, over the frame before.
+/// The clocks of both hosts are compared, so each direction can be told
+/// apart from the round trip.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PeerPath {
+    /// How far ahead the clock of the peer is, in ms.
+    pub offset_ms: f32,
+    /// Median delay from here to the peer, in ms.
+    pub up_ms: f32,
+    /// Median delay from the peer to here, in ms.
+    pub down_ms: f32,
+    /// Percent of the probes lost on the way to the peer.
+    pub up_loss_pct: f32,
+    /// Percent of the replies lost on the way back.
+    pub down_loss_pct: f32,
+}
+
+impl PeerPath {
+    /// Worst of both for each direction, with the last offset.
+    pub fn max(&self, other: &PeerPath) -> PeerPath {
+        PeerPath {
+            offset_ms: other.offset_ms,
+            up_ms: self.up_ms.max(other.up_ms),
+            down_ms: self.down_ms.max(other.down_ms),
+            up_loss_pct: self.up_loss_pct.max(other.up_loss_pct),
+            down_loss_pct: self.down_loss_pct.max(other.down_loss_pct),
+        }
+    }
+}
+
+impl LinkStats {
+    /// Worst of both for each field, so a busy second or a flap is not lost
+    /// when frames are aggregated.
+    pub fn max(&self, other: &LinkStats) -> LinkStats {
+        LinkStats {
+            rx_bytes_sec: self.rx_bytes_sec.max(other.rx_bytes_sec),
+            tx_bytes_sec: self.tx_bytes_sec.max(other.tx_bytes_sec),
+            errors: self.errors.max(other.errors),
+            drops: self.drops.max(other.drops),
+            carrier_changes: self.carrier_changes.max(other.carrier_changes),
+        }
+    }
+}
+
+impl LogEvent {
+    pub fn encode<W: std::io::Write>(
+        &self,
+        wr: &mut W,
+    ) -> Result<(), rmp::encode::ValueWriteError> {
+        match self {
+            LogEvent::ProbeRate(freq) => {
+                rmp::encode::write_map_len(wr, 2)?;
+                rmp::encode::write_str(wr, "event")?;
+                rmp::encode::write_str(wr, "probe_rate")?;
+                rmp::encode::write_str(wr, "freq")?;
+                rmp::encode::write_f32(wr, *freq)?;
+            }
+            LogEvent::Discontinuity(d) => {
+                rmp::encode::write_map_len(wr, 3)?;
+                rmp::encode::write_str(wr, "event")?;
+                rmp::encode::write_str(wr, "discontinuity")?;
+                rmp::encode::write_str(wr, "step_secs")?;
+                rmp::encode::write_f64(wr, d.step_secs)?;
+                rmp::encode::write_str(wr, "suspend_secs")?;
+                rmp::encode::write_f64(wr, d.suspend_secs)?;
+            }
+            LogEvent::DaemonStart(start) => {
+                rmp::encode::write_map_len(wr, 3)?;
+                rmp::encode::write_str(wr, "event")?;
+                rmp::encode::write_str(wr, "daemon_start")?;
+                rmp::encode::write_str(wr, "reason")?;
+                rmp::encode::write_str(wr, start.reason.as_str())?;
+                rmp::encode::write_str(wr, "config_hash")?;
+                rmp::encode::write_u64(wr, start.config_hash)?;
+            }
+            LogEvent::DaemonStop(reason) => {
+                rmp::encode::write_map_len(wr, 2)?;
+                rmp::encode::write_str(wr, "event")?;
+                rmp::encode::write_str(wr, "daemon_stop")?;
+                rmp::encode::write_str(wr, "reason")?;
+                rmp::encode::write_str(wr, reason.as_str())?;
+            }
+            LogEvent::Wireless(w) => {
+                rmp::encode::write_map_len(wr, 6)?;
+                rmp::encode::write_str(wr, "event")?;
+                rmp::encode::write_str(wr, "wireless")?;
+                rmp::encode::write_str(wr, "link")?;
+                rmp::encode::write_f32(wr, w.link)?;
+                rmp::encode::write_str(wr, "signal_dbm")?;
+                rmp::encode::write_f32(wr, w.signal_dbm)?;
+                rmp::encode::write_str(wr, "noise_dbm")?;
+                rmp::encode::write_f32(wr, w.noise_dbm)?;
+                rmp::encode::write_str(wr, "retries")?;
+                rmp::encode::write_u32(wr, w.retries)?;
+                rmp::encode::write_str(wr, "missed_beacons")?;
+                rmp::encode::write_u32(wr, w.missed_beacons)?;
+            }
+            LogEvent::LinkStats(l) => {
+                rmp::encode::write_map_len(wr, 6)?;
+                rmp::encode::write_str(wr, "event")?;
+                rmp::encode::write_str(wr, "link_stats")?;
+                rmp::encode::write_str(wr, "rx_bytes_sec")?;
+                rmp::encode::write_f32(wr, l.rx_bytes_sec)?;
+                rmp::encode::write_str(wr, "tx_bytes_sec")?;
+                rmp::encode::write_f32(wr, l.tx_bytes_sec)?;
+                rmp::encode::write_str(wr, "errors")?;
+                rmp::encode::write_u32(wr, l.errors)?;
+                rmp::encode::write_str(wr, "drops")?;
+                rmp::encode::write_u32(wr, l.drops)?;
+                rmp::encode::write_str(wr, "carrier_changes")?;
+                rmp::encode::write_u32(wr, l.carrier_changes)?;
+            }
+            LogEvent::HostHealth(h) => {
+                let len = 4 + h.cpu_pressure.is_some() as u32;
+                rmp::encode::write_map_len(wr, len)?;
+                rmp::encode::write_str(wr, "event")?;
+                rmp::encode::write_str(wr, "host_health")?;
+                rmp::encode::write_str(wr, "load1")?;
+                rmp::encode::write_f32(wr, h.load1)?;
+                if let Some(pressure) = h.cpu_pressure {
+                    rmp::encode::write_str(wr, "cpu_pressure")?;
+                    rmp::encode::write_f32(wr, pressure)?;
+                }
+                rmp::encode::write_str(wr, "send_late_ms")?;
+                rmp::encode::write_f32(wr, h.send_late_ms)?;
+                rmp::encode::write_str(wr, "recv_delay_ms")?;
+                rmp::encode::write_f32(wr, h.recv_delay_ms)?;
+            }
+            LogEvent::Untrusted(untrusted) => {
+                rmp::encode::write_map_len(wr, 2)?;
+                rmp::encode::write_str(wr, "event")?;
+                rmp::encode::write_str(wr, "data_quality")?;
+                rmp::encode::write_str(wr, "untrusted")?;
+                rmp::encode::write_bool(wr, *untrusted)
+                    .map_err(rmp::encode::ValueWriteError::InvalidDataWrite)?;
+            }
+            LogEvent::PeerPath(p) => {
+                rmp::encode::write_map_len(wr, 6)?;
+                rmp::encode::write_str(wr, "event")?;
+                rmp::encode::write_str(wr, "peer_path")?;
+                rmp::encode::write_str(wr, "offset_ms")?;
+                rmp::encode::write_f32(wr, p.offset_ms)?;
+                rmp::encode::write_str(wr, "up_ms")?;
+                rmp::encode::write_f32(wr, p.up_ms)?;
+                rmp::encode::write_str(wr, "down_ms")?;
+                rmp::encode::write_f32(wr, p.down_ms)?;
+                rmp::encode::write_str(wr, "up_loss_pct")?;
+                rmp::encode::write_f32(wr, p.up_loss_pct)?;
+                rmp::encode::write_str(wr, "down_loss_pct")?;
+                rmp::encode::write_f32(wr, p.down_loss_pct)?;
+            }
+            LogEvent::Unknown(name) => {
+                rmp::encode::write_map_len(wr, 1)?;
+                rmp::encode::write_str(wr, "event")?;
+                rmp::encode::write_str(wr, name)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn to_rmp(&self) -> Vec<u8> {
+        let mut v = vec![];
+        self.encode(&mut v).unwrap();
+        v
+    }
+
+    /// Builds the event from a map already read from the log.
+    pub fn from_map(map: Map) -> Result<Self> {
+        let fields = map.into_strhashmap()?;
+        let event = get_field(&fields, "event")?.string()?;
+        Ok(match event.as_str() {
+            "probe_rate" => LogEvent::ProbeRate(get_f64(&fields, "freq")? as f32),
+            "discontinuity" => LogEvent::Discontinuity(Discontinuity {
+                step_secs: get_f64(&fields, "step_secs")?,
+                suspend_secs: get_f64(&fields, "suspend_secs")?,
+            }),
+            "daemon_start" => LogEvent::DaemonStart(DaemonStart {
+                reason: StartReason::parse(&get_field(&fields, "reason")?.string()?),
+                config_hash: get_field(&fields, "config_hash")?.int()? as u64,
+            }),
+            "daemon_stop" => {
+                LogEvent::DaemonStop(StopReason::parse(&get_field(&fields, "reason")?.string()?))
+            }
+            "wireless" => LogEvent::Wireless(Wireless {
+                link: get_f64(&fields, "link")? as f32,
+                signal_dbm: get_f64(&fields, "signal_dbm")? as f32,
+                noise_dbm: get_f64(&fields, "noise_dbm")? as f32,
+                retries: get_field(&fields, "retries")?.int()? as u32,
+                missed_beacons: get_field(&fields, "missed_beacons")?.int()? as u32,
+            }),
+            "link_stats" => LogEvent::LinkStats(LinkStats {
+                rx_bytes_sec: get_f64(&fields, "rx_bytes_sec")? as f32,
+                tx_bytes_sec: get_f64(&fields, "tx_bytes_sec")? as f32,
+                errors: get_field(&fields, "errors")?.int()? as u32,
+                drops: get_field(&fields, "drops")?.int()? as u32,
+                carrier_changes: get_field(&fields, "carrier_changes")?.int()? as u32,
+            }),
+            "host_health" => LogEvent::HostHealth(HostHealth {
+                load1: get_f64(&fields, "load1")? as f32,
+                cpu_pressure: match fields.contains_key("cpu_pressure") {
+                    true => Some(get_f64(&fields, "cpu_pressure")? as f32),
+                    false => None,
+                },
+                send_late_ms: get_f64(&fields, "send_late_ms")? as f32,
+                recv_delay_ms: get_f64(&fields, "recv_delay_ms")? as f32,
+            }),
+            "data_quality" => match get_field(&fields, "untrusted")? {
+                Variant::Bool(untrusted) => LogEvent::Untrusted(*untrusted),
+                _ => Err(XError::UnexpectedData(
+                    "untrusted expected to be a bool".to_owned(),
+                ))?,
+            },
+            "peer_path" => LogEvent::PeerPath(PeerPath {
+                offset_ms: get_f64(&fields, "offset_ms")? as f32,
+                up_ms: get_f64(&fields, "up_ms")? as f32,
+                down_ms: get_f64(&fields, "down_ms")? as f32,
+                up_loss_pct: get_f64(&fields, "up_loss_pct")? as f32,
+                down_loss_pct: get_f64(&fields, "down_loss_pct")? as f32,
+            }),
+            _ => LogEvent::Unknown(event),
+        })
+    }
+}
+
+fn get_field<'a>(fields: &'a HashMap<String, Variant>, name: &str) -> Result<&'a Variant> {
+    Ok(fields
+        .get(name)
+        .ok_or_else(|| XError::HeaderFieldMissing(name.to_owned()))?)
+}
+
+fn get_f64(fields: &HashMap<String, Variant>, name: &str) -> Result<f64> {
+    match get_field(fields, name)? {
+        Variant::Float(v) => Ok(v.as_f64()),
+        Variant::Integer(v) => Ok(*v as f64),
+        _ => Err(XError::UnexpectedData(format!(
+            "{} expected to be a number",
+            name
+        )))?,
+    }
+}
+
+/// State carried by the events, as it applies to a given frame.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FrameMeta {
+    /// Pings per second in effect, if the log recorded it.
+    pub probe_rate: Option<f32>,
+    /// Set only on the first frame after a discontinuity.
+    pub discontinuity: Option<Discontinuity>,
+    /// Set only on the first frame measured by a daemon run.
+    pub daemon_start: Option<DaemonStart>,
+    /// Set only on the first frame after a recorded stop, if any follows.
+    pub daemon_stop: Option<StopReason>,
+    /// Last sample of the WiFi link, if the log has them.
+    pub wireless: Option<Wireless>,
+    /// Last sample of the interface counters, if the log has them.
+    pub link_stats: Option<LinkStats>,
+    /// Last sample of the load of the host, if the log has them.
+    pub host_health: Option<HostHealth>,
+    /// Set while the host was too busy for the latency to be trusted.
+    pub untrusted: bool,
+    /// Last measure of the path to a peer, in the logs of peers.
+    pub peer_path: Option<PeerPath>,
+}
+
+impl FrameMeta {
+    /// Updates the state with an event read from the log.
+    pub fn apply(&mut self, event: &LogEvent) {
+        match event {
+            LogEvent::ProbeRate(freq) => self.probe_rate = Some(*freq),
+            LogEvent::Discontinuity(d) => {
+                let d = match self.discontinuity {
+                    Some(prev) => prev.add(d),
+                    None => *d,
+                };
+                self.discontinuity = Some(d);
+            }
+            LogEvent::DaemonStart(start) => self.daemon_start = Some(*start),
+            LogEvent::DaemonStop(reason) => self.daemon_stop = Some(*reason),
+            LogEvent::Wireless(w) => self.wireless = Some(*w),
+            LogEvent::LinkStats(l) => self.link_stats = Some(*l),
+            LogEvent::HostHealth(h) => self.host_health = Some(*h),
+            LogEvent::Untrusted(untrusted) => self.untrusted = *untrusted,
+            LogEvent::PeerPath(p) => self.peer_path = Some(*p),
+            LogEvent::Unknown(_) => {}
+        }
+    }
+
+    /// State for the frame after this one, dropping what applies only once.
+    pub fn next(&self) -> Self {
+        Self {
+            discontinuity: None,
+            daemon_start: None,
+            daemon_stop: None,
+            ..*self
+        }
+    }
+
+    /// Events to write so a reader at state "self" ends up at "new".
+    pub fn diff(&self, new: &FrameMeta) -> Vec<LogEvent> {
+        let mut events = vec![];
+        if let Some(reason) = new.daemon_stop {
+            events.push(LogEvent::DaemonStop(reason));
+        }
+        if let Some(start) = new.daemon_start {
+            events.push(LogEvent::DaemonStart(start));
+        }
+        if self.probe_rate != new.probe_rate {
+            if let Some(freq) = new.probe_rate {
+                events.push(LogEvent::ProbeRate(freq));
+            }
+        }
+        if let Some(d) = new.discontinuity {
+            events.push(LogEvent::Discontinuity(d));
+        }
+        if self.wireless != new.wireless {
+            if let Some(w) = new.wireless {
+                events.push(LogEvent::Wireless(w));
+            }
+        }
+        if self.link_stats != new.link_stats {
+            if let Some(l) = new.link_stats {
+                events.push(LogEvent::LinkStats(l));
+            }
+        }
+        if self.host_health != new.host_health {
+            if let Some(h) = new.host_health {
+                events.push(LogEvent::HostHealth(h));
+            }
+        }
+        if self.untrusted != new.untrusted {
+            events.push(LogEvent::Untrusted(new.untrusted));
+        }
+        if self.peer_path != new.peer_path {
+            if let Some(p) = new.peer_path {
+                events.push(LogEvent::PeerPath(p));
+            }
+        }
+        events
+    }
+
+    /// Combines the state of several frames into one, for aggregated frames.
+    pub fn fold(data: &[FrameMeta]) -> Self {
+        let rates: Vec<f32> = data.iter().filter_map(|x| x.probe_rate).collect();
+        let probe_rate = match rates.is_empty() {
+            true => None,
+            false => Some(rates.iter().sum::<f32>() / rates.len() as f32),
+        };
+        let discontinuity = data
+            .iter()
+            .filter_map(|x| x.discontinuity)
+            .reduce(|a, b| a.add(&b));
+        Self {
+            probe_rate,
+            discontinuity,
+            daemon_start: data.iter().find_map(|x| x.daemon_start),
+            daemon_stop: data.iter().find_map(|x| x.daemon_stop),
+            wireless: data.iter().rev().find_map(|x| x.wireless),
+            link_stats: data
+                .iter()
+                .filter_map(|x| x.link_stats)
+                .reduce(|a, b| a.max(&b)),
+            host_health: data.iter().rev().find_map(|x| x.host_health),
+            untrusted: data.iter().any(|x| x.untrusted),
+            peer_path: data
+                .iter()
+                .filter_map(|x| x.peer_path)
+                .reduce(|a, b| a.max(&b)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_roundtrip() {
+        for event in [
+            LogEvent::ProbeRate(12.5),
+            LogEvent::Discontinuity(Discontinuity {
+                step_secs: -3600.0,
+                suspend_secs: 0.0,
+            }),
+            LogEvent::DaemonStart(DaemonStart {
+                reason: StartReason::Recovered,
+                config_hash: u64::MAX - 1,
+            }),
+            LogEvent::DaemonStop(StopReason::Signal),
+            LogEvent::Wireless(Wireless {
+                link: 54.0,
+                signal_dbm: -56.0,
+                noise_dbm: -256.0,
+                retries: 12,
+                missed_beacons: 3,
+            }),
+            LogEvent::LinkStats(LinkStats {
+                rx_bytes_sec: 1.25e6,
+                tx_bytes_sec: 3000.0,
+                errors: 0,
+                drops: 7,
+                carrier_changes: 2,
+            }),
+            LogEvent::HostHealth(HostHealth {
+                load1: 3.5,
+                cpu_pressure: Some(12.25),
+                send_late_ms: 40.0,
+                recv_delay_ms: 0.5,
+            }),
+            LogEvent::HostHealth(HostHealth {
+                load1: 0.25,
+                ..Default::default()
+            }),
+            LogEvent::Untrusted(true),
+            LogEvent::PeerPath(PeerPath {
+                offset_ms: -1.5,
+                up_ms: 42.0,
+                down_ms: 3.25,
+                up_loss_pct: 10.0,
+                down_loss_pct: 0.0,
+            }),
+            LogEvent::Unknown("from_the_future".to_owned()),
+        ] {
+            let buf = event.to_rmp();
+            let map = Variant::read(&mut &buf[..]).unwrap().map().unwrap();
+            assert_eq!(LogEvent::from_map(map).unwrap(), event);
+        }
+    }
+
+    #[test]
+    fn test_meta_diff_apply() {
+        let old = FrameMeta::default();
+        let new = FrameMeta {
+            probe_rate: Some(50.0),
+            ..Default::default()
+        };
+        let events = old.diff(&new);
+        assert_eq!(events, vec![LogEvent::ProbeRate(50.0)]);
+        let mut meta = old;
+        for ev in events.iter() {
+            meta.apply(ev);
+        }
+        assert_eq!(meta, new);
+        assert!(new.diff(&new).is_empty());
+        assert_eq!(FrameMeta::fold(&[old, new, new]).probe_rate, Some(50.0));
+
+        // Discontinuities are written every time, and last one frame.
+        let jump = Discontinuity {
+            step_secs: 2.0,
+            suspend_secs: 60.0,
+        };
+        let after = FrameMeta {
+            discontinuity: Some(jump),
+            ..new
+        };
+        let events = new.diff(&after);
+        assert_eq!(events, vec![LogEvent::Discontinuity(jump)]);
+        let mut meta = new;
+        meta.apply(&events[0]);
+        assert_eq!(meta, after);
+        assert_eq!(meta.next(), new);
+        let folded = FrameMeta::fold(&[after, new, after]).discontinuity;
+        assert_eq!(folded.unwrap().suspend_secs, 120.0);
+
+        // A restart records the stop and start before the first frame.
+        let start = DaemonStart {
+            reason: StartReason::Started,
+            config_hash: 42,
+        };
+        let restarted = FrameMeta {
+            daemon_start: Some(start),
+            daemon_stop: Some(StopReason::Signal),
+            ..new
+        };
+        let events = new.diff(&restarted);
+        assert_eq!(
+            events,
+            vec![
+                LogEvent::DaemonStop(StopReason::Signal),
+                LogEvent::DaemonStart(start)
+            ]
+        );
+        let mut meta = new;
+        events.iter().for_each(|ev| meta.apply(ev));
+        assert_eq!(meta, restarted);
+        assert_eq!(meta.next(), new);
+        assert_eq!(StartReason::parse("from_the_future"), StartReason::Other);
+
+        // WiFi samples are state too, written only when they change.
+        let wifi = FrameMeta {
+            wireless: Some(Wireless {
+                link: 54.0,
+                signal_dbm: -56.0,
+                ..Default::default()
+            }),
+            ..new
+        };
+        assert_eq!(new.diff(&wifi).len(), 1);
+        assert!(wifi.diff(&wifi).is_empty());
+        assert_eq!(wifi.next(), wifi);
+
+        // Interface counters fold to the worst second.
+        let busy = |rx, carrier_changes| FrameMeta {
+            link_stats: Some(LinkStats {
+                rx_bytes_sec: rx,
+                carrier_changes,
+                ..Default::default()
+            }),
+            ..new
+        };
+        assert_eq!(new.diff(&busy(1e6, 0)).len(), 1);
+        let folded = FrameMeta::fold(&[busy(1e6, 0), new, busy(10.0, 2)]).link_stats;
+        assert_eq!(folded.unwrap().rx_bytes_sec, 1e6);
+        assert_eq!(folded.unwrap().carrier_changes, 2);
+
+        // Untrusted frames taint the aggregate, and trust comes back with an
+        // event of its own.
+        let busy_host = FrameMeta {
+            untrusted: true,
+            ..new
+        };
+        assert_eq!(new.diff(&busy_host), vec![LogEvent::Untrusted(true)]);
+        assert_eq!(busy_host.diff(&new), vec![LogEvent::Untrusted(false)]);
+        assert!(FrameMeta::fold(&[new, busy_host, new]).untrusted);
+    }
+}
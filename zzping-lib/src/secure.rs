@@ -0,0 +1,304 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Authenticates the UDP datagrams between the daemon and the GUIs with a
+//! pre-shared key, and optionally encrypts them.
+//!
+//! Each datagram is wrapped as:
+//!
+//! ```text
+//! "ZZS1" | flags: u8 | sender: u64 | seq: u64 | sent_ms: i64 | payload | tag: [u8; 32]
+//! ```
+//!
+//! Numbers are big endian. The sender is random for each run, seq counts the
+//! datagrams it sealed and sent_ms is its wall clock. The tag is the
+//! HMAC-SHA256 of everything before it. With encryption, the payload is
+//! XChaCha20 with the sender and seq as the nonce, and the tag covers the
+//! encrypted payload.
+//!
+//! Replays are told by the sequence: each sender gets a window of the last
+//! 64 seen. A sender not seen before must have a clock close to ours, so
+//! datagrams recorded from an older run can't be replayed to a receiver
+//! that just started.
+
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use chacha20::XChaCha20;
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha2::Sha256;
+use thiserror::Error;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const MAGIC: &[u8; 4] = b"ZZS1";
+const FLAG_ENCRYPTED: u8 = 0x1;
+const HEADER_LEN: usize = 4 + 1 + 8 + 8 + 8;
+const TAG_LEN: usize = 32;
+
+/// Shortest key accepted. The key is used as is, so it has to be hard to
+/// guess by itself.
+pub const MIN_PSK_LEN: usize = 16;
+
+/// How far the clock of a sender not seen before can be from ours.
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(120);
+
+/// Senders remembered. The one not heard from for longer goes first.
+const MAX_SENDERS: usize = 16;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum SecureError {
+    #[error("not a sealed datagram, is the psk set on both sides?")]
+    NotSealed,
+    #[error("the authentication tag doesn't match, is the psk the same on both sides?")]
+    BadTag,
+    #[error("the datagram is not encrypted, but encryption is required")]
+    NotEncrypted,
+    #[error("datagram replayed (sequence {0})")]
+    Replayed(u64),
+    #[error("new sender with a clock {0}s apart from ours, or an old datagram replayed")]
+    Stale(i64),
+}
+
+/// Whether "datagram" looks sealed, to tell apart a psk missing on this side.
+pub fn is_sealed(datagram: &[u8]) -> bool {
+    datagram.starts_with(MAGIC)
+}
+
+/// Sequences seen from a sender.
+#[derive(Debug, Clone, Copy)]
+struct Window {
+    highest: u64,
+    /// Bit n set if "highest - n" was seen.
+    seen: u64,
+    last_used: SystemTime,
+}
+
+impl Window {
+    fn new(seq: u64, now: SystemTime) -> Self {
+        Self {
+            highest: seq,
+            seen: 1,
+            last_used: now,
+        }
+    }
+
+    /// Marks "seq" as seen. False if it was already, or too old to know.
+    fn check(&mut self, seq: u64, now: SystemTime) -> bool {
+        if seq > self.highest {
+            let shift = seq - self.highest;
+            self.seen = match shift < 64 {
+                true => self.seen << shift,
+                false => 0,
+            } | 1;
+            self.highest = seq;
+        } else {
+            let bit = match self.highest - seq {
+                age if age < 64 => 1 << age,
+                _ => return false,
+            };
+            if self.seen & bit != 0 {
+                return false;
+            }
+            self.seen |= bit;
+        }
+        self.last_used = now;
+        true
+    }
+}
+
+/// One side of the channel: seals what it sends, opens what it receives.
+pub struct SecureChannel {
+    mac_key: [u8; 32],
+    enc_key: [u8; 32],
+    /// Encrypt what's sent, and refuse plain datagrams.
+    encrypt: bool,
+    sender: u64,
+    seq: u64,
+    peers: HashMap<u64, Window>,
+}
+
+impl std::fmt::Debug for SecureChannel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SecureChannel")
+            .field("encrypt", &self.encrypt)
+            .field("sender", &self.sender)
+            .field("seq", &self.seq)
+            .finish()
+    }
+}
+
+impl SecureChannel {
+    pub fn new(psk: &str, encrypt: bool) -> Self {
+        Self {
+            mac_key: derive_key(psk, b"zzping datagram authentication"),
+            enc_key: derive_key(psk, b"zzping datagram encryption"),
+            encrypt,
+            sender: rand::thread_rng().gen(),
+            seq: 0,
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Wraps "payload" to be sent.
+    pub fn seal(&mut self, payload: &[u8], now: SystemTime) -> Vec<u8> {
+        self.seq += 1;
+        let mut out = Vec::with_capacity(HEADER_LEN + payload.len() + TAG_LEN);
+        out.extend_from_slice(MAGIC);
+        out.push(match self.encrypt {
+            true => FLAG_ENCRYPTED,
+            false => 0,
+        });
+        out.extend_from_slice(&self.sender.to_be_bytes());
+        out.extend_from_slice(&self.seq.to_be_bytes());
+        out.extend_from_slice(&unix_ms(now).to_be_bytes());
+        out.extend_from_slice(payload);
+        if self.encrypt {
+            self.cipher(self.sender, self.seq)
+                .apply_keystream(&mut out[HEADER_LEN..]);
+        }
+        let mut mac = self.mac();
+        mac.update(&out);
+        out.extend_from_slice(&mac.finalize().into_bytes());
+        out
+    }
+
+    /// Checks a datagram received and returns its payload.
+    pub fn open(&mut self, datagram: &[u8], now: SystemTime) -> Result<Vec<u8>, SecureError> {
+        if datagram.len() < HEADER_LEN + TAG_LEN || !is_sealed(datagram) {
+            return Err(SecureError::NotSealed);
+        }
+        let (body, tag) = datagram.split_at(datagram.len() - TAG_LEN);
+        let mut mac = self.mac();
+        mac.update(body);
+        mac.verify_slice(tag).map_err(|_| SecureError::BadTag)?;
+
+        let number = |at: usize| u64::from_be_bytes(body[at..at + 8].try_into().unwrap());
+        let encrypted = body[4] & FLAG_ENCRYPTED != 0;
+        let (sender, seq, sent_ms) = (number(5), number(13), number(21) as i64);
+        if self.encrypt && !encrypted {
+            return Err(SecureError::NotEncrypted);
+        }
+        match self.peers.get_mut(&sender) {
+            Some(window) => {
+                if !window.check(seq, now) {
+                    return Err(SecureError::Replayed(seq));
+                }
+            }
+            None => {
+                let skew_secs = (unix_ms(now) - sent_ms) / 1000;
+                if skew_secs.unsigned_abs() > MAX_CLOCK_SKEW.as_secs() {
+                    return Err(SecureError::Stale(skew_secs));
+                }
+                if self.peers.len() >= MAX_SENDERS {
+                    let oldest = self.peers.iter().min_by_key(|(_, w)| w.last_used);
+                    let oldest = *oldest.unwrap().0;
+                    self.peers.remove(&oldest);
+                }
+                self.peers.insert(sender, Window::new(seq, now));
+            }
+        }
+
+        let mut payload = body[HEADER_LEN..].to_vec();
+        if encrypted {
+            self.cipher(sender, seq).apply_keystream(&mut payload);
+        }
+        Ok(payload)
+    }
+
+    fn mac(&self) -> HmacSha256 {
+        HmacSha256::new_from_slice(&self.mac_key).unwrap()
+    }
+
+    fn cipher(&self, sender: u64, seq: u64) -> XChaCha20 {
+        let mut nonce = [0_u8; 24];
+        nonce[..8].copy_from_slice(&sender.to_be_bytes());
+        nonce[8..16].copy_from_slice(&seq.to_be_bytes());
+        XChaCha20::new(&self.enc_key.into(), &nonce.into())
+    }
+}
+
+/// A key for each use, so the same psk is never used for two things.
+fn derive_key(psk: &str, purpose: &[u8]) -> [u8; 32] {
+    let mut mac = HmacSha256::new_from_slice(psk.as_bytes()).unwrap();
+    mac.update(purpose);
+    mac.finalize().into_bytes().into()
+}
+
+fn unix_ms(t: SystemTime) -> i64 {
+    match t.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(d) => d.as_millis() as i64,
+        Err(e) => -(e.duration().as_millis() as i64),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PSK: &str = "correct horse battery staple";
+
+    #[test]
+    fn test_seal_open() {
+        let now = SystemTime::now();
+        for encrypt in [false, true] {
+            let mut daemon = SecureChannel::new(PSK, encrypt);
+            let mut gui = SecureChannel::new(PSK, encrypt);
+            let sealed = daemon.seal(b"frame stats", now);
+            assert!(is_sealed(&sealed));
+            let shows_plain = sealed.windows(11).any(|w| w == b"frame stats");
+            assert_eq!(shows_plain, !encrypt);
+            assert_eq!(gui.open(&sealed, now).unwrap(), b"frame stats");
+            assert_eq!(gui.open(&sealed, now), Err(SecureError::Replayed(1)));
+
+            // Any change is caught, and so is another key.
+            let mut tampered = daemon.seal(b"frame stats", now);
+            tampered[HEADER_LEN] ^= 1;
+            assert_eq!(gui.open(&tampered, now), Err(SecureError::BadTag));
+            let mut other = SecureChannel::new("another key, just as long", encrypt);
+            let sealed = daemon.seal(b"frame stats", now);
+            assert_eq!(other.open(&sealed, now), Err(SecureError::BadTag));
+            assert_eq!(gui.open(b"\x95plain", now), Err(SecureError::NotSealed));
+        }
+
+        // A receiver that encrypts refuses plain datagrams.
+        let mut plain = SecureChannel::new(PSK, false);
+        let mut strict = SecureChannel::new(PSK, true);
+        let sealed = plain.seal(b"x", now);
+        assert_eq!(strict.open(&sealed, now), Err(SecureError::NotEncrypted));
+    }
+
+    #[test]
+    fn test_replay_window() {
+        let now = SystemTime::now();
+        let mut daemon = SecureChannel::new(PSK, false);
+        let mut gui = SecureChannel::new(PSK, false);
+        let sealed: Vec<Vec<u8>> = (0..100).map(|_| daemon.seal(b"x", now)).collect();
+
+        // Out of order is fine, twice is not, and too far back is refused.
+        assert!(gui.open(&sealed[10], now).is_ok());
+        assert!(gui.open(&sealed[5], now).is_ok());
+        assert!(gui.open(&sealed[5], now).is_err());
+        assert!(gui.open(&sealed[90], now).is_ok());
+        assert!(gui.open(&sealed[20], now).is_err());
+        assert!(gui.open(&sealed[40], now).is_ok());
+
+        // Recorded from a run long ago, to a receiver that never saw it.
+        let mut fresh = SecureChannel::new(PSK, false);
+        let later = now + Duration::from_secs(3600);
+        assert_eq!(fresh.open(&sealed[0], later), Err(SecureError::Stale(3600)));
+    }
+}
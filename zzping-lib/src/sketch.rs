@@ -0,0 +1,172 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Latency sketches: histograms with logarithmic buckets that can be merged,
+//! so the percentiles of many frames together can be told from their sketches
+//! alone.
+//!
+//! Bucket 0 holds the zeros, and bucket k the values over those of bucket k-1
+//! up to GAMMA^(k-1) microseconds. Any percentile read back is within
+//! RELATIVE_ERROR of a value that was there, whatever the number of frames
+//! merged. Only the buckets used are kept, so a frame with a couple of replies
+//! takes a few bytes.
+//!
+//! Encoded as an array of unsigned ints: for each bucket used, the distance to
+//! the previous one (or its index for the first) and its count.
+
+use anyhow::Result;
+use dynrmp::variant::Variant;
+
+use crate::dynrmp;
+
+/// Relative error of the values read back.
+pub const RELATIVE_ERROR: f64 = 0.01;
+
+/// Ratio between the bounds of a bucket.
+const GAMMA: f64 = (1.0 + RELATIVE_ERROR) / (1.0 - RELATIVE_ERROR);
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LatencySketch {
+    /// Index and count of the buckets used, by index.
+    buckets: Vec<(u16, u32)>,
+}
+
+impl LatencySketch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_values(values: &[u128]) -> Self {
+        let mut sketch = Self::new();
+        for v in values {
+            sketch.add(*v);
+        }
+        sketch
+    }
+
+    pub fn add(&mut self, value_us: u128) {
+        let index = match value_us {
+            0 => 0,
+            v => (((v as f64).ln() / GAMMA.ln()).ceil() as u16).saturating_add(1),
+        };
+        self.add_bucket(index, 1);
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        for (index, n) in other.buckets.iter() {
+            self.add_bucket(*index, *n);
+        }
+    }
+
+    fn add_bucket(&mut self, index: u16, n: u32) {
+        match self.buckets.binary_search_by_key(&index, |b| b.0) {
+            Ok(at) => self.buckets[at].1 += n,
+            Err(at) => self.buckets.insert(at, (index, n)),
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().map(|b| b.1 as u64).sum()
+    }
+
+    /// The value at "p" (0.0 to 1.0) of the way through the values, or None
+    /// if there are none.
+    pub fn quantile(&self, p: f32) -> Option<i64> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let rank = (p.clamp(0.0, 1.0) as f64 * (count - 1) as f64).round() as u64;
+        let mut seen = 0;
+        for (index, n) in self.buckets.iter() {
+            seen += *n as u64;
+            if seen > rank {
+                return Some(Self::value_of(*index));
+            }
+        }
+        self.buckets.last().map(|b| Self::value_of(b.0))
+    }
+
+    /// The value of a bucket, as far from both of its bounds.
+    fn value_of(index: u16) -> i64 {
+        match index {
+            0 => 0,
+            k => (2.0 * GAMMA.powi(k as i32 - 1) / (GAMMA + 1.0)).round() as i64,
+        }
+    }
+
+    pub fn write_rmp(&self, wr: &mut Vec<u8>) -> Result<(), rmp::encode::ValueWriteError> {
+        rmp::encode::write_array_len(wr, 2 * self.buckets.len() as u32)?;
+        let mut prev = 0;
+        for (index, n) in self.buckets.iter() {
+            rmp::encode::write_uint(wr, (*index - prev) as u64)?;
+            rmp::encode::write_uint(wr, *n as u64)?;
+            prev = *index;
+        }
+        Ok(())
+    }
+
+    pub fn from_variant(var: &Variant) -> Result<Self> {
+        let values = var.slice()?;
+        let mut buckets = Vec::with_capacity(values.len() / 2);
+        let mut index = 0_u16;
+        for pair in values.chunks_exact(2) {
+            index += pair[0].int()? as u16;
+            buckets.push((index, pair[1].int()? as u32));
+        }
+        Ok(Self { buckets })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sketch_merge() {
+        // Two frames far apart: percentiles of the raw values, within error.
+        let fast: Vec<u128> = (0..500).map(|n| 1000 + n).collect();
+        let slow: Vec<u128> = (0..100).map(|n| 80_000 + n * 100).collect();
+        let mut sketch = LatencySketch::from_values(&fast);
+        sketch.merge(&LatencySketch::from_values(&slow));
+        assert_eq!(sketch.count(), 600);
+        let mut all: Vec<u128> = fast.iter().chain(slow.iter()).copied().collect();
+        all.sort_unstable();
+        for p in [0.0, 0.25, 0.5, 0.8, 0.9, 0.99, 1.0] {
+            let want = all[(p * 599.0_f32).round() as usize] as f64;
+            let got = sketch.quantile(p).unwrap() as f64;
+            assert!(
+                (got - want).abs() <= want * RELATIVE_ERROR + 1.0,
+                "p{}: {} vs {}",
+                p,
+                got,
+                want
+            );
+        }
+
+        // Merging is the same as adding everything to one.
+        let mut one = LatencySketch::new();
+        for v in slow.iter().chain(fast.iter()) {
+            one.add(*v);
+        }
+        assert_eq!(one, sketch);
+        assert_eq!(LatencySketch::new().quantile(0.5), None);
+
+        let mut buf = vec![];
+        sketch.write_rmp(&mut buf).unwrap();
+        let var = Variant::read(&mut &buf[..]).unwrap();
+        assert_eq!(LatencySketch::from_variant(&var).unwrap(), sketch);
+        assert_eq!(LatencySketch::from_values(&[0, 0]).quantile(0.5), Some(0));
+    }
+}
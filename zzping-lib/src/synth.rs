@@ -0,0 +1,623 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Synthetic logs, generated from a scenario description.
+//!
+//! A Scenario describes a connection: its baseline latency and loss, how they
+//! change over the day, WiFi-like latency spikes, outages where nothing gets
+//! back, gaps where the daemon wasn't running, suspends of the host and jumps
+//! of the wall clock.
+//! The Generator simulates every ping sent over it and yields the frames the
+//! daemon would have written, counting inflight, lost and received packets the
+//! same way. The same scenario and seed always yield the same frames.
+//!
+//! Times in the scenario are seconds of real time since the start of the log;
+//! wall clock jumps don't move them.
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, VecDeque};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Timelike, Utc};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::Deserialize;
+
+use crate::framedata::{FrameData, FrameTime};
+use crate::logevent::{DaemonStart, Discontinuity, FrameMeta, StartReason, StopReason};
+
+/// Time until a ping without reply is declared lost. Same as the daemon default.
+const INFLIGHT_TIME: Duration = Duration::from_secs(10);
+/// Time lost pings are remembered. Same as the daemon default.
+const LOST_TIME: Duration = Duration::from_secs(10);
+/// Maximum time between keyframes. Same as the daemon.
+const KEYFRAME_EVERY: Duration = Duration::from_secs(15);
+/// Highest probe_rate and frame_rate accepted, per second. Over it, the
+/// interval between events would round down to nothing.
+const MAX_RATE: f32 = 100_000.0;
+/// Start written on the first frame, and after each gap, which ends with the
+/// daemon stopped by a signal.
+const DAEMON_START: DaemonStart = DaemonStart {
+    reason: StartReason::Started,
+    config_hash: 0,
+};
+
+/// Description of the connection to simulate.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Scenario {
+    /// Wall clock time of the first frame, in RFC 3339.
+    pub start: String,
+    /// Length of the log.
+    pub duration_secs: u64,
+    /// Seed for all the random choices.
+    pub seed: u64,
+    /// Pings per second.
+    pub probe_rate: f32,
+    /// Frames per second, as refresh_freq in the daemon.
+    pub frame_rate: f32,
+    /// Round trip time while nothing else happens.
+    pub latency: Latency,
+    /// Chance of each ping being lost, in percent.
+    pub loss_pct: f64,
+    /// Daily cycle, i.e. latency going up on the evenings.
+    pub diurnal: Option<Diurnal>,
+    /// Short bursts of latency and loss, as seen on WiFi.
+    pub spikes: Option<Spikes>,
+    /// Spans where no ping gets a reply.
+    pub outages: Vec<Span>,
+    /// Spans where the daemon wasn't running, with no pings nor frames.
+    pub gaps: Vec<Span>,
+    /// Spans where the host was suspended. The daemon writes a discontinuity
+    /// when it wakes up.
+    pub suspends: Vec<Span>,
+    /// Steps of the wall clock, as done by NTP.
+    pub clock_jumps: Vec<ClockJump>,
+}
+
+impl Default for Scenario {
+    fn default() -> Self {
+        Self {
+            start: "2021-01-01T00:00:00Z".to_owned(),
+            duration_secs: 3600,
+            seed: 0,
+            probe_rate: 100.0,
+            frame_rate: 50.0,
+            latency: Latency::default(),
+            loss_pct: 0.0,
+            diurnal: None,
+            spikes: None,
+            outages: vec![],
+            gaps: vec![],
+            suspends: vec![],
+            clock_jumps: vec![],
+        }
+    }
+}
+
+/// Round trip time: a base, plus a random tail.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Latency {
+    pub base_ms: f64,
+    /// Mean of the exponentially distributed time added to each ping.
+    pub jitter_ms: f64,
+}
+
+impl Default for Latency {
+    fn default() -> Self {
+        Self {
+            base_ms: 10.0,
+            jitter_ms: 1.0,
+        }
+    }
+}
+
+/// Change over the day, highest at "peak_hour" (UTC) and none 12h later.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Diurnal {
+    /// Latency added at the peak.
+    pub amplitude_ms: f64,
+    /// Loss added at the peak, in percent.
+    pub loss_pct: f64,
+    pub peak_hour: f64,
+}
+
+impl Default for Diurnal {
+    fn default() -> Self {
+        Self {
+            amplitude_ms: 5.0,
+            loss_pct: 0.0,
+            peak_hour: 21.0,
+        }
+    }
+}
+
+/// Latency spikes, starting at random.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Spikes {
+    /// Mean number of spikes per hour.
+    pub per_hour: f64,
+    pub duration_ms: u64,
+    /// Mean latency added during a spike. Each ping gets 0.5 to 1.5 times this.
+    pub extra_ms: f64,
+    /// Loss added during a spike, in percent.
+    pub loss_pct: f64,
+}
+
+impl Default for Spikes {
+    fn default() -> Self {
+        Self {
+            per_hour: 30.0,
+            duration_ms: 500,
+            extra_ms: 100.0,
+            loss_pct: 10.0,
+        }
+    }
+}
+
+/// Span of time, from "at_secs" since the start.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct Span {
+    pub at_secs: u64,
+    pub duration_secs: u64,
+}
+
+impl Span {
+    fn start(&self) -> Duration {
+        Duration::from_secs(self.at_secs)
+    }
+    fn end(&self) -> Duration {
+        Duration::from_secs(self.at_secs + self.duration_secs)
+    }
+    fn contains(&self, t: Duration) -> bool {
+        t >= self.start() && t < self.end()
+    }
+}
+
+/// Wall clock jump of "jump_secs" (negative goes back) at "at_secs".
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct ClockJump {
+    pub at_secs: u64,
+    pub jump_secs: i64,
+}
+
+impl Scenario {
+    pub fn from_ron(contents: &str) -> Result<Self> {
+        let scenario: Self = ron::from_str(contents).context("Error parsing the scenario")?;
+        scenario.check()?;
+        Ok(scenario)
+    }
+
+    pub fn from_filepath(filepath: &str) -> Result<Self> {
+        let contents = std::fs::read_to_string(filepath)
+            .with_context(|| format!("Error reading {}", filepath))?;
+        Self::from_ron(&contents)
+    }
+
+    /// Wall clock time of the first frame.
+    pub fn start_time(&self) -> Result<DateTime<Utc>> {
+        Ok(DateTime::parse_from_rfc3339(&self.start)
+            .with_context(|| format!("Invalid start time {:?}", self.start))?
+            .with_timezone(&Utc))
+    }
+
+    /// Rejects values the generator can't work with.
+    pub fn check(&self) -> Result<()> {
+        self.start_time()?;
+        for (name, rate) in [
+            ("probe_rate", self.probe_rate),
+            ("frame_rate", self.frame_rate),
+        ] {
+            if !(rate > 0.0 && rate <= MAX_RATE) {
+                bail!("{} must be above 0 and at most {}", name, MAX_RATE);
+            }
+        }
+        if self.latency.base_ms < 0.0 || self.latency.jitter_ms < 0.0 {
+            bail!("latency can't be negative");
+        }
+        if let Some(spikes) = &self.spikes {
+            if spikes.per_hour < 0.0 {
+                bail!("spikes per_hour can't be negative");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Time between events happening "rate" times per second, rounded to the
+/// nanosecond so they don't drift.
+fn interval(rate: f32) -> Duration {
+    Duration::from_nanos((1e9 / rate as f64).round() as u64)
+}
+
+/// Ping whose reply is on its way back, ordered by arrival.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Reply {
+    arrival: Duration,
+    sent: Duration,
+}
+
+/// Simulates the pings of a Scenario and yields the frames of its log.
+#[derive(Debug)]
+pub struct Generator {
+    scenario: Scenario,
+    rng: StdRng,
+    start: DateTime<Utc>,
+    end: Duration,
+    ping_interval: Duration,
+    frame_interval: Duration,
+    next_ping: Duration,
+    next_frame: Duration,
+    /// Wall clock time minus the monotonic one, moved by the clock jumps.
+    wall_offset: chrono::Duration,
+    /// Clock jumps not applied yet.
+    jumps: VecDeque<ClockJump>,
+    /// Discontinuity to write with the next frame.
+    discontinuity: Option<Discontinuity>,
+    /// Daemon start and stop to write with the next frame.
+    daemon_start: Option<DaemonStart>,
+    daemon_stop: Option<StopReason>,
+    next_spike: Duration,
+    spike_until: Duration,
+    last_keyframe: Option<Duration>,
+    /// Replies on their way back, earliest first.
+    awaiting: BinaryHeap<Reverse<Reply>>,
+    /// Replies already back: arrival and round trip time.
+    arrived: VecDeque<(Duration, Duration)>,
+    /// Send time of the pings that won't get a reply, until forgotten.
+    lost: VecDeque<Duration>,
+}
+
+impl Generator {
+    pub fn new(scenario: Scenario) -> Result<Self> {
+        scenario.check()?;
+        let mut jumps: Vec<ClockJump> = scenario.clock_jumps.clone();
+        jumps.sort_by_key(|x| x.at_secs);
+        let mut generator = Self {
+            rng: StdRng::seed_from_u64(scenario.seed),
+            start: scenario.start_time()?,
+            end: Duration::from_secs(scenario.duration_secs),
+            ping_interval: interval(scenario.probe_rate),
+            frame_interval: interval(scenario.frame_rate),
+            next_ping: Duration::ZERO,
+            next_frame: Duration::ZERO,
+            wall_offset: chrono::Duration::zero(),
+            jumps: jumps.into(),
+            discontinuity: None,
+            daemon_start: Some(DAEMON_START),
+            daemon_stop: None,
+            next_spike: Duration::ZERO,
+            spike_until: Duration::ZERO,
+            last_keyframe: None,
+            awaiting: BinaryHeap::new(),
+            arrived: VecDeque::new(),
+            lost: VecDeque::new(),
+            scenario,
+        };
+        generator.next_spike = generator.time_to_spike();
+        Ok(generator)
+    }
+
+    /// Random time until the next spike starts.
+    fn time_to_spike(&mut self) -> Duration {
+        let per_hour = self.scenario.spikes.as_ref().map_or(0.0, |x| x.per_hour);
+        if per_hour <= 0.0 {
+            return Duration::MAX;
+        }
+        let u: f64 = 1.0 - self.rng.gen::<f64>();
+        Duration::from_secs_f64(-u.ln() * 3600.0 / per_hour)
+    }
+
+    fn wall(&self, t: Duration) -> DateTime<Utc> {
+        self.start + chrono::Duration::from_std(t).unwrap() + self.wall_offset
+    }
+
+    /// End of the span that "t" falls in, if any.
+    fn span_end(spans: &[Span], t: Duration) -> Option<Duration> {
+        spans
+            .iter()
+            .filter(|x| x.contains(t))
+            .map(|x| x.end())
+            .max()
+    }
+
+    /// End of the gap or suspend that "t" falls in, if any. No pings are sent
+    /// during them.
+    fn pause_end(&self, t: Duration) -> Option<Duration> {
+        let gap = Self::span_end(&self.scenario.gaps, t);
+        gap.max(Self::span_end(&self.scenario.suspends, t))
+    }
+
+    /// Sends a ping at "t", deciding whether and when its reply gets back.
+    fn ping(&mut self, t: Duration) {
+        let scenario = &self.scenario;
+        let mut loss_pct = scenario.loss_pct;
+        let mut rtt_ms = scenario.latency.base_ms;
+        if let Some(diurnal) = &scenario.diurnal {
+            let wall = self.wall(t);
+            let hour = wall.num_seconds_from_midnight() as f64 / 3600.0;
+            let angle = (hour - diurnal.peak_hour) / 24.0 * std::f64::consts::TAU;
+            let factor = (1.0 + angle.cos()) / 2.0;
+            rtt_ms += diurnal.amplitude_ms * factor;
+            loss_pct += diurnal.loss_pct * factor;
+        }
+        if let Some(spikes) = scenario.spikes.clone() {
+            while self.next_spike <= t {
+                self.spike_until = self.next_spike + Duration::from_millis(spikes.duration_ms);
+                self.next_spike = self.next_spike.saturating_add(self.time_to_spike());
+            }
+            if t < self.spike_until {
+                rtt_ms += spikes.extra_ms * (0.5 + self.rng.gen::<f64>());
+                loss_pct += spikes.loss_pct;
+            }
+        }
+        let outage = self.scenario.outages.iter().any(|x| x.contains(t));
+        if outage || self.rng.gen_bool((loss_pct / 100.0).clamp(0.0, 1.0)) {
+            self.lost.push_back(t);
+            return;
+        }
+        let u: f64 = 1.0 - self.rng.gen::<f64>();
+        rtt_ms += -u.ln() * self.scenario.latency.jitter_ms;
+        let rtt = Duration::from_secs_f64(rtt_ms / 1000.0);
+        self.awaiting.push(Reverse(Reply {
+            arrival: t + rtt,
+            sent: t,
+        }));
+    }
+
+    /// Forgets all pings, as a daemon restart would.
+    fn restart(&mut self) {
+        self.awaiting.clear();
+        self.arrived.clear();
+        self.lost.clear();
+        self.last_keyframe = None;
+        self.daemon_start = Some(DAEMON_START);
+        self.daemon_stop = Some(StopReason::Signal);
+    }
+
+    /// Wakes up from a suspend of "span". Replies due meanwhile are never
+    /// read, so they end up lost.
+    fn resume(&mut self, span: Duration) {
+        for Reverse(reply) in std::mem::take(&mut self.awaiting).into_sorted_vec() {
+            let at = self.lost.partition_point(|x| *x < reply.sent);
+            self.lost.insert(at, reply.sent);
+        }
+        let d = Discontinuity {
+            step_secs: 0.0,
+            suspend_secs: span.as_secs_f64(),
+        };
+        self.add_discontinuity(d);
+    }
+
+    fn add_discontinuity(&mut self, d: Discontinuity) {
+        self.discontinuity = Some(match self.discontinuity {
+            Some(prev) => prev.add(&d),
+            None => d,
+        });
+    }
+
+    /// Builds the frame written at "t", after sending the pings up to it.
+    fn frame(&mut self, t: Duration) -> FrameData {
+        while self.next_ping <= t {
+            if let Some(end) = self.pause_end(self.next_ping) {
+                self.next_ping = end;
+                continue;
+            }
+            self.ping(self.next_ping);
+            self.next_ping += self.ping_interval;
+        }
+        while let Some(Reverse(reply)) = self.awaiting.peek().copied() {
+            if reply.arrival > t {
+                break;
+            }
+            self.awaiting.pop();
+            self.arrived
+                .push_back((reply.arrival, reply.arrival - reply.sent));
+        }
+        let recv_window = self.frame_interval + self.frame_interval / 2;
+        while matches!(self.arrived.front(), Some((arrival, _)) if t - *arrival >= recv_window) {
+            self.arrived.pop_front();
+        }
+        while matches!(self.lost.front(), Some(sent) if t - *sent >= INFLIGHT_TIME + LOST_TIME) {
+            self.lost.pop_front();
+        }
+        // Pings without reply yet count as inflight once a frame old, and as
+        // lost after INFLIGHT_TIME.
+        let declared = self.lost.partition_point(|x| t - *x >= INFLIGHT_TIME);
+        let pending_lost = self.lost.partition_point(|x| t - *x >= self.frame_interval);
+        let pending_recv = self
+            .awaiting
+            .iter()
+            .filter(|Reverse(x)| t - x.sent >= self.frame_interval)
+            .count();
+        let mut recv_us: Vec<u128> = self.arrived.iter().map(|x| x.1.as_micros()).collect();
+        recv_us.sort_unstable();
+
+        while matches!(self.jumps.front(), Some(jump) if Duration::from_secs(jump.at_secs) <= t) {
+            let jump = self.jumps.pop_front().unwrap();
+            self.wall_offset = self.wall_offset + chrono::Duration::seconds(jump.jump_secs);
+            self.add_discontinuity(Discontinuity {
+                step_secs: jump.jump_secs as f64,
+                suspend_secs: 0.0,
+            });
+        }
+        let discontinuity = self.discontinuity.take();
+        let time = match self.last_keyframe {
+            Some(last) if discontinuity.is_none() && t - last <= KEYFRAME_EVERY => {
+                FrameTime::Elapsed(t - last)
+            }
+            _ => {
+                self.last_keyframe = Some(t);
+                FrameTime::Timestamp(self.wall(t))
+            }
+        };
+        FrameData {
+            time,
+            inflight: pending_recv + pending_lost - declared,
+            lost_packets: declared,
+            recv_us,
+            meta: FrameMeta {
+                probe_rate: Some(self.scenario.probe_rate),
+                discontinuity,
+                daemon_start: self.daemon_start.take(),
+                daemon_stop: self.daemon_stop.take(),
+                wireless: None,
+                link_stats: None,
+                host_health: None,
+                untrusted: false,
+                peer_path: None,
+            },
+        }
+    }
+}
+
+impl Iterator for Generator {
+    type Item = FrameData;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut t = self.next_frame;
+        if let Some(end) = Self::span_end(&self.scenario.gaps, t) {
+            self.restart();
+            t = end;
+        }
+        if let Some(end) = Self::span_end(&self.scenario.suspends, t) {
+            let span = self
+                .scenario
+                .suspends
+                .iter()
+                .find(|x| x.contains(t))
+                .unwrap();
+            self.resume(end - span.start());
+            t = end;
+        }
+        if t >= self.end {
+            return None;
+        }
+        self.next_frame = t + self.frame_interval;
+        Some(self.frame(t))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framedata::FrameDataVec;
+
+    fn scenario() -> Scenario {
+        Scenario {
+            duration_secs: 120,
+            probe_rate: 20.0,
+            frame_rate: 10.0,
+            loss_pct: 1.0,
+            spikes: Some(Spikes::default()),
+            outages: vec![Span {
+                at_secs: 30,
+                duration_secs: 5,
+            }],
+            gaps: vec![Span {
+                at_secs: 60,
+                duration_secs: 10,
+            }],
+            clock_jumps: vec![ClockJump {
+                at_secs: 90,
+                jump_secs: -3600,
+            }],
+            suspends: vec![Span {
+                at_secs: 100,
+                duration_secs: 5,
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_scenario_parse() {
+        let s = Scenario::from_ron(
+            "Scenario(duration_secs: 60, latency: (base_ms: 20.0), \
+             outages: [(at_secs: 10, duration_secs: 2)])",
+        )
+        .unwrap();
+        assert_eq!(s.duration_secs, 60);
+        assert_eq!(s.latency.base_ms, 20.0);
+        assert_eq!(s.latency.jitter_ms, 1.0);
+        assert_eq!(s.outages.len(), 1);
+        assert!(Scenario::from_ron("Scenario(probe_rate: 0.0)").is_err());
+        assert!(Scenario::from_ron("Scenario(probe_rate: 1e12)").is_err());
+        assert!(Scenario::from_ron("Scenario(frame_rate: 1e9)").is_err());
+        assert!(Scenario::from_ron("Scenario(start: \"yesterday\")").is_err());
+    }
+
+    #[test]
+    fn test_generator() {
+        let frames: Vec<FrameData> = Generator::new(scenario()).unwrap().collect();
+        let again: Vec<FrameData> = Generator::new(scenario()).unwrap().collect();
+        let recv = |v: &[FrameData]| v.iter().map(|x| x.recv_us.clone()).collect::<Vec<_>>();
+        assert_eq!(recv(&frames), recv(&again));
+        // 120s at 10 frames per second, minus the 10s gap and 5s suspend.
+        assert_eq!(frames.len(), 1050);
+
+        // Written as the daemon does, and read back with absolute times.
+        let mut buf = vec![];
+        let mut meta = FrameMeta::default();
+        for frame in frames.iter() {
+            for event in meta.diff(&frame.meta) {
+                event.encode(&mut buf).unwrap();
+            }
+            meta = frame.meta;
+            frame.encode(&mut buf).unwrap();
+        }
+        let mut fdv = FrameDataVec::new();
+        fdv.read(&mut &buf[..], frames.len() as u64).unwrap();
+        let start = scenario().start_time().unwrap();
+        let at = |secs: f64| {
+            let wall = start + chrono::Duration::milliseconds((secs * 1000.0) as i64);
+            fdv.v
+                .iter()
+                .position(|x| x.time == FrameTime::Timestamp(wall))
+        };
+        let normal = &fdv.v[at(20.0).unwrap()];
+        assert!(normal.recv_us.len() >= 2 && normal.recv_us[0] >= 10_000);
+        // Nothing gets back during the outage, and the pings sent in it are
+        // lost 10s later.
+        assert!(fdv.v[at(32.0).unwrap()].recv_us.is_empty());
+        assert!(fdv.v[at(32.0).unwrap()].inflight >= 35);
+        assert!(fdv.v[at(44.0).unwrap()].lost_packets >= 80);
+        // The gap starts over with a keyframe and nothing pending.
+        assert!(at(65.0).is_none());
+        let after_gap = &fdv.v[at(70.0).unwrap()];
+        assert_eq!((after_gap.inflight, after_gap.lost_packets), (0, 0));
+        assert_eq!(after_gap.meta.daemon_start, Some(DAEMON_START));
+        assert_eq!(after_gap.meta.daemon_stop, Some(StopReason::Signal));
+        assert_eq!(fdv.v[0].meta.daemon_start, Some(DAEMON_START));
+        assert_eq!(fdv.v[1].meta.daemon_start, None);
+        assert!(matches!(frames[600].time, FrameTime::Timestamp(_)));
+        // The clock jump goes back an hour, with a keyframe.
+        let jumped = at(90.0 - 3600.0).unwrap();
+        assert!(matches!(frames[jumped].time, FrameTime::Timestamp(_)));
+        let step = fdv.v[jumped].meta.discontinuity.unwrap();
+        assert_eq!((step.step_secs, step.suspend_secs), (-3600.0, 0.0));
+        assert_eq!(fdv.v[jumped + 1].meta.discontinuity, None);
+        // The pings pending on suspend get lost.
+        assert!(at(102.0 - 3600.0).is_none());
+        let resumed = &fdv.v[at(105.0 - 3600.0).unwrap()];
+        let suspend = resumed.meta.discontinuity.unwrap();
+        assert_eq!((suspend.step_secs, suspend.suspend_secs), (0.0, 5.0));
+        assert!(resumed.inflight > 0);
+        assert_eq!(fdv.v.len() - jumped, 250);
+    }
+}